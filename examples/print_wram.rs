@@ -0,0 +1,92 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Prints a WRAM byte every 60 ticks while a song plays, e.g. to follow a driver's position in
+//! its song data.
+//!
+//! `cargo run --example print_wram -- song.gbs C0A0 [SONG]`
+
+use std::{io, ops::ControlFlow, process};
+
+use gbsdiff::{
+    gbs::Gbs,
+    run::{self, CpuView, SimHooks, SimParams, Termination},
+};
+
+const PERIOD: u64 = 60;
+/// In double speed, this only lasts half as long.
+const CYCLES_PER_SEC: u32 = 1 << 20;
+
+struct PrintWram {
+    addr: u16,
+}
+
+impl SimHooks for PrintWram {
+    fn on_tick_end(&mut self, tick: u64, cpu: &CpuView) -> ControlFlow<Termination> {
+        if tick % PERIOD == 0 {
+            println!("tick {:5}: ${:02x}", tick, cpu.read(self.addr));
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+fn main() {
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    let (path, addr, song) = match args.as_slice() {
+        [path, addr] => (path, addr, None),
+        [path, addr, song] => (path, addr, Some(song)),
+        _ => {
+            eprintln!("Usage: print_wram <file.gbs> <WRAM address, in hex> [song]");
+            process::exit(2);
+        }
+    };
+    let addr = match u16::from_str_radix(addr.trim_start_matches('$'), 16) {
+        Ok(addr @ 0xC000..=0xDFFF) => addr,
+        _ => {
+            eprintln!("{:?} is not a WRAM address", addr);
+            process::exit(2);
+        }
+    };
+    let data = std::fs::read(path).unwrap_or_else(|err| {
+        eprintln!("{}: {}", path, err);
+        process::exit(2);
+    });
+    let gbs = Gbs::new(&data).unwrap_or_else(|err| {
+        eprintln!("{}: {}", path, err);
+        process::exit(2);
+    });
+    let song = song.map_or(gbs.first_song(), |song| {
+        song.parse().unwrap_or_else(|err| {
+            eprintln!("Bad song {:?}: {}", song, err);
+            process::exit(2);
+        })
+    });
+
+    // Five minutes, or until the song falls silent for five seconds.
+    let mut params = SimParams::new(CYCLES_PER_SEC * 60 * 5);
+    params.silence_timeout = CYCLES_PER_SEC * 5;
+    // Real drivers' INIT may well decompress things for a while.
+    params.max_func_cycles = 5_000_000;
+    let mut hooks = PrintWram { addr };
+    match run::simulate_song_with_hooks(
+        &gbs,
+        song,
+        &params,
+        None,
+        None::<io::Sink>,
+        None,
+        &mut hooks,
+    ) {
+        Ok(logbook) => println!(
+            "Ran for {} ticks, and ended due to {}",
+            logbook.ticks_simulated, logbook.termination
+        ),
+        Err(failure) => {
+            eprintln!("Simulation failed: {}", failure.error);
+            process::exit(1);
+        }
+    }
+}
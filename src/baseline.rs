@@ -0,0 +1,251 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with baselines, i.e. lists of known differences that shouldn't be reported.
+//!
+//! Baseline files have one difference per line: the song ID and tick (in decimal), then the
+//! register, the kind of difference, and the values involved (in hex). Anything after a `#` is a
+//! comment, so that entries can be annotated, and deleted by hand once they are no longer wanted.
+
+use std::{collections::BTreeMap, fmt::Display, io::Write};
+
+use crate::{diff::DiagnosticKind, Diagnostic};
+
+/// What identifies a difference across runs.
+///
+/// Cycles are deliberately not part of it, since any code change shifts them around; for the same
+/// reason, neither is how far a write [moved](DiagnosticKind::Moved), nor the extent of a
+/// [channel reallocation](DiagnosticKind::ChannelReallocation). The diagnostic's level isn't either,
+/// so that acknowledged differences stay acknowledged regardless of e.g. `--jitter`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BaselineKey {
+    song: u8,
+    tick: u64,
+    reg: u16,
+    kind: &'static str,
+    values: Vec<u16>,
+}
+
+impl BaselineKey {
+    /// `song` is the "before" song's ID.
+    pub fn new(song: u8, diag: &Diagnostic<DiagnosticKind>) -> Self {
+        let values = match diag.kind {
+            DiagnosticKind::Removed(_, value)
+            | DiagnosticKind::Added(_, value)
+            | DiagnosticKind::Moved(_, value, _)
+            | DiagnosticKind::TriggerChanged(_, _, value)
+            | DiagnosticKind::SlippedTick(_, value, ..) => vec![value.into()],
+            DiagnosticKind::OtherValue(_, before, after) => vec![before.into(), after.into()],
+            DiagnosticKind::OtherReg(before_reg, value, _) => vec![before_reg, value.into()],
+            DiagnosticKind::ChannelReallocation { from, to, .. } => vec![from.into(), to.into()],
+            DiagnosticKind::FreqChanged { before, after, .. } => vec![before, after],
+            DiagnosticKind::FreqOrderSwapped { high_first, .. } => vec![high_first.into()],
+            DiagnosticKind::DivResetMoved(_) => vec![],
+        };
+        Self {
+            song,
+            tick: diag.when.tick,
+            reg: diag.kind.reg(),
+            kind: diag.kind.name(),
+            values,
+        }
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    fn parse(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        let mut next = |what: &str| {
+            words
+                .next()
+                .ok_or_else(|| format!("missing {}", what))
+                .map(str::to_string)
+        };
+        let song = next("song ID")?;
+        let tick = next("tick")?;
+        let reg = next("register")?;
+        let kind = next("kind")?;
+        let values = words
+            .map(|value| {
+                u16::from_str_radix(value, 16).map_err(|err| format!("value {:?}: {}", value, err))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            song: song
+                .parse()
+                .map_err(|err| format!("song ID {:?}: {}", song, err))?,
+            tick: tick
+                .parse()
+                .map_err(|err| format!("tick {:?}: {}", tick, err))?,
+            reg: u16::from_str_radix(&reg, 16)
+                .map_err(|err| format!("register {:?}: {}", reg, err))?,
+            kind: DiagnosticKind::NAMES
+                .iter()
+                .find(|name| **name == kind)
+                .ok_or_else(|| {
+                    format!(
+                        "unknown kind {:?}, expected one of: {}",
+                        kind,
+                        DiagnosticKind::NAMES.join(", ")
+                    )
+                })?,
+            values,
+        })
+    }
+}
+
+impl Display for BaselineKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {:04x} {}",
+            self.song, self.tick, self.reg, self.kind
+        )?;
+        for value in &self.values {
+            write!(f, " {:02x}", value)?;
+        }
+        Ok(())
+    }
+}
+
+/// The known differences, with how many times each is still expected to occur.
+#[derive(Debug, Default)]
+pub struct Baseline(BTreeMap<BaselineKey, usize>);
+
+pub fn parse(text: &str) -> Result<Baseline, String> {
+    let mut baseline = Baseline::default();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split_once('#').map_or(line, |(line, _)| line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let key = BaselineKey::parse(line).map_err(|err| format!("line {}: {}", i + 1, err))?;
+        *baseline.0.entry(key).or_default() += 1;
+    }
+    Ok(baseline)
+}
+
+impl Baseline {
+    /// Returns whether the difference is known, in which case it is no longer expected.
+    pub fn take(&mut self, key: &BaselineKey) -> bool {
+        match self.0.get_mut(key) {
+            Some(count) if *count != 0 => {
+                *count -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The song's entries that haven't been [taken](Self::take), in order.
+    pub fn stale(&self, song: u8) -> impl Iterator<Item = &BaselineKey> {
+        self.0
+            .iter()
+            .filter(move |(key, _)| key.song == song)
+            .flat_map(|(key, &count)| std::iter::repeat(key).take(count))
+    }
+}
+
+/// Each entry is followed by a comment describing the difference.
+pub fn write<W: Write>(file: &mut W, entries: &[(BaselineKey, String)]) -> std::io::Result<()> {
+    writeln!(
+        file,
+        "# Known differences for gbsdiff's --baseline: song, tick, register, kind, values."
+    )?;
+    writeln!(
+        file,
+        "# Delete a line to have that difference reported again."
+    )?;
+    for (key, description) in entries {
+        writeln!(file, "{}  # {}", key, description)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Address, DiagnosticLevel, Timestamp};
+
+    fn diag(tick: u64, cycle: u32, kind: DiagnosticKind) -> Diagnostic<DiagnosticKind> {
+        Diagnostic {
+            when: Timestamp { tick, cycle },
+            pc: Address(1, 0x4000 + cycle as u16),
+            level: DiagnosticLevel::Warning,
+            kind,
+        }
+    }
+
+    #[test]
+    fn keys_are_stable_across_runs() {
+        let key = BaselineKey::new(1, &diag(10, 100, DiagnosticKind::Moved(0xFF12, 0xF0, 4)));
+        // Neither the cycle, PC, level, nor how far the write moved matter.
+        let mut moved_further = diag(10, 250, DiagnosticKind::Moved(0xFF12, 0xF0, -12));
+        moved_further.level = DiagnosticLevel::Error;
+        assert_eq!(BaselineKey::new(1, &moved_further), key);
+
+        let realloc = |ticks, notes| {
+            let kind = DiagnosticKind::ChannelReallocation {
+                from: 1,
+                to: 2,
+                ticks,
+                notes,
+            };
+            BaselineKey::new(1, &diag(3, 0, kind))
+        };
+        assert_eq!(realloc((3, 8), 2), realloc((3, 20), 7));
+
+        // Keys survive being written out and read back.
+        let entries = [
+            (key.clone(), "moved".to_string()),
+            (realloc((3, 8), 2), "reallocated".to_string()),
+        ];
+        let mut file = Vec::new();
+        write(&mut file, &entries).unwrap();
+        let mut baseline = parse(std::str::from_utf8(&file).unwrap()).unwrap();
+        assert!(baseline.take(&key));
+        assert!(baseline.take(&realloc((3, 8), 2)));
+        assert_eq!(baseline.stale(1).count(), 0);
+    }
+
+    #[test]
+    fn mismatched_keys_are_not_taken() {
+        let key = |song, tick, kind| BaselineKey::new(song, &diag(tick, 0, kind));
+        let mut baseline = parse(&format!(
+            "# A comment\n\n{}  # twice\n{}\n",
+            key(1, 10, DiagnosticKind::OtherValue(0xFF12, 0xF0, 0xA0)),
+            key(1, 10, DiagnosticKind::OtherValue(0xFF12, 0xF0, 0xA0)),
+        ))
+        .unwrap();
+
+        // Every part of the key must match.
+        assert!(!baseline.take(&key(2, 10, DiagnosticKind::OtherValue(0xFF12, 0xF0, 0xA0))));
+        assert!(!baseline.take(&key(1, 11, DiagnosticKind::OtherValue(0xFF12, 0xF0, 0xA0))));
+        assert!(!baseline.take(&key(1, 10, DiagnosticKind::OtherValue(0xFF17, 0xF0, 0xA0))));
+        assert!(!baseline.take(&key(1, 10, DiagnosticKind::OtherValue(0xFF12, 0xF0, 0xA1))));
+        assert!(!baseline.take(&key(1, 10, DiagnosticKind::Removed(0xFF12, 0xF0))));
+
+        // Each entry only acknowledges one occurrence.
+        let expected = key(1, 10, DiagnosticKind::OtherValue(0xFF12, 0xF0, 0xA0));
+        assert_eq!(baseline.stale(1).count(), 2);
+        assert!(baseline.take(&expected));
+        assert!(baseline.take(&expected));
+        assert!(!baseline.take(&expected));
+        assert_eq!(baseline.stale(1).count(), 0);
+    }
+
+    #[test]
+    fn malformed_entries_are_rejected() {
+        let err = |text| parse(text).unwrap_err();
+        assert_eq!(err("1 10 ff12"), "line 1: missing kind");
+        assert!(err("\n1 10 ff12 sideways f0").starts_with("line 2: unknown kind \"sideways\""));
+        assert!(err("1 10 ff12 removed zz").starts_with("line 1: value \"zz\""));
+        assert!(err("256 10 ff12 removed f0").starts_with("line 1: song ID \"256\""));
+    }
+}
@@ -0,0 +1,219 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with turning internal panics into a bug report bundle.
+//!
+//! The bundle only contains the input files themselves if explicitly requested; otherwise,
+//! only their sizes and hashes are recorded.
+
+use std::{
+    backtrace::Backtrace,
+    cell::{Cell, RefCell},
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use parse_display::Display;
+
+use crate::fnv1a;
+
+/// The exit code used when a bug report has been written.
+pub const EXIT_CODE: i32 = 3;
+
+#[derive(Debug, Display, Clone, Copy)]
+#[display(style = "lowercase")]
+pub enum Phase {
+    Startup,
+    Init,
+    Play,
+    Comparing,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Progress {
+    song: Option<u8>,
+    phase: Phase,
+}
+
+thread_local! {
+    static PROGRESS: Cell<Progress> = const { Cell::new(Progress { song: None, phase: Phase::Startup }) };
+    static TICK: Cell<u64> = const { Cell::new(0) };
+    static RESULTS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+pub fn set_song(song: u8) {
+    PROGRESS.with(|progress| {
+        progress.set(Progress {
+            song: Some(song),
+            ..progress.get()
+        })
+    });
+}
+
+pub fn set_phase(phase: Phase) {
+    PROGRESS.with(|progress| {
+        progress.set(Progress {
+            phase,
+            ..progress.get()
+        })
+    });
+}
+
+pub fn set_tick(tick: u64) {
+    TICK.with(|cur_tick| cur_tick.set(tick));
+}
+
+/// Records the outcome of a song that has been fully processed.
+pub fn record_result(result: String) {
+    RESULTS.with(|results| results.borrow_mut().push(result));
+}
+
+/// An input file, as it will be described in the bundle.
+#[derive(Debug)]
+pub struct Input {
+    pub path: String,
+    pub size: usize,
+    pub hash: u64,
+    /// Only present if the user asked for the inputs to be included.
+    pub data: Option<Vec<u8>>,
+}
+
+impl Input {
+    pub fn new(path: &str, data: &[u8], include_data: bool) -> Self {
+        Self {
+            path: path.to_string(),
+            size: data.len(),
+            hash: fnv1a(data),
+            data: include_data.then(|| data.to_vec()),
+        }
+    }
+}
+
+/// Replaces the panic hook with one that writes a bug report bundle into a new directory within `dir`.
+pub fn install(dir: PathBuf, options: String, inputs: Vec<Input>) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+
+        match write_bundle(&dir, &info.to_string(), &options, &inputs) {
+            Ok(path) => eprintln!(
+                "gbsdiff crashed; please attach the contents of {} to a bug report",
+                path.display()
+            ),
+            Err(err) => eprintln!(
+                "gbsdiff crashed, and writing the bug report failed: {}",
+                err
+            ),
+        }
+        crate::report::exit(EXIT_CODE);
+    }));
+}
+
+fn write_bundle(
+    dir: &Path,
+    panic: &str,
+    options: &str,
+    inputs: &[Input],
+) -> std::io::Result<PathBuf> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let path = dir.join(format!("gbsdiff-bug-{}", timestamp));
+    fs::create_dir_all(&path)?;
+
+    // `write!`ing to a `String` cannot fail.
+    let mut report = String::new();
+    writeln!(report, "gbsdiff {}", env!("CARGO_PKG_VERSION")).unwrap();
+    writeln!(report, "\n== Panic ==\n{}", panic).unwrap();
+    writeln!(report, "\n== Backtrace ==\n{}", Backtrace::force_capture()).unwrap();
+
+    let progress = PROGRESS.with(Cell::get);
+    writeln!(report, "\n== Progress ==").unwrap();
+    match progress.song {
+        Some(song) => writeln!(report, "song {}", song).unwrap(),
+        None => writeln!(report, "no song started").unwrap(),
+    }
+    writeln!(report, "phase: {}", progress.phase).unwrap();
+    writeln!(report, "tick: {}", TICK.with(Cell::get)).unwrap();
+
+    writeln!(report, "\n== Options ==\n{}", options).unwrap();
+
+    writeln!(report, "\n== Inputs ==").unwrap();
+    for (i, input) in inputs.iter().enumerate() {
+        writeln!(
+            report,
+            "{}: {} bytes, FNV-1a {:016x}",
+            input.path, input.size, input.hash
+        )
+        .unwrap();
+        if let Some(data) = &input.data {
+            fs::write(path.join(format!("input{}.gbs", i)), data)?;
+        }
+    }
+
+    writeln!(report, "\n== Completed songs ==").unwrap();
+    RESULTS.with(|results| {
+        for result in results.borrow().iter() {
+            writeln!(report, "{}", result).unwrap();
+        }
+    });
+
+    fs::write(path.join("report.txt"), report)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundles_describe_the_crash() {
+        let dir = std::env::temp_dir().join(format!("gbsdiff-test-bug-{}", std::process::id()));
+        // Progress is per-thread, so other tests can't interfere.
+        record_result("song 1: identical".to_string());
+        set_song(2);
+        set_phase(Phase::Play);
+        set_tick(42);
+        let inputs = [
+            Input::new("before.gbs", b"GBS\x01before", false),
+            Input::new("after.gbs", b"GBS\x01after", true),
+        ];
+
+        let payload = std::panic::catch_unwind(|| panic!("the simulated crash")).unwrap_err();
+        let panic = payload.downcast_ref::<&str>().unwrap();
+        let path = write_bundle(&dir, panic, "--jitter 4", &inputs).unwrap();
+
+        let report = fs::read_to_string(path.join("report.txt")).unwrap();
+        let section = |name: &str| {
+            let start = report
+                .find(&format!("== {} ==\n", name))
+                .unwrap_or_else(|| panic!("no {} section in:\n{}", name, report));
+            let section = &report[start + name.len() + 7..];
+            section[..section.find("\n\n==").unwrap_or(section.len())].to_string()
+        };
+        assert!(report.starts_with(&format!("gbsdiff {}\n", env!("CARGO_PKG_VERSION"))));
+        assert_eq!(section("Panic"), "the simulated crash");
+        assert!(!section("Backtrace").is_empty());
+        assert_eq!(section("Progress"), "song 2\nphase: play\ntick: 42");
+        assert_eq!(section("Options"), "--jitter 4");
+        assert_eq!(
+            section("Inputs"),
+            format!(
+                "before.gbs: 10 bytes, FNV-1a {:016x}\nafter.gbs: 9 bytes, FNV-1a {:016x}",
+                fnv1a(b"GBS\x01before"),
+                fnv1a(b"GBS\x01after")
+            )
+        );
+        assert_eq!(section("Completed songs"), "song 1: identical\n");
+        // Only the inputs that were asked for are included.
+        assert!(!path.join("input0.gbs").exists());
+        assert_eq!(fs::read(path.join("input1.gbs")).unwrap(), b"GBS\x01after");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
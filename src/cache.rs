@@ -0,0 +1,606 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with keeping simulation results on disk, so that re-running gbsdiff against
+//! an unchanged file doesn't simulate it all over again.
+//!
+//! Each simulated song gets its own file, named after a hash of everything that affects the
+//! simulation; files that cannot be loaded for any reason are simply ignored.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    gbs::Gbs,
+    run::{
+        Accessed, BankCycles, DebugMarker, DiagnosticKind, IoAccess, Logbook, Profile, RecentPcs,
+        SimParams, Termination,
+    },
+    Address, Diagnostic, DiagnosticLevel, Timestamp,
+};
+
+/// Identifies cache files, and their format version.
+const MAGIC: &[u8; 8] = b"GBSDCA17";
+
+/// Bumped whenever the fields hashed by [`key`] or their encoding change.
+const KEY_VERSION: u8 = 1;
+
+/// Computes the name of the cache file for that song.
+///
+/// gbsdiff's own version is part of the key, since any change to the simulator may change its
+/// results.
+pub fn key(gbs: &Gbs, song_id: u8, params: &SimParams) -> String {
+    let mut key = Writer(vec![KEY_VERSION]);
+    key.str(env!("CARGO_PKG_VERSION"));
+    key.str(env!("GBSDIFF_GIT_HASH"));
+    key.u8(song_id);
+    key.params(params);
+    key.vec(gbs.tick_pattern(), |key, cycles| key.u32(*cycles));
+    key.vec(gbs.data(), |key, byte| key.u8(*byte));
+    format!("{:016x}.bin", crate::fnv1a(&key.0))
+}
+
+fn path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(key)
+}
+
+/// Returns `None` if there is no usable cache file.
+pub fn load(dir: &Path, key: &str) -> Option<Logbook> {
+    let data = fs::read(path(dir, key)).ok()?;
+    let mut reader = Reader(data.strip_prefix(MAGIC)?);
+    let logbook = reader.logbook()?;
+    reader.0.is_empty().then_some(logbook)
+}
+
+pub fn store(dir: &Path, key: &str, logbook: &Logbook) -> io::Result<()> {
+    let mut writer = Writer(MAGIC.to_vec());
+    writer.logbook(logbook);
+    fs::create_dir_all(dir)?;
+    // Write to a temporary file first, so that an interrupted run doesn't leave a truncated file.
+    let tmp_path = path(dir, &format!("{}.tmp", key));
+    fs::write(&tmp_path, &writer.0)?;
+    fs::rename(tmp_path, path(dir, key))
+}
+
+/// All multi-byte fields are little-endian; lengths and `usize`s are stored as `u64`.
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    fn vec<T>(&mut self, items: &[T], mut item: impl FnMut(&mut Self, &T)) {
+        self.usize(items.len());
+        for value in items {
+            item(self, value);
+        }
+    }
+
+    fn str(&mut self, string: &str) {
+        self.vec(string.as_bytes(), |this, byte| this.u8(*byte));
+    }
+
+    fn opt<T>(&mut self, value: Option<&T>, item: impl FnOnce(&mut Self, &T)) {
+        match value {
+            None => self.u8(0),
+            Some(value) => {
+                self.u8(1);
+                item(self, value);
+            }
+        }
+    }
+
+    fn reg(&mut self, &(addr, value): &(u16, u8)) {
+        self.u16(addr);
+        self.u8(value);
+    }
+
+    /// Only what affects the simulation's results; destructuring makes new fields impossible to
+    /// forget about.
+    fn params(&mut self, params: &SimParams) {
+        let SimParams {
+            max_level,
+            max_recorded_diagnostics,
+            timeout,
+            allow_timeout,
+            silence_timeout,
+            watch,
+            wait_for,
+            // Tracing bypasses the cache altogether.
+            trace_filter: _,
+            trace_format: _,
+            wave_read_mode,
+            debug_markers,
+            max_func_cycles,
+            max_stall_ticks,
+            show_progress: _,
+            deadline: _,
+            pokes,
+            init_regs,
+            init_addr,
+            play_addr,
+            uninit_check,
+            stub_regs,
+            profile,
+            promotions,
+            watchpoints,
+            read_watchpoints,
+            shadow_addrs,
+            log_reads,
+        } = params;
+        self.u8(*max_level as u8);
+        self.usize(*max_recorded_diagnostics);
+        self.u32(*timeout);
+        self.u8(u8::from(*allow_timeout));
+        self.u32(*silence_timeout);
+        self.opt(watch.as_ref(), Self::reg);
+        self.opt(wait_for.as_ref(), Self::reg);
+        self.u8(*wave_read_mode as u8);
+        self.u8(*debug_markers as u8);
+        self.u32(*max_func_cycles);
+        self.u64(*max_stall_ticks);
+        self.vec(pokes, Self::reg);
+        self.vec(&init_regs.0, |this, &(reg, value)| {
+            this.u8(reg as u8);
+            this.u16(value);
+        });
+        self.opt(init_addr.as_ref(), |this, addr| this.u16(*addr));
+        self.opt(play_addr.as_ref(), |this, addr| this.u16(*addr));
+        self.u8(u8::from(*uninit_check));
+        self.vec(stub_regs, Self::reg);
+        match profile {
+            Profile::Apu => self.u8(0),
+            Profile::ApuSerial => self.u8(1),
+            Profile::Custom(ranges) => {
+                self.u8(2);
+                self.vec(ranges, |this, range| {
+                    this.u16(*range.start());
+                    this.u16(*range.end());
+                });
+            }
+        }
+        self.vec(promotions, |this, promotion| {
+            this.str(promotion.kind);
+            this.opt(promotion.level.as_ref(), |this, level| {
+                this.u8(*level as u8)
+            });
+        });
+        self.vec(watchpoints, |this, addr| this.u16(*addr));
+        self.vec(read_watchpoints, |this, addr| this.u16(*addr));
+        self.vec(shadow_addrs, |this, addr| this.u16(*addr));
+        self.u8(u8::from(*log_reads));
+    }
+
+    fn timestamp(&mut self, when: &Timestamp) {
+        self.u64(when.tick);
+        self.u32(when.cycle);
+    }
+
+    fn address(&mut self, addr: &Address) {
+        self.u8(addr.0);
+        self.u16(addr.1);
+    }
+
+    fn access(&mut self, access: &IoAccess) {
+        self.timestamp(&access.when);
+        self.address(&access.pc);
+        self.u16(access.addr);
+        self.u8(access.data);
+    }
+
+    fn diagnostic(&mut self, diag: &Diagnostic<DiagnosticKind>) {
+        self.timestamp(&diag.when);
+        self.address(&diag.pc);
+        self.u8(diag.level as u8);
+        match &diag.kind {
+            DiagnosticKind::UnsupportedRead(addr) => {
+                self.u8(0);
+                self.u16(addr.0);
+            }
+            DiagnosticKind::UnsupportedWrite(addr, value) => {
+                self.u8(1);
+                self.u16(addr.0);
+                self.u8(*value);
+            }
+            DiagnosticKind::EchoRamRead(addr) => {
+                self.u8(2);
+                self.u16(addr.0);
+            }
+            DiagnosticKind::EchoRamWrite(addr, value) => {
+                self.u8(3);
+                self.u16(addr.0);
+                self.u8(*value);
+            }
+            DiagnosticKind::TooLong(cycles, budget, pc, bank_cycles) => {
+                self.u8(4);
+                self.u32(*cycles);
+                self.u32(*budget);
+                self.address(pc);
+                self.vec(&bank_cycles.0, |w, &(bank, cycles)| {
+                    w.u8(bank);
+                    w.u32(cycles);
+                });
+            }
+            DiagnosticKind::DebugOp(addr) => {
+                self.u8(5);
+                self.address(addr);
+            }
+            DiagnosticKind::BankOutOfRange(bank, nb_banks) => {
+                self.u8(6);
+                self.u8(*bank);
+                self.usize(*nb_banks);
+            }
+            DiagnosticKind::BankSwitchFlood(max) => {
+                self.u8(7);
+                self.u32(*max);
+            }
+            DiagnosticKind::HramExecution(addr) => {
+                self.u8(8);
+                self.address(addr);
+            }
+            DiagnosticKind::StaleWaveRead(addr) => {
+                self.u8(9);
+                self.u16(addr.0);
+            }
+            DiagnosticKind::ApproximatePcmRead(reg) => {
+                self.u8(10);
+                self.u8(*reg);
+            }
+            DiagnosticKind::StubbedRead(addr, value) => {
+                self.u8(11);
+                self.u16(addr.0);
+                self.u8(*value);
+            }
+            DiagnosticKind::AudioStall(tick, len) => {
+                self.u8(12);
+                self.u64(*tick);
+                self.u64(*len);
+            }
+            DiagnosticKind::ApuPoweredOff(tick) => {
+                self.u8(13);
+                self.u64(*tick);
+            }
+            DiagnosticKind::InertRegWrite(addr, value) => {
+                self.u8(14);
+                self.u16(addr.0);
+                self.u8(*value);
+            }
+            DiagnosticKind::UninitializedRead(addr) => {
+                self.u8(15);
+                self.address(addr);
+            }
+            DiagnosticKind::WatchedWrite(addr, value, recent_pcs) => {
+                self.u8(16);
+                self.u16(addr.0);
+                self.u8(*value);
+                self.vec(&recent_pcs.0, Self::address);
+            }
+            DiagnosticKind::WatchedRead(addr, value, recent_pcs) => {
+                self.u8(17);
+                self.u16(addr.0);
+                self.u8(*value);
+                self.vec(&recent_pcs.0, Self::address);
+            }
+        }
+    }
+
+    fn logbook(&mut self, logbook: &Logbook) {
+        self.vec(&logbook.diagnostics, Self::diagnostic);
+        self.usize(logbook.unrecorded.len());
+        for (&(kind, level), ticks) in &logbook.unrecorded {
+            self.u8(DiagnosticKind::NAMES
+                .iter()
+                .position(|name| *name == kind)
+                .unwrap() as u8);
+            self.u8(level as u8);
+            self.vec(ticks, |w, &(tick, count)| {
+                w.u64(tick);
+                w.usize(count);
+            });
+        }
+        self.vec(&logbook.io_log, Self::access);
+        self.vec(&logbook.read_log, Self::access);
+        self.vec(&logbook.shadow_log, Self::access);
+        self.usize(logbook.stale_wave_reads);
+        self.vec(&logbook.tick_cycles, |w, &cycles| w.u32(cycles));
+        self.vec(&logbook.last_write_cycles, |w, cycle| match cycle {
+            Some(cycle) => {
+                w.u8(1);
+                w.u32(*cycle);
+            }
+            None => w.u8(0),
+        });
+        self.vec(&logbook.stack_depths, |w, &depth| w.u16(depth));
+        self.vec(&logbook.exit_banks, |w, &bank| w.u8(bank));
+        self.vec(&logbook.debug_markers, |w, marker| {
+            w.timestamp(&marker.when);
+            w.address(&marker.pc);
+        });
+        self.vec(&logbook.length_expiries, |w, &(tick, channel)| {
+            w.u64(tick);
+            w.u8(channel);
+        });
+        self.u64(logbook.ticks_simulated);
+        self.u64(logbook.warmup_ticks);
+        self.u64(logbook.total_cycles);
+        self.u8(match logbook.termination {
+            Termination::Silence => 0,
+            Termination::Watch => 1,
+            Termination::Timeout => 2,
+        });
+    }
+}
+
+/// The counterpart to [`Writer`]; every method returns `None` on truncated or invalid data.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.0.get(..N)?.try_into().ok()?;
+        self.0 = &self.0[N..];
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes().map(|[byte]| byte)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes().map(u64::from_le_bytes)
+    }
+
+    fn usize(&mut self) -> Option<usize> {
+        self.u64()?.try_into().ok()
+    }
+
+    fn vec<T>(&mut self, mut item: impl FnMut(&mut Self) -> Option<T>) -> Option<Vec<T>> {
+        let len = self.usize()?;
+        // Don't trust the length for the allocation, in case the file is corrupted.
+        let mut items = Vec::with_capacity(len.min(self.0.len()));
+        for _ in 0..len {
+            items.push(item(self)?);
+        }
+        Some(items)
+    }
+
+    fn timestamp(&mut self) -> Option<Timestamp> {
+        Some(Timestamp {
+            tick: self.u64()?,
+            cycle: self.u32()?,
+        })
+    }
+
+    fn address(&mut self) -> Option<Address> {
+        Some(Address(self.u8()?, self.u16()?))
+    }
+
+    fn access(&mut self) -> Option<IoAccess> {
+        Some(IoAccess {
+            when: self.timestamp()?,
+            pc: self.address()?,
+            addr: self.u16()?,
+            data: self.u8()?,
+        })
+    }
+
+    fn diagnostic(&mut self) -> Option<Diagnostic<DiagnosticKind>> {
+        let when = self.timestamp()?;
+        let pc = self.address()?;
+        let level = *DiagnosticLevel::ALL.get(usize::from(self.u8()?))?;
+        let kind = match self.u8()? {
+            0 => DiagnosticKind::UnsupportedRead(Accessed(self.u16()?)),
+            1 => DiagnosticKind::UnsupportedWrite(Accessed(self.u16()?), self.u8()?),
+            2 => DiagnosticKind::EchoRamRead(Accessed(self.u16()?)),
+            3 => DiagnosticKind::EchoRamWrite(Accessed(self.u16()?), self.u8()?),
+            4 => DiagnosticKind::TooLong(
+                self.u32()?,
+                self.u32()?,
+                self.address()?,
+                BankCycles(self.vec(|r| Some((r.u8()?, r.u32()?)))?),
+            ),
+            5 => DiagnosticKind::DebugOp(self.address()?),
+            6 => DiagnosticKind::BankOutOfRange(self.u8()?, self.usize()?),
+            7 => DiagnosticKind::BankSwitchFlood(self.u32()?),
+            8 => DiagnosticKind::HramExecution(self.address()?),
+            9 => DiagnosticKind::StaleWaveRead(Accessed(self.u16()?)),
+            10 => DiagnosticKind::ApproximatePcmRead(self.u8()?),
+            11 => DiagnosticKind::StubbedRead(Accessed(self.u16()?), self.u8()?),
+            12 => DiagnosticKind::AudioStall(self.u64()?, self.u64()?),
+            13 => DiagnosticKind::ApuPoweredOff(self.u64()?),
+            14 => DiagnosticKind::InertRegWrite(Accessed(self.u16()?), self.u8()?),
+            15 => DiagnosticKind::UninitializedRead(self.address()?),
+            16 => DiagnosticKind::WatchedWrite(
+                Accessed(self.u16()?),
+                self.u8()?,
+                RecentPcs(self.vec(Self::address)?),
+            ),
+            17 => DiagnosticKind::WatchedRead(
+                Accessed(self.u16()?),
+                self.u8()?,
+                RecentPcs(self.vec(Self::address)?),
+            ),
+            _ => return None,
+        };
+        Some(Diagnostic {
+            when,
+            pc,
+            level,
+            kind,
+        })
+    }
+
+    fn logbook(&mut self) -> Option<Logbook> {
+        Some(Logbook {
+            diagnostics: self.vec(Self::diagnostic)?,
+            unrecorded: self
+                .vec(|r| {
+                    let kind = *DiagnosticKind::NAMES.get(usize::from(r.u8()?))?;
+                    let level = *DiagnosticLevel::ALL.get(usize::from(r.u8()?))?;
+                    let ticks = r.vec(|r| Some((r.u64()?, r.usize()?)))?;
+                    Some(((kind, level), ticks))
+                })?
+                .into_iter()
+                .collect(),
+            io_log: self.vec(Self::access)?,
+            read_log: self.vec(Self::access)?,
+            shadow_log: self.vec(Self::access)?,
+            stale_wave_reads: self.usize()?,
+            tick_cycles: self.vec(Self::u32)?,
+            last_write_cycles: self.vec(|r| match r.u8()? {
+                0 => Some(None),
+                1 => Some(Some(r.u32()?)),
+                _ => None,
+            })?,
+            stack_depths: self.vec(Self::u16)?,
+            exit_banks: self.vec(Self::u8)?,
+            debug_markers: self.vec(|r| {
+                Some(DebugMarker {
+                    when: r.timestamp()?,
+                    pc: r.address()?,
+                })
+            })?,
+            length_expiries: self.vec(|r| Some((r.u64()?, r.u8()?)))?,
+            ticks_simulated: self.u64()?,
+            warmup_ticks: self.u64()?,
+            // Songs resumed from a snapshot are never cached.
+            first_tick: 0,
+            total_cycles: self.u64()?,
+            termination: match self.u8()? {
+                0 => Termination::Silence,
+                1 => Termination::Watch,
+                2 => Termination::Timeout,
+                _ => return None,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        gbs::{Code, GbsBuilder},
+        run::simulate_song,
+        DiagnosticLevel,
+    };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("gbsdiff-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn setup(dir: &Path) -> (Vec<u8>, SimParams, Logbook) {
+        // Writing to echo RAM gets diagnostics recorded alongside the writes.
+        let play = Code::default()
+            .ld_a(0x80)
+            .ldh_to(0xFF26)
+            .raw(&[0xEA, 0x00, 0xE0]); // `ld [$e000], a`
+        let data = GbsBuilder::default().play(play.ret()).build();
+        let gbs = Gbs::new(&data).unwrap();
+        let mut params = SimParams::new(gbs.cycles_per_tick() * 10);
+        params.max_level = DiagnosticLevel::Note;
+        let logbook = simulate_song(&gbs, 1, &params, None, None::<io::Sink>, None).unwrap();
+        store(dir, &key(&gbs, 1, &params), &logbook).unwrap();
+        (data, params, logbook)
+    }
+
+    #[test]
+    fn stored_logbooks_are_loaded_back() {
+        let dir = temp_dir("hit");
+        let (data, params, logbook) = setup(&dir);
+        let gbs = Gbs::new(&data).unwrap();
+        let loaded = load(&dir, &key(&gbs, 1, &params)).unwrap();
+        assert!(!logbook.io_log.is_empty() && !logbook.diagnostics.is_empty());
+        assert_eq!(loaded.io_log, logbook.io_log);
+        assert_eq!(loaded.diagnostics, logbook.diagnostics);
+        assert_eq!(loaded.termination, logbook.termination);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn changed_params_miss() {
+        let dir = temp_dir("miss");
+        let (data, params, _) = setup(&dir);
+        let gbs = Gbs::new(&data).unwrap();
+        let base = key(&gbs, 1, &params);
+        let changed = [
+            SimParams {
+                timeout: params.timeout + 1,
+                ..params.clone()
+            },
+            SimParams {
+                pokes: vec![(0xC000, 1)],
+                ..params.clone()
+            },
+            SimParams {
+                max_level: DiagnosticLevel::Warning,
+                ..params.clone()
+            },
+        ];
+        for params in &changed {
+            let key = key(&gbs, 1, params);
+            assert_ne!(key, base);
+            assert!(load(&dir, &key).is_none());
+        }
+        assert_ne!(key(&gbs, 2, &params), base);
+        // But not what doesn't affect the results.
+        let unaffected = SimParams {
+            show_progress: !params.show_progress,
+            deadline: Some(std::time::Instant::now()),
+            ..params.clone()
+        };
+        assert_eq!(key(&gbs, 1, &unaffected), base);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_files_are_ignored() {
+        let dir = temp_dir("corrupted");
+        let (data, params, _) = setup(&dir);
+        let gbs = Gbs::new(&data).unwrap();
+        let key = key(&gbs, 1, &params);
+        let contents = fs::read(path(&dir, &key)).unwrap();
+
+        fs::write(path(&dir, &key), &contents[..contents.len() - 1]).unwrap();
+        assert!(load(&dir, &key).is_none());
+        fs::write(path(&dir, &key), [&contents[..], &[0]].concat()).unwrap();
+        assert!(load(&dir, &key).is_none());
+        let mut wrong_magic = contents.clone();
+        wrong_magic[0] ^= 1;
+        fs::write(path(&dir, &key), wrong_magic).unwrap();
+        assert!(load(&dir, &key).is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,47 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with which APU channel each register belongs to, for grouping differences by
+//! channel (`--group-by channel`).
+
+use parse_display::Display;
+
+use crate::regs;
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Channel {
+    #[display("Channel 1")]
+    Ch1,
+    #[display("Channel 2")]
+    Ch2,
+    /// Wave RAM included.
+    #[display("Channel 3")]
+    Ch3,
+    #[display("Channel 4")]
+    Ch4,
+    /// NR50, NR51, and NR52.
+    #[display("Global")]
+    Global,
+    /// Anything that isn't an APU register, and the unused ones in between.
+    #[display("Other")]
+    Other,
+}
+
+impl Channel {
+    /// In the order in which groups are printed.
+    pub const ALL: [Self; 6] = [
+        Self::Ch1,
+        Self::Ch2,
+        Self::Ch3,
+        Self::Ch4,
+        Self::Global,
+        Self::Other,
+    ];
+
+    pub fn of(reg: u16) -> Self {
+        regs::channel(reg).unwrap_or(Self::Other)
+    }
+}
@@ -0,0 +1,1850 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with comparing the songs of two GBS files, which is gbsdiff's main mode.
+//!
+//! Each pair of songs is either simulated (or loaded from the cache) in full and then compared, or
+//! compared while being simulated with `--stream`; songs that only one of the files has are then
+//! simulated on their own.
+
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashSet},
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::Instant,
+};
+
+use owo_colors::{OwoColorize, Stream::Stderr};
+
+use crate::{
+    baseline, bug_report, cache, channel, compare_streamed,
+    cpu_usage::{self, CpuStats, LastWriteStats},
+    csv, cycles_per_sec, determinism, diff, early_exit, explain, fails_at, fingerprint, focus,
+    format_secs,
+    gbs::{self, Gbs},
+    identify, known_difference, merge, parse_error, parse_gbs, read_file, read_text, realloc,
+    render, replay,
+    report::{self, Phase, Reporter},
+    report_context, report_sim_diagnostics, report_unrecorded,
+    run::{self, DebugMarkers, Logbook, Sram},
+    shadow, state, sym, tempo, throughput, ticks_to_secs, time_to_ticks, transcript, unified,
+    unrecorded_error_ticks, waves,
+    write_pairs::{self, VulnerablePairs},
+    Args, BeforeOrAfter, CompareMode, DiagnosticLevel, Fatal, GroupBy, InitOutcomes, Options,
+    SongIDs, TickWindow, STDIN_PATH,
+};
+
+/// Compares the songs of `args.before` and `after_path`, and returns the exit code.
+pub fn run(
+    args: &Args,
+    after_path: &String,
+    out: &report::Sink,
+    options: Options,
+) -> Result<i32, Fatal> {
+    let Options {
+        sim_params,
+        presets,
+        symbols,
+        sym_warnings,
+        baseline,
+        ignore_regs,
+        shadows,
+        trace_file,
+        verbosity,
+    } = options;
+
+    // `--stat` replaces it before any song gets compared.
+    let mut reporter = report::Reporters(vec![Box::new(if args.stat {
+        report::TextReporter::new(report::Verbosity::Quiet, out)
+    } else {
+        report::TextReporter {
+            verbosity,
+            out: report::Output::new(out, args.pager, args.spill_threshold),
+        }
+    })]);
+    if let Some(path) = &args.html {
+        reporter.0.push(Box::new(report::HtmlReporter::new(
+            path.clone(),
+            &args.before,
+            after_path,
+        )));
+    }
+    for warning in &sym_warnings {
+        reporter.warning(warning);
+    }
+
+    if let Some(template) = &args.render {
+        if !template.contains("{song}") || !template.contains("{side}") {
+            eprintln!(
+                "{}: the --render path must contain both `{{song}}` and `{{side}}`",
+                colorize!(Stderr, "Error", bright_red, bold),
+            );
+            return Err(Fatal);
+        }
+    }
+
+    let csv_dir = args.csv.as_ref().map(std::path::Path::new);
+    if let Some(dir) = csv_dir {
+        if let Err(err) = fs::create_dir_all(dir) {
+            eprintln!(
+                "{}: Failed to create {}: {}",
+                colorize!(Stderr, "Error", bright_red, bold),
+                dir.display(),
+                err
+            );
+            return Err(Fatal);
+        }
+    }
+
+    let unified_out: Option<Box<dyn Write>> = match args.unified.as_deref() {
+        None => None,
+        Some("-") => Some(Box::new(out.clone())),
+        Some(path) => match File::create(path) {
+            Ok(file) => Some(Box::new(BufWriter::new(file))),
+            Err(err) => {
+                eprintln!(
+                    "{}: Failed to create {}: {}",
+                    colorize!(Stderr, "Error", bright_red, bold),
+                    path,
+                    err
+                );
+                return Err(Fatal);
+            }
+        },
+    };
+
+    if args.before == STDIN_PATH && *after_path == STDIN_PATH {
+        eprintln!(
+            "{}: only one of the two files can be read from stdin",
+            colorize!(Stderr, "Error", bright_red, bold),
+        );
+        return Err(Fatal);
+    }
+    let before_data = read_file(&args.before, &mut reporter)?;
+    let before_gbs = parse_gbs(&before_data, &args.before, args, &sim_params, &mut reporter)?;
+    let after_data = read_file(after_path, &mut reporter)?;
+    let after_gbs = parse_gbs(&after_data, after_path, args, &sim_params, &mut reporter)?;
+    if args.stat {
+        // The table needs the tick rate, so it can only take over once the files are parsed.
+        reporter.0[0] = Box::new(report::StatReporter::new(
+            out,
+            ticks_to_secs(1, &before_gbs),
+        ));
+    }
+    // Likewise for its timestamps.
+    if let Some(path) = &args.markdown {
+        reporter.0.push(Box::new(report::MarkdownReporter::new(
+            path.clone(),
+            &args.before,
+            after_path,
+            ticks_to_secs(1, &before_gbs),
+        )));
+    }
+
+    if let Some(dir) = &args.bug_report {
+        bug_report::install(
+            dir.into(),
+            format!("{:#?}", args),
+            vec![
+                bug_report::Input::new(&args.before, &before_data, args.bug_report_include_inputs),
+                bug_report::Input::new(after_path, &after_data, args.bug_report_include_inputs),
+            ],
+        );
+    }
+
+    let normalize_time = args.normalize_time && !before_gbs.same_timing(&after_gbs);
+    if !before_gbs.same_timing(&after_gbs) {
+        reporter.warning(&format_args!(
+            "Earlier GBS is {}, but later is {}",
+            before_gbs.timing_description(),
+            after_gbs.timing_description(),
+        ));
+        if normalize_time {
+            reporter
+                .line(&"    Writes will be compared by their absolute timing instead of by tick.");
+        } else {
+            reporter.line(&"    Tick numbers are not comparable between the two, so expect a lot of spurious differences.");
+            reporter.line(&"    Consider passing `--normalize-time` to compare writes by their absolute timing instead.");
+        }
+    }
+
+    for (gbs, path) in [(&before_gbs, &args.before), (&after_gbs, after_path)] {
+        reporter.detail(&format_args!(
+            "{}: {} songs (first is #{}), load ${:04x}, init ${:04x}, play ${:04x}, SP ${:04x}",
+            path,
+            gbs.nb_songs(),
+            gbs.first_song(),
+            gbs.addr(gbs::AddressKind::Load),
+            gbs.addr(gbs::AddressKind::Init),
+            gbs.addr(gbs::AddressKind::Play),
+            gbs.stack_ptr(),
+        ));
+        reporter.detail(&format_args!(
+            "{}: {}, {} cycles per tick{}",
+            path,
+            gbs.timing_description(),
+            gbs.cycles_per_tick(),
+            if gbs.tick_pattern().is_empty() {
+                ""
+            } else {
+                " on average"
+            },
+        ));
+    }
+
+    let nb_songs = std::cmp::min(before_gbs.nb_songs(), after_gbs.nb_songs());
+    let song_pairs: Vec<_> = match (args.before_song, args.after_song) {
+        (None, None) => before_gbs.songs().zip(after_gbs.songs()).collect(),
+        (before, after) => {
+            let song_ids = (before.or(after).unwrap(), after.or(before).unwrap());
+            for (gbs, path, song_id) in [
+                (&before_gbs, &args.before, song_ids.0),
+                (&after_gbs, after_path, song_ids.1),
+            ] {
+                let songs = gbs.songs();
+                if !songs.contains(&song_id) {
+                    eprintln!(
+                        "{}: {} has no song {} (its songs are {} to {})",
+                        colorize!(Stderr, "Error", bright_red, bold),
+                        path,
+                        song_id,
+                        songs.start(),
+                        songs.end(),
+                    );
+                    return Err(Fatal);
+                }
+            }
+            vec![song_ids]
+        }
+    };
+    let pairs_overridden = args.before_song.is_some() || args.after_song.is_some();
+    if before_gbs.nb_songs() != after_gbs.nb_songs() && !pairs_overridden {
+        reporter.warning(&format_args!(
+            "Earlier GBS has {} songs, later has {}; only comparing the first {}, the others will only be simulated",
+            before_gbs.nb_songs(),
+            after_gbs.nb_songs(),
+            nb_songs,
+        ));
+    }
+
+    if args.identify {
+        let mut signatures = identify::builtin_signatures();
+        if let Some(path) = &args.driver_signatures {
+            let text = read_text(path)?;
+            signatures
+                .extend(identify::parse_signatures(&text).map_err(|err| parse_error(path, err))?);
+        }
+
+        for (gbs, path) in [(&before_gbs, &args.before), (&after_gbs, after_path)] {
+            // Only the first few ticks are needed; if they cannot even be simulated, the static
+            // signals are still worth checking.
+            let params = run::SimParams {
+                timeout: gbs.cycles_per_tick() * identify::NB_TICKS as u32,
+                allow_timeout: true,
+                ..sim_params.clone()
+            };
+            let io_log =
+                run::simulate_song(gbs, gbs.first_song(), &params, None, None::<io::Sink>, None)
+                    .map(|log| log.io_log)
+                    .unwrap_or_default();
+            match identify::identify(&signatures, gbs, &io_log) {
+                Some(found) => reporter.line(&format_args!("Driver of {}: {}", path, found)),
+                None => reporter.line(&format_args!("Driver of {}: unknown", path)),
+            }
+        }
+    }
+
+    let focus = focus::Focus {
+        banks: args.focus_bank.clone(),
+        ranges: args.focus_pc.clone(),
+    };
+
+    // Cached songs would be missing from the trace.
+    let mut cache_dir = args.cache_dir.as_ref().map(std::path::Path::new);
+    if cache_dir.is_some() && trace_file.is_some() {
+        reporter.warning(&"--trace is given, so cached simulation results will not be used");
+    }
+    // Each song's results would depend on the songs simulated before it.
+    if cache_dir.is_some() && args.persist_sram {
+        reporter.warning(&"--persist-sram is given, so simulation results will not be cached");
+        cache_dir = None;
+    }
+    if cache_dir.is_some() && args.verify_determinism {
+        reporter.warning(
+            &"--verify-determinism is given, so cached simulation results will not be used",
+        );
+    }
+    // Resumed songs lack everything before the snapshot, and saving one requires simulating.
+    if cache_dir.is_some() && (args.save_state.is_some() || args.load_state.is_some()) {
+        reporter.warning(
+            &"--save-state or --load-state is given, so simulation results will not be cached",
+        );
+        cache_dir = None;
+    }
+    let srams = args
+        .persist_sram
+        .then(|| (Box::new([0; 0x2000]), Box::new([0; 0x2000])));
+
+    let mut session = Session {
+        args,
+        sim_params,
+        presets,
+        symbols,
+        baseline,
+        new_baseline: Vec::new(),
+        ignore_regs,
+        shadows,
+        trace_file,
+        reporter,
+        before_data: &before_data,
+        after_data: &after_data,
+        before_gbs,
+        after_gbs,
+        after_path,
+        normalize_time,
+        focus,
+        cache_dir,
+        csv_dir,
+        unified_out,
+        srams,
+        nondeterministic: false,
+        stats: throughput::RunStats::default(),
+        init_outcomes: (InitOutcomes::default(), InitOutcomes::default()),
+        failed: Vec::new(),
+        init_only: Vec::new(),
+        fingerprints: Vec::new(),
+    };
+    let run_start = Instant::now();
+    for song_ids in song_pairs {
+        bug_report::set_song(song_ids.0);
+        let song_start = Instant::now();
+
+        session
+            .reporter
+            .song_start(&SongIDs::Both(song_ids.0, song_ids.1));
+        if args.stream {
+            session.stream_songs(song_ids, song_start);
+        } else if let Some((logs, replay_sram)) = session.simulate_songs(song_ids)? {
+            session.compare_songs(song_ids, logs, replay_sram, song_start);
+        }
+    }
+    // Only the requested pair is of interest when it was overridden.
+    if !pairs_overridden {
+        session.simulate_surplus(nb_songs);
+    }
+    session.finish(run_start)
+}
+
+/// Everything that carries over from one pair of songs to the next.
+struct Session<'a> {
+    args: &'a Args,
+    sim_params: run::SimParams,
+    /// Echoed with each song, so that the run can be reproduced.
+    presets: String,
+    symbols: Option<sym::Symbols>,
+    baseline: Option<baseline::Baseline>,
+    /// Only collected for `--write-baseline`.
+    new_baseline: Vec<(baseline::BaselineKey, String)>,
+    ignore_regs: Vec<u16>,
+    shadows: Vec<shadow::Shadow>,
+    trace_file: Option<BufWriter<File>>,
+    reporter: report::Reporters,
+    before_data: &'a [u8],
+    after_data: &'a [u8],
+    before_gbs: Gbs<'a>,
+    after_gbs: Gbs<'a>,
+    after_path: &'a String,
+    normalize_time: bool,
+    focus: focus::Focus,
+    cache_dir: Option<&'a Path>,
+    csv_dir: Option<&'a Path>,
+    unified_out: Option<Box<dyn Write + 'a>>,
+    /// One per file, carried from each song to the next.
+    srams: Option<(Box<Sram>, Box<Sram>)>,
+    /// Set by `--verify-determinism`.
+    nondeterministic: bool,
+    stats: throughput::RunStats,
+    init_outcomes: (InitOutcomes, InitOutcomes),
+    failed: Vec<SongIDs>,
+    /// Of the failing songs, those whose PLAY stream matched.
+    init_only: Vec<SongIDs>,
+    /// Of the songs that differ.
+    fingerprints: Vec<(SongIDs, fingerprint::Fingerprint)>,
+}
+
+impl Session<'_> {
+    /// Compares a pair of songs while simulating them (`--stream`).
+    fn stream_songs(&mut self, song_ids: (u8, u8), song_start: Instant) {
+        let Self {
+            args,
+            ref sim_params,
+            ref presets,
+            ref symbols,
+            ref ignore_regs,
+            ref mut reporter,
+            ref before_gbs,
+            ref after_gbs,
+            after_path,
+            ref mut srams,
+            ref mut stats,
+            ref mut init_outcomes,
+            ref mut failed,
+            ref mut init_only,
+            ref mut fingerprints,
+            ..
+        } = *self;
+        reporter.progress(
+            "Comparing",
+            &format_args!(
+                "songs {}{} while simulating them",
+                SongIDs::Both(song_ids.0, song_ids.1),
+                presets
+            ),
+        );
+        let streamed = compare_streamed(
+            args,
+            sim_params,
+            (before_gbs, after_gbs),
+            (&args.before, after_path),
+            song_ids,
+            ignore_regs,
+            srams.as_mut().map(|srams| (&mut *srams.0, &mut *srams.1)),
+            reporter,
+            symbols.as_ref(),
+            stats,
+            init_outcomes,
+        );
+        let Some(streamed) = streamed else {
+            bug_report::record_result(format!(
+                "songs {}: simulation failed",
+                SongIDs::Both(song_ids.0, song_ids.1),
+            ));
+            failed.push(SongIDs::Both(song_ids.0, song_ids.1));
+            return;
+        };
+        if let Some(fingerprint) = streamed.fingerprint {
+            fingerprints.push((SongIDs::Both(song_ids.0, song_ids.1), fingerprint));
+        }
+        bug_report::record_result(format!(
+            "songs {}: {}",
+            SongIDs::Both(song_ids.0, song_ids.1),
+            if streamed.ok { "OK" } else { "failed" }
+        ));
+        reporter.song_end(&SongIDs::Both(song_ids.0, song_ids.1), streamed.ok, None);
+        if !streamed.ok {
+            failed.push(SongIDs::Both(song_ids.0, song_ids.1));
+            if streamed.init_only {
+                init_only.push(SongIDs::Both(song_ids.0, song_ids.1));
+            }
+        }
+        stats.peak_diagnostics = stats.peak_diagnostics.max(streamed.nb_diagnostics);
+        stats
+            .songs
+            .push((SongIDs::Both(song_ids.0, song_ids.1), song_start.elapsed()));
+    }
+
+    /// Simulates a pair of songs, or loads them from the cache. Returns `None` if either failed to
+    /// simulate, which has been reported already; otherwise, also returns the SRAM that the
+    /// "after" song started from, for `--replay-reads`.
+    #[allow(clippy::type_complexity)]
+    fn simulate_songs(
+        &mut self,
+        song_ids: (u8, u8),
+    ) -> Result<Option<((Logbook, Logbook), Option<Box<Sram>>)>, Fatal> {
+        let Self {
+            args,
+            ref sim_params,
+            ref presets,
+            ref mut trace_file,
+            ref mut reporter,
+            before_data,
+            after_data,
+            ref before_gbs,
+            ref after_gbs,
+            after_path,
+            cache_dir,
+            ref mut srams,
+            ref mut nondeterministic,
+            ref mut stats,
+            ref mut init_outcomes,
+            ref mut failed,
+            ..
+        } = *self;
+        let cache_keys = (
+            cache::key(before_gbs, song_ids.0, sim_params),
+            cache::key(after_gbs, song_ids.1, sim_params),
+        );
+        let load_cached = |key| {
+            cache_dir
+                .filter(|_| trace_file.is_none() && !args.verify_determinism)
+                .and_then(|dir| cache::load(dir, key))
+        };
+        let cached = (load_cached(&cache_keys.0), load_cached(&cache_keys.1));
+        reporter.progress(
+            "Simulating",
+            &format_args!(
+                "songs {}{}{}",
+                SongIDs::Both(song_ids.0, song_ids.1),
+                presets,
+                match (cached.0.is_some(), cached.1.is_some()) {
+                    (true, true) => " (cached)",
+                    (true, false) => " (before cached)",
+                    (false, true) => " (after cached)",
+                    (false, false) => "",
+                }
+            ),
+        );
+        // The replay must start from the same SRAM as the "after" song.
+        let replay_sram = srams.as_ref().map(|srams| srams.1.clone());
+        macro_rules! simulate {
+            ($gbs:expr, $data:expr, $song_id:expr, $path:expr, $key:expr, $sram:expr, $side:ident, $outcomes:expr) => {{
+                let start = Instant::now();
+                let state_name = format!("song-{}-{}.state", $song_id, stringify!($side));
+                let resume = args
+                    .load_state
+                    .as_ref()
+                    .map(|dir| {
+                        let path = std::path::Path::new(dir).join(&state_name);
+                        run::snapshot::load(&path, $data, $song_id).map_err(|err| {
+                            eprintln!(
+                                "{} while loading {}: {}",
+                                colorize!(Stderr, "Error", bright_red, bold),
+                                path.display(),
+                                err
+                            );
+                            Fatal
+                        })
+                    })
+                    .transpose()?;
+                let save_at = args
+                    .at_tick
+                    .or_else(|| args.at_time.map(|time| time_to_ticks(time, $gbs)));
+                let sram: Option<&mut run::Sram> = $sram;
+                // The first run replaces the SRAM contents, but the second must start from the same.
+                let initial_sram = sram.as_deref().copied().filter(|_| args.verify_determinism);
+                match run::simulate_song_resumable(
+                    $gbs,
+                    $song_id,
+                    &sim_params,
+                    trace_file.as_mut(),
+                    sram,
+                    resume.as_ref(),
+                    save_at,
+                ) {
+                    Ok((log, snapshot)) => {
+                        if args.verify_determinism {
+                            let mut sram = initial_sram;
+                            let divergence = match run::simulate_song_resumable(
+                                $gbs,
+                                $song_id,
+                                &sim_params,
+                                None::<io::Sink>,
+                                sram.as_mut(),
+                                resume.as_ref(),
+                                None,
+                            ) {
+                                Ok((second, _)) => determinism::first_divergence(&log, &second)
+                                    .map(|divergence| divergence.to_string()),
+                                Err(err) => Some(format!("the second run failed: {}", err)),
+                            };
+                            if let Some(divergence) = divergence {
+                                eprintln!(
+                                    "{}: simulating {} song {} twice gave different results: {}",
+                                    colorize!(Stderr, "Internal error", bright_red, bold),
+                                    $path,
+                                    $song_id,
+                                    divergence
+                                );
+                                *nondeterministic = true;
+                            }
+                        }
+                        if let (Some(dir), Some(tick)) = (&args.save_state, save_at) {
+                            let path = std::path::Path::new(dir).join(&state_name);
+                            match snapshot {
+                                Some(snapshot) => {
+                                    run::snapshot::save(&path, $data, $song_id, &snapshot)
+                                        .map_err(|err| {
+                                            eprintln!(
+                                                "{} while saving {}: {}",
+                                                colorize!(Stderr, "Error", bright_red, bold),
+                                                path.display(),
+                                                err
+                                            );
+                                            Fatal
+                                        })?
+                                }
+                                None => reporter.warning(&format_args!(
+                                    "{}: song {} ended before tick {}, so its state was not saved",
+                                    $path, $song_id, tick
+                                )),
+                            }
+                        }
+                        $outcomes.record($song_id, None, args.early_init_cycles);
+                        stats.$side.add(
+                            &log,
+                            ticks_to_secs(log.ticks_simulated, $gbs),
+                            start.elapsed(),
+                        );
+                        if let Some(dir) = cache_dir {
+                            if let Err(err) = cache::store(dir, $key, &log) {
+                                reporter.warning(&format_args!(
+                                    "Failed to write to the cache directory: {}",
+                                    err
+                                ));
+                            }
+                        }
+                        log
+                    }
+                    Err(err) => {
+                        $outcomes.record($song_id, Some(&err), args.early_init_cycles);
+                        reporter.simulation_failed(&$path, $song_id, &err);
+                        bug_report::record_result(format!(
+                            "songs {}: simulation failed: {}",
+                            SongIDs::Both(song_ids.0, song_ids.1),
+                            err
+                        ));
+                        failed.push(SongIDs::Both(song_ids.0, song_ids.1));
+                        return Ok(None);
+                    }
+                }
+            }};
+        }
+        stats.nb_cached += usize::from(cached.0.is_some()) + usize::from(cached.1.is_some());
+        let logs = (
+            match cached.0 {
+                Some(log) => {
+                    init_outcomes
+                        .0
+                        .record(song_ids.0, None, args.early_init_cycles);
+                    log
+                }
+                None => simulate!(
+                    &before_gbs,
+                    &before_data,
+                    song_ids.0,
+                    args.before,
+                    &cache_keys.0,
+                    srams.as_mut().map(|srams| &mut *srams.0),
+                    before,
+                    init_outcomes.0
+                ),
+            },
+            match cached.1 {
+                Some(log) => {
+                    init_outcomes
+                        .1
+                        .record(song_ids.1, None, args.early_init_cycles);
+                    log
+                }
+                None => simulate!(
+                    &after_gbs,
+                    &after_data,
+                    song_ids.1,
+                    after_path,
+                    &cache_keys.1,
+                    srams.as_mut().map(|srams| &mut *srams.1),
+                    after,
+                    init_outcomes.1
+                ),
+            },
+        );
+        Ok(Some((logs, replay_sram)))
+    }
+
+    /// Compares a pair of songs that have been simulated in full, and reports the differences.
+    fn compare_songs(
+        &mut self,
+        song_ids: (u8, u8),
+        mut logs: (Logbook, Logbook),
+        mut replay_sram: Option<Box<Sram>>,
+        song_start: Instant,
+    ) {
+        let Self {
+            args,
+            ref sim_params,
+            ref symbols,
+            ref mut baseline,
+            ref mut new_baseline,
+            ref ignore_regs,
+            ref shadows,
+            ref mut reporter,
+            ref before_gbs,
+            ref after_gbs,
+            after_path,
+            normalize_time,
+            ref focus,
+            csv_dir,
+            ref mut unified_out,
+            ref mut stats,
+            ref mut failed,
+            ref mut init_only,
+            ref mut fingerprints,
+            ..
+        } = *self;
+        // Done after caching, so that the cached results don't depend on it.
+        for logbook in [&mut logs.0, &mut logs.1] {
+            logbook
+                .io_log
+                .retain(|access| !ignore_regs.contains(&access.addr));
+        }
+
+        write_logs(reporter, args, csv_dir, song_ids, &logs);
+
+        reporter.progress(
+            "Comparing",
+            &format_args!("songs {}", SongIDs::Both(song_ids.0, song_ids.1)),
+        );
+        bug_report::set_phase(bug_report::Phase::Comparing);
+
+        let after_io_log = if normalize_time {
+            Cow::Owned(run::rebase_ticks(
+                &logs.1.io_log,
+                after_gbs.cycles_per_tick(),
+                before_gbs.cycles_per_tick(),
+            ))
+        } else {
+            Cow::Borrowed(&logs.1.io_log)
+        };
+
+        // Simulation still ran from the very beginning, so that the state is correct within the window.
+        let windows = (
+            TickWindow::new(args, before_gbs, &logs.0),
+            TickWindow::new(
+                args,
+                if normalize_time {
+                    before_gbs
+                } else {
+                    after_gbs
+                },
+                &logs.1,
+            ),
+        );
+        for (window, path) in [(&windows.0, &args.before), (&windows.1, after_path)] {
+            if window.truncated {
+                reporter.warning(&format_args!(
+                    "--to lies past the end of {}'s simulation ({} ticks); comparing up to its end",
+                    path, window.compared.end,
+                ));
+            }
+            if args.skip_ticks != 0 && window.skipped.end >= window.compared.end {
+                reporter.warning(&format_args!(
+                    "--skip-ticks skips all of {}'s simulation ({} ticks)",
+                    path, window.compared.end,
+                ));
+            }
+        }
+        let compared_logs = (
+            windows.0.compared_log(&logs.0.io_log),
+            windows.1.compared_log(&after_io_log),
+        );
+        let io_logs = (&*compared_logs.0, &*compared_logs.1);
+        if let Some(out) = unified_out {
+            let fuzzy = if args.unified_fuzz {
+                unified::fuzz(io_logs, args.jitter)
+            } else {
+                Default::default()
+            };
+            let patch = unified::unified_diff(
+                &unified::render(io_logs.0, &fuzzy.0),
+                &unified::render(io_logs.1, &fuzzy.1),
+                (
+                    &format!("{}\tsong #{}", args.before, song_ids.0),
+                    &format!("{}\tsong #{}", after_path, song_ids.1),
+                ),
+            );
+            if let Err(err) = out.write_all(patch.as_bytes()) {
+                reporter.warning(&format_args!("Failed to write the unified diff: {}", err));
+                *unified_out = None;
+            }
+        }
+        let nb_skipped = Cell::new(0usize);
+        let in_window = |window: &TickWindow, tick| {
+            let contained = window.contains(tick);
+            if !contained {
+                nb_skipped.set(nb_skipped.get() + 1);
+            }
+            contained
+        };
+
+        let mut ok = true;
+        let mut phases = report::PhaseTally::default();
+        // The diffs are sorted by tick, but the tick state is compared separately.
+        let mut first_difference = None;
+        let mut tick = u64::MAX;
+        let sim_diags = match args.print_diagnostics {
+            BeforeOrAfter::Before => Some((&logs.0, &windows.0)),
+            BeforeOrAfter::After => Some((&logs.1, &windows.1)),
+            BeforeOrAfter::None => None,
+        }
+        .map(|(logs, window)| {
+            logs.diagnostics
+                .iter()
+                .filter(|diag| window.compared.contains(&diag.when.tick))
+                .filter(move |diag| {
+                    window.skipped.contains(&diag.when.tick) || in_window(window, diag.when.tick)
+                })
+                .map(move |diag| {
+                    let mut diag = diag.clone();
+                    if window.skipped.contains(&diag.when.tick) {
+                        diag.level = DiagnosticLevel::Note;
+                    }
+                    diag
+                })
+        })
+        .into_iter()
+        .flatten();
+
+        // Only printed once one of its diagnostics is, since they may all be cut.
+        let mut pending_tick = None;
+        // Everything gets counted for `--stat`.
+        let new_budget = || {
+            if args.stat {
+                report::Budget::new(usize::MAX, usize::MAX)
+            } else {
+                report::Budget::new(args.max_reports, args.max_total_reports)
+            }
+        };
+        let mut budget = new_budget();
+        let mut nb_diagnostics = 0usize;
+        // Evaluates to whether the diagnostic was printed.
+        // `$before` runs after the tick's header, if the diagnostic is printed.
+        macro_rules! report {
+            ($diag:expr) => {
+                report!($diag, {})
+            };
+            ($diag:expr, $before:block) => {{
+                nb_diagnostics += 1;
+                phases.count($diag.when.tick, $diag.level);
+                if budget.admit($diag.level) {
+                    if let Some(tick) = pending_tick.take() {
+                        reporter.tick(tick);
+                    }
+                    $before
+                    reporter.diagnostic(
+                        $diag.level,
+                        $diag.when.cycle,
+                        &sym::Pc(&$diag.pc, symbols.as_ref()),
+                        &$diag.kind,
+                    );
+                    true
+                } else {
+                    false
+                }
+            }};
+        }
+        // Each explanation is only given the first time it applies in a song.
+        let mut explained = HashSet::new();
+        let mut fingerprinter = fingerprint::Fingerprinter::default();
+        let mut nb_known = 0usize;
+        let mut diff_csv = csv_dir.and_then(|dir| {
+            csv::create(
+                dir,
+                &format!("diff-song-{}-{}.csv", song_ids.0, song_ids.1),
+                args.force,
+            )
+            .and_then(csv::DiffWriter::new)
+            .map_err(|err| reporter.warning(&format_args!("Failed to write CSV: {}", err)))
+            .ok()
+        });
+
+        for (logbook, gbs, path) in [
+            (&logs.0, &before_gbs, &args.before),
+            (&logs.1, &after_gbs, after_path),
+        ] {
+            reporter.info(&format_args!(
+                "{}: song ran for {} ticks ({}) and ended due to {}",
+                path,
+                logbook.ticks_simulated,
+                format_secs(ticks_to_secs(logbook.ticks_simulated, gbs)),
+                logbook.termination,
+            ));
+            if args.wait_for.is_some() {
+                reporter.info(&format_args!(
+                    "{}: skipped {} warm-up ticks before --wait-for held",
+                    path, logbook.warmup_ticks,
+                ));
+            }
+        }
+        // Measured in the "before" file's ticks, in case the two don't tick at the same rate.
+        let after_length = (ticks_to_secs(logs.1.ticks_simulated, after_gbs)
+            * cycles_per_sec(before_gbs) as f64
+            / f64::from(before_gbs.cycles_per_tick()))
+        .round() as u64;
+        let length_diff = logs.0.ticks_simulated.abs_diff(after_length);
+        if length_diff > args.length_tolerance && DiagnosticLevel::Warning <= args.max_level {
+            ok = false;
+            phases.fail(Phase::Play);
+            if budget.admit(DiagnosticLevel::Warning) {
+                reporter.finding(
+                    DiagnosticLevel::Warning,
+                    &format_args!(
+                        "the songs' lengths differ by {} ticks ({} before, {} after)",
+                        length_diff,
+                        format_secs(ticks_to_secs(logs.0.ticks_simulated, before_gbs)),
+                        format_secs(ticks_to_secs(logs.1.ticks_simulated, after_gbs)),
+                    ),
+                );
+            }
+        }
+
+        let drift = tempo::detect(
+            &tempo::note_events(io_logs.0),
+            &tempo::note_events(io_logs.1),
+        );
+        if let Some(drift) = &drift {
+            if DiagnosticLevel::Warning <= args.max_level {
+                reporter.finding(DiagnosticLevel::Warning, drift);
+            }
+        }
+        let nb_drifted = Cell::new(0usize);
+
+        // Per register.
+        let indirect = RefCell::new(BTreeMap::<u16, usize>::new());
+        if focus.is_active() {
+            reporter.heading(&"Direct findings");
+        }
+
+        let compare_writes = args.compare != CompareMode::State;
+        let mut generator = compare_writes.then(|| {
+            diff::DiffGenerator::new(io_logs.0, io_logs.1, args.jitter, args.div_phase_tolerance)
+        });
+        let mut write_diags: Vec<_> = generator.iter_mut().flatten().collect();
+        let matched = generator
+            .map(diff::DiffGenerator::into_matched)
+            .unwrap_or_default();
+        diff::pair_freq_writes(&mut write_diags, io_logs);
+        diff::pair_slipped_writes(
+            &mut write_diags,
+            io_logs,
+            args.slip_window,
+            args.jitter,
+            &before_gbs.cadence(),
+        );
+        realloc::collapse(&mut write_diags);
+        if !args.strict_values {
+            diff::relax_field_changes(&mut write_diags, args.relax_duty);
+        }
+        let wave_writes_differ = write_diags
+            .iter()
+            .any(|diag| waves::WAVE_RAM.contains(&diag.kind.reg()));
+        let diff_diags = write_diags
+            .into_iter()
+            .filter(|diag| diag.level <= args.max_level)
+            .filter(|diag| {
+                let window = if diag.kind.is_from_before() {
+                    &windows.0
+                } else {
+                    &windows.1
+                };
+                in_window(window, diag.when.tick)
+            })
+            .filter(|diag| {
+                let Some(drift) = drift.as_ref().filter(|_| args.suppress_drift_cascade) else {
+                    return true;
+                };
+                let start = if diag.kind.is_from_before() {
+                    drift.cascade_start.0
+                } else {
+                    drift.cascade_start.1
+                };
+                let drifted = diag.when.tick >= start;
+                nb_drifted.set(nb_drifted.get() + usize::from(drifted));
+                !drifted
+            })
+            .filter(|diag| {
+                let direct = !focus.is_active() || focus.is_direct(&diag.pc);
+                if !direct {
+                    *indirect.borrow_mut().entry(diag.kind.reg()).or_default() += 1;
+                }
+                direct
+            })
+            .filter(|diag| {
+                let known = known_difference(args, baseline, new_baseline, song_ids.0, diag);
+                nb_known += usize::from(known);
+                !known
+            })
+            // The counts above must cover every diagnostic, regardless of how many get reported.
+            .collect::<Vec<_>>();
+        // Prints a diagnostic, after its tick's header if it is the first of that tick to be.
+        macro_rules! emit {
+            ($diagnostic:expr) => {
+                let when = match &$diagnostic {
+                    merge::Either::Left(diag) => &diag.when,
+                    merge::Either::Right(diag) => &diag.when,
+                };
+                if tick != when.tick {
+                    tick = when.tick;
+                    pending_tick = Some(tick);
+                }
+                match $diagnostic {
+                    merge::Either::Left(diag) => {
+                        report!(diag);
+                    }
+                    merge::Either::Right(diagnostic) => {
+                        // Context lines don't count against the budget, like explanations.
+                        let (preceding, following) = if args.context != 0 {
+                            diff::context(io_logs, &matched, &diagnostic, args.context)
+                        } else {
+                            Default::default()
+                        };
+                        let reported = report!(diagnostic, {
+                            report_context(reporter, &preceding);
+                        });
+                        if reported {
+                            report_context(reporter, &following);
+                        }
+                        if reported && args.explain {
+                            // The other half of the period may also be written just after, in the same tick.
+                            let partner = explain::period_partner(&diagnostic.kind).and_then(|reg| {
+                                let mut writes = io_logs.0.iter().filter(|access| access.addr == reg);
+                                writes
+                                    .clone()
+                                    .rev()
+                                    .find(|access| access.when <= diagnostic.when)
+                                    .or_else(|| {
+                                        writes.find(|access| access.when.tick == diagnostic.when.tick)
+                                    })
+                                    .map(|access| access.data)
+                            });
+                            let explanation = explain::explain(&diagnostic.kind, partner);
+                            if explained.insert(explanation.key) {
+                                reporter.line(&format_args!("    {}", explanation.text));
+                            }
+                        }
+                    }
+                }
+            };
+        }
+        // Only filled with `--group-by channel`; simulation diagnostics count as "other".
+        let mut groups: [Vec<_>; channel::Channel::ALL.len()] = Default::default();
+        // Both are reported in chronological order, simulation diagnostics first within a cycle.
+        for diagnostic in merge::merge(
+            sim_diags,
+            diff_diags.into_iter(),
+            |diag| diag.when.clone(),
+            |diag| diag.when.clone(),
+        ) {
+            let group = match &diagnostic {
+                merge::Either::Left(_) => channel::Channel::Other,
+                merge::Either::Right(diagnostic) => {
+                    first_difference.get_or_insert(diagnostic.when.tick);
+                    ok = false;
+                    phases.fail(Phase::of(diagnostic.when.tick));
+                    fingerprinter.add(&diagnostic.kind);
+                    if let Some(Err(err)) = diff_csv.as_mut().map(|writer| writer.write(diagnostic))
+                    {
+                        reporter.warning(&format_args!("Failed to write CSV: {}", err));
+                        diff_csv = None;
+                    }
+                    channel::Channel::of(diagnostic.kind.reg())
+                }
+            };
+            match args.group_by {
+                GroupBy::Time => {
+                    emit!(diagnostic);
+                }
+                GroupBy::Channel => groups[group as usize].push(diagnostic),
+            }
+        }
+        // Each channel gets its own budget, so that a noisy one doesn't hide the others.
+        for (channel, group) in channel::Channel::ALL.iter().zip(groups) {
+            if group.is_empty() {
+                continue;
+            }
+            reporter.heading(&format_args!("{}: {} diagnostics", channel, group.len()));
+            let song_budget = std::mem::replace(&mut budget, new_budget());
+            tick = u64::MAX;
+            for diagnostic in group {
+                emit!(diagnostic);
+            }
+            budget.report_cuts(reporter);
+            budget = song_budget;
+        }
+
+        // Simulation diagnostics are only errors if promoted to such, in which case they must fail
+        // the song, even if they aren't being printed.
+        for (logbook, window) in [(&logs.0, &windows.0), (&logs.1, &windows.1)] {
+            for diag in &logbook.diagnostics {
+                if diag.level == DiagnosticLevel::Error && window.contains(diag.when.tick) {
+                    ok = false;
+                    phases.fail(Phase::of(diag.when.tick));
+                }
+            }
+            for tick in unrecorded_error_ticks(logbook).filter(|&tick| window.contains(tick)) {
+                ok = false;
+                phases.fail(Phase::of(tick));
+            }
+        }
+
+        if args.compare != CompareMode::Writes {
+            let after_expiries: Vec<_> = if normalize_time {
+                logs.1
+                    .length_expiries
+                    .iter()
+                    .map(|&(tick, channel)| {
+                        // Expiries happen on the last cycle of their tick.
+                        let cycles = (tick + 1) * u64::from(after_gbs.cycles_per_tick()) - 1;
+                        (cycles / u64::from(before_gbs.cycles_per_tick()), channel)
+                    })
+                    .collect()
+            } else {
+                logs.1.length_expiries.clone()
+            };
+            let state_diffs: Vec<_> = state::compare(
+                &state::snapshots(&logs.0.io_log, &logs.0.length_expiries),
+                &state::snapshots(&after_io_log, &after_expiries),
+            )
+            .into_iter()
+            .filter(|(tick, _)| in_window(&windows.1, *tick))
+            .collect();
+            ok &= state_diffs.is_empty();
+            if let Some(&(tick, _)) = state_diffs.first() {
+                first_difference =
+                    Some(first_difference.map_or(tick, |first: u64| first.min(tick)));
+            }
+            let mut needs_heading = true;
+            for (tick, diff) in &state_diffs {
+                phases.count(*tick, DiagnosticLevel::Error);
+                phases.fail(Phase::of(*tick));
+                if budget.admit(DiagnosticLevel::Error) {
+                    if needs_heading {
+                        reporter.heading(&"Tick state differences");
+                        needs_heading = false;
+                    }
+                    reporter.finding(
+                        DiagnosticLevel::Error,
+                        &format_args!("at the end of tick {}, {}", tick, diff),
+                    );
+                }
+            }
+        }
+        if !shadows.is_empty() {
+            let after_shadow_log = if normalize_time {
+                Cow::Owned(run::rebase_ticks(
+                    &logs.1.shadow_log,
+                    after_gbs.cycles_per_tick(),
+                    before_gbs.cycles_per_tick(),
+                ))
+            } else {
+                Cow::Borrowed(&logs.1.shadow_log)
+            };
+            let shadow_diffs: Vec<_> = diff::DiffGenerator::new(
+                &windows.0.compared_log(&logs.0.shadow_log),
+                &windows.1.compared_log(&after_shadow_log),
+                args.jitter,
+                args.div_phase_tolerance,
+            )
+            .filter(|diag| diag.level <= args.max_level)
+            .filter(|diag| {
+                let known = known_difference(args, baseline, new_baseline, song_ids.0, diag);
+                nb_known += usize::from(known);
+                !known
+            })
+            .collect();
+            let mut needs_heading = true;
+            for diag in &shadow_diffs {
+                phases.count(diag.when.tick, diag.level);
+                if diag.level == DiagnosticLevel::Error {
+                    ok = false;
+                    phases.fail(Phase::of(diag.when.tick));
+                }
+                fingerprinter.add(&diag.kind);
+                if let Some(Err(err)) = diff_csv.as_mut().map(|writer| writer.write(diag)) {
+                    reporter.warning(&format_args!("Failed to write CSV: {}", err));
+                    diff_csv = None;
+                }
+                if !budget.admit(diag.level) {
+                    continue;
+                }
+                if needs_heading {
+                    reporter.heading(&"Shadow register differences");
+                    needs_heading = false;
+                }
+                let shadow = shadows
+                    .iter()
+                    .find(|shadow| shadow.addr == diag.kind.reg())
+                    .expect("Shadow writes are only logged for shadows");
+                reporter.finding(
+                    diag.level,
+                    &format_args!(
+                        "at tick {} ({}, shadow of {}): {}",
+                        diag.when.tick,
+                        shadow.name,
+                        diff::RegDispl(shadow.reg),
+                        diag.kind
+                    ),
+                );
+            }
+
+            // Those are about the driver itself rather than the differences between both files,
+            // so they don't fail the comparison.
+            if DiagnosticLevel::Warning <= args.max_level {
+                for (logbook, window, path) in [
+                    (&logs.0, &windows.0, &args.before),
+                    (&logs.1, &windows.1, after_path),
+                ] {
+                    let mismatches: Vec<_> =
+                        shadow::check(&logbook.io_log, &logbook.shadow_log, shadows)
+                            .into_iter()
+                            .filter(|mismatch| window.contains(mismatch.tick))
+                            .collect();
+                    let mut needs_heading = true;
+                    for mismatch in &mismatches {
+                        phases.count(mismatch.tick, DiagnosticLevel::Warning);
+                        if !budget.admit(DiagnosticLevel::Warning) {
+                            continue;
+                        }
+                        if needs_heading {
+                            reporter
+                                .heading(&format_args!("Shadow register mismatches in {}", path));
+                            needs_heading = false;
+                        }
+                        reporter.finding(DiagnosticLevel::Warning, mismatch);
+                    }
+                }
+            }
+        }
+        if let Some(Err(err)) = diff_csv.map(csv::DiffWriter::finish) {
+            reporter.warning(&format_args!("Failed to write CSV: {}", err));
+        }
+
+        let unrecorded = match args.print_diagnostics {
+            BeforeOrAfter::Before => Some((&logs.0, &windows.0)),
+            BeforeOrAfter::After => Some((&logs.1, &windows.1)),
+            BeforeOrAfter::None => None,
+        };
+        if let Some((logbook, window)) = unrecorded {
+            report_unrecorded(
+                reporter,
+                &logbook.unrecorded_within(|tick| window.contains(tick)),
+            );
+        }
+
+        if nb_drifted.get() != 0 {
+            ok = false;
+            phases.fail(Phase::Play);
+            reporter.heading(&format_args!(
+                "{} differences past the tempo drift suppressed (--suppress-drift-cascade)",
+                nb_drifted.get()
+            ));
+        }
+        if let Some(baseline) = baseline.as_ref() {
+            if nb_known != 0 {
+                reporter.heading(&format_args!(
+                    "{} known differences suppressed (--baseline)",
+                    nb_known
+                ));
+            }
+            if DiagnosticLevel::Note <= args.max_level {
+                for key in baseline
+                    .stale(song_ids.0)
+                    .filter(|key| windows.0.contains(key.tick()))
+                {
+                    reporter.finding(
+                        DiagnosticLevel::Note,
+                        &format_args!("stale baseline entry: {}", key),
+                    );
+                }
+            }
+        }
+
+        let indirect = indirect.into_inner();
+        if !indirect.is_empty() {
+            let nb_indirect: usize = indirect.values().sum();
+            if args.hide_indirect {
+                reporter.heading(&format_args!(
+                    "{} indirect findings hidden (--hide-indirect)",
+                    nb_indirect
+                ));
+            } else {
+                ok = false;
+                phases.fail(Phase::Play);
+                reporter.heading(&format_args!("Indirect findings: {}", nb_indirect));
+                for (reg, count) in &indirect {
+                    reporter.line(&format_args!("{}: {}", diff::RegDispl(*reg), count));
+                }
+            }
+        }
+
+        for &tick in &args.show_tick {
+            reporter.heading(&format_args!("Tick {}, side by side", tick));
+            let rows = transcript::rows(&logs.0.io_log, &after_io_log, tick, args.jitter);
+            if rows.is_empty() {
+                reporter.line(&"No writes from either file");
+            }
+            for row in rows {
+                reporter.columns(&row.before, &row.after, row.level);
+            }
+        }
+
+        report_metrics(reporter, args, after_gbs.cycles_per_tick(), &logs, &windows);
+
+        let spans = (
+            write_pairs::measure(io_logs.0),
+            write_pairs::measure(io_logs.1),
+        );
+        let growths: Vec<_> = write_pairs::compare(&spans.0, &spans.1, args.pair_span_threshold)
+            .into_iter()
+            .filter(|growth| windows.1.contains(growth.tick))
+            .collect();
+        // The overall stats are only worth printing as context for gaps that grew.
+        if !growths.is_empty() && DiagnosticLevel::Warning <= args.max_level {
+            reporter.info(&format_args!(
+                "Vulnerable write pairs (before): {}",
+                VulnerablePairs::new(&spans.0, args.pair_span_threshold)
+            ));
+            reporter.info(&format_args!(
+                "Vulnerable write pairs (after):  {}",
+                VulnerablePairs::new(&spans.1, args.pair_span_threshold)
+            ));
+            for growth in &growths {
+                if budget.admit(DiagnosticLevel::Warning) {
+                    reporter.finding(DiagnosticLevel::Warning, growth);
+                }
+            }
+        }
+
+        // Wave RAM may well have been filled before the compared ticks, so its contents are
+        // followed from the start.
+        let window_snapshots = |io_log: &[run::IoAccess], window: &TickWindow| {
+            let mut snapshots = waves::trigger_snapshots(io_log);
+            snapshots.retain(|snapshot| window.contains(snapshot.when.tick));
+            snapshots
+        };
+        let snapshots = (
+            window_snapshots(&logs.0.io_log, &windows.0),
+            window_snapshots(&after_io_log, &windows.1),
+        );
+        let trigger_diffs = waves::compare_triggers(&snapshots.0, &snapshots.1);
+        if DiagnosticLevel::Warning <= args.max_level {
+            for diff in &trigger_diffs {
+                if budget.admit(DiagnosticLevel::Warning) {
+                    reporter.finding(DiagnosticLevel::Warning, diff);
+                }
+            }
+        }
+        let refills = (
+            waves::refill_sequences(io_logs.0),
+            waves::refill_sequences(io_logs.1),
+        );
+        if DiagnosticLevel::Error <= args.max_level {
+            let refill_diffs: Vec<_> = waves::compare_refills(&refills.0, &refills.1)
+                .into_iter()
+                .filter(|diff| windows.1.contains(diff.tick))
+                .collect();
+            ok &= refill_diffs.is_empty();
+            for diff in &refill_diffs {
+                phases.count(diff.tick, DiagnosticLevel::Error);
+                phases.fail(Phase::of(diff.tick));
+                if budget.admit(DiagnosticLevel::Error) {
+                    reporter.finding(DiagnosticLevel::Error, diff);
+                }
+            }
+        }
+        // Every finding that counts against the budget has been reported by now.
+        budget.report_cuts(reporter);
+        if trigger_diffs.is_empty()
+            && wave_writes_differ
+            && !snapshots.0.is_empty()
+            && DiagnosticLevel::Note <= args.max_level
+        {
+            reporter.finding(
+                DiagnosticLevel::Note,
+                &"wave RAM writes differ, but every CH3 trigger plays the same waveform",
+            );
+        }
+
+        report_waveforms(reporter, args, &snapshots);
+
+        for (logs, path) in [(&logs.0, &args.before), (&logs.1, after_path)] {
+            if logs.stale_wave_reads != 0 {
+                reporter.warning(&format_args!(
+                    "{} read wave RAM {} times while CH3 was playing; results may not match hardware (see --wave-read-mode)",
+                    path,
+                    logs.stale_wave_reads,
+                ));
+            }
+        }
+
+        if args.replay_reads {
+            let forced_reads = replay::ReadQueues::new(&logs.0.read_log);
+            match run::simulate_song(
+                after_gbs,
+                song_ids.1,
+                sim_params,
+                Some(&forced_reads),
+                None::<io::Sink>,
+                replay_sram.as_deref_mut(),
+            ) {
+                Ok(replay_logs) => {
+                    let replay_io_log = if normalize_time {
+                        Cow::Owned(run::rebase_ticks(
+                            &replay_logs.io_log,
+                            after_gbs.cycles_per_tick(),
+                            before_gbs.cycles_per_tick(),
+                        ))
+                    } else {
+                        Cow::Borrowed(&replay_logs.io_log)
+                    };
+                    let nb_findings = diff::DiffGenerator::new(
+                        io_logs.0,
+                        &windows.1.compared_log(&replay_io_log),
+                        args.jitter,
+                        args.div_phase_tolerance,
+                    )
+                    .filter(|diag| diag.level <= args.max_level)
+                    .count();
+                    reporter.line(&format_args!(
+                        "Read replay: {}",
+                        replay::Outcome::new(!ok, nb_findings)
+                    ));
+                    for mismatch in forced_reads.count_mismatches() {
+                        reporter.line(&format_args!("Read replay: {}", mismatch));
+                    }
+                }
+                Err(err) => reporter.line(&format_args!("Read replay: simulation failed: {}", err)),
+            }
+        }
+
+        if nb_skipped.get() != 0 {
+            reporter.line(&format_args!(
+                "Skipped {} findings outside of the compared window",
+                nb_skipped.get()
+            ));
+        }
+
+        if let Some(tick) = first_difference {
+            reporter.first_difference(tick);
+        }
+        if !ok {
+            phases.report(reporter);
+        }
+        if let Some(fingerprint) = fingerprinter.finish() {
+            reporter.fingerprint(&fingerprint);
+            fingerprints.push((SongIDs::Both(song_ids.0, song_ids.1), fingerprint));
+        }
+        bug_report::record_result(format!(
+            "songs {}: {}",
+            SongIDs::Both(song_ids.0, song_ids.1),
+            if ok { "OK" } else { "failed" }
+        ));
+        reporter.song_end(
+            &SongIDs::Both(song_ids.0, song_ids.1),
+            ok,
+            windows.1.is_partial().then(|| windows.1.compared.clone()),
+        );
+        if !ok {
+            failed.push(SongIDs::Both(song_ids.0, song_ids.1));
+            if phases.init_only() {
+                init_only.push(SongIDs::Both(song_ids.0, song_ids.1));
+            }
+        }
+        stats.peak_diagnostics = stats.peak_diagnostics.max(nb_diagnostics);
+        stats
+            .songs
+            .push((SongIDs::Both(song_ids.0, song_ids.1), song_start.elapsed()));
+    }
+
+    /// Songs that only exist in one of the files can't be compared, but should at least run
+    /// cleanly; `nb_songs` are in both.
+    fn simulate_surplus(&mut self, nb_songs: u8) {
+        let Self {
+            args,
+            ref sim_params,
+            ref presets,
+            ref symbols,
+            ref mut trace_file,
+            ref mut reporter,
+            ref before_gbs,
+            ref after_gbs,
+            after_path,
+            ref mut srams,
+            ref mut stats,
+            ref mut init_outcomes,
+            ref mut failed,
+            ..
+        } = *self;
+        let surplus_is_before = before_gbs.nb_songs() > nb_songs;
+        let (surplus_gbs, surplus_path) = if surplus_is_before {
+            (&before_gbs, &args.before)
+        } else {
+            (&after_gbs, after_path)
+        };
+        for song_id in surplus_gbs.songs().skip(nb_songs.into()) {
+            let song_ids = if surplus_is_before {
+                SongIDs::BeforeOnly(song_id)
+            } else {
+                SongIDs::AfterOnly(song_id)
+            };
+            bug_report::set_song(song_id);
+
+            reporter.song_start(&song_ids);
+            reporter.progress("Simulating", &format_args!("song {}{}", song_ids, presets));
+            let song_start = Instant::now();
+            let result = run::simulate_song(
+                surplus_gbs,
+                song_id,
+                sim_params,
+                None,
+                trace_file.as_mut(),
+                srams.as_mut().map(|srams| {
+                    if surplus_is_before {
+                        &mut *srams.0
+                    } else {
+                        &mut *srams.1
+                    }
+                }),
+            );
+            if surplus_is_before {
+                &mut init_outcomes.0
+            } else {
+                &mut init_outcomes.1
+            }
+            .record(song_id, result.as_ref().err(), args.early_init_cycles);
+            let logs = match result {
+                Ok(logs) => {
+                    let side = if surplus_is_before {
+                        &mut stats.before
+                    } else {
+                        &mut stats.after
+                    };
+                    side.add(
+                        &logs,
+                        ticks_to_secs(logs.ticks_simulated, surplus_gbs),
+                        song_start.elapsed(),
+                    );
+                    logs
+                }
+                Err(err) => {
+                    reporter.simulation_failed(surplus_path, song_id, &err);
+                    bug_report::record_result(format!(
+                        "song {}: simulation failed: {}",
+                        song_ids, err
+                    ));
+                    failed.push(song_ids);
+                    continue;
+                }
+            };
+            reporter.line(if surplus_is_before {
+                &"Removed song, not compared"
+            } else {
+                &"New song, not compared"
+            });
+
+            report_sim_diagnostics(reporter, &logs, args, symbols.as_ref());
+            let ok = !fails_at(&logs, DiagnosticLevel::Error);
+            bug_report::record_result(format!(
+                "song {}: {}",
+                song_ids,
+                if ok { "OK" } else { "failed" }
+            ));
+            reporter.song_end(&song_ids, ok, None);
+            if !ok {
+                failed.push(song_ids);
+            }
+            stats.songs.push((song_ids, song_start.elapsed()));
+        }
+    }
+
+    /// Reports what concerns the whole run, and returns the exit code.
+    fn finish(mut self, run_start: Instant) -> Result<i32, Fatal> {
+        let Self {
+            args,
+            ref mut new_baseline,
+            ref mut reporter,
+            ref before_gbs,
+            ref after_gbs,
+            after_path,
+            ref mut nondeterministic,
+            ref mut stats,
+            ref mut init_outcomes,
+            ref mut failed,
+            ref mut init_only,
+            ref mut fingerprints,
+            ..
+        } = self;
+        if let Some(path) = args.write_baseline.as_ref() {
+            if let Err(err) =
+                File::create(path).and_then(|mut file| baseline::write(&mut file, new_baseline))
+            {
+                eprintln!(
+                    "{}: Failed to write baseline to {}: {}",
+                    colorize!(Stderr, "Error", bright_red, bold),
+                    path,
+                    err
+                );
+                return Err(Fatal);
+            }
+        }
+
+        for (outcomes, gbs, path) in [
+            (&init_outcomes.0, &before_gbs, &args.before),
+            (&init_outcomes.1, &after_gbs, after_path),
+        ] {
+            if let Some((first, last)) = outcomes.past_song_table(gbs) {
+                reporter.warning(&format_args!(
+                    "{}: {} fail during INIT almost immediately; the header's song count ({}) may exceed the driver's song table",
+                    path,
+                    if first == last {
+                        format!("song {}", first)
+                    } else {
+                        format!("songs {}-{}", first, last)
+                    },
+                    gbs.nb_songs(),
+                ));
+            }
+        }
+
+        for (fingerprint, songs) in fingerprint::shared(fingerprints) {
+            let songs: Vec<_> = songs.iter().map(SongIDs::to_string).collect();
+            reporter.info(&format_args!(
+                "Songs {} share diff fingerprint {}; likely the same root cause",
+                songs.join(", "),
+                fingerprint
+            ));
+        }
+
+        stats.total = run_start.elapsed();
+        reporter.summary(failed, init_only, stats);
+        Ok(if *nondeterministic {
+            determinism::EXIT_CODE
+        } else if failed.is_empty() {
+            0
+        } else {
+            1
+        })
+    }
+}
+
+/// Writes both songs' logs to the files requested by `--render` and `--csv`.
+fn write_logs(
+    reporter: &mut dyn Reporter,
+    args: &Args,
+    csv_dir: Option<&Path>,
+    song_ids: (u8, u8),
+    logs: &(Logbook, Logbook),
+) {
+    if let Some(template) = &args.render {
+        for (logbook, song_id, side) in [
+            (&logs.0, song_ids.0, "before"),
+            (&logs.1, song_ids.1, "after"),
+        ] {
+            let path = render::path(template, song_id, side);
+            let text = render::render(&logbook.io_log, logbook.ticks_simulated);
+            if let Err(err) = fs::write(&path, text) {
+                reporter.warning(&format_args!("Failed to write {}: {}", path, err));
+            }
+        }
+    }
+    if let Some(dir) = csv_dir {
+        for (logbook, song_id, side) in [
+            (&logs.0, song_ids.0, "before"),
+            (&logs.1, song_ids.1, "after"),
+        ] {
+            if let Err(err) =
+                csv::create(dir, &format!("song-{}-{}.csv", song_id, side), args.force)
+                    .and_then(|mut file| csv::write_io_log(&mut file, &logbook.io_log))
+            {
+                reporter.warning(&format_args!("Failed to write CSV: {}", err));
+            }
+        }
+    }
+}
+
+/// Reports how the songs' CPU and stack usage compare, and where their ticks end up; none of this
+/// fails the comparison.
+fn report_metrics(
+    reporter: &mut dyn Reporter,
+    args: &Args,
+    cycles_per_tick: u32,
+    logs: &(Logbook, Logbook),
+    windows: &(TickWindow, TickWindow),
+) {
+    if let (Some(before_stats), Some(after_stats)) = (
+        CpuStats::new(windows.0.play_ticks(&logs.0.tick_cycles)),
+        CpuStats::new(windows.1.play_ticks(&logs.1.tick_cycles)),
+    ) {
+        reporter.info(&format_args!("CPU usage (before): {}", before_stats));
+        reporter.info(&format_args!("CPU usage (after):  {}", after_stats));
+
+        if let Some(increase) = before_stats
+            .regression(&after_stats, args.cpu_regression_threshold)
+            .filter(|_| DiagnosticLevel::Warning <= args.max_level)
+        {
+            reporter.finding(
+                DiagnosticLevel::Warning,
+                &format_args!(
+                    "slowest tick got {:.1}% slower ({} -> {} cycles)",
+                    increase, before_stats.max, after_stats.max,
+                ),
+            );
+        }
+
+        let mut hotspots =
+            cpu_usage::hotspots(&logs.0.tick_cycles, &logs.1.tick_cycles, 2, usize::MAX);
+        hotspots.retain(|hotspot| windows.1.contains(hotspot.tick));
+        hotspots.truncate(5);
+        if !hotspots.is_empty() {
+            reporter.line(&"Ticks where \"after\" took at least twice as long as \"before\":");
+            for hotspot in &hotspots {
+                reporter.line(&format_args!("    {}", hotspot));
+            }
+        }
+    }
+
+    if let Some(comparison) = early_exit::compare(
+        windows.0.play_ticks(&logs.0.tick_cycles),
+        windows.1.play_ticks(&logs.1.tick_cycles),
+        windows.0.first_play_tick(),
+    ) {
+        let level = if comparison.divergence_percent() > f64::from(args.cadence_threshold) {
+            DiagnosticLevel::Warning
+        } else {
+            DiagnosticLevel::Note
+        };
+        if (comparison.before != comparison.after || comparison.first_divergence.is_some())
+            && level <= args.max_level
+        {
+            reporter.finding(level, &comparison);
+        }
+    }
+
+    if let (Some(&before_depth), Some(&after_depth)) = (
+        windows.0.play_ticks(&logs.0.stack_depths).iter().max(),
+        windows.1.play_ticks(&logs.1.stack_depths).iter().max(),
+    ) {
+        reporter.info(&format_args!(
+            "Max stack usage: {} bytes (before) vs {} bytes (after)",
+            before_depth, after_depth,
+        ));
+        if DiagnosticLevel::Warning <= args.max_level
+            && after_depth > before_depth.saturating_add(args.stack_growth_threshold)
+        {
+            reporter.finding(
+                DiagnosticLevel::Warning,
+                &format_args!(
+                    "stack usage grew by {} bytes ({} -> {})",
+                    after_depth - before_depth,
+                    before_depth,
+                    after_depth,
+                ),
+            );
+        }
+    }
+
+    if DiagnosticLevel::Warning <= args.max_level {
+        for diff in diff::compare_exit_banks(&logs.0.exit_banks, &logs.1.exit_banks, |tick| {
+            windows.1.contains(tick)
+        }) {
+            reporter.finding(DiagnosticLevel::Warning, &diff);
+        }
+        if args.debug_markers == DebugMarkers::Anchor {
+            let in_window = |markers: &[run::DebugMarker], window: &TickWindow| {
+                markers
+                    .iter()
+                    .filter(|marker| window.contains(marker.when.tick))
+                    .map(|marker| marker.when.tick)
+                    .collect::<Vec<_>>()
+            };
+            for diff in diff::compare_markers(
+                &in_window(&logs.0.debug_markers, &windows.0),
+                &in_window(&logs.1.debug_markers, &windows.1),
+            ) {
+                reporter.finding(DiagnosticLevel::Warning, &diff);
+            }
+        }
+    }
+
+    if let (Some(before_stats), Some(after_stats)) = (
+        LastWriteStats::new(windows.0.play_ticks(&logs.0.last_write_cycles)),
+        LastWriteStats::new(windows.1.play_ticks(&logs.1.last_write_cycles)),
+    ) {
+        reporter.info(&format_args!("Last writes (before): {}", before_stats));
+        reporter.info(&format_args!("Last writes (after):  {}", after_stats));
+
+        let mut losses = cpu_usage::margin_losses(
+            &logs.0.last_write_cycles,
+            &logs.1.last_write_cycles,
+            cycles_per_tick,
+            args.write_margin,
+        );
+        losses.retain(|loss| windows.1.contains(loss.tick));
+        if DiagnosticLevel::Warning <= args.max_level {
+            if let Some(worst) = losses.first() {
+                reporter.finding(
+                    DiagnosticLevel::Warning,
+                    &format_args!(
+                        "{} ticks have less than {} cycles of margin left after their last write; worst is {}",
+                        losses.len(),
+                        args.write_margin,
+                        worst,
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// Reports which CH3 waveforms each song plays, if they differ.
+fn report_waveforms(
+    reporter: &mut dyn Reporter,
+    args: &Args,
+    snapshots: &(Vec<waves::TriggerSnapshot>, Vec<waves::TriggerSnapshot>),
+) {
+    let inventories = (
+        waves::Inventory::new(&snapshots.0),
+        waves::Inventory::new(&snapshots.1),
+    );
+    if !inventories.0.is_empty() || !inventories.1.is_empty() {
+        reporter.info(&format_args!("CH3 waveforms (before): {}", inventories.0));
+        reporter.info(&format_args!("CH3 waveforms (after):  {}", inventories.1));
+
+        let mut print_wave = |level: DiagnosticLevel, wave: &waves::Waveform, what: &str| {
+            if level <= args.max_level {
+                reporter.finding(
+                    level,
+                    &format_args!("waveform {} {}", waves::WaveHash(wave), what),
+                );
+                if args.show_waves {
+                    reporter.line(&format_args!(
+                        "    {} {}",
+                        waves::WaveHex(wave),
+                        waves::WaveBars(wave)
+                    ));
+                }
+            }
+        };
+        match waves::compare(&inventories.0, &inventories.1) {
+            waves::InventoryDiff::Same => (),
+            waves::InventoryDiff::DifferentWaves {
+                before_only,
+                after_only,
+            } => {
+                for wave in before_only {
+                    print_wave(DiagnosticLevel::Warning, wave, "is only played before");
+                }
+                for wave in after_only {
+                    print_wave(DiagnosticLevel::Warning, wave, "is only played after");
+                }
+            }
+            waves::InventoryDiff::DifferentUsage(usage) => {
+                for (wave, before, after) in usage {
+                    print_wave(
+                        DiagnosticLevel::Note,
+                        wave,
+                        &format!(
+                            "is played by both builds, but triggered {} times before vs {} after",
+                            before, after
+                        ),
+                    );
+                }
+            }
+        }
+    }
+}
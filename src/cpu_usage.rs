@@ -0,0 +1,234 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with comparing how much CPU time the PLAY routine takes.
+
+use std::fmt::Display;
+
+/// Summary of the cycles spent by each PLAY call of a song.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuStats {
+    pub max: u32,
+    pub mean: f64,
+    pub p99: u32,
+}
+
+impl CpuStats {
+    /// `tick_cycles` should not include the INIT "tick", since it is not bound by the tick budget.
+    pub fn new(tick_cycles: &[u32]) -> Option<Self> {
+        let mut cycles = tick_cycles.to_vec();
+        if cycles.is_empty() {
+            return None;
+        }
+        cycles.sort_unstable();
+
+        let total: u64 = cycles.iter().copied().map(u64::from).sum();
+        // Nearest-rank percentile.
+        let p99_rank = (cycles.len() * 99 + 99) / 100;
+        Some(Self {
+            max: *cycles.last().unwrap(),
+            mean: total as f64 / cycles.len() as f64,
+            p99: cycles[p99_rank - 1],
+        })
+    }
+
+    /// By how many percent `after`'s max exceeds `self`'s (negative if it went down).
+    pub fn max_increase_percent(&self, after: &Self) -> f64 {
+        (f64::from(after.max) - f64::from(self.max)) * 100.0 / f64::from(self.max.max(1))
+    }
+
+    /// Returns `after`'s increase if it is more than `threshold` percent.
+    pub fn regression(&self, after: &Self, threshold: u16) -> Option<f64> {
+        let increase = self.max_increase_percent(after);
+        (increase > f64::from(threshold)).then_some(increase)
+    }
+}
+
+impl Display for CpuStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "max {} cycles, mean {:.1}, 99th percentile {}",
+            self.max, self.mean, self.p99
+        )
+    }
+}
+
+/// A tick where the "after" PLAY call took much longer than the "before" one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hotspot {
+    pub tick: u64,
+    pub before: u32,
+    pub after: u32,
+}
+
+impl Hotspot {
+    fn ratio(&self) -> f64 {
+        f64::from(self.after) / f64::from(self.before.max(1))
+    }
+}
+
+impl Display for Hotspot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "tick {}: {} -> {} cycles ({:.1}x)",
+            self.tick,
+            self.before,
+            self.after,
+            self.ratio()
+        )
+    }
+}
+
+/// Returns the (at most) `count` ticks where "after" spent at least `factor` times the cycles of "before", worst first.
+pub fn hotspots(before: &[u32], after: &[u32], factor: u16, count: usize) -> Vec<Hotspot> {
+    let mut hotspots: Vec<_> = before
+        .iter()
+        .zip(after)
+        .enumerate()
+        .skip(1) // INIT is not bound by the tick budget.
+        .filter(|(_, (&before, &after))| u64::from(after) >= u64::from(before) * u64::from(factor))
+        .map(|(tick, (&before, &after))| Hotspot {
+            tick: tick as u64,
+            before,
+            after,
+        })
+        .collect();
+    hotspots.sort_by(|a, b| b.ratio().total_cmp(&a.ratio()).then(a.tick.cmp(&b.tick)));
+    hotspots.truncate(count);
+    hotspots
+}
+
+/// How late within their ticks the last I/O writes of a song land.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LastWriteStats {
+    pub earliest: u32,
+    pub median: u32,
+    pub latest: u32,
+}
+
+impl LastWriteStats {
+    /// `last_write_cycles` is indexed by tick; like for [`CpuStats`], INIT is ignored.
+    pub fn new(last_write_cycles: &[Option<u32>]) -> Option<Self> {
+        let mut cycles: Vec<_> = last_write_cycles
+            .get(1..)?
+            .iter()
+            .flatten()
+            .copied()
+            .collect();
+        if cycles.is_empty() {
+            return None;
+        }
+        cycles.sort_unstable();
+
+        Some(Self {
+            earliest: cycles[0],
+            median: cycles[cycles.len() / 2],
+            latest: *cycles.last().unwrap(),
+        })
+    }
+}
+
+impl Display for LastWriteStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "earliest on cycle {}, median {}, latest {}",
+            self.earliest, self.median, self.latest
+        )
+    }
+}
+
+/// A tick whose last write got close to the tick budget in "after", whereas it wasn't in "before".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MarginLoss {
+    pub tick: u64,
+    pub before: u32,
+    pub after: u32,
+    pub budget: u32,
+}
+
+impl MarginLoss {
+    fn margin(&self) -> u32 {
+        self.budget.saturating_sub(self.after)
+    }
+}
+
+impl Display for MarginLoss {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "tick {}: last APU write moved from cycle {} to cycle {} of {} — little margin remains",
+            self.tick, self.before, self.after, self.budget
+        )
+    }
+}
+
+/// Returns the ticks where "after"'s last write leaves less than `margin` cycles before the end of the `budget`,
+/// but "before"'s did not; the smallest remaining margin comes first.
+pub fn margin_losses(
+    before: &[Option<u32>],
+    after: &[Option<u32>],
+    budget: u32,
+    margin: u16,
+) -> Vec<MarginLoss> {
+    let has_margin = |cycle: u32| budget.saturating_sub(cycle) >= margin.into();
+    let mut losses: Vec<_> = before
+        .iter()
+        .zip(after)
+        .enumerate()
+        .skip(1) // INIT is not bound by the tick budget.
+        .filter_map(|(tick, (before, after))| match (before, after) {
+            (Some(before), Some(after)) if has_margin(*before) && !has_margin(*after) => {
+                Some(MarginLoss {
+                    tick: tick as u64,
+                    before: *before,
+                    after: *after,
+                    budget,
+                })
+            }
+            _ => None,
+        })
+        .collect();
+    losses.sort_by_key(|loss| (loss.margin(), loss.tick));
+    losses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_aggregate_ticks() {
+        assert_eq!(CpuStats::new(&[]), None);
+        assert_eq!(
+            CpuStats::new(&[100, 300, 200]),
+            Some(CpuStats {
+                max: 300,
+                mean: 200.0,
+                p99: 300,
+            })
+        );
+        // The 99th percentile leaves out the slowest 1%, and doesn't care about the ticks' order.
+        let ticks: Vec<u32> = (1..=200).rev().collect();
+        let stats = CpuStats::new(&ticks).unwrap();
+        assert_eq!((stats.max, stats.p99), (200, 198));
+        assert_eq!(stats.mean, 100.5);
+    }
+
+    #[test]
+    fn regressions_must_exceed_the_threshold() {
+        let stats = |max| CpuStats::new(&[max]).unwrap();
+        assert_eq!(stats(100).regression(&stats(110), 10), None);
+        assert_eq!(stats(100).regression(&stats(111), 10), Some(11.0));
+        assert_eq!(stats(100).regression(&stats(50), 0), None);
+        assert_eq!(stats(100).regression(&stats(100), 0), None);
+        assert_eq!(stats(100).regression(&stats(101), 0), Some(1.0));
+        // Going from nothing to something doesn't divide by zero.
+        assert_eq!(stats(0).regression(&stats(3), 10), Some(300.0));
+    }
+}
@@ -0,0 +1,297 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with exporting IO logs and differences as CSV, for spreadsheet analysis.
+//!
+//! Rows are written as they are produced, so that nothing is buffered besides the file itself.
+
+use std::{
+    borrow::Cow,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    diff::{DiagnosticKind, RegDispl},
+    run::IoAccess,
+    Diagnostic,
+};
+
+/// Quotes the field if it contains anything that would otherwise be misparsed.
+fn field(text: &str) -> Cow<'_, str> {
+    if text.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", text.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+fn write_row<W: Write>(out: &mut W, fields: &[&str]) -> io::Result<()> {
+    for (i, text) in fields.iter().enumerate() {
+        if i != 0 {
+            out.write_all(b",")?;
+        }
+        out.write_all(field(text).as_bytes())?;
+    }
+    out.write_all(b"\r\n")
+}
+
+/// Creates a file in `dir`, refusing to overwrite an existing one unless `force` is set.
+pub fn create(dir: &Path, name: &str, force: bool) -> io::Result<BufWriter<File>> {
+    let path = dir.join(name);
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    match options.open(&path) {
+        Ok(file) => Ok(BufWriter::new(file)),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Err(io::Error::new(
+            err.kind(),
+            format!(
+                "{} already exists (use --force to overwrite)",
+                path.display()
+            ),
+        )),
+        Err(err) => Err(io::Error::new(
+            err.kind(),
+            format!("{}: {}", path.display(), err),
+        )),
+    }
+}
+
+pub fn write_io_log<W: Write>(out: &mut W, log: &[IoAccess]) -> io::Result<()> {
+    write_row(
+        out,
+        &[
+            "tick",
+            "cycle",
+            "bank",
+            "pc",
+            "register_name",
+            "address_hex",
+            "value_hex",
+        ],
+    )?;
+    for access in log {
+        let (bank, pc) = access.pc.canonical();
+        write_row(
+            out,
+            &[
+                &access.when.tick.to_string(),
+                &access.when.cycle.to_string(),
+                &format!("{:02x}", bank),
+                &format!("{:04x}", pc),
+                &RegDispl(access.addr).to_string(),
+                &format!("{:04x}", access.addr),
+                &format!("{:02x}", access.data),
+            ],
+        )?;
+    }
+    out.flush()
+}
+
+/// Writes rows of differences, one call at a time.
+pub struct DiffWriter<W: Write>(W);
+
+impl<W: Write> DiffWriter<W> {
+    pub fn new(mut out: W) -> io::Result<Self> {
+        write_row(
+            &mut out,
+            &[
+                "tick",
+                "cycle",
+                "bank",
+                "pc",
+                "level",
+                "kind",
+                "register_name",
+                "address_hex",
+                "before_value",
+                "after_value",
+                "cycle_delta",
+            ],
+        )?;
+        Ok(Self(out))
+    }
+
+    pub fn write(&mut self, diag: &Diagnostic<DiagnosticKind>) -> io::Result<()> {
+        let hex = |value: u8| format!("{:02x}", value);
+        let (before, after) = match diag.kind {
+            DiagnosticKind::Removed(_, value) => (hex(value), String::new()),
+            DiagnosticKind::Added(_, value) => (String::new(), hex(value)),
+            DiagnosticKind::Moved(_, value, _)
+            | DiagnosticKind::OtherReg(_, value, _)
+            | DiagnosticKind::SlippedTick(_, value, ..) => (hex(value), hex(value)),
+            DiagnosticKind::OtherValue(_, before, after) => (hex(before), hex(after)),
+            DiagnosticKind::TriggerChanged(_, _, value) => {
+                (hex(value ^ crate::diff::TRIGGER_BIT), hex(value))
+            }
+            DiagnosticKind::FreqChanged { before, after, .. } => {
+                (format!("{:03x}", before), format!("{:03x}", after))
+            }
+            DiagnosticKind::ChannelReallocation { .. }
+            | DiagnosticKind::FreqOrderSwapped { .. }
+            | DiagnosticKind::DivResetMoved(_) => (String::new(), String::new()),
+        };
+        let delta = match diag.kind {
+            DiagnosticKind::Moved(_, _, delta) | DiagnosticKind::DivResetMoved(delta) => {
+                delta.to_string()
+            }
+            _ => String::new(),
+        };
+        let (bank, pc) = diag.pc.canonical();
+        write_row(
+            &mut self.0,
+            &[
+                &diag.when.tick.to_string(),
+                &diag.when.cycle.to_string(),
+                &format!("{:02x}", bank),
+                &format!("{:04x}", pc),
+                diag.level.name(),
+                diag.kind.name(),
+                &RegDispl(diag.kind.reg()).to_string(),
+                &format!("{:04x}", diag.kind.reg()),
+                &before,
+                &after,
+                &delta,
+            ],
+        )
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Address, DiagnosticLevel, Timestamp};
+
+    /// Splits CSV text back into rows of fields, undoing [`field`]'s quoting.
+    fn read(text: &str) -> Vec<Vec<String>> {
+        let mut rows = vec![];
+        let mut row = vec![];
+        let mut text = text;
+        while !text.is_empty() {
+            let value = if let Some(quoted) = text.strip_prefix('"') {
+                let mut value = String::new();
+                let mut rest = quoted;
+                loop {
+                    let end = rest.find('"').expect("unterminated quoted field");
+                    value.push_str(&rest[..end]);
+                    rest = &rest[end + 1..];
+                    match rest.strip_prefix('"') {
+                        Some(after) => {
+                            value.push('"');
+                            rest = after;
+                        }
+                        None => break,
+                    }
+                }
+                text = rest;
+                value
+            } else {
+                let end = text.find([',', '\r']).expect("unterminated row");
+                let value = text[..end].to_string();
+                text = &text[end..];
+                value
+            };
+            row.push(value);
+            if let Some(rest) = text.strip_prefix("\r\n") {
+                rows.push(std::mem::take(&mut row));
+                text = rest;
+            } else {
+                text = text.strip_prefix(',').expect("garbage after field");
+            }
+        }
+        assert!(row.is_empty(), "last row is unterminated");
+        rows
+    }
+
+    #[test]
+    fn fields_round_trip() {
+        let fields = ["plain", "with, comma", "\"quoted\"", "multi\r\nline", ""];
+        let mut out = vec![];
+        write_row(&mut out, &fields).unwrap();
+        write_row(&mut out, &["second"]).unwrap();
+        assert_eq!(
+            read(std::str::from_utf8(&out).unwrap()),
+            [&fields[..], &["second"]]
+        );
+    }
+
+    #[test]
+    fn io_logs_round_trip() {
+        let log =
+            [(1, 12, 0xFF12, 0xF0), (3, 456, 0xFF30, 0x01)].map(|(tick, cycle, addr, data)| {
+                IoAccess {
+                    when: Timestamp { tick, cycle },
+                    pc: Address(2, 0x4123),
+                    addr,
+                    data,
+                }
+            });
+        let mut out = vec![];
+        write_io_log(&mut out, &log).unwrap();
+        let rows = read(std::str::from_utf8(&out).unwrap());
+        assert_eq!(rows.len(), 1 + log.len());
+        assert_eq!(rows[0][..2], ["tick", "cycle"]);
+        assert_eq!(rows[1], ["1", "12", "02", "4123", "NR12", "ff12", "f0"]);
+        for (row, access) in rows[1..].iter().zip(&log) {
+            assert_eq!(row[0].parse::<u64>().unwrap(), access.when.tick);
+            assert_eq!(row[1].parse::<u32>().unwrap(), access.when.cycle);
+            assert_eq!(u16::from_str_radix(&row[5], 16).unwrap(), access.addr);
+            assert_eq!(u8::from_str_radix(&row[6], 16).unwrap(), access.data);
+        }
+    }
+
+    #[test]
+    fn diffs_round_trip() {
+        let diag = |kind| Diagnostic {
+            when: Timestamp { tick: 7, cycle: 30 },
+            pc: Address(0, 0x0456),
+            level: DiagnosticLevel::Warning,
+            kind,
+        };
+        let mut writer = DiffWriter::new(vec![]).unwrap();
+        writer
+            .write(&diag(DiagnosticKind::OtherValue(0xFF12, 0xF0, 0xA0)))
+            .unwrap();
+        writer
+            .write(&diag(DiagnosticKind::Moved(0xFF13, 0x40, -5)))
+            .unwrap();
+        writer
+            .write(&diag(DiagnosticKind::Removed(0xFF14, 0x87)))
+            .unwrap();
+        let rows = read(std::str::from_utf8(&writer.0).unwrap());
+
+        assert_eq!(rows.len(), 4);
+        assert!(rows.iter().all(|row| row.len() == rows[0].len()));
+        assert_eq!(
+            rows[1],
+            [
+                "7",
+                "30",
+                "00",
+                "0456",
+                "Warning",
+                "other-value",
+                "NR12",
+                "ff12",
+                "f0",
+                "a0",
+                ""
+            ]
+        );
+        assert_eq!(rows[2][5..], ["moved", "NR13", "ff13", "40", "40", "-5"]);
+        assert_eq!(rows[3][5..], ["removed", "NR14", "ff14", "87", "", ""]);
+    }
+}
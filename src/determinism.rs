@@ -0,0 +1,331 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with checking that simulating a song twice gives the same results
+//! (`--verify-determinism`), since no difference between two files means anything otherwise.
+
+use std::fmt::{Debug, Display};
+
+use crate::{
+    diff::RegDispl,
+    run::{IoAccess, Logbook},
+};
+
+/// The exit code used when a song was not simulated deterministically, which is a bug in gbsdiff.
+pub const EXIT_CODE: i32 = 4;
+
+/// The first thing that differed between both runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    what: &'static str,
+    /// `None` for fields that are not lists.
+    index: Option<usize>,
+    tick: Option<u64>,
+    /// The entry of each run; `None` if that run's list was shorter.
+    entries: (Option<String>, Option<String>),
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.what)?;
+        if let Some(index) = self.index {
+            write!(f, "[{}]", index)?;
+        }
+        if let Some(tick) = self.tick {
+            write!(f, " (tick {})", tick)?;
+        }
+        let entry = |entry: &Option<String>| entry.clone().unwrap_or_else(|| "nothing".into());
+        write!(
+            f,
+            " was {} the first time, but {} the second",
+            entry(&self.entries.0),
+            entry(&self.entries.1)
+        )
+    }
+}
+
+/// `tick` gives the tick an entry belongs to from it and its index, and `show` formats it.
+fn compare_lists<T: PartialEq>(
+    what: &'static str,
+    first: &[T],
+    second: &[T],
+    tick: impl Fn(usize, &T) -> u64,
+    show: impl Fn(&T) -> String,
+) -> Option<Divergence> {
+    let index = first
+        .iter()
+        .zip(second)
+        .position(|(first, second)| first != second)
+        .or_else(|| (first.len() != second.len()).then(|| first.len().min(second.len())))?;
+    let (first, second) = (first.get(index), second.get(index));
+    Some(Divergence {
+        what,
+        index: Some(index),
+        tick: first.or(second).map(|entry| tick(index, entry)),
+        entries: (first.map(&show), second.map(&show)),
+    })
+}
+
+fn show_access(access: &IoAccess) -> String {
+    format!(
+        "${:02x} at {} on cycle {} (PC = ${:x})",
+        access.data,
+        RegDispl(access.addr),
+        access.when.cycle,
+        access.pc
+    )
+}
+
+fn debug<T: Debug>(entry: &T) -> String {
+    format!("{:?}", entry)
+}
+
+fn compare_values<T: PartialEq + Debug>(
+    what: &'static str,
+    first: &T,
+    second: &T,
+) -> Option<Divergence> {
+    (first != second).then(|| Divergence {
+        what,
+        index: None,
+        tick: None,
+        entries: (Some(format!("{:?}", first)), Some(format!("{:?}", second))),
+    })
+}
+
+/// Everything is compared in order of appearance, so that the first divergence reported is
+/// the likeliest cause of the others.
+pub fn first_divergence(first: &Logbook, second: &Logbook) -> Option<Divergence> {
+    // The per-tick lists are indexed by tick.
+    fn by_index<T>(tick: usize, _: &T) -> u64 {
+        tick as u64
+    }
+    compare_lists(
+        "I/O write",
+        &first.io_log,
+        &second.io_log,
+        |_, access| access.when.tick,
+        show_access,
+    )
+    .or_else(|| {
+        compare_lists(
+            "I/O read",
+            &first.read_log,
+            &second.read_log,
+            |_, access| access.when.tick,
+            show_access,
+        )
+    })
+    .or_else(|| {
+        compare_lists(
+            "shadow write",
+            &first.shadow_log,
+            &second.shadow_log,
+            |_, access| access.when.tick,
+            show_access,
+        )
+    })
+    .or_else(|| {
+        compare_lists(
+            "diagnostic",
+            &first.diagnostics,
+            &second.diagnostics,
+            |_, diag| diag.when.tick,
+            |diag| {
+                format!(
+                    "{} on cycle {} (PC = ${:x}): {}",
+                    diag.level, diag.when.cycle, diag.pc, diag.kind
+                )
+            },
+        )
+    })
+    .or_else(|| {
+        compare_lists(
+            "debug marker",
+            &first.debug_markers,
+            &second.debug_markers,
+            |_, marker| marker.when.tick,
+            debug,
+        )
+    })
+    .or_else(|| {
+        compare_lists(
+            "length expiry",
+            &first.length_expiries,
+            &second.length_expiries,
+            |_, &(tick, _)| tick,
+            debug,
+        )
+    })
+    .or_else(|| {
+        compare_lists(
+            "tick length",
+            &first.tick_cycles,
+            &second.tick_cycles,
+            by_index,
+            debug,
+        )
+    })
+    .or_else(|| {
+        compare_lists(
+            "last write",
+            &first.last_write_cycles,
+            &second.last_write_cycles,
+            by_index,
+            debug,
+        )
+    })
+    .or_else(|| {
+        compare_lists(
+            "stack depth",
+            &first.stack_depths,
+            &second.stack_depths,
+            by_index,
+            debug,
+        )
+    })
+    .or_else(|| {
+        compare_lists(
+            "exit bank",
+            &first.exit_banks,
+            &second.exit_banks,
+            by_index,
+            debug,
+        )
+    })
+    .or_else(|| {
+        compare_values(
+            "unrecorded diagnostics",
+            &first.unrecorded,
+            &second.unrecorded,
+        )
+    })
+    .or_else(|| {
+        compare_values(
+            "stale wave RAM reads",
+            &first.stale_wave_reads,
+            &second.stale_wave_reads,
+        )
+    })
+    .or_else(|| compare_values("warm-up ticks", &first.warmup_ticks, &second.warmup_ticks))
+    .or_else(|| compare_values("length", &first.ticks_simulated, &second.ticks_simulated))
+    .or_else(|| compare_values("cycle count", &first.total_cycles, &second.total_cycles))
+    .or_else(|| compare_values("termination", &first.termination, &second.termination))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, ops::ControlFlow};
+
+    use super::*;
+    use crate::{
+        gbs::{Code, Gbs, GbsBuilder},
+        run::{self, CpuView, SimHooks, SimParams, Termination},
+        Address, Timestamp,
+    };
+
+    /// Ends the song once `ticks` reaches `limit`; since `ticks` outlives each run, a second run
+    /// ends earlier than the first, like a simulator with leftover state would behave.
+    struct Flaky<'a> {
+        ticks: &'a Cell<u64>,
+        limit: u64,
+    }
+
+    impl SimHooks for Flaky<'_> {
+        fn on_tick_end(&mut self, _tick: u64, _cpu: &CpuView) -> ControlFlow<Termination> {
+            self.ticks.set(self.ticks.get() + 1);
+            if self.ticks.get() == self.limit {
+                ControlFlow::Break(Termination::Watch)
+            } else {
+                ControlFlow::Continue(())
+            }
+        }
+    }
+
+    #[test]
+    fn injected_nondeterminism_is_pinpointed() {
+        // Writes the tick's number to NR13.
+        let play = Code::default()
+            .raw(&[0x21, 0x80, 0xFF, 0x34, 0x7E]) // `ld hl, $ff80; inc [hl]; ld a, [hl]`
+            .ldh_to(0xFF13)
+            .ret();
+        let data = GbsBuilder::default()
+            .stack_ptr(0xDFFE)
+            .init(Code::default().write(0xFF80, 0).ret())
+            .play(play)
+            .build();
+        let gbs = Gbs::new(&data).unwrap();
+        let params = SimParams::new(gbs.cycles_per_tick() * 10);
+        let ticks = Cell::new(0);
+        let simulate = || {
+            let mut hooks = Flaky {
+                ticks: &ticks,
+                limit: 15,
+            };
+            run::simulate_song_with_hooks(
+                &gbs,
+                1,
+                &params,
+                None,
+                None::<std::io::Sink>,
+                None,
+                &mut hooks,
+            )
+            .unwrap()
+        };
+
+        let (first, second) = (simulate(), simulate());
+        assert_eq!(first_divergence(&first, &first), None);
+        assert_eq!(first.termination, Termination::Timeout);
+        assert_eq!(second.termination, Termination::Watch);
+        let divergence = first_divergence(&first, &second).unwrap();
+        let sixth = &first.io_log[5];
+        assert_eq!((sixth.when.tick, sixth.data), (6, 6));
+        assert_eq!(
+            divergence,
+            Divergence {
+                what: "I/O write",
+                index: Some(5),
+                tick: Some(6),
+                entries: (Some(show_access(sixth)), None),
+            }
+        );
+        assert_eq!(
+            divergence.to_string(),
+            format!(
+                "I/O write[5] (tick 6) was $06 at NR13 on cycle {} (PC = ${:x}) the first time, but nothing the second",
+                sixth.when.cycle, sixth.pc
+            )
+        );
+    }
+
+    #[test]
+    fn divergences_are_reported_in_order() {
+        let write = |tick, data| IoAccess {
+            when: Timestamp { tick, cycle: 100 },
+            pc: Address(1, 0x4567),
+            addr: 0xFF12,
+            data,
+        };
+        let logbook = |io_log, ticks_simulated| Logbook {
+            io_log,
+            ticks_simulated,
+            ..Default::default()
+        };
+        let first = logbook(vec![write(1, 0xF0), write(2, 0xA0)], 3);
+
+        let second = logbook(vec![write(1, 0xF0), write(2, 0xA1)], 4);
+        assert_eq!(
+            first_divergence(&first, &second).unwrap().to_string(),
+            "I/O write[1] (tick 2) was $a0 at NR12 on cycle 100 (PC = $01:4567) the first time, but $a1 at NR12 on cycle 100 (PC = $01:4567) the second"
+        );
+        let second = logbook(vec![write(1, 0xF0), write(2, 0xA0)], 4);
+        assert_eq!(
+            first_divergence(&first, &second).unwrap().to_string(),
+            "length was 3 the first time, but 4 the second"
+        );
+    }
+}
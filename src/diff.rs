@@ -4,36 +4,132 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::{cmp::Ordering, fmt::Display};
+use std::{cmp::Ordering, collections::BTreeMap, fmt::Display};
 
 use gb_cpu_sim::reg::HwReg;
 
-use crate::{run::IoAccess, Diagnostic, DiagnosticLevel};
+use crate::{gbs::Cadence, regs, run::IoAccess, Diagnostic, DiagnosticLevel};
 
 #[derive(Debug)]
 pub struct DiffGenerator<'a> {
     // Parameters
     logs: (&'a [IoAccess], &'a [IoAccess]),
     jitter: u16,
+    /// Whether a moved DIV reset excuses writes moved by as much for the rest of the song, instead
+    /// of just the rest of its tick.
+    song_wide_div_phase: bool,
 
     // State
     indices: (usize, usize),
+    /// Where the bursts being aligned positionally end, if any; see [`burst`].
+    burst_ends: Option<(usize, usize)>,
+    div_phase: DivPhase,
+    /// The indices of the writes that matched exactly, in order.
+    matched: Vec<(usize, usize)>,
+}
+
+/// What a diff carries over from one tick to the next; see [`DiffGenerator::resume`].
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DivPhase {
+    /// The last tick a difference was found in.
+    diverged_tick: Option<u64>,
+    /// The tick of the last moved DIV reset, and by how many cycles it moved.
+    div_shift: Option<(u64, i64)>,
 }
 
 impl<'a> DiffGenerator<'a> {
-    pub(crate) fn new(before_log: &'a [IoAccess], after_log: &'a [IoAccess], jitter: u16) -> Self {
+    pub(crate) fn new(
+        before_log: &'a [IoAccess],
+        after_log: &'a [IoAccess],
+        jitter: u16,
+        song_wide_div_phase: bool,
+    ) -> Self {
+        Self::resume(
+            before_log,
+            after_log,
+            DivPhase::default(),
+            jitter,
+            song_wide_div_phase,
+        )
+    }
+
+    /// Picks up where the diff of the ticks before the logs' left off; since writes are only ever
+    /// paired within their tick, the logs may be diffed a few ticks at a time.
+    pub(crate) fn resume(
+        before_log: &'a [IoAccess],
+        after_log: &'a [IoAccess],
+        div_phase: DivPhase,
+        jitter: u16,
+        song_wide_div_phase: bool,
+    ) -> Self {
         Self {
             logs: (before_log, after_log),
             jitter,
+            song_wide_div_phase,
             indices: (0, 0),
+            burst_ends: None,
+            div_phase,
+            matched: Vec::new(),
         }
     }
+
+    /// What the diff of the following ticks needs to carry on; see [`Self::resume`].
+    pub(crate) fn div_phase(&self) -> DivPhase {
+        self.div_phase.clone()
+    }
+
+    /// The pairs of writes that matched exactly, as indices into both logs, in order.
+    pub(crate) fn into_matched(self) -> Vec<(usize, usize)> {
+        self.matched
+    }
+
+    /// Resetting DIV resets the timer's phase, so if the reset moved, so does every timer-driven
+    /// event after it; writes that moved by the same amount (give or take the jitter) are only noted.
+    fn account_for_div_phase(
+        &mut self,
+        mut diag: Diagnostic<DiagnosticKind>,
+    ) -> Diagnostic<DiagnosticKind> {
+        let tick = diag.when.tick;
+        let first_in_tick = self.div_phase.diverged_tick != Some(tick);
+        self.div_phase.diverged_tick = Some(tick);
+
+        match diag.kind {
+            DiagnosticKind::Moved(DIV, _, delta) if first_in_tick => {
+                self.div_phase.div_shift = Some((tick, delta));
+                diag.kind = DiagnosticKind::DivResetMoved(delta);
+                if diag.level == DiagnosticLevel::Error {
+                    diag.level = DiagnosticLevel::Warning;
+                }
+            }
+            DiagnosticKind::Moved(_, _, delta) if diag.level == DiagnosticLevel::Error => {
+                if let Some((shift_tick, shift)) = self.div_phase.div_shift {
+                    if (tick == shift_tick || self.song_wide_div_phase)
+                        && (delta - shift).unsigned_abs() < self.jitter.into()
+                    {
+                        diag.level = DiagnosticLevel::Note;
+                    }
+                }
+            }
+            _ => (),
+        }
+        diag
+    }
 }
 
+/// Writing any value to it resets it to 0.
+const DIV: u16 = 0xFF04;
+
 impl Iterator for DiffGenerator<'_> {
     type Item = Diagnostic<DiagnosticKind>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        let diag = self.next_difference()?;
+        Some(self.account_for_div_phase(diag))
+    }
+}
+
+impl DiffGenerator<'_> {
+    fn next_difference(&mut self) -> Option<Diagnostic<DiagnosticKind>> {
         loop {
             // Only a single code path loops back.
             return match (
@@ -82,9 +178,51 @@ impl Iterator for DiffGenerator<'_> {
                         Ordering::Equal => (),
                     }
 
+                    // Within bursts, writes are paired by position, and whichever burst is longer
+                    // gets its excess reported once the other is exhausted.
+                    if let Some((end0, end1)) = self.burst_ends {
+                        match (self.indices.0 < end0, self.indices.1 < end1) {
+                            (true, true) => (),
+                            (true, false) => {
+                                self.indices.0 += 1;
+                                return diagnose(
+                                    before,
+                                    DiagnosticLevel::Error,
+                                    DiagnosticKind::Removed(before.addr, before.data),
+                                );
+                            }
+                            (false, true) => {
+                                self.indices.1 += 1;
+                                return diagnose(
+                                    after,
+                                    DiagnosticLevel::Error,
+                                    DiagnosticKind::Added(after.addr, after.data),
+                                );
+                            }
+                            (false, false) => self.burst_ends = None,
+                        }
+                    }
+                    if self.burst_ends.is_none() && before.addr == after.addr {
+                        if let (Some((len0, step0)), Some((len1, step1))) = (
+                            burst(self.logs.0, self.indices.0),
+                            burst(self.logs.1, self.indices.1),
+                        ) {
+                            if step0 == step1 {
+                                self.burst_ends =
+                                    Some((self.indices.0 + len0, self.indices.1 + len1));
+                            }
+                        }
+                    }
+
                     // If the two match exactly, we have nothing to report; try again.
                     // This is the only easy case.
-                    if before == after {
+                    let same_data = same_value(before.addr, before.data, after.data);
+                    if before.when == after.when
+                        && before.pc == after.pc
+                        && before.addr == after.addr
+                        && same_data
+                    {
+                        self.matched.push(self.indices);
                         self.indices.0 += 1;
                         self.indices.1 += 1;
                         continue;
@@ -92,24 +230,22 @@ impl Iterator for DiffGenerator<'_> {
 
                     // So there is a difference: it can be timing, address, or data.
                     // Timing being the most sensitive, it will not be used as a triaging criterion.
-                    match (before.addr == after.addr, before.data == after.data) {
+                    match (before.addr == after.addr, same_data) {
                         (true, true) => {
                             // The write is identical, but has been moved a bit.
                             self.indices.0 += 1;
                             self.indices.1 += 1;
+                            let delta = i64::from(after.when.cycle) - i64::from(before.when.cycle);
+                            // Moving by a whole frame is never just jitter, however large that is.
+                            let distance = delta.unsigned_abs();
                             diagnose(
                                 after,
-                                if before.when.cycle.abs_diff(after.when.cycle) < self.jitter {
+                                if distance < self.jitter.into() && distance < CYCLES_PER_FRAME {
                                     DiagnosticLevel::Note
                                 } else {
                                     DiagnosticLevel::Error
                                 },
-                                DiagnosticKind::Moved(
-                                    before.addr,
-                                    before.data,
-                                    (after.when.cycle as i32)
-                                        .wrapping_sub(before.when.cycle as i32),
-                                ),
+                                DiagnosticKind::Moved(before.addr, before.data, delta),
                             )
                         }
                         // Oh god. Welcome to half-assed heuristics, please do not judge me :(
@@ -121,7 +257,22 @@ impl Iterator for DiffGenerator<'_> {
                             diagnose(
                                 after,
                                 DiagnosticLevel::Error,
-                                DiagnosticKind::OtherValue(before.addr, before.data, after.data),
+                                match RegSemantics::of(before.addr) {
+                                    Some(
+                                        RegSemantics::FreqHighAndTrigger | RegSemantics::Trigger,
+                                    ) if (before.data ^ after.data) == TRIGGER_BIT => {
+                                        DiagnosticKind::TriggerChanged(
+                                            before.addr,
+                                            after.data & TRIGGER_BIT != 0,
+                                            after.data,
+                                        )
+                                    }
+                                    _ => DiagnosticKind::OtherValue(
+                                        before.addr,
+                                        before.data,
+                                        after.data,
+                                    ),
+                                },
                             )
                         }
                         (false, true) => {
@@ -139,9 +290,12 @@ impl Iterator for DiffGenerator<'_> {
                             // Nothing matches.
                             // Let's compare one beyond; if the address matches with the opposite "N+1", assume that they're meant to be paired.
                             // (The value is too volatile, so it's not checked here.)
+                            // Writes of different ticks never get paired, so neither are looked at.
+                            let same_tick =
+                                |access: &&IoAccess| access.when.tick == before.when.tick;
                             match (
-                                self.logs.0.get(self.indices.0 + 1),
-                                self.logs.1.get(self.indices.1 + 1),
+                                self.logs.0.get(self.indices.0 + 1).filter(same_tick),
+                                self.logs.1.get(self.indices.1 + 1).filter(same_tick),
                             ) {
                                 (Some(before2), _) if before2.addr == after.addr => {
                                     self.indices.0 += 1;
@@ -186,6 +340,88 @@ impl Iterator for DiffGenerator<'_> {
     }
 }
 
+/// Bursts shorter than this are left to the usual heuristics.
+const MIN_BURST_LEN: usize = 3;
+
+/// If a burst of writes starts at `log[i]`, returns its length, and by how much the address
+/// increases from one write to the next.
+///
+/// Bursts are writes within a single tick either all to the same register, or to consecutive
+/// wave RAM addresses (e.g. a wave upload); they are aligned positionally, so that a single byte
+/// changing within one yields a single diagnostic instead of a cascade of mispairings.
+fn burst(log: &[IoAccess], i: usize) -> Option<(usize, u16)> {
+    let first = log.get(i)?;
+    let second = log.get(i + 1)?;
+    let step = if second.addr == first.addr {
+        0
+    } else if crate::waves::WAVE_RAM.contains(&first.addr) && second.addr == first.addr + 1 {
+        1
+    } else {
+        return None;
+    };
+
+    let len = log[i..]
+        .iter()
+        .zip(0..)
+        .take_while(|(access, k)| {
+            access.when.tick == first.when.tick
+                && u16::try_from(*k)
+                    .ok()
+                    .and_then(|k| first.addr.checked_add(k * step))
+                    .is_some_and(|addr| {
+                        access.addr == addr && (step == 0 || crate::waves::WAVE_RAM.contains(&addr))
+                    })
+        })
+        .count();
+    (len >= MIN_BURST_LEN).then_some((len, step))
+}
+
+/// One row of two logs laid out next to each other: either a pair of writes, or a write on one side only.
+#[derive(Debug)]
+pub(crate) struct AlignedRow<'a> {
+    pub before: Option<&'a IoAccess>,
+    pub after: Option<&'a IoAccess>,
+    /// The level of the diagnostic the row produced, if it isn't an exact match.
+    pub level: Option<DiagnosticLevel>,
+}
+
+/// Pairs up the writes of both logs the same way [`DiffGenerator`] does.
+pub(crate) fn align<'a>(
+    before_log: &'a [IoAccess],
+    after_log: &'a [IoAccess],
+    jitter: u16,
+) -> Vec<AlignedRow<'a>> {
+    let mut generator = DiffGenerator::new(before_log, after_log, jitter, false);
+    let mut rows = Vec::new();
+    let exact = |i: usize, j: usize| AlignedRow {
+        before: Some(&before_log[i]),
+        after: Some(&after_log[j]),
+        level: None,
+    };
+
+    loop {
+        let start = generator.indices;
+        let diagnostic = generator.next();
+        let consumed = (generator.indices.0 - start.0, generator.indices.1 - start.1);
+        // The generator skips over exact matches before the write(s) it reports.
+        let nb_exact = match (&diagnostic, consumed.0.cmp(&consumed.1)) {
+            (Some(_), Ordering::Equal) => consumed.0 - 1,
+            _ => consumed.0.min(consumed.1),
+        };
+        rows.extend((0..nb_exact).map(|k| exact(start.0 + k, start.1 + k)));
+
+        let Some(diagnostic) = diagnostic else {
+            return rows;
+        };
+        let (i, j) = (start.0 + nb_exact, start.1 + nb_exact);
+        rows.push(AlignedRow {
+            before: (consumed.0 > nb_exact).then(|| &before_log[i]),
+            after: (consumed.1 > nb_exact).then(|| &after_log[j]),
+            level: Some(diagnostic.level),
+        });
+    }
+}
+
 fn diagnose(
     access: &IoAccess,
     level: DiagnosticLevel,
@@ -199,6 +435,565 @@ fn diagnose(
     })
 }
 
+/// A difference in which ROM bank INIT or PLAY leaves mapped, which matters to games that don't
+/// restore it themselves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ExitBankDiff {
+    Init { before: u8, after: u8 },
+    Play { tick: u64, before: u8, after: u8 },
+}
+
+impl Display for ExitBankDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Init { before, after } => write!(
+                f,
+                "INIT now returns with bank {} mapped instead of bank {}",
+                after, before
+            ),
+            Self::Play {
+                tick,
+                before,
+                after,
+            } => write!(
+                f,
+                "PLAY now returns with bank {} mapped instead of bank {} (first differing tick: {})",
+                after, before, tick
+            ),
+        }
+    }
+}
+
+/// Compares the banks left mapped after each tick (indexed by tick, INIT's first); only the first
+/// differing PLAY tick for which `compared` returns true is reported.
+pub(crate) fn compare_exit_banks(
+    before: &[u8],
+    after: &[u8],
+    compared: impl Fn(u64) -> bool,
+) -> Vec<ExitBankDiff> {
+    let mut diffs = Vec::new();
+    if let (Some(&before), Some(&after)) = (before.first(), after.first()) {
+        if before != after {
+            diffs.push(ExitBankDiff::Init { before, after });
+        }
+    }
+    if let Some((tick, (&before, &after))) = before
+        .iter()
+        .zip(after)
+        .enumerate()
+        .skip(1)
+        .find(|&(tick, (before, after))| before != after && compared(tick as u64))
+    {
+        diffs.push(ExitBankDiff::Play {
+            tick: tick as u64,
+            before,
+            after,
+        });
+    }
+    diffs
+}
+
+/// A difference in where the debug markers of both files run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum MarkerDiff {
+    /// `index` is 1-based.
+    Drift {
+        index: usize,
+        before: u64,
+        after: u64,
+    },
+    Count {
+        before: usize,
+        after: usize,
+    },
+}
+
+impl Display for MarkerDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Drift {
+                index,
+                before,
+                after,
+            } => write!(
+                f,
+                "debug marker #{} at tick {} before vs tick {} after",
+                index, before, after
+            ),
+            Self::Count { before, after } => write!(
+                f,
+                "{} debug markers ran before, but {} after",
+                before, after
+            ),
+        }
+    }
+}
+
+/// Compares the ticks on which each file's debug markers ran, in order; only the first marker to
+/// drift is reported, since the following ones usually drift as well.
+pub(crate) fn compare_markers(before: &[u64], after: &[u64]) -> Vec<MarkerDiff> {
+    let mut diffs = Vec::new();
+    if let Some((i, (&before, &after))) = before
+        .iter()
+        .zip(after)
+        .enumerate()
+        .find(|(_, (before, after))| before != after)
+    {
+        diffs.push(MarkerDiff::Drift {
+            index: i + 1,
+            before,
+            after,
+        });
+    }
+    if before.len() != after.len() {
+        diffs.push(MarkerDiff::Count {
+            before: before.len(),
+            after: after.len(),
+        });
+    }
+    diffs
+}
+
+/// Bit 7 of NRx4 (re)starts the channel.
+pub(crate) const TRIGGER_BIT: u8 = 0x80;
+
+/// Bit 6 of NRx4 makes the length timer cut the channel off.
+const LENGTH_ENABLE_BIT: u8 = 0x40;
+
+/// Bit 7 of NR52 turns the whole APU on or off.
+const POWER_BIT: u8 = 0x80;
+
+/// Moves this large are spelled out in frames (114 cycles per scanline, 154 scanlines), since
+/// they can't be timing noise.
+const CYCLES_PER_FRAME: u64 = 114 * 154;
+
+/// Whether two values written to the same register are equivalent; only NR52's power bit can be
+/// written, the rest of it being read-only.
+fn same_value(addr: u16, before: u8, after: u8) -> bool {
+    match HwReg::try_from(addr) {
+        Ok(HwReg::Nr52) => (before ^ after) & POWER_BIT == 0,
+        _ => before == after,
+    }
+}
+
+/// Mentions what a lone NR52 write does, since adding or removing one is a big deal.
+fn power_note(reg: u16, value: u8) -> &'static str {
+    match HwReg::try_from(reg) {
+        Ok(HwReg::Nr52) if value & POWER_BIT == 0 => {
+            " (powers the APU off, clearing its registers)"
+        }
+        Ok(HwReg::Nr52) => " (powers the APU on)",
+        _ => "",
+    }
+}
+
+/// A group of bits within an APU register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Field {
+    pub name: &'static str,
+    pub mask: u8,
+}
+
+impl Field {
+    const SWEEP_PACE: Self = Self::new("sweep pace", 0x70);
+    const SWEEP_DIRECTION: Self = Self::new("sweep direction", 0x08);
+    const SWEEP_STEP: Self = Self::new("sweep step", 0x07);
+    const DUTY: Self = Self::new("duty", 0xC0);
+    const LENGTH: Self = Self::new("length", 0x3F);
+    const WAVE_LENGTH: Self = Self::new("length", 0xFF);
+    const VOLUME: Self = Self::new("volume", 0xF0);
+    const ENVELOPE_DIRECTION: Self = Self::new("envelope direction", 0x08);
+    const ENVELOPE_PACE: Self = Self::new("envelope pace", 0x07);
+    const OUTPUT_LEVEL: Self = Self::new("output level", 0x60);
+    const CLOCK_SHIFT: Self = Self::new("clock shift", 0xF0);
+    const LFSR_WIDTH: Self = Self::new("LFSR width", 0x08);
+    const CLOCK_DIVIDER: Self = Self::new("clock divider", 0x07);
+
+    const fn new(name: &'static str, mask: u8) -> Self {
+        Self { name, mask }
+    }
+
+    /// The fields of the register, if it's split into several, or has a field worth naming.
+    pub fn of(addr: u16) -> &'static [Self] {
+        match HwReg::try_from(addr) {
+            Ok(HwReg::Nr10) => &[Self::SWEEP_PACE, Self::SWEEP_DIRECTION, Self::SWEEP_STEP],
+            Ok(HwReg::Nr11 | HwReg::Nr21) => &[Self::DUTY, Self::LENGTH],
+            Ok(HwReg::Nr12 | HwReg::Nr22 | HwReg::Nr42) => {
+                &[Self::VOLUME, Self::ENVELOPE_DIRECTION, Self::ENVELOPE_PACE]
+            }
+            Ok(HwReg::Nr31) => &[Self::WAVE_LENGTH],
+            Ok(HwReg::Nr32) => &[Self::OUTPUT_LEVEL],
+            Ok(HwReg::Nr41) => &[Self::LENGTH],
+            Ok(HwReg::Nr43) => &[Self::CLOCK_SHIFT, Self::LFSR_WIDTH, Self::CLOCK_DIVIDER],
+            _ => &[],
+        }
+    }
+
+    /// The field's value within the whole register's.
+    pub fn extract(&self, value: u8) -> u8 {
+        (value & self.mask) >> self.mask.trailing_zeros()
+    }
+
+    pub fn fmt_value(&self, value: u8) -> String {
+        let value = self.extract(value);
+        if *self == Self::DUTY {
+            ["12.5%", "25%", "50%", "75%"][usize::from(value)].to_string()
+        } else {
+            value.to_string()
+        }
+    }
+}
+
+/// Demotes value differences that are unlikely to be what the user is looking for to warnings:
+/// length-only changes to NRx1 (or duty-only ones, if `relax_duty` is set), and envelope pace-only
+/// changes to NRx2; composers tweaking instruments produce lots of those.
+pub(crate) fn relax_field_changes(
+    diagnostics: &mut [Diagnostic<DiagnosticKind>],
+    relax_duty: bool,
+) {
+    for diag in diagnostics {
+        let DiagnosticKind::OtherValue(reg, before, after) = diag.kind else {
+            continue;
+        };
+        let relaxed_mask = match HwReg::try_from(reg) {
+            Ok(HwReg::Nr11 | HwReg::Nr21) if relax_duty => Field::DUTY.mask | Field::LENGTH.mask,
+            Ok(HwReg::Nr11 | HwReg::Nr21 | HwReg::Nr41) => Field::LENGTH.mask,
+            Ok(HwReg::Nr31) => Field::WAVE_LENGTH.mask,
+            Ok(HwReg::Nr12 | HwReg::Nr22 | HwReg::Nr42) => Field::ENVELOPE_PACE.mask,
+            _ => continue,
+        };
+        if (before ^ after) & !relaxed_mask == 0 && diag.level == DiagnosticLevel::Error {
+            diag.level = DiagnosticLevel::Warning;
+        }
+    }
+}
+
+/// Where the diagnosed write sits within its tick, counting from the tick's start and from its end.
+fn position_in_tick(log: &[IoAccess], diag: &Diagnostic<DiagnosticKind>) -> Option<(usize, usize)> {
+    let tick = diag.when.tick;
+    let start = log.partition_point(|access| access.when.tick < tick);
+    let end = log.partition_point(|access| access.when.tick <= tick);
+    let idx = start
+        + log[start..end]
+            .iter()
+            .position(|access| access.when == diag.when && access.addr == diag.kind.reg())?;
+    Some((idx - start, end - idx - 1))
+}
+
+/// Up to `nb` of the "after" writes that matched right before and right after the diagnosed one,
+/// within its tick; `matched` is [`DiffGenerator::into_matched`]'s.
+pub(crate) fn context<'a>(
+    logs: (&[IoAccess], &'a [IoAccess]),
+    matched: &[(usize, usize)],
+    diag: &Diagnostic<DiagnosticKind>,
+    nb: usize,
+) -> (Vec<&'a IoAccess>, Vec<&'a IoAccess>) {
+    let from_before = diag.kind.is_from_before();
+    let log = if from_before { logs.0 } else { logs.1 };
+    let start = log.partition_point(|access| access.when.tick < diag.when.tick);
+    let Some(idx) = log[start..]
+        .iter()
+        .position(|access| access.when == diag.when && access.addr == diag.kind.reg())
+        .map(|i| start + i)
+    else {
+        return (Vec::new(), Vec::new());
+    };
+
+    let split = matched
+        .partition_point(|&(before, after)| (if from_before { before } else { after }) < idx);
+    let in_tick = |&&(_, after): &&(usize, usize)| logs.1[after].when.tick == diag.when.tick;
+    let mut preceding: Vec<_> = matched[..split]
+        .iter()
+        .rev()
+        .take_while(in_tick)
+        .take(nb)
+        .map(|&(_, after)| &logs.1[after])
+        .collect();
+    preceding.reverse();
+    let following = matched[split..]
+        .iter()
+        .take_while(in_tick)
+        .take(nb)
+        .map(|&(_, after)| &logs.1[after])
+        .collect();
+    (preceding, following)
+}
+
+/// Pairs up writes removed from the end of a tick with the same writes added at the start of the
+/// next (or vice versa), which is what a tick slightly overrunning its budget looks like, into a
+/// single warning (or a note if within `jitter` cycles).
+///
+/// Only writes within `window` writes of the tick boundary are considered, on both sides.
+pub(crate) fn pair_slipped_writes(
+    diagnostics: &mut Vec<Diagnostic<DiagnosticKind>>,
+    logs: (&[IoAccess], &[IoAccess]),
+    window: usize,
+    jitter: u16,
+    cadence: &Cadence,
+) {
+    if window == 0 {
+        return;
+    }
+    let near_boundary = |log: &[IoAccess], diag: &Diagnostic<DiagnosticKind>, at_end: bool| {
+        position_in_tick(log, diag).is_some_and(|(from_start, from_end)| {
+            (if at_end { from_end } else { from_start }) < window
+        })
+    };
+
+    let mut slipped = Vec::new();
+    for i in 0..diagnostics.len() {
+        let (reg, value, before_side) = match diagnostics[i].kind {
+            DiagnosticKind::Removed(reg, value) => (reg, value, true),
+            DiagnosticKind::Added(reg, value) => (reg, value, false),
+            _ => continue,
+        };
+        let tick = diagnostics[i].when.tick;
+        let (own_log, other_log) = if before_side {
+            (logs.0, logs.1)
+        } else {
+            (logs.1, logs.0)
+        };
+        if slipped.iter().any(|&(a, b)| a == i || b == i)
+            || !near_boundary(own_log, &diagnostics[i], true)
+        {
+            continue;
+        }
+
+        let partner = (i + 1..diagnostics.len())
+            .take_while(|&j| diagnostics[j].when.tick <= tick + 1)
+            .find(|&j| {
+                let diag = &diagnostics[j];
+                let matches = match diag.kind {
+                    DiagnosticKind::Added(other_reg, other_value) if before_side => {
+                        other_reg == reg && same_value(reg, value, other_value)
+                    }
+                    DiagnosticKind::Removed(other_reg, other_value) if !before_side => {
+                        other_reg == reg && same_value(reg, value, other_value)
+                    }
+                    _ => false,
+                };
+                matches
+                    && diag.when.tick == tick + 1
+                    && !slipped.iter().any(|&(a, b)| a == j || b == j)
+                    && near_boundary(other_log, diag, false)
+            });
+        if let Some(j) = partner {
+            slipped.push((i, j));
+        }
+    }
+
+    // The diagnostic that is kept is the one from the "after" log, like for moved writes.
+    let mut removed = vec![false; diagnostics.len()];
+    for (i, j) in slipped {
+        let (before, after) = if diagnostics[i].kind.is_from_before() {
+            (i, j)
+        } else {
+            (j, i)
+        };
+        let (from, to) = (diagnostics[before].when.tick, diagnostics[after].when.tick);
+        let delta = i64::try_from(cadence.cycles_before(to)).unwrap()
+            - i64::try_from(cadence.cycles_before(from)).unwrap()
+            + i64::from(diagnostics[after].when.cycle)
+            - i64::from(diagnostics[before].when.cycle);
+        let DiagnosticKind::Added(reg, value) = diagnostics[after].kind else {
+            unreachable!();
+        };
+        diagnostics[after].kind = DiagnosticKind::SlippedTick(reg, value, from, to);
+        diagnostics[after].level = if delta.unsigned_abs() < jitter.into() {
+            DiagnosticLevel::Note
+        } else {
+            DiagnosticLevel::Warning
+        };
+        removed[before] = true;
+    }
+    let mut removed = removed.into_iter();
+    diagnostics.retain(|_| !removed.next().unwrap());
+}
+
+/// A pulse channel's NRx3 and NRx4 writes this many writes apart or more aren't considered a pair.
+const FREQ_PAIR_WINDOW: usize = 4;
+
+/// Which pulse channel (1-based) the register sets the period of, and its NRx3 and NRx4.
+fn pulse_freq_regs(addr: u16) -> Option<(u8, u16, u16)> {
+    match HwReg::try_from(addr) {
+        Ok(HwReg::Nr13 | HwReg::Nr14) => Some((1, HwReg::Nr13.into(), HwReg::Nr14.into())),
+        Ok(HwReg::Nr23 | HwReg::Nr24) => Some((2, HwReg::Nr23.into(), HwReg::Nr24.into())),
+        _ => None,
+    }
+}
+
+/// A pulse channel's NRx3 and NRx4 writes within a tick, taken together.
+#[derive(Debug, Clone, Copy)]
+struct FreqPair {
+    /// All 11 bits of it.
+    period: u16,
+    trigger: bool,
+    /// Whether NRx4 is written before NRx3.
+    high_first: bool,
+    /// The index of the pair's last write.
+    last: usize,
+}
+
+impl FreqPair {
+    /// Only if the tick writes to each register exactly once, and close enough together.
+    fn find(log: &[IoAccess], tick: u64, (low, high): (u16, u16)) -> Option<Self> {
+        let start = log.partition_point(|access| access.when.tick < tick);
+        let end = log.partition_point(|access| access.when.tick <= tick);
+        let only = |reg: u16| {
+            let mut writes = (start..end).filter(|&i| log[i].addr == reg);
+            match (writes.next(), writes.next()) {
+                (Some(i), None) => Some(i),
+                _ => None,
+            }
+        };
+        let (lo, hi) = (only(low)?, only(high)?);
+
+        (lo.abs_diff(hi) < FREQ_PAIR_WINDOW).then(|| Self {
+            period: u16::from(log[hi].data & 7) << 8 | u16::from(log[lo].data),
+            trigger: log[hi].data & TRIGGER_BIT != 0,
+            high_first: hi < lo,
+            last: lo.max(hi),
+        })
+    }
+}
+
+/// How many semitones higher a pulse channel sounds with the `after` period than the `before` one.
+fn semitones(before: u16, after: u16) -> f64 {
+    // The channel's frequency is inversely proportional to 2048 minus the period.
+    12.0 * ((2048.0 - f64::from(before)) / (2048.0 - f64::from(after))).log2()
+}
+
+/// Replaces the differences in how a tick writes a pulse channel's NRx3 and NRx4 with a single
+/// one about the resulting frequency, since drivers write them as a pair; this notably makes
+/// writing them in the opposite order a note instead of a cascade of errors.
+///
+/// Ticks where either side doesn't write them as a pair, or where the trigger bit differs, are left
+/// to the per-write diagnostics.
+pub(crate) fn pair_freq_writes(
+    diagnostics: &mut Vec<Diagnostic<DiagnosticKind>>,
+    logs: (&[IoAccess], &[IoAccess]),
+) {
+    // For each tick and channel, the diagnostics about its frequency, and whether any of them is
+    // also about something else (or isn't about individual writes), which prevents pairing.
+    let mut groups = BTreeMap::<_, (Vec<usize>, bool)>::new();
+    for (i, diag) in diagnostics.iter().enumerate() {
+        let written = match diag.kind {
+            DiagnosticKind::Removed(reg, ..)
+            | DiagnosticKind::Added(reg, ..)
+            | DiagnosticKind::Moved(reg, ..)
+            | DiagnosticKind::OtherValue(reg, ..)
+            | DiagnosticKind::TriggerChanged(reg, ..) => Some([reg, reg]),
+            DiagnosticKind::OtherReg(before, _, after) => Some([before, after]),
+            _ => None,
+        };
+        let Some(regs @ (channel, ..)) = written
+            .unwrap_or([diag.kind.reg(); 2])
+            .into_iter()
+            .find_map(pulse_freq_regs)
+        else {
+            continue;
+        };
+        let group = groups.entry((diag.when.tick, regs)).or_default();
+        group.0.push(i);
+        group.1 |= written.is_none()
+            || written.is_some_and(|regs| {
+                regs.iter()
+                    .any(|&reg| pulse_freq_regs(reg).map(|(other, ..)| other) != Some(channel))
+            });
+    }
+
+    let mut removed = vec![false; diagnostics.len()];
+    for ((tick, (channel, low, high)), (indices, blocked)) in groups {
+        if blocked {
+            continue;
+        }
+        let (Some(before), Some(after)) = (
+            FreqPair::find(logs.0, tick, (low, high)),
+            FreqPair::find(logs.1, tick, (low, high)),
+        ) else {
+            continue;
+        };
+        if before.trigger != after.trigger {
+            continue;
+        }
+        let (level, kind) = if before.period != after.period {
+            (
+                DiagnosticLevel::Error,
+                DiagnosticKind::FreqChanged {
+                    channel,
+                    before: before.period,
+                    after: after.period,
+                },
+            )
+        } else if before.high_first != after.high_first {
+            (
+                // Unless triggering, the channel only briefly plays the intermediate period.
+                if after.trigger {
+                    DiagnosticLevel::Warning
+                } else {
+                    DiagnosticLevel::Note
+                },
+                DiagnosticKind::FreqOrderSwapped {
+                    channel,
+                    high_first: after.high_first,
+                    trigger: after.trigger,
+                },
+            )
+        } else {
+            continue;
+        };
+
+        // Like for moved writes, the diagnostic is attributed to the "after" log.
+        let access = &logs.1[after.last];
+        diagnostics[indices[0]] = Diagnostic {
+            when: access.when.clone(),
+            pc: access.pc.clone(),
+            level,
+            kind,
+        };
+        for &i in &indices[1..] {
+            removed[i] = true;
+        }
+    }
+    let mut removed = removed.into_iter();
+    diagnostics.retain(|_| !removed.next().unwrap());
+}
+
+/// What the bits of an APU register mean, insofar as the differ cares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RegSemantics {
+    /// NRx3: the low 8 bits of the channel's period.
+    FreqLow,
+    /// NRx4 of channels 1-3: the trigger bit, and the upper 3 bits of the channel's period.
+    FreqHighAndTrigger,
+    /// NR44: the trigger bit, and no period bits.
+    Trigger,
+}
+
+impl RegSemantics {
+    fn of(addr: u16) -> Option<Self> {
+        match HwReg::try_from(addr) {
+            Ok(HwReg::Nr13 | HwReg::Nr23 | HwReg::Nr33) => Some(Self::FreqLow),
+            Ok(HwReg::Nr14 | HwReg::Nr24 | HwReg::Nr34) => Some(Self::FreqHighAndTrigger),
+            Ok(HwReg::Nr44) => Some(Self::Trigger),
+            _ => None,
+        }
+    }
+
+    /// How many period steps separate the two values, if the register has period bits and only those differ.
+    fn freq_steps(self, before: u8, after: u8) -> Option<i16> {
+        match self {
+            Self::FreqLow => Some(i16::from(after) - i16::from(before)),
+            Self::FreqHighAndTrigger
+                if (before ^ after) & (TRIGGER_BIT | LENGTH_ENABLE_BIT) == 0 =>
+            {
+                Some((i16::from(after & 7) - i16::from(before & 7)) * 256)
+            }
+            Self::FreqHighAndTrigger | Self::Trigger => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum DiagnosticKind {
     /// Present before, but not after.
@@ -206,133 +1001,755 @@ pub enum DiagnosticKind {
     /// Present after, but not before.
     Added(u16, u8),
     /// A few cycles apart.
-    Moved(u16, u8, i32),
+    Moved(u16, u8, i64),
     /// Same reg, different values.
     OtherValue(u16, u8, u8),
     /// Same value, different reg.
     OtherReg(u16, u8, u16),
+    /// Same NRx4 write, except that the trigger bit was added (true) or removed (false).
+    TriggerChanged(u16, bool, u8),
+    /// The same write, but pushed across a tick boundary (from the "before" tick to the "after" one).
+    SlippedTick(u16, u8, u64, u64),
+    /// A write to DIV, which resets the timer's phase, moved by that many cycles.
+    DivResetMoved(i64),
+    /// A pulse channel's NRx3 and NRx4 writes combine into another period (channels are 1-based).
+    FreqChanged {
+        channel: u8,
+        before: u16,
+        after: u16,
+    },
+    /// A pulse channel's NRx3 and NRx4 are written in the opposite order, with the same values;
+    /// `high_first` is whether NRx4 is now written first.
+    FreqOrderSwapped {
+        channel: u8,
+        high_first: bool,
+        trigger: bool,
+    },
+    /// The same writes were removed from a channel and added to another (channels are 1-based).
+    ChannelReallocation {
+        from: u8,
+        to: u8,
+        ticks: (u64, u64),
+        notes: usize,
+    },
+}
+
+impl DiagnosticKind {
+    /// Every [`Self::name`].
+    pub const NAMES: [&'static str; 12] = [
+        "removed",
+        "added",
+        "moved",
+        "other-value",
+        "other-reg",
+        "trigger-added",
+        "trigger-removed",
+        "channel-reallocation",
+        "slipped-tick",
+        "div-reset-moved",
+        "freq-changed",
+        "freq-order-swapped",
+    ];
+
+    /// A short name for the kind of difference, for machine-readable outputs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Removed(..) => "removed",
+            Self::Added(..) => "added",
+            Self::Moved(..) => "moved",
+            Self::OtherValue(..) => "other-value",
+            Self::OtherReg(..) => "other-reg",
+            Self::TriggerChanged(_, true, _) => "trigger-added",
+            Self::TriggerChanged(_, false, _) => "trigger-removed",
+            Self::ChannelReallocation { .. } => "channel-reallocation",
+            Self::SlippedTick(..) => "slipped-tick",
+            Self::DivResetMoved(..) => "div-reset-moved",
+            Self::FreqChanged { .. } => "freq-changed",
+            Self::FreqOrderSwapped { .. } => "freq-order-swapped",
+        }
+    }
+
+    /// Whether the diagnostic's timestamp is taken from the "before" log (as opposed to "after").
+    pub fn is_from_before(&self) -> bool {
+        matches!(self, Self::Removed(..))
+    }
+
+    /// The register written to (for [`Self::OtherReg`], in the "after" log).
+    pub fn reg(&self) -> u16 {
+        match self {
+            Self::Removed(reg, ..)
+            | Self::Added(reg, ..)
+            | Self::Moved(reg, ..)
+            | Self::OtherValue(reg, ..)
+            | Self::TriggerChanged(reg, ..)
+            | Self::SlippedTick(reg, ..) => *reg,
+            Self::DivResetMoved(..) => DIV,
+            Self::OtherReg(_, _, after) => *after,
+            // The "to" channel's NRx4.
+            Self::ChannelReallocation { to, .. } => 0xFF10 + u16::from(to - 1) * 5 + 4,
+            // The channel's NRx4.
+            Self::FreqChanged { channel, .. } | Self::FreqOrderSwapped { channel, .. } => {
+                0xFF10 + u16::from(channel - 1) * 5 + 4
+            }
+        }
+    }
 }
 
 impl Display for DiagnosticKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Removed(reg, value) => {
-                write!(f, "Missing write of ${:02x} to {}", value, RegDispl(*reg))
+                write!(
+                    f,
+                    "Missing write of ${:02x} to {}{}",
+                    value,
+                    RegDispl(*reg),
+                    power_note(*reg, *value)
+                )
             }
             Self::Added(reg, value) => {
-                write!(f, "New write of ${:02x} to {}", value, RegDispl(*reg))
+                write!(
+                    f,
+                    "New write of ${:02x} to {}{}",
+                    value,
+                    RegDispl(*reg),
+                    power_note(*reg, *value)
+                )
             }
-            Self::Moved(reg, value, delta) => write!(
+            Self::Moved(reg, value, delta) => {
+                write!(
+                    f,
+                    "Wrote ${:02x} to {} {} cycles",
+                    value,
+                    RegDispl(*reg),
+                    delta.abs()
+                )?;
+                if delta.unsigned_abs() >= CYCLES_PER_FRAME {
+                    write!(
+                        f,
+                        " (about {:.1} frames)",
+                        delta.unsigned_abs() as f64 / CYCLES_PER_FRAME as f64
+                    )?;
+                }
+                write!(f, " {}", if *delta < 0 { "earlier" } else { "later" })
+            }
+            Self::OtherValue(reg, before, after) => {
+                write!(
+                    f,
+                    "Wrote ${:02x} to {} instead of ${:02x}",
+                    after,
+                    RegDispl(*reg),
+                    before,
+                )?;
+                match RegSemantics::of(*reg).and_then(|reg| reg.freq_steps(*before, *after)) {
+                    Some(steps) if steps != 0 => write!(
+                        f,
+                        " ({} frequency steps {})",
+                        steps.abs(),
+                        if steps < 0 { "lower" } else { "higher" }
+                    ),
+                    _ => {
+                        let changes: Vec<_> = Field::of(*reg)
+                            .iter()
+                            .filter(|field| (before ^ after) & field.mask != 0)
+                            .map(|field| {
+                                format!(
+                                    "{} {}->{}",
+                                    field.name,
+                                    field.fmt_value(*before),
+                                    field.fmt_value(*after)
+                                )
+                            })
+                            .collect();
+                        if changes.is_empty() {
+                            Ok(())
+                        } else {
+                            write!(f, " ({})", changes.join(", "))
+                        }
+                    }
+                }
+            }
+            Self::OtherReg(before, value, after) => write!(
                 f,
-                "Wrote ${:02x} to {} {} cycles {}",
+                "${:02x} is written to {} instead of {}",
                 value,
+                RegDispl(*after),
+                RegDispl(*before),
+            ),
+            Self::ChannelReallocation {
+                from,
+                to,
+                ticks,
+                notes,
+            } => write!(
+                f,
+                "{} notes moved from CH{} to CH{} between ticks {} and {}",
+                notes, from, to, ticks.0, ticks.1,
+            ),
+            Self::TriggerChanged(reg, added, value) => write!(
+                f,
+                "Channel retrigger {} on {} write of ${:02x}",
+                if *added { "added" } else { "removed" },
                 RegDispl(*reg),
-                delta.abs(),
-                if *delta < 0 { "earlier" } else { "later" }
+                value,
             ),
-            Self::OtherValue(reg, before, after) => write!(
+            Self::DivResetMoved(delta) => write!(
                 f,
-                "Wrote ${:02x} to {} instead of ${:02x}",
+                "DIV reset moved {} cycles {}; later timing may be phase-shifted",
+                delta.abs(),
+                if *delta < 0 { "earlier" } else { "later" },
+            ),
+            Self::FreqChanged {
+                channel,
+                before,
                 after,
-                RegDispl(*reg),
+            } => write!(
+                f,
+                "Frequency changed from ${:03x} to ${:03x} on CH{} (≈ {:+.1} semitones)",
                 before,
+                after,
+                channel,
+                semitones(*before, *after),
             ),
-            Self::OtherReg(before, value, after) => write!(
+            Self::FreqOrderSwapped {
+                channel,
+                high_first,
+                trigger,
+            } => {
+                write!(f, "Frequency write order swapped on CH{}", channel)?;
+                if *trigger {
+                    write!(
+                        f,
+                        "; the channel is {} retriggered before NR{}3 is written",
+                        if *high_first { "now" } else { "no longer" },
+                        channel,
+                    )
+                } else {
+                    write!(f, " (no audible change)")
+                }
+            }
+            Self::SlippedTick(reg, value, from, to) => write!(
                 f,
-                "${:02x} is written to {} instead of {}",
+                "Wrote ${:02x} to {} at the {} of tick {} instead of the {} of tick {}",
                 value,
-                RegDispl(*after),
-                RegDispl(*before),
+                RegDispl(*reg),
+                if to > from { "start" } else { "end" },
+                to,
+                if to > from { "end" } else { "start" },
+                from,
             ),
         }
     }
 }
 
-struct RegDispl(u16);
+pub(crate) struct RegDispl(pub u16);
 
 impl Display for RegDispl {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match HwReg::try_from(self.0) {
-            Ok(HwReg::Ramg) => write!(f, "RAMG"),
-            Ok(HwReg::Romb0) => write!(f, "ROMB0"),
-            Ok(HwReg::Romb1) => write!(f, "ROMB1"),
-            Ok(HwReg::Ramb) => write!(f, "RAMB"),
-            Ok(HwReg::Rtclatch) => write!(f, "RTCLATCH"),
-            Ok(HwReg::P1) => write!(f, "P1"),
-            Ok(HwReg::Sb) => write!(f, "SB"),
-            Ok(HwReg::Sc) => write!(f, "SC"),
-            Ok(HwReg::Div) => write!(f, "DIV"),
-            Ok(HwReg::Tima) => write!(f, "TIMA"),
-            Ok(HwReg::Tma) => write!(f, "TMA"),
-            Ok(HwReg::Tac) => write!(f, "TAC"),
-            Ok(HwReg::If) => write!(f, "IF"),
-            Ok(HwReg::Nr10) => write!(f, "NR10"),
-            Ok(HwReg::Nr11) => write!(f, "NR11"),
-            Ok(HwReg::Nr12) => write!(f, "NR12"),
-            Ok(HwReg::Nr13) => write!(f, "NR13"),
-            Ok(HwReg::Nr14) => write!(f, "NR14"),
-            Ok(HwReg::Nr21) => write!(f, "NR21"),
-            Ok(HwReg::Nr22) => write!(f, "NR22"),
-            Ok(HwReg::Nr23) => write!(f, "NR23"),
-            Ok(HwReg::Nr24) => write!(f, "NR24"),
-            Ok(HwReg::Nr30) => write!(f, "NR30"),
-            Ok(HwReg::Nr31) => write!(f, "NR31"),
-            Ok(HwReg::Nr32) => write!(f, "NR32"),
-            Ok(HwReg::Nr33) => write!(f, "NR33"),
-            Ok(HwReg::Nr34) => write!(f, "NR34"),
-            Ok(HwReg::Nr41) => write!(f, "NR41"),
-            Ok(HwReg::Nr42) => write!(f, "NR42"),
-            Ok(HwReg::Nr43) => write!(f, "NR43"),
-            Ok(HwReg::Nr44) => write!(f, "NR44"),
-            Ok(HwReg::Nr50) => write!(f, "NR50"),
-            Ok(HwReg::Nr51) => write!(f, "NR51"),
-            Ok(HwReg::Nr52) => write!(f, "NR52"),
-            Ok(HwReg::Wave0) => write!(f, "Wave RAM[0]"),
-            Ok(HwReg::Wave1) => write!(f, "Wave RAM[1]"),
-            Ok(HwReg::Wave2) => write!(f, "Wave RAM[2]"),
-            Ok(HwReg::Wave3) => write!(f, "Wave RAM[3]"),
-            Ok(HwReg::Wave4) => write!(f, "Wave RAM[4]"),
-            Ok(HwReg::Wave5) => write!(f, "Wave RAM[5]"),
-            Ok(HwReg::Wave6) => write!(f, "Wave RAM[6]"),
-            Ok(HwReg::Wave7) => write!(f, "Wave RAM[7]"),
-            Ok(HwReg::Wave8) => write!(f, "Wave RAM[8]"),
-            Ok(HwReg::Wave9) => write!(f, "Wave RAM[9]"),
-            Ok(HwReg::WaveA) => write!(f, "Wave RAM[10]"),
-            Ok(HwReg::WaveB) => write!(f, "Wave RAM[11]"),
-            Ok(HwReg::WaveC) => write!(f, "Wave RAM[12]"),
-            Ok(HwReg::WaveD) => write!(f, "Wave RAM[13]"),
-            Ok(HwReg::WaveE) => write!(f, "Wave RAM[14]"),
-            Ok(HwReg::WaveF) => write!(f, "Wave RAM[15]"),
-            Ok(HwReg::Lcdc) => write!(f, "LCDC"),
-            Ok(HwReg::Stat) => write!(f, "STAT"),
-            Ok(HwReg::Scy) => write!(f, "SCY"),
-            Ok(HwReg::Scx) => write!(f, "SCX"),
-            Ok(HwReg::Ly) => write!(f, "LY"),
-            Ok(HwReg::Lyc) => write!(f, "LYC"),
-            Ok(HwReg::Dma) => write!(f, "DMA"),
-            Ok(HwReg::Bgp) => write!(f, "BGP"),
-            Ok(HwReg::Obp0) => write!(f, "OBP0"),
-            Ok(HwReg::Obp1) => write!(f, "OBP1"),
-            Ok(HwReg::Wy) => write!(f, "WY"),
-            Ok(HwReg::Wx) => write!(f, "WX"),
-            Ok(HwReg::Key1) => write!(f, "KEY1"),
-            Ok(HwReg::Vbk) => write!(f, "VBK"),
-            Ok(HwReg::Hdma1) => write!(f, "HDMA1"),
-            Ok(HwReg::Hdma2) => write!(f, "HDMA2"),
-            Ok(HwReg::Hdma3) => write!(f, "HDMA3"),
-            Ok(HwReg::Hdma4) => write!(f, "HDMA4"),
-            Ok(HwReg::Hdma5) => write!(f, "HDMA5"),
-            Ok(HwReg::Rp) => write!(f, "RP"),
-            Ok(HwReg::Bcps) => write!(f, "BCPS"),
-            Ok(HwReg::Bcpd) => write!(f, "BCPD"),
-            Ok(HwReg::Ocps) => write!(f, "OCPS"),
-            Ok(HwReg::Ocpd) => write!(f, "OCPD"),
-            Ok(HwReg::Svbk) => write!(f, "SVBK"),
-            Ok(HwReg::Pcm12) => write!(f, "PCM12"),
-            Ok(HwReg::Pcm34) => write!(f, "PCM34"),
-            Ok(HwReg::Ie) => write!(f, "IE"),
-            Ok(reg) => write!(f, "{}", u16::from(reg)),
-            Err(addr) => write!(f, "${addr:04x}"),
+        match (regs::name(self.0), HwReg::try_from(self.0)) {
+            (Some(name), _) => write!(f, "{}", name),
+            (None, Ok(reg)) => write!(f, "{}", u16::from(reg)),
+            (None, Err(addr)) => write!(f, "${addr:04x}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freq_steps_only_count_period_changes() {
+        let high = RegSemantics::FreqHighAndTrigger;
+        assert_eq!(RegSemantics::FreqLow.freq_steps(0x40, 0x3E), Some(-2));
+        assert_eq!(high.freq_steps(0x87, 0x86), Some(-256));
+        assert_eq!(high.freq_steps(0x05, 0x07), Some(512));
+        // The trigger or length enable bits changing is more than a frequency change.
+        assert_eq!(high.freq_steps(0x87, 0x06), None);
+        assert_eq!(high.freq_steps(0x87, 0xC6), None);
+        assert_eq!(high.freq_steps(0x47, 0x07), None);
+        assert_eq!(RegSemantics::Trigger.freq_steps(0x80, 0x00), None);
+
+        let other_value =
+            |reg, before, after| DiagnosticKind::OtherValue(reg, before, after).to_string();
+        assert_eq!(
+            other_value(0xFF14, 0x87, 0x86),
+            "Wrote $86 to NR14 instead of $87 (256 frequency steps lower)"
+        );
+        assert_eq!(
+            other_value(0xFF14, 0x87, 0xC6),
+            "Wrote $c6 to NR14 instead of $87"
+        );
+    }
+
+    fn write(tick: u64, cycle: u32, addr: u16, data: u8) -> IoAccess {
+        IoAccess {
+            when: crate::Timestamp { tick, cycle },
+            pc: crate::Address(1, 0x4000),
+            addr,
+            data,
         }
     }
+
+    fn cadence() -> Cadence {
+        let data = crate::gbs::GbsBuilder::default().build();
+        crate::gbs::Gbs::new(&data).unwrap().cadence()
+    }
+
+    /// The diagnostics' levels and kinds, after pairing slipped writes.
+    fn slipped(
+        before: &[IoAccess],
+        after: &[IoAccess],
+        window: usize,
+        jitter: u16,
+    ) -> Vec<(DiagnosticLevel, String)> {
+        let mut diags: Vec<_> = DiffGenerator::new(before, after, jitter, false).collect();
+        pair_slipped_writes(&mut diags, (before, after), window, jitter, &cadence());
+        diags
+            .iter()
+            .map(|diag| (diag.level, format!("{:?}", diag.kind)))
+            .collect()
+    }
+
+    #[test]
+    fn writes_slipping_past_the_tick_boundary_are_paired() {
+        let tick_len = cadence().tick_cycles(1);
+        let before = [
+            write(1, 100, 0xFF12, 0xF0),
+            write(1, tick_len - 40, 0xFF13, 0x40),
+            write(1, tick_len - 20, 0xFF14, 0x87),
+            write(2, 100, 0xFF12, 0xA0),
+        ];
+        let after = [
+            write(1, 100, 0xFF12, 0xF0),
+            write(1, tick_len - 40, 0xFF13, 0x40),
+            write(2, 10, 0xFF14, 0x87),
+            write(2, 100, 0xFF12, 0xA0),
+        ];
+        // 30 cycles late, which is a warning unless within the jitter.
+        assert_eq!(
+            slipped(&before, &after, 2, 4),
+            [(
+                DiagnosticLevel::Warning,
+                "SlippedTick(65300, 135, 1, 2)".into()
+            )]
+        );
+        assert_eq!(
+            slipped(&before, &after, 2, 30),
+            [(
+                DiagnosticLevel::Warning,
+                "SlippedTick(65300, 135, 1, 2)".into()
+            )]
+        );
+        assert_eq!(
+            slipped(&before, &after, 2, 31),
+            [(
+                DiagnosticLevel::Note,
+                "SlippedTick(65300, 135, 1, 2)".into()
+            )]
+        );
+        // It also works the other way around.
+        assert_eq!(
+            slipped(&after, &before, 2, 4),
+            [(
+                DiagnosticLevel::Warning,
+                "SlippedTick(65300, 135, 2, 1)".into()
+            )]
+        );
+    }
+
+    #[test]
+    fn other_differences_are_not_slips() {
+        let tick_len = cadence().tick_cycles(1);
+        let before = [
+            write(1, tick_len - 20, 0xFF14, 0x87),
+            write(3, 100, 0xFF12, 0xF0),
+        ];
+        let kinds = |after: &[IoAccess]| -> Vec<_> {
+            slipped(&before, after, 2, 4)
+                .into_iter()
+                .map(|(_, kind)| kind)
+                .collect()
+        };
+        // Another value.
+        assert_eq!(
+            kinds(&[write(2, 10, 0xFF14, 0x86), write(3, 100, 0xFF12, 0xF0)]),
+            ["Removed(65300, 135)", "Added(65300, 134)"]
+        );
+        // More than one tick later.
+        assert_eq!(
+            kinds(&[write(3, 10, 0xFF14, 0x87), write(3, 100, 0xFF12, 0xF0)]),
+            ["Removed(65300, 135)", "Added(65300, 135)"]
+        );
+        // Not at the end of a tick in the first place.
+        let before = [
+            write(3, 100, 0xFF12, 0xF0),
+            write(3, 200, 0xFF13, 0x40),
+            write(3, 300, 0xFF14, 0x87),
+        ];
+        let after = [
+            write(3, 200, 0xFF13, 0x40),
+            write(3, 300, 0xFF14, 0x87),
+            write(4, 10, 0xFF12, 0xF0),
+        ];
+        assert_eq!(
+            slipped(&before, &after, 2, 4),
+            [
+                (DiagnosticLevel::Error, "Removed(65298, 240)".into()),
+                (DiagnosticLevel::Error, "Added(65298, 240)".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn slips_must_be_within_the_window() {
+        let tick_len = cadence().tick_cycles(1);
+        let before = [
+            write(1, tick_len - 40, 0xFF13, 0x40),
+            write(1, tick_len - 30, 0xFF14, 0x87),
+            write(1, tick_len - 20, 0xFF25, 0xFF),
+            write(2, 100, 0xFF12, 0xF0),
+        ];
+        let after = [
+            write(1, tick_len - 40, 0xFF13, 0x40),
+            write(1, tick_len - 20, 0xFF25, 0xFF),
+            write(2, 10, 0xFF14, 0x87),
+            write(2, 100, 0xFF12, 0xF0),
+        ];
+        // NR14 was second to last in its tick, and is first in the next one.
+        let kinds = |window| -> Vec<_> {
+            slipped(&before, &after, window, 4)
+                .into_iter()
+                .map(|(_, kind)| kind)
+                .collect()
+        };
+        assert_eq!(kinds(2), ["SlippedTick(65300, 135, 1, 2)"]);
+        assert_eq!(kinds(1), ["Removed(65300, 135)", "Added(65300, 135)"]);
+        assert_eq!(kinds(0), ["Removed(65300, 135)", "Added(65300, 135)"]);
+    }
+
+    /// The diagnostics' levels and descriptions, after pairing frequency writes.
+    fn paired(before: &[IoAccess], after: &[IoAccess]) -> Vec<(DiagnosticLevel, String)> {
+        let mut diags: Vec<_> = DiffGenerator::new(before, after, 4, false).collect();
+        pair_freq_writes(&mut diags, (before, after));
+        diags
+            .iter()
+            .map(|diag| (diag.level, diag.kind.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn freq_writes_are_paired() {
+        let pair = |nrx3, nrx4, high_first| {
+            let writes = [(0xFF13, nrx3), (0xFF14, nrx4)];
+            let (first, second) = if high_first {
+                (writes[1], writes[0])
+            } else {
+                (writes[0], writes[1])
+            };
+            [
+                write(1, 10, 0xFF12, 0xF0),
+                write(1, 20, first.0, first.1),
+                write(1, 30, second.0, second.1),
+            ]
+        };
+
+        assert_eq!(
+            paired(&pair(0x40, 0x87, false), &pair(0x42, 0x87, false)),
+            [(
+                DiagnosticLevel::Error,
+                "Frequency changed from $740 to $742 on CH1 (≈ +0.2 semitones)".into()
+            )]
+        );
+        // The period's extremes.
+        assert_eq!(
+            paired(&pair(0x00, 0x80, false), &pair(0xFF, 0x87, true)),
+            [(
+                DiagnosticLevel::Error,
+                "Frequency changed from $000 to $7ff on CH1 (≈ +132.0 semitones)".into()
+            )]
+        );
+        // Swapping the writes only matters much when triggering.
+        assert_eq!(
+            paired(&pair(0x40, 0x07, false), &pair(0x40, 0x07, true))[0].0,
+            DiagnosticLevel::Note
+        );
+        assert_eq!(
+            paired(&pair(0x40, 0x87, false), &pair(0x40, 0x87, true))[0].0,
+            DiagnosticLevel::Warning
+        );
+        // A trigger being added or removed is left to the per-write diagnostics.
+        assert_eq!(
+            paired(&pair(0x40, 0x87, false), &pair(0x42, 0x07, false)).len(),
+            2
+        );
+
+        // Writes that don't form a pair (only one of them, or one twice) are left alone too.
+        let lone = |nrx3| [write(1, 20, 0xFF13, nrx3)];
+        assert_eq!(
+            paired(&lone(0x40), &lone(0x42)),
+            [(
+                DiagnosticLevel::Error,
+                "Wrote $42 to NR13 instead of $40 (2 frequency steps higher)".into()
+            )]
+        );
+        let mut twice = pair(0x40, 0x87, false).to_vec();
+        twice.push(write(1, 40, 0xFF13, 0x42));
+        assert_eq!(
+            paired(&pair(0x40, 0x87, false), &twice),
+            [(DiagnosticLevel::Error, "New write of $42 to NR13".into())]
+        );
+    }
+
+    #[test]
+    fn periods_convert_to_semitones() {
+        let close = |actual: f64, expected: f64| (actual - expected).abs() < 1e-9;
+        assert!(close(semitones(0x700, 0x700), 0.0));
+        // Halving `2048 - period` doubles the frequency, i.e. an octave up.
+        assert!(close(semitones(0, 1024), 12.0));
+        assert!(close(semitones(1024, 1536), 12.0));
+        assert!(close(semitones(1536, 1024), -12.0));
+        // The extremes, 2047 being the highest pitch.
+        assert!(close(semitones(0, 2047), 132.0));
+        assert!(close(semitones(2047, 0), -132.0));
+        assert!(semitones(2046, 2047).is_finite());
+    }
+
+    #[test]
+    fn bursts_are_found() {
+        let upload = |tick, data: &[u8]| -> Vec<_> {
+            data.iter()
+                .zip(0..)
+                .map(|(&data, i)| write(tick, 10 + u32::from(i) * 4, 0xFF30 + i, data))
+                .collect()
+        };
+        let log = upload(1, &[0x01, 0x23, 0x45, 0x67]);
+        assert_eq!(burst(&log, 0), Some((4, 1)));
+        assert_eq!(burst(&log, 1), Some((3, 1)));
+        assert_eq!(burst(&log, 2), None);
+
+        // Bursts to one register, which stop at the tick boundary.
+        let mut log = vec![write(1, 10, 0xFF12, 0xF0)];
+        log.extend((0..4).map(|i| write(1, 20 + i, 0xFF25, i as u8)));
+        log.push(write(2, 10, 0xFF25, 0xFF));
+        assert_eq!(burst(&log, 0), None);
+        assert_eq!(burst(&log, 1), Some((4, 0)));
+
+        // Consecutive addresses only count within wave RAM.
+        let log: Vec<_> = (0..4).map(|i| write(1, i, 0xFF10 + i as u16, 0)).collect();
+        assert_eq!(burst(&log, 0), None);
+        let log: Vec<_> = (0..4).map(|i| write(1, i, 0xFF3E + i as u16, 0)).collect();
+        assert_eq!(burst(&log, 0), None);
+    }
+
+    #[test]
+    fn bursts_are_aligned_positionally() {
+        let upload = |data: &[u8]| -> Vec<_> {
+            data.iter()
+                .zip(0..)
+                .map(|(&data, i)| write(1, 10 + u32::from(i) * 4, 0xFF30 + i, data))
+                .chain([write(1, 200, 0xFF1A, 0x80)])
+                .collect()
+        };
+        let diff = |before: &[IoAccess], after: &[IoAccess]| -> Vec<_> {
+            DiffGenerator::new(before, after, 4, false)
+                .map(|diag| diag.kind.to_string())
+                .collect()
+        };
+        let wave = [0x01, 0x23, 0x45, 0x67, 0x89, 0xAB];
+
+        // A single byte changing is a single difference.
+        let mut changed = wave;
+        changed[2] = 0x44;
+        assert_eq!(
+            diff(&upload(&wave), &upload(&changed)),
+            ["Wrote $44 to Wave RAM[2] instead of $45"]
+        );
+        // A longer upload only reports the excess.
+        let longer = upload(&[&wave[..], &[0xCD, 0xEF]].concat());
+        assert_eq!(
+            diff(&upload(&wave), &longer),
+            [
+                "New write of $cd to Wave RAM[6]",
+                "New write of $ef to Wave RAM[7]"
+            ]
+        );
+        assert_eq!(
+            diff(&longer, &upload(&wave)),
+            [
+                "Missing write of $cd to Wave RAM[6]",
+                "Missing write of $ef to Wave RAM[7]"
+            ]
+        );
+    }
+
+    /// The diagnostics for a single write whose value changed, after relaxing field changes.
+    fn relaxed(addr: u16, before: u8, after: u8, relax_duty: bool) -> (DiagnosticLevel, String) {
+        let logs = ([write(1, 10, addr, before)], [write(1, 10, addr, after)]);
+        let mut diags: Vec<_> = DiffGenerator::new(&logs.0, &logs.1, 4, false).collect();
+        relax_field_changes(&mut diags, relax_duty);
+        assert_eq!(diags.len(), 1, "{:#?}", diags);
+        (diags[0].level, diags[0].kind.to_string())
+    }
+
+    #[test]
+    fn field_changes_are_named() {
+        use DiagnosticLevel::*;
+
+        assert_eq!(
+            relaxed(0xFF11, 0x80, 0xC0, false),
+            (
+                Error,
+                "Wrote $c0 to NR11 instead of $80 (duty 50%->75%)".into()
+            )
+        );
+        assert_eq!(
+            relaxed(0xFF22, 0x51, 0x68, false),
+            (
+                Error,
+                "Wrote $68 to NR43 instead of $51 (clock shift 5->6, LFSR width 0->1, clock divider 1->0)"
+                    .into()
+            )
+        );
+        assert_eq!(
+            relaxed(0xFF12, 0xF3, 0xAB, false),
+            (
+                Error,
+                "Wrote $ab to NR12 instead of $f3 (volume 15->10, envelope direction 0->1)".into()
+            )
+        );
+        // Registers without fields are only described by their values.
+        assert_eq!(
+            relaxed(0xFF24, 0x77, 0x33, false),
+            (Error, "Wrote $33 to NR50 instead of $77".into())
+        );
+    }
+
+    #[test]
+    fn length_and_envelope_pace_changes_only_warn() {
+        use DiagnosticLevel::*;
+
+        let level = |addr, before, after, relax_duty| relaxed(addr, before, after, relax_duty).0;
+        assert_eq!(level(0xFF11, 0x80, 0xBF, false), Warning);
+        assert_eq!(level(0xFF16, 0x80, 0x81, false), Warning);
+        assert_eq!(level(0xFF1B, 0x00, 0xFF, false), Warning);
+        assert_eq!(level(0xFF20, 0x00, 0x3F, false), Warning);
+        assert_eq!(level(0xFF12, 0xF3, 0xF1, false), Warning);
+        assert_eq!(level(0xFF21, 0xF3, 0xF0, false), Warning);
+        // Anything else changing along with them is still an error.
+        assert_eq!(level(0xFF11, 0x80, 0xFF, false), Error);
+        assert_eq!(level(0xFF12, 0xF3, 0xE1, false), Error);
+        assert_eq!(level(0xFF12, 0xF3, 0xFB, false), Error);
+        // Unless it's the duty, and it's relaxed too.
+        assert_eq!(level(0xFF11, 0x80, 0xFF, true), Warning);
+        assert_eq!(level(0xFF16, 0x00, 0xC0, true), Warning);
+        // NR41 has no duty.
+        assert_eq!(level(0xFF20, 0x00, 0xC0, true), Error);
+        assert_eq!(level(0xFF13, 0x00, 0x01, true), Error);
+    }
+
+    #[test]
+    fn exit_banks_are_compared() {
+        let (before, after) = ([1, 2, 2, 2, 2], [1, 2, 3, 2, 4]);
+        assert_eq!(compare_exit_banks(&before, &before, |_| true), []);
+        assert_eq!(
+            compare_exit_banks(&before, &after, |_| true),
+            [ExitBankDiff::Play {
+                tick: 2,
+                before: 2,
+                after: 3
+            }]
+        );
+        // Only the first compared tick is reported.
+        assert_eq!(
+            compare_exit_banks(&before, &after, |tick| tick > 2),
+            [ExitBankDiff::Play {
+                tick: 4,
+                before: 2,
+                after: 4
+            }]
+        );
+        assert_eq!(compare_exit_banks(&before, &after, |_| false), []);
+        // INIT is always compared, and separately.
+        let diffs = compare_exit_banks(&[1, 2, 2], &[3, 2, 5], |_| true);
+        assert_eq!(
+            diffs,
+            [
+                ExitBankDiff::Init {
+                    before: 1,
+                    after: 3
+                },
+                ExitBankDiff::Play {
+                    tick: 2,
+                    before: 2,
+                    after: 5
+                }
+            ]
+        );
+        assert_eq!(
+            diffs[0].to_string(),
+            "INIT now returns with bank 3 mapped instead of bank 1"
+        );
+        assert_eq!(
+            diffs[1].to_string(),
+            "PLAY now returns with bank 5 mapped instead of bank 2 (first differing tick: 2)"
+        );
+        // Songs of different lengths are compared over their common ticks.
+        assert_eq!(compare_exit_banks(&[1, 2], &[1, 2, 3], |_| true), []);
+        assert_eq!(compare_exit_banks(&[], &[1, 2], |_| true), []);
+    }
+
+    #[test]
+    fn context_is_taken_from_matching_writes_within_the_tick() {
+        let log = |nr13, extra: bool| {
+            let mut log = vec![
+                write(0, 5, 0xFF24, 0x77),
+                write(1, 10, 0xFF10, 0x01),
+                write(1, 20, 0xFF11, 0x02),
+                write(1, 30, 0xFF12, 0x03),
+                write(1, 40, 0xFF13, nr13),
+                write(1, 50, 0xFF14, 0x05),
+                write(1, 60, 0xFF16, 0x06),
+                write(2, 10, 0xFF17, 0x07),
+            ];
+            if extra {
+                log.insert(6, write(1, 55, 0xFF1A, 0x80));
+            }
+            log
+        };
+        let logs = (log(0x04, true), log(0x09, false));
+        let mut generator = DiffGenerator::new(&logs.0, &logs.1, 4, false);
+        let diags: Vec<_> = generator.by_ref().collect();
+        let matched = generator.into_matched();
+        let context = |diag, nb| {
+            let (preceding, following) = context((&logs.0, &logs.1), &matched, diag, nb);
+            let regs = |accesses: Vec<&IoAccess>| -> Vec<_> {
+                accesses.iter().map(|access| access.addr).collect()
+            };
+            (regs(preceding), regs(following))
+        };
+
+        let [changed, removed] = &diags[..] else {
+            panic!("{:#?}", diags);
+        };
+        assert!(matches!(
+            changed.kind,
+            DiagnosticKind::OtherValue(0xFF13, 0x04, 0x09)
+        ));
+        assert!(matches!(
+            removed.kind,
+            DiagnosticKind::Removed(0xFF1A, 0x80)
+        ));
+        assert_eq!(
+            context(changed, 2),
+            (vec![0xFF11, 0xFF12], vec![0xFF14, 0xFF16])
+        );
+        // Writes from other ticks are not context.
+        assert_eq!(
+            context(changed, 10),
+            (vec![0xFF10, 0xFF11, 0xFF12], vec![0xFF14, 0xFF16])
+        );
+        // Removed writes are located in the "before" log, but their context is still "after"'s.
+        assert_eq!(context(removed, 1), (vec![0xFF14], vec![0xFF16]));
+    }
 }
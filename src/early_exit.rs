@@ -0,0 +1,180 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with comparing how often the PLAY routine takes an early exit.
+//!
+//! Many drivers return almost immediately when no new row needs processing, so the ticks' cycle
+//! counts fall into two clusters: "idle" ticks and "full" ones. Which ticks are full follows the
+//! sequencer's timing, which makes it a cheap proxy for it having changed. The split between both
+//! clusters is picked automatically (Otsu's method), over both files' ticks at once so that the
+//! same tick is classified the same way on both sides.
+
+use std::fmt::Display;
+
+/// The full ticks must take at least this many times as long as the idle ones on average, or the
+/// driver is deemed not to have a fast path at all, and the spread is just noise.
+const MIN_SPREAD: u32 = 2;
+
+/// The smallest cycle count of a full tick, or `None` if the ticks don't form two clusters.
+pub fn threshold(tick_cycles: &[u32]) -> Option<u32> {
+    let mut cycles = tick_cycles.to_vec();
+    cycles.sort_unstable();
+    let total: u64 = cycles.iter().copied().map(u64::from).sum();
+
+    // Maximise the between-class variance, i.e. `n0 * n1 * (mean0 - mean1)²`; trying each split
+    // between two distinct values is fine, since there are at most a few thousand ticks.
+    let mut best: Option<(f64, u32, f64, f64)> = None;
+    let mut sum_below = 0;
+    for (i, pair) in cycles.windows(2).enumerate() {
+        sum_below += u64::from(pair[0]);
+        if pair[0] == pair[1] {
+            continue;
+        }
+        let (n0, n1) = ((i + 1) as f64, (cycles.len() - i - 1) as f64);
+        let mean0 = sum_below as f64 / n0;
+        let mean1 = (total - sum_below) as f64 / n1;
+        let variance = n0 * n1 * (mean1 - mean0) * (mean1 - mean0);
+        if best.map_or(true, |(best_variance, ..)| variance > best_variance) {
+            best = Some((variance, pair[1], mean0, mean1));
+        }
+    }
+
+    let (_, threshold, mean0, mean1) = best?;
+    (mean1 >= mean0 * f64::from(MIN_SPREAD)).then_some(threshold)
+}
+
+/// How many PLAY ticks went down either path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Counts {
+    pub full: usize,
+    pub idle: usize,
+}
+
+impl Counts {
+    fn new(tick_cycles: &[u32], threshold: u32) -> Self {
+        let full = tick_cycles
+            .iter()
+            .filter(|&&cycles| cycles >= threshold)
+            .count();
+        Self {
+            full,
+            idle: tick_cycles.len() - full,
+        }
+    }
+}
+
+/// Both files' ticks, classified with the same threshold.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comparison {
+    pub threshold: u32,
+    pub before: Counts,
+    pub after: Counts,
+    /// The first tick that took a different path in both files, if any.
+    pub first_divergence: Option<u64>,
+    /// How many of the ticks both files have took a different path.
+    pub nb_diverging: usize,
+    pub nb_common: usize,
+}
+
+impl Comparison {
+    /// How many percent of the common ticks took a different path.
+    pub fn divergence_percent(&self) -> f64 {
+        self.nb_diverging as f64 * 100.0 / self.nb_common.max(1) as f64
+    }
+}
+
+impl Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "PLAY early exits (ticks under {} cycles) changed: before: {} full ticks / {} idle ticks; after: {}/{}",
+            self.threshold, self.before.full, self.before.idle, self.after.full, self.after.idle,
+        )?;
+        if let Some(tick) = self.first_divergence {
+            write!(
+                f,
+                " — first divergence at tick {} ({} ticks, {:.1}%)",
+                tick,
+                self.nb_diverging,
+                self.divergence_percent(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// `before` and `after` are the cycles spent by each PLAY tick, the first of which is `first_tick`.
+/// `None` if the driver doesn't seem to have a fast path.
+pub fn compare(before: &[u32], after: &[u32], first_tick: u64) -> Option<Comparison> {
+    let threshold = threshold(&[before, after].concat())?;
+    let is_full = |cycles: &u32| *cycles >= threshold;
+    let mut diverging = before
+        .iter()
+        .map(is_full)
+        .zip(after.iter().map(is_full))
+        .enumerate()
+        .filter(|(_, (before, after))| before != after)
+        .map(|(i, _)| i);
+    let first_divergence = diverging.next().map(|i| first_tick + i as u64);
+
+    Some(Comparison {
+        threshold,
+        before: Counts::new(before, threshold),
+        after: Counts::new(after, threshold),
+        first_divergence,
+        nb_diverging: first_divergence.map_or(0, |_| 1 + diverging.count()),
+        nb_common: before.len().min(after.len()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks_are_split_into_two_clusters() {
+        assert_eq!(threshold(&[100, 2000, 100, 105, 2100, 100]), Some(2000));
+        // The clusters needn't be the same size.
+        assert_eq!(threshold(&[300, 300, 300, 300, 300, 300, 900]), Some(900));
+        // Noise isn't a fast path.
+        assert_eq!(threshold(&[1000, 1010, 1100, 1020, 1500]), None);
+        assert_eq!(threshold(&[500; 10]), None);
+        assert_eq!(threshold(&[500]), None);
+        assert_eq!(threshold(&[]), None);
+    }
+
+    #[test]
+    fn paths_are_compared_tick_by_tick() {
+        let before = [100, 2000, 100, 100, 2000, 100];
+        let after = [100, 2000, 100, 2000, 100, 100, 2000];
+        let comparison = compare(&before, &after, 1).unwrap();
+        assert_eq!(
+            comparison,
+            Comparison {
+                threshold: 2000,
+                before: Counts { full: 2, idle: 4 },
+                after: Counts { full: 3, idle: 4 },
+                first_divergence: Some(4),
+                nb_diverging: 2,
+                nb_common: 6,
+            }
+        );
+        assert_eq!(
+            comparison.to_string(),
+            "PLAY early exits (ticks under 2000 cycles) changed: before: 2 full ticks / 4 idle ticks; after: 3/4 — first divergence at tick 4 (2 ticks, 33.3%)"
+        );
+
+        // Both files going down the same paths, with some jitter.
+        let comparison = compare(&before, &[110, 1900, 90, 100, 2050, 100], 1).unwrap();
+        assert_eq!(
+            (comparison.first_divergence, comparison.nb_diverging),
+            (None, 0)
+        );
+        assert!(!comparison.to_string().contains("divergence"));
+
+        assert_eq!(compare(&[1000; 4], &[1000, 1010, 1000], 1), None);
+    }
+}
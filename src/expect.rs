@@ -0,0 +1,450 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with checking a song against a script of the writes it is expected to make,
+//! for when there is no "before" GBS to compare it to yet, e.g. while bringing up a new driver.
+//!
+//! Such scripts are passed in place of the "before" file, with a `.expect` extension. Each line
+//! lists the writes made on one tick, or on each of an (inclusive) range of ticks:
+//!
+//! ```text
+//! tick 1: NR52=80 NR51=ff NR50=77
+//! tick 2..64: NR13=* NR14=87  # `*` matches any value
+//! ```
+//!
+//! Registers are either named, or given as hex addresses. Within a tick, writes to the same
+//! register must happen in the order listed, but the order of writes to different registers is
+//! not checked. Ticks that no line mentions are not checked at all, but those that are must not
+//! contain any other writes.
+
+use std::{fmt::Display, ops::RangeInclusive};
+
+use crate::{diff::RegDispl, regs, run::IoAccess, Address, Timestamp};
+
+/// The script's lines, in chronological order; no two of them describe the same tick.
+#[derive(Debug, Clone, Default)]
+pub struct Script(Vec<Line>);
+
+#[derive(Debug, Clone)]
+struct Line {
+    line_no: usize,
+    ticks: RangeInclusive<u64>,
+    writes: Vec<ExpectedWrite>,
+}
+
+/// `None` is a wildcard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExpectedWrite(u16, Option<u8>);
+
+fn parse_reg(name: &str) -> Result<u16, String> {
+    regs::parse(name).map_or_else(
+        || {
+            let name = name.strip_prefix('$').unwrap_or(name);
+            u16::from_str_radix(name, 16)
+                .map_err(|_| format!("{:?} is neither a register name nor an address", name))
+        },
+        Ok,
+    )
+}
+
+fn parse_ticks(ticks: &str) -> Result<RangeInclusive<u64>, String> {
+    let parse = |tick: &str| {
+        tick.trim()
+            .parse()
+            .map_err(|err| format!("bad tick {:?}: {}", tick, err))
+    };
+    let (start, end) = match ticks.split_once("..") {
+        Some((start, end)) => (parse(start)?, parse(end)?),
+        None => {
+            let tick = parse(ticks)?;
+            (tick, tick)
+        }
+    };
+    if start > end {
+        return Err(format!("tick range {}..{} is empty", start, end));
+    }
+    Ok(start..=end)
+}
+
+fn parse_write(write: &str) -> Result<ExpectedWrite, String> {
+    let (reg, value) = write
+        .split_once('=')
+        .ok_or_else(|| format!("{:?} must be of the form REG=VALUE", write))?;
+    let value = match value {
+        "*" => None,
+        value => Some(
+            u8::from_str_radix(value.strip_prefix('$').unwrap_or(value), 16)
+                .map_err(|err| format!("bad value {:?}: {}", value, err))?,
+        ),
+    };
+    Ok(ExpectedWrite(parse_reg(reg)?, value))
+}
+
+pub fn parse(text: &str) -> Result<Script, String> {
+    let mut script = Script::default();
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line.split_once('#').map_or(line, |(line, _)| line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let parse_line = || {
+            let (ticks, writes) = line
+                .strip_prefix("tick ")
+                .and_then(|line| line.split_once(':'))
+                .ok_or("expected `tick N: ...` or `tick N..M: ...`")?;
+            let writes = writes
+                .split_whitespace()
+                .map(parse_write)
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, String>((parse_ticks(ticks)?, writes))
+        };
+        let (ticks, writes) = parse_line().map_err(|err| format!("line {}: {}", line_no, err))?;
+        if let Some(prev) = script
+            .0
+            .iter()
+            .find(|prev| prev.ticks.start() <= ticks.end() && ticks.start() <= prev.ticks.end())
+        {
+            return Err(format!(
+                "line {}: tick {} is already described on line {}",
+                line_no,
+                ticks.start().max(prev.ticks.start()),
+                prev.line_no
+            ));
+        }
+        script.0.push(Line {
+            line_no,
+            ticks,
+            writes,
+        });
+    }
+    script.0.sort_by_key(|line| *line.ticks.start());
+    Ok(script)
+}
+
+/// A way in which a tick did not go as expected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    Missing {
+        tick: u64,
+        line: usize,
+        reg: u16,
+        value: Option<u8>,
+    },
+    OtherValue {
+        line: usize,
+        when: Timestamp,
+        pc: Address,
+        reg: u16,
+        expected: u8,
+        actual: u8,
+    },
+    Unexpected {
+        line: usize,
+        when: Timestamp,
+        pc: Address,
+        reg: u16,
+        value: u8,
+    },
+    /// The song ended before all of the script's ticks.
+    NotReached { tick: u64, line: usize },
+}
+
+impl Mismatch {
+    pub fn tick(&self) -> u64 {
+        match self {
+            Self::Missing { tick, .. } | Self::NotReached { tick, .. } => *tick,
+            Self::OtherValue { when, .. } | Self::Unexpected { when, .. } => when.tick,
+        }
+    }
+
+    /// Where the offending write was, if there was one.
+    pub fn write(&self) -> Option<(&Timestamp, &Address)> {
+        match self {
+            Self::Missing { .. } | Self::NotReached { .. } => None,
+            Self::OtherValue { when, pc, .. } | Self::Unexpected { when, pc, .. } => {
+                Some((when, pc))
+            }
+        }
+    }
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing {
+                line,
+                reg,
+                value: Some(value),
+                ..
+            } => write!(
+                f,
+                "missing write of ${:02x} to {} (line {})",
+                value,
+                RegDispl(*reg),
+                line
+            ),
+            Self::Missing {
+                line,
+                reg,
+                value: None,
+                ..
+            } => write!(f, "missing write to {} (line {})", RegDispl(*reg), line),
+            Self::OtherValue {
+                line,
+                reg,
+                expected,
+                actual,
+                ..
+            } => write!(
+                f,
+                "wrote ${:02x} to {} instead of ${:02x} (line {})",
+                actual,
+                RegDispl(*reg),
+                expected,
+                line
+            ),
+            Self::Unexpected {
+                line, reg, value, ..
+            } => write!(
+                f,
+                "unexpected write of ${:02x} to {} (line {})",
+                value,
+                RegDispl(*reg),
+                line
+            ),
+            Self::NotReached { tick, line } => write!(
+                f,
+                "tick {} was never simulated, so line {} could not be checked",
+                tick, line
+            ),
+        }
+    }
+}
+
+/// Returns the mismatches in chronological order; if the song ended before the script does, a
+/// [`Mismatch::NotReached`] comes last.
+pub fn check(script: &Script, io_log: &[IoAccess], ticks_simulated: u64) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+    for line in &script.0 {
+        for tick in *line.ticks.start()..=(*line.ticks.end()).min(ticks_simulated) {
+            check_tick(&mut mismatches, line, tick, io_log);
+        }
+    }
+    if let Some(line) = script
+        .0
+        .iter()
+        .find(|line| *line.ticks.end() > ticks_simulated)
+    {
+        mismatches.push(Mismatch::NotReached {
+            tick: (*line.ticks.start()).max(ticks_simulated + 1),
+            line: line.line_no,
+        });
+    }
+    mismatches
+}
+
+/// Each expected write is matched with the first write of the tick to the same register that
+/// hasn't been matched yet.
+fn check_tick(mismatches: &mut Vec<Mismatch>, line: &Line, tick: u64, io_log: &[IoAccess]) {
+    let writes = crate::run::slice_ticks(io_log, &(tick..tick + 1));
+    let mut matched = vec![false; writes.len()];
+    let mut tick_mismatches = Vec::new();
+    for &ExpectedWrite(reg, value) in &line.writes {
+        let Some((i, write)) = writes
+            .iter()
+            .enumerate()
+            .find(|&(i, write)| !matched[i] && write.addr == reg)
+        else {
+            mismatches.push(Mismatch::Missing {
+                tick,
+                line: line.line_no,
+                reg,
+                value,
+            });
+            continue;
+        };
+        matched[i] = true;
+        match value {
+            Some(expected) if expected != write.data => {
+                tick_mismatches.push(Mismatch::OtherValue {
+                    line: line.line_no,
+                    when: write.when.clone(),
+                    pc: write.pc.clone(),
+                    reg,
+                    expected,
+                    actual: write.data,
+                });
+            }
+            _ => {}
+        }
+    }
+    tick_mismatches.extend(
+        writes
+            .iter()
+            .zip(&matched)
+            .filter(|(_, &matched)| !matched)
+            .map(|(write, _)| Mismatch::Unexpected {
+                line: line.line_no,
+                when: write.when.clone(),
+                pc: write.pc.clone(),
+                reg: write.addr,
+                value: write.data,
+            }),
+    );
+    tick_mismatches.sort_by_key(|mismatch| mismatch.write().map(|(when, _)| when.clone()));
+    mismatches.extend(tick_mismatches);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(script: &Script) -> Vec<(usize, RangeInclusive<u64>, Vec<ExpectedWrite>)> {
+        script
+            .0
+            .iter()
+            .map(|line| (line.line_no, line.ticks.clone(), line.writes.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn valid_scripts_parse() {
+        let script = parse(
+            "tick 2..64: NR13=* ff14=$87\n\
+             tick 1: NR52=80 $FF25=ff NR50=77\n\
+             tick 65:\n",
+        )
+        .unwrap();
+        assert_eq!(
+            lines(&script),
+            [
+                (
+                    2,
+                    1..=1,
+                    vec![
+                        ExpectedWrite(0xFF26, Some(0x80)),
+                        ExpectedWrite(0xFF25, Some(0xFF)),
+                        ExpectedWrite(0xFF24, Some(0x77)),
+                    ]
+                ),
+                (
+                    1,
+                    2..=64,
+                    vec![
+                        ExpectedWrite(0xFF13, None),
+                        ExpectedWrite(0xFF14, Some(0x87))
+                    ]
+                ),
+                // A tick that must not have any writes.
+                (3, 65..=65, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let script = parse(
+            "# Bring-up of the new driver\n\
+             \n\
+             tick 1: NR52=80  # APU on\n\
+             \t  \n\
+             # tick 2: NR52=00\n\
+             tick 3: NR12=f0#no space needed\n",
+        )
+        .unwrap();
+        assert_eq!(
+            lines(&script),
+            [
+                (3, 1..=1, vec![ExpectedWrite(0xFF26, Some(0x80))]),
+                (6, 3..=3, vec![ExpectedWrite(0xFF12, Some(0xF0))]),
+            ]
+        );
+        assert_eq!(lines(&parse("").unwrap()), []);
+    }
+
+    #[test]
+    fn malformed_scripts_are_rejected() {
+        let err = |text| parse(text).unwrap_err();
+        assert_eq!(
+            err("\nNR52=80"),
+            "line 2: expected `tick N: ...` or `tick N..M: ...`"
+        );
+        assert_eq!(
+            err("tick 1 NR52=80"),
+            "line 1: expected `tick N: ...` or `tick N..M: ...`"
+        );
+        assert!(err("tick one: NR52=80").starts_with("line 1: bad tick \"one\""));
+        assert_eq!(
+            err("tick 5..4: NR52=80"),
+            "line 1: tick range 5..4 is empty"
+        );
+        assert_eq!(
+            err("tick 1: NR52"),
+            "line 1: \"NR52\" must be of the form REG=VALUE"
+        );
+        assert!(err("tick 1: NR52=100").starts_with("line 1: bad value \"100\""));
+        assert_eq!(
+            err("tick 1: NR99=00"),
+            "line 1: \"NR99\" is neither a register name nor an address"
+        );
+        assert_eq!(
+            err("tick 1..10: NR52=80\n# comment\ntick 4: NR52=00"),
+            "line 3: tick 4 is already described on line 1"
+        );
+    }
+
+    #[test]
+    fn writes_are_checked_per_register() {
+        let script = parse("tick 1: NR12=f0 NR13=* NR12=00\ntick 3..4: NR14=87").unwrap();
+        let write = |tick, cycle, addr, data| IoAccess {
+            when: Timestamp { tick, cycle },
+            pc: Address(1, 0x4000),
+            addr,
+            data,
+        };
+        let log = [
+            // Different registers may be written in any order.
+            write(1, 10, 0xFF13, 0x42),
+            write(1, 20, 0xFF12, 0xF0),
+            write(1, 30, 0xFF12, 0x01),
+            // Tick 2 isn't checked.
+            write(2, 10, 0xFF30, 0x12),
+            write(3, 10, 0xFF14, 0x87),
+            write(3, 20, 0xFF25, 0xFF),
+        ];
+        assert_eq!(
+            check(&script, &log, 10),
+            [
+                Mismatch::OtherValue {
+                    line: 1,
+                    when: Timestamp { tick: 1, cycle: 30 },
+                    pc: Address(1, 0x4000),
+                    reg: 0xFF12,
+                    expected: 0x00,
+                    actual: 0x01,
+                },
+                Mismatch::Unexpected {
+                    line: 2,
+                    when: Timestamp { tick: 3, cycle: 20 },
+                    pc: Address(1, 0x4000),
+                    reg: 0xFF25,
+                    value: 0xFF,
+                },
+                Mismatch::Missing {
+                    tick: 4,
+                    line: 2,
+                    reg: 0xFF14,
+                    value: Some(0x87),
+                },
+            ]
+        );
+        assert_eq!(
+            check(&script, &log[..3], 3).last(),
+            Some(&Mismatch::NotReached { tick: 4, line: 2 })
+        );
+    }
+}
@@ -0,0 +1,248 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with explaining what differences are likely to mean, for `--explain`.
+//!
+//! Value differences are looked up by register class and by which bits differ; the first matching
+//! entry of the table wins, and anything unknown gets a generic explanation.
+
+use gb_cpu_sim::reg::HwReg;
+
+use crate::diff::DiagnosticKind;
+
+/// Groups registers whose bits mean the same thing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RegClass {
+    /// NR10.
+    Sweep,
+    /// NR11 and NR21.
+    DutyAndLength,
+    /// NR31 and NR41.
+    Length,
+    /// NR12, NR22, and NR42.
+    Envelope,
+    /// NR30.
+    WaveDac,
+    /// NR32.
+    WaveLevel,
+    /// NR13, NR23, and NR33.
+    FreqLow,
+    /// NR14, NR24, and NR34.
+    FreqHighAndTrigger,
+    /// NR43.
+    Noise,
+    /// NR44.
+    Trigger,
+    /// NR50.
+    MasterVolume,
+    /// NR51.
+    Panning,
+    /// NR52.
+    Power,
+    WaveRam,
+    /// The MBC's ROM bank registers.
+    RomBank,
+    Other,
+}
+
+impl RegClass {
+    fn of(addr: u16) -> Self {
+        match HwReg::try_from(addr) {
+            Ok(HwReg::Nr10) => Self::Sweep,
+            Ok(HwReg::Nr11 | HwReg::Nr21) => Self::DutyAndLength,
+            Ok(HwReg::Nr31 | HwReg::Nr41) => Self::Length,
+            Ok(HwReg::Nr12 | HwReg::Nr22 | HwReg::Nr42) => Self::Envelope,
+            Ok(HwReg::Nr30) => Self::WaveDac,
+            Ok(HwReg::Nr32) => Self::WaveLevel,
+            Ok(HwReg::Nr13 | HwReg::Nr23 | HwReg::Nr33) => Self::FreqLow,
+            Ok(HwReg::Nr14 | HwReg::Nr24 | HwReg::Nr34) => Self::FreqHighAndTrigger,
+            Ok(HwReg::Nr43) => Self::Noise,
+            Ok(HwReg::Nr44) => Self::Trigger,
+            Ok(HwReg::Nr50) => Self::MasterVolume,
+            Ok(HwReg::Nr51) => Self::Panning,
+            Ok(HwReg::Nr52) => Self::Power,
+            Ok(HwReg::Romb0 | HwReg::Romb1) => Self::RomBank,
+            _ if crate::waves::WAVE_RAM.contains(&addr) => Self::WaveRam,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// What a difference probably means; `key` identifies it, so that each is only given once per song.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    pub key: &'static str,
+    pub text: String,
+}
+
+impl Explanation {
+    fn new(key: &'static str, text: impl Into<String>) -> Self {
+        Self {
+            key,
+            text: text.into(),
+        }
+    }
+}
+
+/// Explanations for value differences: the register class, the bits that must differ (any of
+/// them), the key, and the text.
+const VALUE_TABLE: &[(RegClass, u8, &str, &str)] = &[
+    (RegClass::Sweep, 0x7F, "sweep", "CH1's frequency sweep differs, so the pitch slides differently (or stops sliding); check the instrument's sweep setting."),
+    (RegClass::DutyAndLength, 0xC0, "duty", "The duty cycle differs, changing the pulse wave's timbre (12.5% sounds thin, 50% hollow); usually a different instrument, or a duty effect behaving differently."),
+    (RegClass::DutyAndLength, 0x3F, "length", "The length timer differs; this is only audible if the length is enabled in NRx4, in which case the note cuts off at a different time."),
+    (RegClass::Length, 0xFF, "length", "The length timer differs; this is only audible if the length is enabled in NRx4, in which case the note cuts off at a different time."),
+    (RegClass::Envelope, 0xF0, "volume", "The initial volume differs, so the note is louder or quieter; check volume commands, and the instrument's envelope."),
+    (RegClass::Envelope, 0x0F, "envelope", "The envelope's direction or pace differs, so the note fades (or swells) differently over time; beware that hardware reacts oddly to NRx2 being rewritten without a retrigger."),
+    (RegClass::WaveDac, 0x80, "wave-dac", "CH3's DAC is switched on or off differently, which starts or silences the wave channel; drivers usually do this around wave RAM updates."),
+    (RegClass::WaveLevel, 0x60, "wave-level", "CH3's output level differs (mute, 100%, 50%, or 25%), so the wave channel is louder or quieter."),
+    (RegClass::FreqHighAndTrigger, 0x40, "length-enable", "Whether the length timer is enabled differs, so the note either cuts off by itself, or keeps playing until the driver stops it."),
+    (RegClass::Trigger, 0x40, "length-enable", "Whether the length timer is enabled differs, so the note either cuts off by itself, or keeps playing until the driver stops it."),
+    (RegClass::Noise, 0x08, "lfsr-width", "The noise switches between 15-bit mode (hiss) and 7-bit mode (metallic, almost tonal); usually a different drum or noise instrument."),
+    (RegClass::Noise, 0xF7, "noise-freq", "The noise frequency differs, so the noise sounds higher or lower; usually a different drum, or a noise pitch effect."),
+    (RegClass::MasterVolume, 0xFF, "master-volume", "The master volume differs, so everything is louder or quieter in one or both speakers; check fade-ins and fade-outs."),
+    (RegClass::Panning, 0xFF, "panning", "The panning differs, so some channels are heard from different speakers (or muted); check panning commands, and channel muting by sound effects."),
+    (RegClass::Power, 0x80, "power", "The APU is turned on or off differently, which resets all sound registers."),
+    (RegClass::WaveRam, 0xFF, "waveform", "CH3's waveform differs, changing its timbre; either a different wave is loaded, or it's loaded while CH3 plays, which is unreliable on hardware."),
+    (RegClass::RomBank, 0xFF, "bank", "A different ROM bank is mapped: probably code or data moved to a different bank, which is fine unless the bank doesn't contain what the driver then reads."),
+];
+
+/// The register holding the other half of the period, if `reg` holds one half of it.
+pub fn period_partner(kind: &DiagnosticKind) -> Option<u16> {
+    match kind {
+        DiagnosticKind::OtherValue(reg, ..) => match RegClass::of(*reg) {
+            RegClass::FreqLow => Some(reg + 1),
+            RegClass::FreqHighAndTrigger => Some(reg - 1),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// How many semitones higher the second period sounds than the first.
+fn semitones(before: u16, after: u16) -> f64 {
+    // The frequency is proportional to `1 / (2048 - period)`.
+    12.0 * (f64::from(2048 - before) / f64::from(2048 - after)).log2()
+}
+
+/// Explains a period difference; `partner` is the value of the register holding the period's
+/// other half, if known, so that the difference can be converted to semitones.
+fn explain_period(reg: u16, before: u8, after: u8, partner: Option<u8>) -> Explanation {
+    let low = RegClass::of(reg) == RegClass::FreqLow;
+    let period = |value: u8, partner: u8| {
+        let (low, high) = if low {
+            (value, partner)
+        } else {
+            (partner, value)
+        };
+        u16::from(high & 7) << 8 | u16::from(low)
+    };
+    let steps = match partner {
+        Some(partner) => i32::from(period(after, partner)) - i32::from(period(before, partner)),
+        None if low => i32::from(after) - i32::from(before),
+        None => (i32::from(after & 7) - i32::from(before & 7)) * 256,
+    };
+
+    let text = match partner {
+        Some(partner) => {
+            let semitones = semitones(period(before, partner), period(after, partner));
+            format!(
+                "The pitch is about {:.1} semitones {} ({} period steps): {}.",
+                semitones.abs(),
+                if semitones < 0.0 { "lower" } else { "higher" },
+                steps.abs(),
+                if semitones.abs() < 0.5 {
+                    "that is a detune, e.g. vibrato, a pitch slide, or a fine-tuning setting"
+                } else {
+                    "that is a different note, e.g. a transposition, an arpeggio, or a wrong note in the song data"
+                },
+            )
+        }
+        None => format!(
+            "The period differs by {} steps; small differences are usually a detune (vibrato, pitch slides), large ones a different note.",
+            steps.abs()
+        ),
+    };
+    Explanation::new("frequency", text)
+}
+
+fn explain_value(reg: u16, before: u8, after: u8, partner: Option<u8>) -> Explanation {
+    let class = RegClass::of(reg);
+    let differing = before ^ after;
+    if matches!(class, RegClass::FreqLow)
+        || matches!(class, RegClass::FreqHighAndTrigger) && differing & 0x07 != 0
+    {
+        return explain_period(reg, before, after, partner);
+    }
+
+    VALUE_TABLE
+        .iter()
+        .find(|(entry_class, mask, ..)| *entry_class == class && differing & mask != 0)
+        .map_or_else(
+            || {
+                Explanation::new(
+                    "value",
+                    format!(
+                        "A different value is written (bits ${:02x} differ); the driver probably computed it from different song data or state.",
+                        differing
+                    ),
+                )
+            },
+            |(_, _, key, text)| Explanation::new(key, *text),
+        )
+}
+
+/// `partner` is the value of the [`period_partner`] register, if any, in the "before" file.
+pub fn explain(kind: &DiagnosticKind, partner: Option<u8>) -> Explanation {
+    match kind {
+        DiagnosticKind::OtherValue(reg, before, after) => {
+            explain_value(*reg, *before, *after, partner)
+        }
+        DiagnosticKind::TriggerChanged(_, true, _) => Explanation::new(
+            "retrigger-added",
+            "The channel is now restarted here, which resets its envelope and length, and is heard as a new attack (or a click); check note and instrument commands.",
+        ),
+        DiagnosticKind::TriggerChanged(_, false, _) => Explanation::new(
+            "retrigger-removed",
+            "The channel is no longer restarted here, so the previous note keeps going instead of a new one starting (a tie or a glide); check note and instrument commands.",
+        ),
+        DiagnosticKind::Moved(..) => Explanation::new(
+            "moved",
+            "The same write happens at a different time within the tick, usually because the code before it got faster or slower; it's rarely audible, unless it moves between the two halves of a register pair.",
+        ),
+        DiagnosticKind::DivResetMoved(..) => Explanation::new(
+            "div-reset-moved",
+            "Writing to DIV resets the timer's phase, so moving that write shifts every timer-driven event after it; writes later in the tick that moved by the same amount are only noted.",
+        ),
+        DiagnosticKind::SlippedTick(..) => Explanation::new(
+            "slipped-tick",
+            "The same write happens on the other side of a tick boundary, usually because the tick ran slightly over its budget (or no longer does); it's late by a tick's worth of time, which is rarely audible, but the tick may be close to overrunning.",
+        ),
+        DiagnosticKind::Removed(..) | DiagnosticKind::Added(..) => Explanation::new(
+            "missing",
+            "A write happens in only one of the files: the driver probably took a different code path, because of different song data, or an effect that started or stopped.",
+        ),
+        DiagnosticKind::OtherReg(..) => Explanation::new(
+            "other-reg",
+            "The same value is written to another register, most likely that of another channel; check the driver's channel pointers and offsets.",
+        ),
+        DiagnosticKind::FreqChanged { .. } => Explanation::new(
+            "freq-changed",
+            "The channel plays another pitch: a pitch slide, vibrato, or transposition is computed differently, or the note itself changed; check pitch effects and the period table.",
+        ),
+        DiagnosticKind::FreqOrderSwapped { trigger: false, .. } => Explanation::new(
+            "freq-order-swapped",
+            "The two halves of the period are written the other way around, which is inaudible since the channel keeps playing in between.",
+        ),
+        DiagnosticKind::FreqOrderSwapped { trigger: true, .. } => Explanation::new(
+            "freq-order-swapped-trigger",
+            "The two halves of the period are written the other way around, so the channel is (or no longer is) restarted with the old low byte for a few cycles; this is rarely audible, but can click.",
+        ),
+        DiagnosticKind::ChannelReallocation { .. } => Explanation::new(
+            "reallocation",
+            "The same notes are played by another channel; the driver allocates channels differently, e.g. because a sound effect or a priority setting changed.",
+        ),
+    }
+}
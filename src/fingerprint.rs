@@ -0,0 +1,158 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with fingerprinting each song's differences, so that songs broken by the same
+//! bug can be spotted without reading all of their listings.
+//!
+//! Only what was written where goes into a fingerprint, never when: a driver bug that drops a
+//! write drops it whatever the song's tempo, so the same regression in two songs must hash the
+//! same even though their timings differ.
+
+use std::fmt::Display;
+
+use crate::{diff::DiagnosticKind, SongIDs};
+
+/// A short digest of a song's differences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint(u32);
+
+impl Display for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:06x}", self.0)
+    }
+}
+
+/// Which of a difference's fields identify it: its kind, its register, and the values involved
+/// on either side, if any. Cycle offsets, ticks, and note counts are left out.
+fn canonical(kind: &DiagnosticKind) -> (&'static str, u16, Option<u16>, Option<u16>) {
+    let (before, after) = match *kind {
+        DiagnosticKind::Removed(_, value) => (Some(value.into()), None),
+        DiagnosticKind::Added(_, value) => (None, Some(value.into())),
+        DiagnosticKind::Moved(_, value, _)
+        | DiagnosticKind::TriggerChanged(_, _, value)
+        | DiagnosticKind::SlippedTick(_, value, ..) => (Some(value.into()), Some(value.into())),
+        DiagnosticKind::OtherValue(_, before, after) => (Some(before.into()), Some(after.into())),
+        // The value is the same on both sides, but the register isn't.
+        DiagnosticKind::OtherReg(before, value, _) => (Some(before), Some(value.into())),
+        DiagnosticKind::DivResetMoved(_) => (None, None),
+        DiagnosticKind::FreqChanged { before, after, .. } => (Some(before), Some(after)),
+        DiagnosticKind::FreqOrderSwapped {
+            high_first,
+            trigger,
+            ..
+        } => (Some(high_first.into()), Some(trigger.into())),
+        DiagnosticKind::ChannelReallocation { from, to, .. } => {
+            (Some(from.into()), Some(to.into()))
+        }
+    };
+    (kind.name(), kind.reg(), before, after)
+}
+
+/// Accumulates a song's differences, in the order they are reported.
+#[derive(Debug, Default)]
+pub struct Fingerprinter(Vec<u8>);
+
+impl Fingerprinter {
+    pub fn add(&mut self, kind: &DiagnosticKind) {
+        let (name, reg, before, after) = canonical(kind);
+        self.0.extend(name.as_bytes());
+        // Names never contain NUL, so this can't be mistaken for part of one.
+        self.0.push(0);
+        self.0.extend(reg.to_le_bytes());
+        for value in [before, after] {
+            match value {
+                Some(value) => {
+                    self.0.push(1);
+                    self.0.extend(value.to_le_bytes());
+                }
+                None => self.0.push(0),
+            }
+        }
+    }
+
+    /// `None` if nothing was added.
+    pub fn finish(&self) -> Option<Fingerprint> {
+        (!self.0.is_empty()).then(|| Fingerprint((crate::fnv1a(&self.0) >> 40) as u32))
+    }
+}
+
+/// The fingerprints shared by several songs, each with those songs, in order of first appearance.
+pub fn shared(fingerprints: &[(SongIDs, Fingerprint)]) -> Vec<(Fingerprint, Vec<SongIDs>)> {
+    let mut groups: Vec<(Fingerprint, Vec<SongIDs>)> = Vec::new();
+    for &(songs, fingerprint) in fingerprints {
+        match groups.iter_mut().find(|(other, _)| *other == fingerprint) {
+            Some((_, group)) => group.push(songs),
+            None => groups.push((fingerprint, vec![songs])),
+        }
+    }
+    groups.retain(|(_, songs)| songs.len() > 1);
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprint(kinds: &[DiagnosticKind]) -> Option<Fingerprint> {
+        let mut fingerprinter = Fingerprinter::default();
+        for kind in kinds {
+            fingerprinter.add(kind);
+        }
+        fingerprinter.finish()
+    }
+
+    #[test]
+    fn only_what_was_written_where_counts() {
+        use DiagnosticKind::*;
+
+        let base = fingerprint(&[Moved(0xFF12, 0xF0, 3), Removed(0xFF14, 0x87)]).unwrap();
+        assert_eq!(base.to_string().len(), 6);
+        // Timings don't matter...
+        assert_eq!(
+            fingerprint(&[Moved(0xFF12, 0xF0, -120), Removed(0xFF14, 0x87)]),
+            Some(base)
+        );
+        // ...but values, registers, kinds, and order do.
+        for kinds in [
+            [Moved(0xFF12, 0xF1, 3), Removed(0xFF14, 0x87)],
+            [Moved(0xFF17, 0xF0, 3), Removed(0xFF14, 0x87)],
+            [Moved(0xFF12, 0xF0, 3), Added(0xFF14, 0x87)],
+            [Removed(0xFF14, 0x87), Moved(0xFF12, 0xF0, 3)],
+        ] {
+            assert_ne!(fingerprint(&kinds), Some(base), "{:?}", kinds);
+        }
+        // Nor does a value being absent look like it being 0.
+        assert_ne!(
+            fingerprint(&[Removed(0xFF14, 0x00)]),
+            fingerprint(&[Added(0xFF14, 0x00)])
+        );
+        assert_eq!(fingerprint(&[]), None);
+    }
+
+    #[test]
+    fn shared_fingerprints_are_grouped() {
+        let (a, b, c) = (Fingerprint(0xA), Fingerprint(0xB), Fingerprint(0xC));
+        let groups = |fingerprints: &[(u8, Fingerprint)]| -> Vec<_> {
+            let fingerprints: Vec<_> = fingerprints
+                .iter()
+                .map(|&(id, fingerprint)| (SongIDs::Both(id, id), fingerprint))
+                .collect();
+            shared(&fingerprints)
+                .into_iter()
+                .map(|(fingerprint, songs)| {
+                    let songs: Vec<_> = songs.iter().map(ToString::to_string).collect();
+                    (fingerprint, songs.join(","))
+                })
+                .collect()
+        };
+        assert_eq!(
+            groups(&[(1, b), (2, a), (3, c), (4, a), (5, b), (6, a)]),
+            [(b, "1,5".into()), (a, "2,4,6".into())]
+        );
+        assert_eq!(groups(&[(1, a), (2, b)]), []);
+        assert_eq!(Fingerprint(0xABC).to_string(), "000abc");
+    }
+}
@@ -0,0 +1,71 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with telling apart differences caused directly by the code being focused on,
+//! from those that are likely knock-on effects.
+
+use crate::Address;
+
+/// An inclusive range of (bank, address) code locations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PcRange {
+    start: (u8, u16),
+    end: (u8, u16),
+}
+
+impl PcRange {
+    fn contains(&self, pc: (u8, u16)) -> bool {
+        (self.start..=self.end).contains(&pc)
+    }
+}
+
+/// Parses `[BB:]AAAA-[BB:]AAAA`, all hex numbers; a single location is also accepted.
+pub fn parse_pc_range(arg: &str) -> Result<PcRange, String> {
+    let parse_location = |location: &str| -> Result<(u8, u16), String> {
+        let (bank, addr) = match location.trim().split_once(':') {
+            Some((bank, addr)) => (
+                u8::from_str_radix(bank.trim(), 16)
+                    .map_err(|err| format!("invalid bank: {}", err))?,
+                addr,
+            ),
+            None => (0, location),
+        };
+        let addr = u16::from_str_radix(addr.trim(), 16)
+            .map_err(|err| format!("invalid address: {}", err))?;
+        Ok(Address(bank, addr).canonical())
+    };
+
+    let (start, end) = match arg.split_once('-') {
+        Some((start, end)) => (parse_location(start)?, parse_location(end)?),
+        None => {
+            let location = parse_location(arg)?;
+            (location, location)
+        }
+    };
+    if start > end {
+        return Err("the start of the range is after its end".to_string());
+    }
+    Ok(PcRange { start, end })
+}
+
+/// Which code locations are being focused on.
+#[derive(Debug, Clone, Default)]
+pub struct Focus {
+    pub banks: Vec<u8>,
+    pub ranges: Vec<PcRange>,
+}
+
+impl Focus {
+    pub fn is_active(&self) -> bool {
+        !self.banks.is_empty() || !self.ranges.is_empty()
+    }
+
+    /// Whether a difference at this PC is a direct consequence of the focused code.
+    pub fn is_direct(&self, pc: &Address) -> bool {
+        let pc = pc.canonical();
+        self.banks.contains(&pc.0) || self.ranges.iter().any(|range| range.contains(pc))
+    }
+}
@@ -6,17 +6,28 @@
 
 //! This module deals with parsing GBS files.
 
-use std::hint::unreachable_unchecked;
+use std::{hint::unreachable_unchecked, ops::RangeInclusive};
 
 use parse_display::Display;
 
 #[derive(Debug)]
-pub struct Gbs<'gbs>(&'gbs [u8]);
+pub struct Gbs<'gbs> {
+    data: &'gbs [u8],
+    /// Overrides the header's PLAY rate if not empty (`--tick-pattern`).
+    tick_pattern: &'gbs [u32],
+    /// Override the header's INIT and PLAY addresses, respectively (`--init-addr`, `--play-addr`).
+    entry_overrides: [Option<u16>; 2],
+}
 
 impl<'gbs> Gbs<'gbs> {
     const HEADER_LEN: usize = 0x70;
+    /// The only version whose layout is fully understood.
+    pub const KNOWN_VERSION: u8 = 1;
     /// The driver should never access this area.
     pub const MIN_ROM_ADDR: u16 = 0x400;
+    /// INIT and PLAY may point to SRAM or WRAM, if INIT copies code there beforehand; real
+    /// players accept that, even though the spec says they "should" lie within ROM.
+    const RAM_ENTRY_AREA: std::ops::Range<u16> = 0xA000..0xE000;
 
     pub fn new(data: &'gbs [u8]) -> Result<Self, FormatError<'gbs>> {
         if data.len() < Self::HEADER_LEN {
@@ -28,45 +39,107 @@ impl<'gbs> Gbs<'gbs> {
             return Err(FormatError::BadMagic(magic));
         }
 
-        let version = data[3];
-        if version != 1 {
-            return Err(FormatError::UnsupportedVersion(version));
+        // Other versions are assumed to share the fixed-offset fields, possibly with extensions
+        // after the header; but if those fields don't make sense, the layout is truly unknown.
+        let gbs = Self {
+            data,
+            tick_pattern: &[],
+            entry_overrides: [None; 2],
+        };
+        match gbs.validate() {
+            Ok(()) => Ok(gbs),
+            Err(err) if gbs.version() != Self::KNOWN_VERSION => Err(
+                FormatError::UnsupportedVersion(gbs.version(), Box::new(err)),
+            ),
+            Err(err) => Err(err),
         }
+    }
 
-        let gbs = Self(data);
-        if gbs.nb_songs() == 0 {
+    fn validate(&self) -> Result<(), FormatError<'gbs>> {
+        if self.nb_songs() == 0 {
             return Err(FormatError::ZeroSongs);
         }
+        if self.last_song_id() > u16::from(u8::MAX) {
+            return Err(FormatError::SongIdOverflow(
+                self.first_song(),
+                self.last_song_id(),
+            ));
+        }
 
-        let load_addr = gbs.addr(AddressKind::Load);
-        if !(Self::MIN_ROM_ADDR..=0x4000).contains(&load_addr) {
+        // Loading at $4000 or later would leave the fixed bank empty.
+        let load_addr = self.addr(AddressKind::Load);
+        if !(Self::MIN_ROM_ADDR..0x4000).contains(&load_addr) {
             return Err(FormatError::BadAddress(AddressKind::Load, load_addr));
         }
         for kind in [AddressKind::Init, AddressKind::Play] {
-            let addr = gbs.addr(kind);
-            if !(load_addr..0x8000).contains(&addr) {
-                return Err(FormatError::BadAddress(kind, addr));
-            }
+            self.check_entry(kind, self.addr(kind))?;
         }
 
-        Ok(gbs)
+        Ok(())
+    }
+
+    /// Requires the load address to have been checked already.
+    fn check_entry(&self, kind: AddressKind, addr: u16) -> Result<(), FormatError<'gbs>> {
+        if Self::RAM_ENTRY_AREA.contains(&addr) {
+            return Ok(());
+        }
+        let load_addr = self.addr(AddressKind::Load);
+        if !(load_addr..0x8000).contains(&addr) {
+            return Err(FormatError::BadAddress(kind, addr));
+        }
+        if usize::from(addr - load_addr) >= self.rom().len() {
+            return Err(FormatError::EntryOutOfFile(kind, addr, self.rom().len()));
+        }
+        Ok(())
+    }
+
+    /// Makes INIT or PLAY start at this address instead of the header's, which is checked the
+    /// same way.
+    pub fn with_entry(mut self, kind: AddressKind, addr: u16) -> Result<Self, FormatError<'gbs>> {
+        self.check_entry(kind, addr)?;
+        self.entry_overrides[kind.override_index()] = Some(addr);
+        Ok(self)
     }
 
     fn read16(&self, ofs: usize) -> u16 {
-        let raw = [self.0[ofs], self.0[ofs + 1]];
+        let raw = [self.data[ofs], self.data[ofs + 1]];
         u16::from_le_bytes(raw)
     }
 
+    pub fn version(&self) -> u8 {
+        self.data[3]
+    }
+
     pub fn nb_songs(&self) -> u8 {
-        self.0[4]
+        self.data[4]
     }
 
     pub fn first_song(&self) -> u8 {
-        self.0[5]
+        self.data[5]
+    }
+
+    /// Computed wide, since the header may not be valid yet.
+    fn last_song_id(&self) -> u16 {
+        u16::from(self.first_song()) + u16::from(self.nb_songs()) - 1
+    }
+
+    /// The IDs of all of the file's songs.
+    pub fn songs(&self) -> RangeInclusive<u8> {
+        // Validation ensures that this fits.
+        self.first_song()..=self.last_song_id() as u8
     }
 
     pub fn addr(&self, kind: AddressKind) -> u16 {
-        self.read16(kind.ofs())
+        match kind {
+            AddressKind::Load => None,
+            _ => self.entry_overrides[kind.override_index()],
+        }
+        .unwrap_or_else(|| self.read16(kind.ofs()))
+    }
+
+    /// Whether that (INIT or PLAY) address points to RAM rather than to the file's contents.
+    pub fn entry_in_ram(&self, kind: AddressKind) -> bool {
+        Self::RAM_ENTRY_AREA.contains(&self.addr(kind))
     }
 
     pub fn stack_ptr(&self) -> u16 {
@@ -74,7 +147,7 @@ impl<'gbs> Gbs<'gbs> {
     }
 
     pub fn timer_mod(&self) -> u8 {
-        self.0[14]
+        self.data[14]
     }
 
     pub fn timer_div_bit(&self) -> u8 {
@@ -87,6 +160,84 @@ impl<'gbs> Gbs<'gbs> {
         }
     }
 
+    /// Makes PLAY calls last these many cycles in turn, instead of what the header says.
+    pub fn with_tick_pattern(self, tick_pattern: &'gbs [u32]) -> Self {
+        Self {
+            tick_pattern,
+            ..self
+        }
+    }
+
+    /// The file's raw contents, header included.
+    pub fn data(&self) -> &'gbs [u8] {
+        self.data
+    }
+
+    pub fn tick_pattern(&self) -> &'gbs [u32] {
+        self.tick_pattern
+    }
+
+    /// How many cycles each PLAY call lasts.
+    pub fn cadence(&self) -> Cadence {
+        if self.tick_pattern.is_empty() {
+            Cadence(vec![self.header_cycles_per_tick()])
+        } else {
+            Cadence(self.tick_pattern.to_vec())
+        }
+    }
+
+    /// How many cycles the song has to spend in each PLAY call, on average if the ticks are of
+    /// varying lengths.
+    pub fn cycles_per_tick(&self) -> u32 {
+        self.cadence().average()
+    }
+
+    fn header_cycles_per_tick(&self) -> u32 {
+        if self.use_timer() {
+            // Up to 512 cycles per TIMA increment, times 256 increments.
+            (1u32 << self.timer_div_bit()) * (256 - u32::from(self.timer_mod()))
+        } else {
+            114 * 154 // 114 cycles/scanline times 154 scanlines
+        }
+    }
+
+    /// Whether both files call PLAY at the same rate, so that their tick numbers are comparable.
+    pub fn same_timing(&self, other: &Self) -> bool {
+        if !self.tick_pattern.is_empty() || !other.tick_pattern.is_empty() {
+            return self.tick_pattern == other.tick_pattern;
+        }
+        self.use_timer() == other.use_timer()
+            && self.double_speed() == other.double_speed()
+            && (!self.use_timer()
+                || (self.timer_mod() == other.timer_mod()
+                    && self.timer_div_bit() == other.timer_div_bit()))
+    }
+
+    /// A human-readable description of what drives the PLAY calls.
+    pub fn timing_description(&self) -> String {
+        let mut description = if self.use_timer() {
+            format!(
+                "timer-driven (TMA = ${:02x}, clock = CPU / {})",
+                self.timer_mod(),
+                1u16 << self.timer_div_bit()
+            )
+        } else {
+            "VBlank-driven".to_string()
+        };
+        if self.double_speed() {
+            description.push_str(", double speed");
+        }
+        if !self.tick_pattern.is_empty() {
+            let pattern: Vec<_> = self.tick_pattern.iter().map(u32::to_string).collect();
+            description = format!(
+                "ticks of {} cycles (overriding: {})",
+                pattern.join(", "),
+                description
+            );
+        }
+        description
+    }
+
     pub fn use_timer(&self) -> bool {
         self.timer_ctrl() & 4 != 0
     }
@@ -96,11 +247,44 @@ impl<'gbs> Gbs<'gbs> {
     }
 
     fn timer_ctrl(&self) -> u8 {
-        self.0[15]
+        self.data[15]
     }
 
     pub fn rom(&self) -> &[u8] {
-        &self.0[0x70..]
+        &self.data[0x70..]
+    }
+}
+
+/// How many cycles each tick lasts; the pattern repeats from tick 1 on, and INIT (tick 0) is
+/// given its first entry.
+#[derive(Debug, Clone)]
+pub struct Cadence(Vec<u32>);
+
+impl Cadence {
+    pub fn tick_cycles(&self, tick: u64) -> u32 {
+        self.0[(tick.saturating_sub(1) % self.0.len() as u64) as usize]
+    }
+
+    /// How many cycles elapsed between the start of INIT and the start of that tick.
+    pub fn cycles_before(&self, tick: u64) -> u64 {
+        let Some(nb_play_ticks) = tick.checked_sub(1) else {
+            return 0;
+        };
+        let len = self.0.len() as u64;
+        let period: u64 = self.0.iter().copied().map(u64::from).sum();
+        let partial: u64 = self.0[..(nb_play_ticks % len) as usize]
+            .iter()
+            .copied()
+            .map(u64::from)
+            .sum();
+        u64::from(self.tick_cycles(0)) + nb_play_ticks / len * period + partial
+    }
+
+    /// Rounded to the nearest cycle.
+    pub fn average(&self) -> u32 {
+        let len = self.0.len() as u64;
+        let period: u64 = self.0.iter().copied().map(u64::from).sum();
+        ((period + len / 2) / len) as u32
     }
 }
 
@@ -110,15 +294,20 @@ pub enum FormatError<'a> {
     TruncatedHeader(usize),
     #[display("expected \"GBS\" magic, got \"{0:?}\"")]
     BadMagic(&'a [u8]),
-    #[display("unsupported version {0}")]
-    UnsupportedVersion(u8),
+    /// The header's fields make no sense, which may be due to the version's layout being different.
+    #[display("unsupported version {0} ({1})")]
+    UnsupportedVersion(u8, Box<FormatError<'a>>),
     #[display("zero songs specified")]
     ZeroSongs,
+    #[display("songs {0} to {1} go past song 255")]
+    SongIdOverflow(u8, u16),
     #[display("bad {0} address ${1:04x}")]
     BadAddress(AddressKind, u16),
+    #[display("{0} address ${1:04x} lies past the end of the file, which only contains {2} bytes of code and data")]
+    EntryOutOfFile(AddressKind, u16, usize),
 }
 
-#[derive(Debug, Display, Clone, Copy)]
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
 #[display(style = "lowercase")]
 pub enum AddressKind {
     Load,
@@ -134,4 +323,386 @@ impl AddressKind {
             Self::Play => 10,
         }
     }
+
+    fn override_index(&self) -> usize {
+        match self {
+            Self::Init => 0,
+            Self::Play => 1,
+            Self::Load => panic!("the load address cannot be overridden"),
+        }
+    }
+}
+
+/// A few instructions, for assembling INIT and PLAY routines without an assembler.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Code(Vec<u8>);
+
+impl Code {
+    /// Bytes that aren't covered by the helpers below.
+    pub fn raw(mut self, bytes: &[u8]) -> Self {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+
+    /// `ld a, value`
+    pub fn ld_a(self, value: u8) -> Self {
+        self.raw(&[0x3E, value])
+    }
+
+    /// `ldh [reg], a`; `reg` must lie within $FF00-$FFFF.
+    pub fn ldh_to(self, reg: u16) -> Self {
+        debug_assert!(reg >= 0xFF00, "ldh can't reach ${:04x}", reg);
+        self.raw(&[0xE0, reg as u8])
+    }
+
+    /// `ld a, value; ldh [reg], a`
+    pub fn write(self, reg: u16, value: u8) -> Self {
+        self.ld_a(value).ldh_to(reg)
+    }
+
+    /// Appends another piece of code.
+    #[cfg(test)]
+    pub fn then(self, code: Code) -> Self {
+        self.raw(&code.0)
+    }
+
+    /// `ret`
+    pub fn ret(self) -> Self {
+        self.raw(&[0xC9])
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// Assembles a valid GBS file in memory: INIT is placed at the load address, PLAY right after it,
+/// and then any extra data.
+#[derive(Debug, Clone)]
+pub(crate) struct GbsBuilder {
+    nb_songs: u8,
+    first_song: u8,
+    load_addr: u16,
+    stack_ptr: u16,
+    timer_mod: u8,
+    timer_ctrl: u8,
+    init: Code,
+    play: Code,
+    extra: Vec<u8>,
+}
+
+impl Default for GbsBuilder {
+    /// A single VBlank-driven song whose INIT and PLAY immediately return.
+    fn default() -> Self {
+        Self {
+            nb_songs: 1,
+            first_song: 1,
+            load_addr: Gbs::MIN_ROM_ADDR,
+            stack_ptr: 0xFFFE,
+            timer_mod: 0,
+            timer_ctrl: 0,
+            init: Code::default().ret(),
+            play: Code::default().ret(),
+            extra: Vec::new(),
+        }
+    }
+}
+
+impl GbsBuilder {
+    pub fn init(mut self, code: Code) -> Self {
+        self.init = code;
+        self
+    }
+
+    pub fn play(mut self, code: Code) -> Self {
+        self.play = code;
+        self
+    }
+
+    fn play_addr(&self) -> u16 {
+        self.load_addr + self.init.len() as u16
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut data = b"GBS".to_vec();
+        data.extend_from_slice(&[Gbs::KNOWN_VERSION, self.nb_songs, self.first_song]);
+        for addr in [
+            self.load_addr,
+            self.load_addr,
+            self.play_addr(),
+            self.stack_ptr,
+        ] {
+            data.extend_from_slice(&addr.to_le_bytes());
+        }
+        data.extend_from_slice(&[self.timer_mod, self.timer_ctrl]);
+        data.resize(Gbs::HEADER_LEN, 0); // No metadata.
+        data.extend_from_slice(&self.init.0);
+        data.extend_from_slice(&self.play.0);
+        data.extend_from_slice(&self.extra);
+        data
+    }
+}
+
+/// Settings that only the unit tests need.
+#[cfg(test)]
+impl GbsBuilder {
+    pub fn songs(mut self, nb_songs: u8, first_song: u8) -> Self {
+        self.nb_songs = nb_songs;
+        self.first_song = first_song;
+        self
+    }
+
+    pub fn load_addr(mut self, load_addr: u16) -> Self {
+        self.load_addr = load_addr;
+        self
+    }
+
+    pub fn stack_ptr(mut self, stack_ptr: u16) -> Self {
+        self.stack_ptr = stack_ptr;
+        self
+    }
+
+    /// The raw TMA and TAC values; TAC's bit 2 enables the timer, and bit 7 double speed.
+    pub fn timer(mut self, timer_mod: u8, timer_ctrl: u8) -> Self {
+        self.timer_mod = timer_mod;
+        self.timer_ctrl = timer_ctrl;
+        self
+    }
+
+    /// Data placed after PLAY, e.g. song tables.
+    pub fn extra(mut self, data: &[u8]) -> Self {
+        self.extra.extend_from_slice(data);
+        self
+    }
+
+    /// Where the extra data will be loaded.
+    pub fn extra_addr(&self) -> u16 {
+        self.play_addr() + self.play.len() as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_builder_is_a_vblank_song() {
+        let data = GbsBuilder::default().build();
+        let gbs = Gbs::new(&data).unwrap();
+        assert_eq!(gbs.version(), Gbs::KNOWN_VERSION);
+        assert_eq!((gbs.nb_songs(), gbs.first_song()), (1, 1));
+        assert_eq!(gbs.addr(AddressKind::Load), Gbs::MIN_ROM_ADDR);
+        assert_eq!(gbs.addr(AddressKind::Init), Gbs::MIN_ROM_ADDR);
+        assert_eq!(gbs.addr(AddressKind::Play), Gbs::MIN_ROM_ADDR + 1);
+        assert_eq!(gbs.stack_ptr(), 0xFFFE);
+        assert!(!gbs.use_timer() && !gbs.double_speed());
+        // Both routines only `ret`.
+        assert_eq!(gbs.rom(), [0xC9, 0xC9]);
+    }
+
+    #[test]
+    fn builder_settings_end_up_in_the_header() {
+        let data = GbsBuilder::default()
+            .songs(3, 2)
+            .load_addr(0x1000)
+            .stack_ptr(0xDFFF)
+            .timer(0xC0, 0x86)
+            .build();
+        let gbs = Gbs::new(&data).unwrap();
+        assert_eq!((gbs.nb_songs(), gbs.first_song()), (3, 2));
+        assert_eq!(gbs.addr(AddressKind::Load), 0x1000);
+        assert_eq!(gbs.addr(AddressKind::Init), 0x1000);
+        assert_eq!(gbs.stack_ptr(), 0xDFFF);
+        assert_eq!(gbs.timer_mod(), 0xC0);
+        assert_eq!(gbs.timer_div_bit(), 5);
+        assert!(gbs.use_timer() && gbs.double_speed());
+    }
+
+    #[test]
+    fn builder_lays_code_and_data_out() {
+        let builder = GbsBuilder::default()
+            .init(Code::default().write(0xFF26, 0x80).ret())
+            .play(Code::default().ld_a(0x12).ldh_to(0xFF13).ret())
+            .extra(&[0xAB, 0xCD]);
+        let data = builder.build();
+        let gbs = Gbs::new(&data).unwrap();
+        assert_eq!(
+            gbs.rom(),
+            [0x3E, 0x80, 0xE0, 0x26, 0xC9, 0x3E, 0x12, 0xE0, 0x13, 0xC9, 0xAB, 0xCD]
+        );
+        assert_eq!(gbs.addr(AddressKind::Play), Gbs::MIN_ROM_ADDR + 5);
+        assert_eq!(builder.extra_addr(), Gbs::MIN_ROM_ADDR + 10);
+        let extra_ofs = usize::from(builder.extra_addr() - Gbs::MIN_ROM_ADDR);
+        assert_eq!(gbs.rom()[extra_ofs..], [0xAB, 0xCD]);
+    }
+
+    /// A GBS file whose INIT and PLAY addresses were overwritten, with $400-$40F as its contents.
+    fn with_entries(init: u16, play: u16) -> Vec<u8> {
+        let mut data = GbsBuilder::default().extra(&[0; 14]).build();
+        data[AddressKind::Init.ofs()..][..2].copy_from_slice(&init.to_le_bytes());
+        data[AddressKind::Play.ofs()..][..2].copy_from_slice(&play.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn entries_must_lie_within_the_file() {
+        let data = with_entries(0x400, 0x40F);
+        assert!(Gbs::new(&data).is_ok());
+
+        let data = with_entries(0x410, 0x400);
+        let err = Gbs::new(&data).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                FormatError::EntryOutOfFile(AddressKind::Init, 0x410, 16)
+            ),
+            "{:?}",
+            err
+        );
+        assert_eq!(
+            err.to_string(),
+            "init address $0410 lies past the end of the file, which only contains 16 bytes of code and data"
+        );
+
+        let data = with_entries(0x400, 0x7FFF);
+        let err = Gbs::new(&data).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                FormatError::EntryOutOfFile(AddressKind::Play, 0x7FFF, 16)
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn entries_may_lie_in_ram() {
+        let data = with_entries(0xC000, 0xA000);
+        let gbs = Gbs::new(&data).unwrap();
+        assert!(gbs.entry_in_ram(AddressKind::Init) && gbs.entry_in_ram(AddressKind::Play));
+        let data = with_entries(0xDFFF, 0x400);
+        assert!(Gbs::new(&data).is_ok());
+    }
+
+    #[test]
+    fn entries_outside_rom_and_ram_are_bad() {
+        for (addr, kind) in [
+            (0x3FF, AddressKind::Init),
+            (0x8000, AddressKind::Init),
+            (0xE000, AddressKind::Init),
+            (0xFF80, AddressKind::Init),
+        ] {
+            let data = with_entries(addr, 0x400);
+            let err = Gbs::new(&data).unwrap_err();
+            assert!(
+                matches!(err, FormatError::BadAddress(bad_kind, bad_addr) if bad_kind == kind && bad_addr == addr),
+                "${:04x}: {:?}",
+                addr,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn overridden_entries_are_checked() {
+        let data = with_entries(0x400, 0x400);
+        let gbs = || Gbs::new(&data).unwrap();
+        let gbs_at = gbs().with_entry(AddressKind::Play, 0x40F).unwrap();
+        assert_eq!(gbs_at.addr(AddressKind::Play), 0x40F);
+        assert_eq!(gbs_at.addr(AddressKind::Init), 0x400);
+        assert!(matches!(
+            gbs().with_entry(AddressKind::Play, 0x410),
+            Err(FormatError::EntryOutOfFile(AddressKind::Play, 0x410, 16))
+        ));
+        assert!(matches!(
+            gbs().with_entry(AddressKind::Init, 0x8000),
+            Err(FormatError::BadAddress(AddressKind::Init, 0x8000))
+        ));
+    }
+
+    #[test]
+    fn timer_ticks_can_exceed_u16() {
+        let cycles_per_tick = |timer_mod, timer_ctrl| {
+            let data = GbsBuilder::default().timer(timer_mod, timer_ctrl).build();
+            Gbs::new(&data).unwrap().cycles_per_tick()
+        };
+        // CPU / 1024 is one increment every 256 cycles.
+        assert_eq!(cycles_per_tick(0x00, 0x04), 512 * 256);
+        assert_eq!(cycles_per_tick(0xFF, 0x04), 512);
+        assert_eq!(cycles_per_tick(0x00, 0x05), 8 * 256);
+        assert_eq!(cycles_per_tick(0xC0, 0x86), 32 * 64);
+        assert_eq!(cycles_per_tick(0x00, 0x00), 114 * 154);
+        assert_eq!(cycles_per_tick(0x00, 0x80), 114 * 154);
+    }
+
+    #[test]
+    fn tick_patterns_repeat() {
+        let data = GbsBuilder::default().build();
+        let pattern = [100_000, 50, 200];
+        let gbs = Gbs::new(&data).unwrap().with_tick_pattern(&pattern);
+        let cadence = gbs.cadence();
+        let ticks: Vec<_> = (0..6).map(|tick| cadence.tick_cycles(tick)).collect();
+        // INIT gets the first entry too.
+        assert_eq!(ticks, [100_000, 100_000, 50, 200, 100_000, 50]);
+        assert_eq!(cadence.cycles_before(0), 0);
+        assert_eq!(cadence.cycles_before(1), 100_000);
+        assert_eq!(cadence.cycles_before(3), 100_000 + 100_000 + 50);
+        assert_eq!(cadence.cycles_before(5), 100_000 + 2 * 100_000 + 50 + 200);
+        assert_eq!(gbs.cycles_per_tick(), 33_417);
+    }
+
+    #[test]
+    fn code_helpers() {
+        let code = Code::default()
+            .write(0xFF12, 0xF0)
+            .then(Code::default().raw(&[0x00]).ret());
+        assert_eq!(code.0, [0x3E, 0xF0, 0xE0, 0x12, 0x00, 0xC9]);
+        assert_eq!(code.len(), 6);
+    }
+
+    #[test]
+    fn other_versions_say_what_is_wrong() {
+        let mut data = with_entries(0x400, 0x40F);
+        data[3] = 2;
+        assert_eq!(Gbs::new(&data).unwrap().version(), 2);
+
+        let mut data = with_entries(0x400, 0x410);
+        data[3] = 2;
+        let err = Gbs::new(&data).unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                FormatError::UnsupportedVersion(2, inner)
+                    if matches!(**inner, FormatError::EntryOutOfFile(AddressKind::Play, 0x410, 16))
+            ),
+            "{:?}",
+            err
+        );
+        assert_eq!(
+            err.to_string(),
+            "unsupported version 2 (play address $0410 lies past the end of the file, which only contains 16 bytes of code and data)"
+        );
+    }
+
+    #[test]
+    fn song_ids_must_fit_in_a_byte() {
+        let songs = |nb_songs, first_song| {
+            let data = GbsBuilder::default().songs(nb_songs, first_song).build();
+            Gbs::new(&data)
+                .map(|gbs| gbs.songs())
+                .map_err(|err| err.to_string())
+        };
+        assert_eq!(songs(3, 2), Ok(2..=4));
+        assert_eq!(songs(1, 255), Ok(255..=255));
+        assert_eq!(songs(255, 1), Ok(1..=255));
+        assert_eq!(
+            songs(2, 255),
+            Err("songs 255 to 256 go past song 255".into())
+        );
+        assert_eq!(
+            songs(255, 255),
+            Err("songs 255 to 509 go past song 255".into())
+        );
+    }
 }
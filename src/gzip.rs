@@ -0,0 +1,492 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with decompressing gzip files (RFC 1952), as build systems may keep old builds
+//! that way.
+//!
+//! The only compression method gzip defines is DEFLATE (RFC 1951), implemented here in the simplest
+//! way possible; GBS files are small enough that speed does not matter.
+
+use parse_display::Display;
+
+pub const MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+/// Anything bigger than this is certainly not a GBS file.
+const MAX_SIZE: usize = 64 << 20;
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display("unexpected end of data")]
+    Truncated,
+    #[display("unsupported compression method {0}")]
+    BadMethod(u8),
+    #[display("invalid block type 3")]
+    BadBlockType,
+    #[display("stored block's length doesn't match its complement")]
+    BadStoredLength,
+    #[display("invalid Huffman code")]
+    BadCode,
+    #[display("back-reference {0} bytes too far")]
+    BadDistance(usize),
+    #[display("decompressed data is larger than {0} bytes")]
+    TooLarge(usize),
+    #[display("CRC mismatch (expected {0:08x}, got {1:08x})")]
+    BadCrc(u32, u32),
+    #[display("size mismatch (expected {0} bytes, got {1})")]
+    BadSize(u32, u32),
+}
+
+/// Decompresses all of the gzip members in `data`, one after the other.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut output = Vec::new();
+    let mut ofs = 0;
+    loop {
+        ofs += member(&data[ofs..], &mut output)?;
+        if !data[ofs..].starts_with(&MAGIC) {
+            return Ok(output);
+        }
+    }
+}
+
+/// Decompresses a single member, and returns its size.
+fn member(data: &[u8], output: &mut Vec<u8>) -> Result<usize, Error> {
+    const FHCRC: u8 = 1 << 1;
+    const FEXTRA: u8 = 1 << 2;
+    const FNAME: u8 = 1 << 3;
+    const FCOMMENT: u8 = 1 << 4;
+
+    let header = data.get(..10).ok_or(Error::Truncated)?;
+    if header[2] != 8 {
+        return Err(Error::BadMethod(header[2]));
+    }
+    let flags = header[3];
+    let mut ofs = 10;
+    if flags & FEXTRA != 0 {
+        let len = data.get(ofs..ofs + 2).ok_or(Error::Truncated)?;
+        ofs += 2 + usize::from(u16::from_le_bytes([len[0], len[1]]));
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flag & flags != 0 {
+            let len = data
+                .get(ofs..)
+                .and_then(|field| field.iter().position(|&c| c == 0))
+                .ok_or(Error::Truncated)?;
+            ofs += len + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        ofs += 2;
+    }
+
+    let start = output.len();
+    let mut bits = BitReader {
+        data: data.get(ofs..).ok_or(Error::Truncated)?,
+        ofs: 0,
+        bit: 0,
+    };
+    inflate(&mut bits, output, start)?;
+    ofs += bits.ofs + usize::from(bits.bit != 0);
+
+    let trailer = data.get(ofs..ofs + 8).ok_or(Error::Truncated)?;
+    let expected_crc = u32::from_le_bytes(trailer[0..4].try_into().unwrap());
+    let expected_size = u32::from_le_bytes(trailer[4..8].try_into().unwrap());
+    let crc = crc32(&output[start..]);
+    if crc != expected_crc {
+        return Err(Error::BadCrc(expected_crc, crc));
+    }
+    // The size is stored modulo 2^32.
+    let size = (output.len() - start) as u32;
+    if size != expected_size {
+        return Err(Error::BadSize(expected_size, size));
+    }
+    Ok(ofs + 8)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// DEFLATE packs bits starting from the least-significant one.
+#[derive(Debug)]
+struct BitReader<'a> {
+    data: &'a [u8],
+    ofs: usize,
+    bit: u8,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u8) -> Result<u32, Error> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = self.data.get(self.ofs).ok_or(Error::Truncated)?;
+            value |= u32::from((byte >> self.bit) & 1) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.ofs += 1;
+            }
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.ofs += 1;
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&[u8], Error> {
+        let bytes = self
+            .data
+            .get(self.ofs..self.ofs + len)
+            .ok_or(Error::Truncated)?;
+        self.ofs += len;
+        Ok(bytes)
+    }
+}
+
+/// A canonical Huffman code.
+#[derive(Debug)]
+struct Huffman {
+    /// How many codes there are of each length.
+    counts: [u16; 16],
+    /// Sorted by code.
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; 16];
+        for &len in lengths {
+            counts[usize::from(len)] += 1;
+        }
+        counts[0] = 0;
+
+        let mut symbols: Vec<_> = (0..lengths.len() as u16)
+            .filter(|&symbol| lengths[usize::from(symbol)] != 0)
+            .collect();
+        symbols.sort_by_key(|&symbol| lengths[usize::from(symbol)]);
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> Result<u16, Error> {
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for &count in &self.counts[1..] {
+            code |= bits.bits(1)? as usize;
+            let count = usize::from(count);
+            if code < first + count {
+                return self
+                    .symbols
+                    .get(index + code - first)
+                    .copied()
+                    .ok_or(Error::BadCode);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(Error::BadCode)
+    }
+}
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Back-references cannot reach before `start`, where the member's output begins.
+fn inflate(bits: &mut BitReader, output: &mut Vec<u8>, start: usize) -> Result<(), Error> {
+    loop {
+        let last = bits.bits(1)? != 0;
+        match bits.bits(2)? {
+            0 => {
+                bits.align();
+                let header = bits.bytes(4)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(Error::BadStoredLength);
+                }
+                output.extend_from_slice(bits.bytes(len.into())?);
+            }
+            1 => {
+                let mut lengths = [0; 288 + 30];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..288].fill(8);
+                lengths[288..].fill(5);
+                let (lits, dists) = lengths.split_at(288);
+                codes(
+                    bits,
+                    output,
+                    start,
+                    &Huffman::new(lits),
+                    &Huffman::new(dists),
+                )?;
+            }
+            2 => {
+                let (lits, dists) = dynamic_codes(bits)?;
+                codes(bits, output, start, &lits, &dists)?;
+            }
+            _ => return Err(Error::BadBlockType),
+        }
+        if output.len() > MAX_SIZE {
+            return Err(Error::TooLarge(MAX_SIZE));
+        }
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+fn dynamic_codes(bits: &mut BitReader) -> Result<(Huffman, Huffman), Error> {
+    const ORDER: [usize; 19] = [
+        16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+    ];
+
+    let nb_lits = bits.bits(5)? as usize + 257;
+    let nb_dists = bits.bits(5)? as usize + 1;
+    let nb_code_lens = bits.bits(4)? as usize + 4;
+
+    let mut code_lens = [0; 19];
+    for &i in &ORDER[..nb_code_lens] {
+        code_lens[i] = bits.bits(3)? as u8;
+    }
+    let code_lens = Huffman::new(&code_lens);
+
+    let mut lengths = Vec::with_capacity(nb_lits + nb_dists);
+    while lengths.len() < nb_lits + nb_dists {
+        let (len, repeat) = match code_lens.decode(bits)? {
+            len @ 0..=15 => (len as u8, 1),
+            16 => (
+                *lengths.last().ok_or(Error::BadCode)?,
+                3 + bits.bits(2)? as usize,
+            ),
+            17 => (0, 3 + bits.bits(3)? as usize),
+            _ => (0, 11 + bits.bits(7)? as usize),
+        };
+        if lengths.len() + repeat > nb_lits + nb_dists {
+            return Err(Error::BadCode);
+        }
+        lengths.extend(std::iter::repeat(len).take(repeat));
+    }
+
+    let (lits, dists) = lengths.split_at(nb_lits);
+    Ok((Huffman::new(lits), Huffman::new(dists)))
+}
+
+fn codes(
+    bits: &mut BitReader,
+    output: &mut Vec<u8>,
+    start: usize,
+    lits: &Huffman,
+    dists: &Huffman,
+) -> Result<(), Error> {
+    loop {
+        let symbol = usize::from(lits.decode(bits)?);
+        match symbol {
+            0..=255 => output.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let i = symbol - 257;
+                let len = usize::from(*LENGTH_BASES.get(i).ok_or(Error::BadCode)?)
+                    + bits.bits(LENGTH_EXTRA[i])? as usize;
+                let i = usize::from(dists.decode(bits)?);
+                let dist = usize::from(*DIST_BASES.get(i).ok_or(Error::BadCode)?)
+                    + bits.bits(DIST_EXTRA[i])? as usize;
+                let available = output.len() - start;
+                if dist > available {
+                    return Err(Error::BadDistance(dist - available));
+                }
+                // The copy may overlap with itself, so it has to go byte by byte.
+                for _ in 0..len {
+                    output.push(output[output.len() - dist]);
+                }
+                if output.len() > MAX_SIZE {
+                    return Err(Error::TooLarge(MAX_SIZE));
+                }
+            }
+        }
+    }
+}
+
+/// Wraps `data` in a gzip member made of stored (uncompressed) blocks.
+#[cfg(test)]
+pub(crate) fn store(data: &[u8]) -> Vec<u8> {
+    let mut deflated = Vec::new();
+    let mut chunks = data.chunks(u16::MAX.into()).peekable();
+    if chunks.peek().is_none() {
+        deflated.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(chunk) = chunks.next() {
+        deflated.push(u8::from(chunks.peek().is_none()));
+        let len = chunk.len() as u16;
+        deflated.extend_from_slice(&len.to_le_bytes());
+        deflated.extend_from_slice(&(!len).to_le_bytes());
+        deflated.extend_from_slice(chunk);
+    }
+    tests::wrap(&deflated, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Wraps a raw DEFLATE stream in a minimal gzip member, whose trailer matches `data`.
+    pub(super) fn wrap(deflated: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut member = vec![MAGIC[0], MAGIC[1], 8, 0, 0, 0, 0, 0, 0, 0xFF];
+        member.extend_from_slice(deflated);
+        member.extend_from_slice(&crc32(data).to_le_bytes());
+        member.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        member
+    }
+
+    /// Huffman codes are packed starting from their most significant bit, the rest from their least.
+    #[derive(Default)]
+    struct BitWriter {
+        bytes: Vec<u8>,
+        nb_bits: usize,
+    }
+
+    impl BitWriter {
+        fn bits(&mut self, value: u32, count: u8) {
+            for i in 0..count {
+                if self.nb_bits % 8 == 0 {
+                    self.bytes.push(0);
+                }
+                *self.bytes.last_mut().unwrap() |= (((value >> i) & 1) as u8) << (self.nb_bits % 8);
+                self.nb_bits += 1;
+            }
+        }
+
+        fn code(&mut self, code: u32, len: u8) {
+            for i in (0..len).rev() {
+                self.bits((code >> i) & 1, 1);
+            }
+        }
+    }
+
+    /// A fixed-code block that copies 3 bytes from `dist` bytes back (between 1 and 4).
+    fn back_reference(dist: u32) -> Vec<u8> {
+        let mut bits = BitWriter::default();
+        bits.bits(1, 1); // Last block.
+        bits.bits(1, 2); // Fixed codes.
+        bits.code(1, 7); // Length 3.
+        bits.code(dist - 1, 5);
+        bits.code(0, 7); // End of block.
+        bits.bytes
+    }
+
+    #[test]
+    fn stored_blocks() {
+        assert_eq!(decompress(&store(b"")).unwrap(), b"");
+        assert_eq!(decompress(&store(b"GBS")).unwrap(), b"GBS");
+        // More than a single block can hold.
+        let data: Vec<_> = (0..70_000u32).map(|i| (i * 7) as u8).collect();
+        assert_eq!(decompress(&store(&data)).unwrap(), data);
+    }
+
+    #[test]
+    fn fixed_blocks() {
+        let data = b"fixed Huffman codes, fixed Huffman codes!";
+        let deflated = [
+            75, 203, 172, 72, 77, 81, 240, 40, 77, 75, 203, 77, 204, 83, 72, 206, 79, 73, 45, 214,
+            81, 72, 195, 20, 84, 4, 0,
+        ];
+        assert_eq!(decompress(&wrap(&deflated, data)).unwrap(), data);
+    }
+
+    #[test]
+    fn dynamic_blocks() {
+        let data: String = (0..9)
+            .map(|i| format!("NR{}{} = ${:02x}; ", i % 5 + 1, i % 4 + 1, (i * 37) & 255))
+            .collect();
+        let deflated = [
+            0x1D, 0xCB, 0xBB, 0x0D, 0xC0, 0x20, 0x10, 0x04, 0xD1, 0x56, 0x2E, 0xA0, 0x80, 0xFB,
+            0x61, 0x19, 0x21, 0x5A, 0x20, 0xA0, 0x03, 0x90, 0xED, 0xFE, 0x4B, 0xB0, 0x76, 0xB3,
+            0x17, 0xCC, 0xCC, 0x65, 0x26, 0x43, 0x8A, 0x6A, 0x97, 0xB9, 0xDC, 0x61, 0xAF, 0x70,
+            0x04, 0x9C, 0x1B, 0xCE, 0x84, 0xAF, 0x0F, 0xAE, 0xEC, 0x5B, 0xC2, 0xC6, 0xFE, 0x34,
+            0xBE, 0xEC, 0x9F, 0x97, 0x2F, 0x7B, 0x0D, 0xBE, 0xEC, 0xFD, 0xEE, 0xF2, 0x03,
+        ];
+        let member = wrap(&deflated, data.as_bytes());
+        assert_eq!(decompress(&member).unwrap(), data.as_bytes());
+        assert!(matches!(
+            decompress(&member[..member.len() - 20]),
+            Err(Error::Truncated)
+        ));
+    }
+
+    #[test]
+    fn back_references_stay_within_their_member() {
+        let first = store(b"abcd");
+        let copy = wrap(&back_reference(4), b"");
+        // Within a member, copies may overlap themselves.
+        let mut bits = BitWriter::default();
+        bits.bits(1, 1);
+        bits.bits(1, 2);
+        bits.code(0x30 + u32::from(b'a'), 8);
+        bits.code(1, 7);
+        bits.code(0, 5);
+        bits.code(0, 7);
+        assert_eq!(decompress(&wrap(&bits.bytes, b"aaaa")).unwrap(), b"aaaa");
+
+        assert!(matches!(decompress(&copy), Err(Error::BadDistance(4))));
+        // Even though the previous member's output is right there.
+        let multi = [first, copy].concat();
+        assert!(matches!(decompress(&multi), Err(Error::BadDistance(4))));
+    }
+
+    #[test]
+    fn members_are_concatenated() {
+        let multi = [store(b"GBS"), store(b""), store(b"diff")].concat();
+        assert_eq!(decompress(&multi).unwrap(), b"GBSdiff");
+    }
+
+    #[test]
+    fn corruption_is_caught() {
+        let mut member = store(b"GBS file");
+        member[15] ^= 0x20;
+        assert!(matches!(decompress(&member), Err(Error::BadCrc(..))));
+        let mut member = store(b"GBS file");
+        member[2] = 7;
+        assert!(matches!(decompress(&member), Err(Error::BadMethod(7))));
+        let mut member = store(b"GBS file");
+        member[11] ^= 1;
+        assert!(matches!(decompress(&member), Err(Error::BadStoredLength)));
+        let mut member = store(b"GBS file");
+        member[10] |= 6; // Block type 3.
+        assert!(matches!(decompress(&member), Err(Error::BadBlockType)));
+        let member = store(b"GBS file");
+        let len = member.len();
+        assert!(matches!(
+            decompress(&member[..len - 1]),
+            Err(Error::Truncated)
+        ));
+    }
+}
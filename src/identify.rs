@@ -0,0 +1,230 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with guessing which sound driver a GBS file was made with.
+//!
+//! Each signature is a set of weighted predicates, checked against the GBS header and code, and
+//! against the writes of the first few ticks of its first song.
+//!
+//! Signature files consist of `[Driver name]` lines, each followed by `WEIGHT PREDICATE [ARGS]`
+//! lines; empty lines and lines starting with `#` are ignored. The predicates are:
+//! - `load-addr XXXX`, `init-addr XXXX`, `play-addr XXXX`: the header's addresses;
+//! - `play-code XX XX ...`: the bytes at the play address;
+//! - `code XX XX ...`: the bytes, anywhere in the ROM;
+//! - `timer`, `vblank`: which interrupt PLAY is called from;
+//! - `first-writes XXXX XXXX ...`: the first registers written to, in order (repeats ignored);
+//! - `wave-init`: wave RAM gets written to during INIT.
+
+use std::fmt::Display;
+
+use crate::{
+    gbs::{AddressKind, Gbs},
+    run::IoAccess,
+};
+
+/// How many ticks of the first song are looked at.
+pub const NB_TICKS: u64 = 16;
+
+/// Scores below this are not considered a match at all.
+const MIN_SCORE: f64 = 0.3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Predicate {
+    Addr(AddressKind, u16),
+    PlayCode(Vec<u8>),
+    Code(Vec<u8>),
+    Timer(bool),
+    FirstWrites(Vec<u16>),
+    WaveInit,
+}
+
+impl Predicate {
+    fn parse(name: &str, args: &[&str]) -> Result<Self, String> {
+        let hex16 = |arg: &str| {
+            u16::from_str_radix(arg, 16)
+                .map_err(|err| format!("invalid address {:?}: {}", arg, err))
+        };
+        let bytes = || {
+            args.iter()
+                .map(|arg| {
+                    u8::from_str_radix(arg, 16)
+                        .map_err(|err| format!("invalid byte {:?}: {}", arg, err))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let single = || match args {
+            [arg] => Ok(*arg),
+            _ => Err(format!("`{}` takes exactly one argument", name)),
+        };
+
+        Ok(match name {
+            "load-addr" => Self::Addr(AddressKind::Load, hex16(single()?)?),
+            "init-addr" => Self::Addr(AddressKind::Init, hex16(single()?)?),
+            "play-addr" => Self::Addr(AddressKind::Play, hex16(single()?)?),
+            "play-code" => Self::PlayCode(bytes()?),
+            "code" => Self::Code(bytes()?),
+            "timer" => Self::Timer(true),
+            "vblank" => Self::Timer(false),
+            "first-writes" => {
+                Self::FirstWrites(args.iter().copied().map(hex16).collect::<Result<_, _>>()?)
+            }
+            "wave-init" => Self::WaveInit,
+            _ => return Err(format!("unknown predicate `{}`", name)),
+        })
+    }
+
+    /// `io_log` is expected to only cover the first [`NB_TICKS`] ticks.
+    fn matches(&self, gbs: &Gbs, io_log: &[IoAccess]) -> bool {
+        let rom = gbs.rom();
+        match self {
+            Self::Addr(kind, addr) => gbs.addr(*kind) == *addr,
+            Self::PlayCode(_) if gbs.entry_in_ram(AddressKind::Play) => false,
+            Self::PlayCode(code) => {
+                let ofs = gbs
+                    .addr(AddressKind::Play)
+                    .wrapping_sub(gbs.addr(AddressKind::Load));
+                rom.get(usize::from(ofs)..)
+                    .is_some_and(|play| play.starts_with(code))
+            }
+            Self::Code(code) => {
+                !code.is_empty() && rom.windows(code.len()).any(|bytes| bytes == code)
+            }
+            Self::Timer(timer) => gbs.use_timer() == *timer,
+            Self::FirstWrites(regs) => {
+                let mut first_writes = Vec::with_capacity(regs.len());
+                for access in io_log {
+                    if first_writes.len() == regs.len() {
+                        break;
+                    }
+                    if first_writes.last() != Some(&access.addr) {
+                        first_writes.push(access.addr);
+                    }
+                }
+                first_writes == *regs
+            }
+            Self::WaveInit => io_log
+                .iter()
+                .any(|access| access.when.tick == 0 && (0xFF30..=0xFF3F).contains(&access.addr)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    name: String,
+    predicates: Vec<(u32, Predicate)>,
+}
+
+/// Only signatures checked against real rips belong here; others can be loaded from a file.
+pub fn builtin_signatures() -> Vec<Signature> {
+    vec![Signature {
+        name: "Carillon Player".to_string(),
+        predicates: vec![
+            (3, Predicate::Addr(AddressKind::Init, 0x4000)),
+            (3, Predicate::Addr(AddressKind::Play, 0x4100)),
+            (1, Predicate::Timer(false)),
+        ],
+    }]
+}
+
+pub fn parse_signatures(text: &str) -> Result<Vec<Signature>, String> {
+    let mut signatures = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |msg: String| format!("line {}: {}", i + 1, msg);
+
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            signatures.push(Signature {
+                name: name.trim().to_string(),
+                predicates: Vec::new(),
+            });
+            continue;
+        }
+
+        let signature: &mut Signature = signatures
+            .last_mut()
+            .ok_or_else(|| error("predicate outside of any `[Driver name]` section".to_string()))?;
+        let words: Vec<_> = line.split_whitespace().collect();
+        let (weight, name, args) = match words.as_slice() {
+            [weight, name, args @ ..] => (weight, name, args),
+            _ => return Err(error("expected `WEIGHT PREDICATE [ARGS]`".to_string())),
+        };
+        let weight = weight
+            .parse()
+            .map_err(|err| error(format!("invalid weight {:?}: {}", weight, err)))?;
+        let predicate = Predicate::parse(name, args).map_err(error)?;
+        signature.predicates.push((weight, predicate));
+    }
+
+    Ok(signatures)
+}
+
+/// The best-matching signature for a file.
+#[derive(Debug, Clone)]
+pub struct Match<'a> {
+    pub signature: &'a Signature,
+    /// Between 0 and 1: the share of the signature's weight whose predicates matched.
+    pub score: f64,
+}
+
+impl Match<'_> {
+    fn confidence(&self) -> &'static str {
+        if self.score >= 0.8 {
+            "high"
+        } else if self.score >= 0.5 {
+            "medium"
+        } else {
+            "low"
+        }
+    }
+}
+
+impl Display for Match<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({} confidence, {:.0}% of the signature matched)",
+            self.signature.name,
+            self.confidence(),
+            self.score * 100.0
+        )
+    }
+}
+
+/// Returns `None` if no signature matches well enough.
+pub fn identify<'a>(
+    signatures: &'a [Signature],
+    gbs: &Gbs,
+    io_log: &[IoAccess],
+) -> Option<Match<'a>> {
+    signatures
+        .iter()
+        .filter_map(|signature| {
+            let total: u32 = signature.predicates.iter().map(|(weight, _)| weight).sum();
+            if total == 0 {
+                return None;
+            }
+            let matched: u32 = signature
+                .predicates
+                .iter()
+                .filter(|(_, predicate)| predicate.matches(gbs, io_log))
+                .map(|(weight, _)| weight)
+                .sum();
+            Some(Match {
+                signature,
+                score: f64::from(matched) / f64::from(total),
+            })
+        })
+        .filter(|candidate| candidate.score >= MIN_SCORE)
+        .max_by(|a, b| a.score.total_cmp(&b.score))
+}
@@ -0,0 +1,2283 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! gbsdiff's command line is [`main`]; other tools may drive the simulation on their own, see
+//! [`run::simulate_song_with_hooks`].
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    fmt::{Display, LowerHex},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read},
+    ops::Range,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use argh::FromArgs;
+use owo_colors::{
+    OwoColorize,
+    Stream::{Stderr, Stdout},
+};
+
+macro_rules! colorize {
+    ($stream:expr, $base:expr, $($func:ident),+ $(,)?) => {
+        ($base $(.if_supports_color($stream, |text| text.$func()))+)
+    };
+}
+
+mod baseline;
+mod bug_report;
+mod cache;
+mod channel;
+mod compare;
+mod cpu_usage;
+mod csv;
+mod determinism;
+mod diff;
+mod early_exit;
+mod expect;
+mod explain;
+mod fingerprint;
+mod focus;
+pub mod gbs;
+mod gzip;
+mod identify;
+use gbs::Gbs;
+mod manifest;
+mod merge;
+mod realloc;
+mod regs;
+mod render;
+mod replay;
+mod report;
+use report::{Phase, Reporter};
+pub mod run;
+mod self_test;
+mod shadow;
+mod state;
+mod stream;
+mod sym;
+mod tempo;
+#[cfg(test)]
+mod tests;
+mod throughput;
+use sym::AddrArg;
+mod transcript;
+mod unified;
+use run::{trace::TraceFormat, DebugMarkers, InitRegs, Profile, TraceFilter, WaveReadMode};
+mod waves;
+mod who_writes;
+mod write_pairs;
+
+const CYCLES_PER_SEC: u32 = 1048576;
+
+// The options that take no GBS file are handled by `parse_args` before `Args` gets parsed, since
+// the positional arguments would be missing; they are only listed here for `--help`.
+#[derive(FromArgs, Debug)]
+/// Analyze differences in audio register writes between two GBS files, or check that a single one simulates cleanly.
+#[argh(
+    note = "Instead of being given GBS files, {command_name} can also be run with one of:",
+    note = "  --version         print the version, how it was built, and which GBS features\n                    are supported, then exit",
+    note = "  --self-test       simulate a tiny built-in GBS file and check that it behaves\n                    as expected, then exit",
+    note = "  --trace-decode    print a binary trace file in the text format, then exit",
+    note = "  --manifest        run each comparison listed in this file (one per line, e.g.\n                    `name = \"x\", before = \"a.gbs\", after = \"b.gbs\"`,\n                    optionally overriding `song`, `jitter`, or `watch`) with\n                    the other options"
+)]
+struct Args {
+    #[argh(option, short = 'l', default = "DiagnosticLevel::Warning")]
+    /// silence diagnostics with a higher level than this (default: warning)
+    max_level: DiagnosticLevel,
+    #[argh(option)]
+    /// change the level of a kind of simulation diagnostic (e.g. `echo-ram-read=error`, or `debug-op=ignore`); simulation errors fail the song (can be repeated)
+    promote: Vec<run::Promotion>,
+    #[argh(option, default = "DiagnosticLevel::Error")]
+    /// when given a single file, fail the songs whose simulation diagnostics reach this level (default: error)
+    fail_on: DiagnosticLevel,
+    #[argh(option, short = 'm', default = "1000")]
+    /// how many diagnostics of each level to show per song, at most; the rest are only counted (default: 1000)
+    max_reports: usize,
+    #[argh(option, default = "1000")]
+    /// how many diagnostics to show per song, at most, all levels combined (default: 1000)
+    max_total_reports: usize,
+    #[argh(option, default = "100_000")]
+    /// how many simulation diagnostics to record per song, at most; the rest are only counted, by kind (default: 100000)
+    max_recorded_diagnostics: usize,
+    #[argh(
+        option,
+        short = 't',
+        default = "TimeArg::Millis(60_000)",
+        from_str_fn(parse_duration_arg)
+    )]
+    /// time out simulation of a song after this long: plain numbers are seconds, but `M:SS`, `1m30s`, `500ms`, and `123456c` (exact CPU cycles) are accepted too, as by all duration options (default: 60)
+    timeout: TimeArg,
+    #[argh(option, from_str_fn(parse_duration_arg))]
+    /// give up on the songs still being simulated after this long in real time, e.g. to get partial results before a CI job's own time limit
+    wall_timeout: Option<TimeArg>,
+    #[argh(option, default = "5000000")]
+    /// consider the CPU locked up if a single INIT or PLAY call takes more than this many cycles (default: 5000000)
+    max_func_cycles: u32,
+    #[argh(option, default = "16")]
+    /// warn if the driver stops writing audio registers for more than this many ticks (default: 16)
+    max_stall_ticks: u64,
+    #[argh(switch, short = 'T')]
+    /// make timeout non-fatal (useful for looping tracks)
+    allow_timeout: bool,
+    #[argh(option, from_str_fn(parse_tick_pattern))]
+    /// make PLAY calls last these many cycles in turn, repeating (e.g. `8772,8772,4386,8772`), instead of what the headers say, for drivers whose players vary the tick length; applies to both files
+    tick_pattern: Option<Vec<u32>>,
+    #[argh(
+        option,
+        short = 's',
+        default = "TimeArg::Millis(4000)",
+        from_str_fn(parse_duration_arg)
+    )]
+    /// consider that a song ended after this long of silence (default: 4)
+    slience_timeout: TimeArg,
+    #[argh(option, default = "2")]
+    /// warn if the songs' lengths differ by more than this many ticks (default: 2)
+    length_tolerance: u64,
+    #[argh(option)]
+    /// load symbols from this RGBDS .sym file, to be used instead of addresses in other options, and shown in diagnostics
+    sym: Option<String>,
+    #[argh(option, short = 'w', from_str_fn(parse_addr_value_arg))]
+    /// consider that a song ended when `ADDR=VALUE` (both hex numbers, or a symbol for ADDR) after a tick, or when PLAY writes VALUE to ADDR
+    watch: Option<(AddrArg, u8)>,
+    #[argh(option, from_str_fn(parse_addr_value_arg))]
+    /// only consider that a song starts once `ADDR=VALUE` (like `--watch`) after INIT or a tick, for drivers that must be ready before PLAY is called; the ticks before then are run, but not compared
+    wait_for: Option<(AddrArg, u8)>,
+    #[argh(option, from_str_fn(parse_addr_value_arg))]
+    /// write `VALUE` to `ADDR` (both hex numbers, or a symbol for ADDR) before INIT, e.g. to preset a config variable; can be repeated
+    poke: Vec<(AddrArg, u8)>,
+    #[argh(switch)]
+    /// don't warn about reads of RAM that the driver hasn't written yet (which is zero here, but garbage on hardware)
+    no_uninit_check: bool,
+    #[argh(option, from_str_fn(parse_stub_reg_arg))]
+    /// make reading the otherwise unsupported I/O register at `ADDR` return `VALUE` (both hex numbers, or a symbol for ADDR), e.g. `ff44=90` for LY; can be repeated
+    stub_reg: Vec<(AddrArg, u8)>,
+    #[argh(option, default = "Profile::Apu")]
+    /// which registers count as audio output, and are thus logged, compared, and keep the song from being silent: `apu` (default), `apu+serial` (also SB and SC), or `custom:` followed by extra hex addresses and ranges, e.g. `custom:ff01-ff02,ff4d`
+    profile: Profile,
+    #[argh(switch)]
+    /// keep SRAM from one song to the next, like some GBS players do, instead of clearing it before each song
+    persist_sram: bool,
+    #[argh(option, default = "InitRegs::default()")]
+    /// set CPU registers before INIT (but after the pokes), e.g. `b=01,c=80,hl=c123`; `a` overrides the song ID
+    init_regs: InitRegs,
+    #[argh(option)]
+    /// call this address (in hex, or a symbol) as INIT instead of the headers' INIT address, e.g. if it is the same as PLAY's and the driver dispatches on its own state
+    init_addr: Option<AddrArg>,
+    #[argh(option)]
+    /// call this address (in hex, or a symbol) as PLAY instead of the headers' PLAY address
+    play_addr: Option<AddrArg>,
+    #[argh(option, from_str_fn(parse_reg_arg))]
+    /// report every write to this address (in hex, a register name, or a symbol) as a note, along with the instructions executed just before it; can be repeated
+    watchpoint: Vec<AddrArg>,
+    #[argh(option, from_str_fn(parse_reg_arg))]
+    /// like --watchpoint, but for reads
+    watchpoint_read: Vec<AddrArg>,
+    #[argh(option, from_str_fn(parse_shadow_arg))]
+    /// `ADDR:REG`: the RAM at `ADDR` (in hex, or a symbol) holds a copy of the register `REG` (like --watchpoint), e.g. `hShadowNR12:NR12`; writes to it are compared between both files, and each write to REG must be preceded by the same write to ADDR within its tick; can be repeated
+    shadow: Vec<(AddrArg, AddrArg)>,
+    #[argh(switch)]
+    /// simulate every song twice, and exit with status 4 if both runs' results differ in any way (which would be a bug in gbsdiff); cached results are not used
+    verify_determinism: bool,
+    #[argh(option)]
+    /// keep simulation results in this directory, and reuse them when a file and the simulation parameters haven't changed
+    cache_dir: Option<String>,
+    #[argh(option)]
+    /// save each song's simulation state after --at-tick or --at-time to this directory, as `song-N-before.state` and `song-N-after.state`, creating it if needed
+    save_state: Option<String>,
+    #[argh(option)]
+    /// the tick after which --save-state saves (0 being INIT)
+    at_tick: Option<u64>,
+    #[argh(option, from_str_fn(parse_duration_arg))]
+    /// how far into each song --save-state saves
+    at_time: Option<TimeArg>,
+    #[argh(option)]
+    /// resume each song from the state that --save-state saved in this directory, instead of simulating it from the start; only the ticks after it are compared
+    load_state: Option<String>,
+    #[argh(option)]
+    /// log CPU activity to this file (significant slowdown)
+    trace: Option<String>,
+    #[argh(option, default = "TraceFilter::All")]
+    /// what to log to the trace file: "all" (default), or only "io" accesses and bank switches
+    trace_filter: TraceFilter,
+    #[argh(option, default = "TraceFormat::Text")]
+    /// format of the trace file: "text" (default), or "binary" (much smaller and faster to write; see --trace-decode)
+    trace_format: TraceFormat,
+    #[argh(option, short = 'd', default = "BeforeOrAfter::After")]
+    /// print the diagnostics of either the "before" GBS, the "after" one, or "none" (default: after)
+    print_diagnostics: BeforeOrAfter,
+    #[argh(option, short = 'j', default = "20")]
+    /// identical IO writes displaced by strictly less cycles than this will be treated as notes instead of errors (default: 20)
+    jitter: u16,
+    #[argh(switch)]
+    /// after a moved DIV reset, only note writes that moved by as much for the rest of the song, instead of the rest of the tick
+    div_phase_tolerance: bool,
+    #[argh(option, default = "4")]
+    /// pair writes missing from the last N writes of a tick with the same writes among the first N of the next tick, as a single warning; 0 disables this (default: 4)
+    slip_window: usize,
+    #[argh(option, default = "2000")]
+    /// songs whose INIT goes haywire within this many cycles are suspected of lying past the end of the driver's song table (default: 2000)
+    early_init_cycles: u32,
+    #[argh(option, default = "10")]
+    /// warn if the slowest tick got slower by more than this many percent (default: 10)
+    cpu_regression_threshold: u16,
+    #[argh(option, default = "5")]
+    /// warn (instead of noting) if more than this many percent of PLAY ticks took the driver's early exit in one file but not the other (default: 5)
+    cadence_threshold: u16,
+    #[argh(option, default = "0")]
+    /// warn if the deepest stack usage grew by more than this many bytes (default: 0)
+    stack_growth_threshold: u16,
+    #[argh(option, default = "100")]
+    /// warn about ticks whose last write lands less than this many cycles before the end of the tick budget (default: 100)
+    write_margin: u16,
+    #[argh(option, from_str_fn(parse_duration_arg))]
+    /// only compare the songs from this far in
+    from: Option<TimeArg>,
+    #[argh(option, from_str_fn(parse_duration_arg))]
+    /// only compare the songs up to this far in
+    to: Option<TimeArg>,
+    #[argh(option, from_str_fn(parse_duration_arg))]
+    /// ignore differences during this long at the start of each song
+    skip_start: Option<TimeArg>,
+    #[argh(option, from_str_fn(parse_duration_arg))]
+    /// ignore differences during this long at the end of each song
+    skip_end: Option<TimeArg>,
+    #[argh(option, default = "0")]
+    /// do not compare the first N PLAY ticks of each song, which are still simulated; their simulation diagnostics are printed as notes, and don't fail the song
+    skip_ticks: u64,
+    #[argh(switch)]
+    /// do not compare INIT either, like --skip-ticks
+    skip_init: bool,
+    #[argh(option)]
+    /// only compare this song of the earlier GBS, against --after-song (by default, the same ID)
+    before_song: Option<u8>,
+    #[argh(option)]
+    /// only compare this song of the later GBS, against --before-song (by default, the same ID)
+    after_song: Option<u8>,
+    #[argh(option, from_str_fn(parse_reg_arg))]
+    /// ignore writes to this register (e.g. `NR51`, `ff25`, or a symbol) in both files (can be repeated)
+    ignore_reg: Vec<AddrArg>,
+    #[argh(option, default = "32")]
+    /// register pairs (e.g. NR13 then NR14) written more than this many cycles apart are considered vulnerable to interrupts (default: 32)
+    pair_span_threshold: u16,
+    #[argh(option)]
+    /// if gbsdiff crashes, write a bug report bundle into this directory
+    bug_report: Option<String>,
+    #[argh(switch)]
+    /// include the GBS files themselves in the bug report bundle (by default, only their hashes are)
+    bug_report_include_inputs: bool,
+    #[argh(switch)]
+    /// print the contents of waveforms that differ, instead of just their hashes
+    show_waves: bool,
+    #[argh(option)]
+    /// show the writes of both files during this tick side by side (can be repeated)
+    show_tick: Vec<u64>,
+    #[argh(switch)]
+    /// if the two files call PLAY at different rates, re-bucket the "after" writes into the "before" file's ticks
+    normalize_time: bool,
+    #[argh(option)]
+    /// report differences caused by code in this ROM bank first (can be repeated)
+    focus_bank: Vec<u8>,
+    #[argh(option, from_str_fn(focus::parse_pc_range))]
+    /// report differences caused by code in this range (e.g. `03:4000-03:4fff`) first (can be repeated)
+    focus_pc: Vec<focus::PcRange>,
+    #[argh(switch)]
+    /// do not report differences outside of the focused banks and ranges, nor count them as failures
+    hide_indirect: bool,
+    #[argh(switch)]
+    /// feed the earlier GBS's I/O reads to the later one, to see if the differences go away (experimental)
+    replay_reads: bool,
+    #[argh(option, default = "WaveReadMode::Stored")]
+    /// what reading wave RAM returns while CH3 plays: `stored`, `ff`, or `current-sample-approx` (default: stored)
+    wave_read_mode: WaveReadMode,
+    #[argh(option, default = "DebugMarkers::Note")]
+    /// what to do about debug opcodes (`ld b, b` and `ld d, d`): `note` them, `ignore` them, or use them as `anchor`s, checking that each one runs on the same tick in both files (default: note)
+    debug_markers: DebugMarkers,
+    #[argh(switch)]
+    /// report all value differences as errors, instead of only warning about length-only and envelope pace-only ones
+    strict_values: bool,
+    #[argh(switch)]
+    /// also only warn about duty-only differences
+    relax_duty: bool,
+    #[argh(switch)]
+    /// when the tempo seems to have changed, don't print the write differences from the tick the files drift more than a tick apart on (they still fail the song)
+    suppress_drift_cascade: bool,
+    #[argh(option, default = "CompareMode::Writes")]
+    /// compare the `writes` themselves, the APU `state` at the end of each tick, or `both` (default: writes)
+    compare: CompareMode,
+    #[argh(switch)]
+    /// simulate both songs side by side and diff their writes tick by tick, so that memory use doesn't grow with the songs' length; only the writes and the simulation diagnostics are compared then, and options that need whole songs can't be used
+    stream: bool,
+    #[argh(option, default = "GroupBy::Time")]
+    /// print each song's differences in chronological order (`time`, default), or in one section per APU `channel`, each with its own --max-reports
+    group_by: GroupBy,
+    #[argh(switch)]
+    /// guess which sound driver each GBS file was made with
+    identify: bool,
+    #[argh(switch)]
+    /// instead of comparing, list the code locations that write each hardware register, with how many times and from which tick on; only one GBS file is needed then
+    who_writes: bool,
+    #[argh(option)]
+    /// load additional driver signatures for --identify from this file
+    driver_signatures: Option<String>,
+    #[argh(option)]
+    /// also write the results as a self-contained HTML file at this path
+    html: Option<String>,
+    #[argh(option)]
+    /// also write a summary table and the failing songs' diagnostics as GitHub-flavored Markdown to this path, for pasting into pull requests
+    markdown: Option<String>,
+    #[argh(option)]
+    /// write a text rendering of each song (one line per tick, with each channel's note and volume) to this path, where `{song}` and `{side}` (before or after) are replaced
+    render: Option<String>,
+    #[argh(option)]
+    /// write each song's IO writes, and its differences, as CSV files (`song-N-before.csv`, `song-M-after.csv`, and `diff-song-N-M.csv`, where N and M are the compared songs' IDs) to this directory, creating it if needed
+    csv: Option<String>,
+    #[argh(switch)]
+    /// let --csv overwrite existing files
+    force: bool,
+    #[argh(option)]
+    /// write the differences as a unified diff between listings of each song's writes, one per line under a header for each tick, to this path (or to stdout if `-`)
+    unified: Option<String>,
+    #[argh(switch)]
+    /// in the --unified diff, replace the cycle of writes that are only displaced by less than --jitter cycles with `~`, so that they don't show up as changes
+    unified_fuzz: bool,
+    #[argh(switch)]
+    /// after the first difference of each kind in a song, explain what it likely means
+    explain: bool,
+    #[argh(option, default = "0")]
+    /// show up to this many matching writes of the same tick before and after each difference, like `diff -u` (default: 0)
+    context: usize,
+    #[argh(option)]
+    /// don't report the known differences listed in this file (see --write-baseline), and note those that no longer occur
+    baseline: Option<String>,
+    #[argh(option)]
+    /// write the differences found to this file, for use with --baseline
+    write_baseline: Option<String>,
+    #[argh(switch)]
+    /// only print one line per song with how many diagnostics of each level it has, and a total
+    stat: bool,
+    #[argh(switch)]
+    /// when stdout is a terminal, pipe the output through $PAGER (`less` by default), like git does
+    pager: bool,
+    #[argh(option, default = "200")]
+    /// when stdout is a terminal, write the output of songs with more than this many diagnostics to a temporary file instead, printing only a summary; 0 disables this (default: 200)
+    spill_threshold: usize,
+    #[argh(switch, short = 'q')]
+    /// only print findings and the final summary, not progress, statistics, nor "OK!" lines
+    quiet: bool,
+    #[argh(switch, short = 'v')]
+    /// also print details about the files and the simulation
+    verbose: bool,
+    #[argh(option, default = "None", from_str_fn(parse_color_arg))]
+    /// whether to colorize output: auto (default), always, never
+    color: Option<bool>,
+
+    #[argh(positional)]
+    /// path to the GBS file that was built before the changes (`-` for stdin; may be gzipped), or to a script of the writes expected of the "after" song, if it ends in `.expect`
+    before: String,
+    #[argh(positional)]
+    /// path to the GBS file that was built after the changes (`-` for stdin; may be gzipped); if omitted, the songs of the "before" file are only simulated and their diagnostics printed
+    after: Option<String>,
+}
+/// Runs gbsdiff on the process's command line, and returns the exit code.
+pub fn main() -> i32 {
+    run(parse_args(), &report::Sink::stdout())
+}
+
+/// An error that ends the run with exit code 2, and has been printed already; it is returned
+/// instead of exiting on the spot, so that a manifest can carry on with its next comparison.
+struct Fatal;
+
+/// Returns the exit code: 1 if any song failed, 2 on errors, [`determinism::EXIT_CODE`] if
+/// `--verify-determinism` caught any song being simulated differently twice.
+///
+/// Everything meant for stdout goes to `out` instead; errors still go to stderr.
+fn run(args: Args, out: &report::Sink) -> i32 {
+    try_run(args, out).unwrap_or(2)
+}
+
+/// Reads a text file given to an option.
+fn read_text(path: &str) -> Result<String, Fatal> {
+    fs::read_to_string(path).map_err(|err| {
+        eprintln!(
+            "{} while reading {}: {}",
+            colorize!(Stderr, "Error", bright_red, bold),
+            path,
+            err
+        );
+        Fatal
+    })
+}
+
+/// Reports an error parsing a file given to an option.
+fn parse_error(path: &str, err: impl Display) -> Fatal {
+    eprintln!(
+        "{} parsing {}: {}",
+        colorize!(Stderr, "Error", bright_red, bold),
+        path,
+        err
+    );
+    Fatal
+}
+
+/// What the options resolve to, which every mode needs.
+struct Options {
+    sim_params: run::SimParams,
+    /// Echoed with each song, so that the run can be reproduced.
+    presets: String,
+    symbols: Option<sym::Symbols>,
+    /// Reported once there is a reporter.
+    sym_warnings: Vec<String>,
+    baseline: Option<baseline::Baseline>,
+    ignore_regs: Vec<u16>,
+    shadows: Vec<shadow::Shadow>,
+    trace_file: Option<BufWriter<File>>,
+    verbosity: report::Verbosity,
+}
+
+impl Options {
+    fn new(args: &Args) -> Result<Self, Fatal> {
+        let symbols = args
+            .sym
+            .as_ref()
+            .map(|path| sym::parse(&read_text(path)?).map_err(|err| parse_error(path, err)))
+            .transpose()?;
+        let baseline = args
+            .baseline
+            .as_ref()
+            .map(|path| baseline::parse(&read_text(path)?).map_err(|err| parse_error(path, err)))
+            .transpose()?;
+        let mut sym_warnings = Vec::new();
+        let mut resolve = |arg: &AddrArg| {
+            let (addr, warning) = arg.resolve(symbols.as_ref()).map_err(|err| {
+                eprintln!("{}: {}", colorize!(Stderr, "Error", bright_red, bold), err);
+                Fatal
+            })?;
+            sym_warnings.extend(warning);
+            Ok(addr)
+        };
+        let watch = args
+            .watch
+            .as_ref()
+            .map(|(addr, value)| Ok((resolve(addr)?, *value)))
+            .transpose()?;
+        let wait_for = args
+            .wait_for
+            .as_ref()
+            .map(|(addr, value)| Ok((resolve(addr)?, *value)))
+            .transpose()?;
+        let init_addr = args.init_addr.as_ref().map(&mut resolve).transpose()?;
+        let play_addr = args.play_addr.as_ref().map(&mut resolve).transpose()?;
+        let pokes = args
+            .poke
+            .iter()
+            .map(|(addr, value)| Ok((resolve(addr)?, *value)))
+            .collect::<Result<_, _>>()?;
+        let stub_regs = args
+            .stub_reg
+            .iter()
+            .map(|(addr, value)| {
+                let addr = resolve(addr)?;
+                if !(0xFF00..=0xFF7F).contains(&addr) {
+                    eprintln!(
+                        "{}: --stub-reg: ${:04x} is not an I/O register",
+                        colorize!(Stderr, "Error", bright_red, bold),
+                        addr
+                    );
+                    return Err(Fatal);
+                }
+                Ok((addr, *value))
+            })
+            .collect::<Result<_, _>>()?;
+        let ignore_regs: Vec<_> = args
+            .ignore_reg
+            .iter()
+            .map(&mut resolve)
+            .collect::<Result<_, _>>()?;
+        let watchpoints = args
+            .watchpoint
+            .iter()
+            .map(&mut resolve)
+            .collect::<Result<_, _>>()?;
+        let read_watchpoints = args
+            .watchpoint_read
+            .iter()
+            .map(&mut resolve)
+            .collect::<Result<_, _>>()?;
+        let shadows: Vec<_> = args
+            .shadow
+            .iter()
+            .map(|(addr_arg, reg)| {
+                shadow::Shadow::new(addr_arg, resolve(addr_arg)?, resolve(reg)?).map_err(|err| {
+                    eprintln!(
+                        "{}: --shadow: {}",
+                        colorize!(Stderr, "Error", bright_red, bold),
+                        err
+                    );
+                    Fatal
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        let sim_params = run::SimParams {
+            max_level: args.max_level,
+            max_recorded_diagnostics: args.max_recorded_diagnostics,
+            timeout: sim_cycles(args.timeout, "--timeout")?,
+            allow_timeout: args.allow_timeout,
+            silence_timeout: sim_cycles(args.slience_timeout, "--slience-timeout")?,
+            watch,
+            wait_for,
+            trace_filter: args.trace_filter,
+            trace_format: args.trace_format,
+            wave_read_mode: args.wave_read_mode,
+            debug_markers: args.debug_markers,
+            max_func_cycles: args.max_func_cycles,
+            max_stall_ticks: args.max_stall_ticks,
+            show_progress: !args.quiet,
+            deadline: args
+                .wall_timeout
+                .map(|time| Instant::now() + time.real_time()),
+            pokes,
+            init_regs: args.init_regs.clone(),
+            init_addr,
+            play_addr,
+            uninit_check: !args.no_uninit_check,
+            stub_regs,
+            profile: args.profile.clone(),
+            promotions: args.promote.clone(),
+            watchpoints,
+            read_watchpoints,
+            shadow_addrs: shadows.iter().map(|shadow| shadow.addr).collect(),
+            // Reads are compared between both runs too.
+            log_reads: args.replay_reads || args.verify_determinism,
+        };
+        let mut presets = String::new();
+        if !sim_params.pokes.is_empty() {
+            let pokes: Vec<_> = sim_params
+                .pokes
+                .iter()
+                .map(|(addr, value)| format!("{:04x}={:02x}", addr, value))
+                .collect();
+            presets.push_str(&format!(", pokes: {}", pokes.join(" ")));
+        }
+        if !sim_params.init_regs.is_empty() {
+            presets.push_str(&format!(", INIT registers: {}", sim_params.init_regs));
+        }
+        if let Some(addr) = sim_params.init_addr {
+            presets.push_str(&format!(", INIT at ${:04x}", addr));
+        }
+        if let Some(addr) = sim_params.play_addr {
+            presets.push_str(&format!(", PLAY at ${:04x}", addr));
+        }
+        if !sim_params.stub_regs.is_empty() {
+            let stubs: Vec<_> = sim_params
+                .stub_regs
+                .iter()
+                .map(|(addr, value)| format!("{:04x}={:02x}", addr, value))
+                .collect();
+            presets.push_str(&format!(", stubbed registers: {}", stubs.join(" ")));
+        }
+        let trace_file = args
+            .trace
+            .as_ref()
+            .map(|path| {
+                let mut file = BufWriter::new(File::create(path).map_err(|err| {
+                    eprintln!("Failed to open trace file: {}", err);
+                    Fatal
+                })?);
+                if args.trace_format == TraceFormat::Binary {
+                    run::trace::write_header(&mut file).unwrap_or_else(trace_write_fail);
+                }
+                Ok(file)
+            })
+            .transpose()?;
+
+        if let Some(args_color) = args.color {
+            owo_colors::set_override(args_color)
+        }
+
+        let verbosity = match (args.quiet, args.verbose) {
+            (false, false) => report::Verbosity::Normal,
+            (true, false) => report::Verbosity::Quiet,
+            (false, true) => report::Verbosity::Verbose,
+            (true, true) => {
+                eprintln!(
+                    "{}: --quiet and --verbose cannot be used together",
+                    colorize!(Stderr, "Error", bright_red, bold),
+                );
+                return Err(Fatal);
+            }
+        };
+
+        Ok(Self {
+            sim_params,
+            presets,
+            symbols,
+            sym_warnings,
+            baseline,
+            ignore_regs,
+            shadows,
+            trace_file,
+            verbosity,
+        })
+    }
+}
+
+fn try_run(args: Args, out: &report::Sink) -> Result<i32, Fatal> {
+    let options = Options::new(&args)?;
+    let state_error = if args.at_tick.is_some() && args.at_time.is_some() {
+        Some("--at-tick and --at-time cannot be used together")
+    } else if args.save_state.is_some() != (args.at_tick.is_some() || args.at_time.is_some()) {
+        Some("--save-state requires either --at-tick or --at-time, and vice versa")
+    } else if args.load_state.is_some() && args.replay_reads {
+        Some("--replay-reads cannot be used with --load-state, since the replay starts from INIT")
+    } else {
+        None
+    };
+    if let Some(err) = state_error
+        .map(str::to_string)
+        .or_else(|| stream_conflict(&args))
+    {
+        eprintln!("{}: {}", colorize!(Stderr, "Error", bright_red, bold), err);
+        return Err(Fatal);
+    }
+    if args.who_writes {
+        return find_writers(&args, out, &options);
+    }
+    let Some(after_path) = args.after.as_ref() else {
+        return inspect(&args, out, &options);
+    };
+    if args.before.ends_with(".expect") {
+        return check_expect(&args, after_path, out, &options);
+    }
+    compare::run(&args, after_path, out, options)
+}
+
+/// Reports which code writes to each register, for every song of each file (`--who-writes`).
+fn find_writers(args: &Args, out: &report::Sink, options: &Options) -> Result<i32, Fatal> {
+    let Options {
+        sim_params,
+        presets,
+        symbols,
+        verbosity,
+        ..
+    } = options;
+    let mut reporter = report::TextReporter::new(*verbosity, out);
+    let mut ok = true;
+    let inputs: Vec<_> = std::iter::once(&args.before)
+        .chain(&args.after)
+        .map(|path| Ok((path, read_file(path, &mut reporter)?)))
+        .collect::<Result<_, Fatal>>()?;
+    for &(path, ref data) in &inputs {
+        let gbs = parse_gbs(data, path, args, sim_params, &mut reporter)?;
+        let song_ids = match args.before_song {
+            Some(song_id) => song_id..=song_id,
+            None => gbs.songs(),
+        };
+        for song_id in song_ids {
+            reporter.progress(
+                "Simulating",
+                &format_args!("{} song #{}{}", path, song_id, presets),
+            );
+            match run::simulate_song(&gbs, song_id, sim_params, None, None::<io::Sink>, None) {
+                Ok(log) => {
+                    reporter.heading(&format_args!("{} song #{}", path, song_id));
+                    who_writes::report(
+                        &mut reporter,
+                        &who_writes::aggregate(&log.io_log),
+                        symbols.as_ref(),
+                    );
+                }
+                Err(err) => {
+                    reporter.simulation_failed(path, song_id, &err);
+                    ok = false;
+                }
+            }
+        }
+    }
+    Ok(if ok { 0 } else { 2 })
+}
+
+/// Checks the "after" song against a script of the writes expected of it.
+fn check_expect(
+    args: &Args,
+    after_path: &str,
+    out: &report::Sink,
+    options: &Options,
+) -> Result<i32, Fatal> {
+    let Options {
+        sim_params,
+        presets,
+        symbols,
+        verbosity,
+        ..
+    } = options;
+    let mut reporter = report::TextReporter::new(*verbosity, out);
+    let text = read_text(&args.before)?;
+    let script = expect::parse(&text).map_err(|err| parse_error(&args.before, err))?;
+    let data = read_file(after_path, &mut reporter)?;
+    let gbs = parse_gbs(&data, after_path, args, sim_params, &mut reporter)?;
+    let song_id = args
+        .after_song
+        .or(args.before_song)
+        .unwrap_or(gbs.first_song());
+    let song_ids = SongIDs::AfterOnly(song_id);
+
+    reporter.progress(
+        "Simulating",
+        &format_args!("{} song #{}{}", after_path, song_id, presets),
+    );
+    let log = match run::simulate_song(&gbs, song_id, sim_params, None, None::<io::Sink>, None) {
+        Ok(log) => log,
+        Err(err) => {
+            reporter.simulation_failed(after_path, song_id, &err);
+            return Err(Fatal);
+        }
+    };
+    reporter.heading(&format_args!(
+        "{} song #{} vs {}",
+        after_path, song_id, args.before
+    ));
+    reporter.song_start(&song_ids);
+    let mismatches = expect::check(&script, &log.io_log, log.ticks_simulated);
+    let mut budget = report::Budget::new(args.max_reports, args.max_total_reports);
+    let mut tick = None;
+    for mismatch in &mismatches {
+        let level = match mismatch {
+            expect::Mismatch::NotReached { .. } => DiagnosticLevel::Warning,
+            _ => DiagnosticLevel::Error,
+        };
+        if level > args.max_level || !budget.admit(level) {
+            continue;
+        }
+        match mismatch.write() {
+            Some((when, pc)) => {
+                if tick != Some(when.tick) {
+                    tick = Some(when.tick);
+                    reporter.tick(when.tick);
+                }
+                reporter.diagnostic(level, when.cycle, &sym::Pc(pc, symbols.as_ref()), mismatch);
+            }
+            None if level == DiagnosticLevel::Error => {
+                if tick != Some(mismatch.tick()) {
+                    tick = Some(mismatch.tick());
+                    reporter.tick(mismatch.tick());
+                }
+                reporter.finding(level, mismatch);
+            }
+            None => reporter.finding(level, mismatch),
+        }
+    }
+    budget.report_cuts(&mut reporter);
+
+    let ok = !mismatches
+        .iter()
+        .any(|mismatch| !matches!(mismatch, expect::Mismatch::NotReached { .. }));
+    reporter.song_end(&song_ids, ok, None);
+    Ok(if ok { 0 } else { 1 })
+}
+
+/// FNV-1a, which is deterministic across platforms and Rust versions.
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn report_context(reporter: &mut dyn Reporter, accesses: &[&run::IoAccess]) {
+    for access in accesses {
+        reporter.context(
+            access.when.cycle,
+            &format_args!("{} = ${:02x}", diff::RegDispl(access.addr), access.data),
+        );
+    }
+}
+
+/// Why `--stream` can't be used with the other options, if it can't.
+fn stream_conflict(args: &Args) -> Option<String> {
+    if !args.stream {
+        return None;
+    }
+    if args.after.is_none() || args.who_writes || args.before.ends_with(".expect") {
+        return Some("--stream only applies to comparing two GBS files".to_string());
+    }
+    // Those need the whole songs' logs, or run the simulation differently.
+    let conflicts = [
+        (args.verify_determinism, "--verify-determinism"),
+        (args.cache_dir.is_some(), "--cache-dir"),
+        (args.save_state.is_some(), "--save-state"),
+        (args.load_state.is_some(), "--load-state"),
+        (args.trace.is_some(), "--trace"),
+        (args.replay_reads, "--replay-reads"),
+        (args.from.is_some(), "--from"),
+        (args.to.is_some(), "--to"),
+        (args.skip_start.is_some(), "--skip-start"),
+        (args.skip_end.is_some(), "--skip-end"),
+        (args.skip_ticks != 0, "--skip-ticks"),
+        (args.skip_init, "--skip-init"),
+        (args.show_waves, "--show-waves"),
+        (!args.show_tick.is_empty(), "--show-tick"),
+        (args.normalize_time, "--normalize-time"),
+        (
+            !args.focus_bank.is_empty() || !args.focus_pc.is_empty(),
+            "--focus-bank or --focus-pc",
+        ),
+        (
+            args.debug_markers == DebugMarkers::Anchor,
+            "--debug-markers anchor",
+        ),
+        (args.suppress_drift_cascade, "--suppress-drift-cascade"),
+        (args.compare != CompareMode::Writes, "--compare"),
+        (args.group_by != GroupBy::Time, "--group-by"),
+        (!args.shadow.is_empty(), "--shadow"),
+        (args.html.is_some(), "--html"),
+        (args.markdown.is_some(), "--markdown"),
+        (args.render.is_some(), "--render"),
+        (args.csv.is_some(), "--csv"),
+        (args.unified.is_some(), "--unified"),
+        (args.explain, "--explain"),
+        (args.context != 0, "--context"),
+        (
+            args.baseline.is_some() || args.write_baseline.is_some(),
+            "--baseline or --write-baseline",
+        ),
+        (args.stat, "--stat"),
+    ];
+    conflicts
+        .iter()
+        .find(|(conflicts, _)| *conflicts)
+        .map(|(_, option)| format!("--stream cannot be used with {}", option))
+}
+
+/// What became of a pair of songs compared with `--stream`.
+struct Streamed {
+    ok: bool,
+    /// Whether only the INIT sequence made it fail.
+    init_only: bool,
+    fingerprint: Option<fingerprint::Fingerprint>,
+    nb_diagnostics: usize,
+}
+
+/// Compares a pair of songs with `--stream`: both are simulated side by side, and each tick's
+/// writes are diffed and forgotten as soon as the next tick has been simulated, so that memory use
+/// doesn't grow with the songs' length. Returns `None` if either song failed to simulate, which
+/// has been reported already.
+#[allow(clippy::too_many_arguments)]
+fn compare_streamed(
+    args: &Args,
+    sim_params: &run::SimParams,
+    gbs: (&Gbs, &Gbs),
+    paths: (&str, &str),
+    song_ids: (u8, u8),
+    ignore_regs: &[u16],
+    mut srams: Option<(&mut run::Sram, &mut run::Sram)>,
+    reporter: &mut dyn Reporter,
+    symbols: Option<&sym::Symbols>,
+    stats: &mut throughput::RunStats,
+    init_outcomes: &mut (InitOutcomes, InitOutcomes),
+) -> Option<Streamed> {
+    let mut hooks = ((), ());
+    let mut times = (Duration::ZERO, Duration::ZERO);
+    let start = Instant::now();
+    let before = run::SongSimulation::new(
+        gbs.0,
+        song_ids.0,
+        sim_params,
+        None,
+        None,
+        srams.as_ref().map(|srams| &*srams.0),
+        &mut hooks.0,
+    );
+    times.0 += start.elapsed();
+    let start = Instant::now();
+    let after = run::SongSimulation::new(
+        gbs.1,
+        song_ids.1,
+        sim_params,
+        None,
+        None,
+        srams.as_ref().map(|srams| &*srams.1),
+        &mut hooks.1,
+    );
+    times.1 += start.elapsed();
+    let mut sims = match (before, after) {
+        (Ok(before), Ok(after)) => (before, after),
+        (before, after) => {
+            for (failure, outcomes, path, song_id) in [
+                (before.err(), &mut init_outcomes.0, paths.0, song_ids.0),
+                (after.err(), &mut init_outcomes.1, paths.1, song_ids.1),
+            ] {
+                outcomes.record(song_id, failure.as_ref(), args.early_init_cycles);
+                if let Some(failure) = failure {
+                    reporter.simulation_failed(path, song_id, &failure);
+                }
+            }
+            return None;
+        }
+    };
+
+    let mut lockstep = stream::Lockstep::new(stream::DiffParams {
+        jitter: args.jitter,
+        song_wide_div_phase: args.div_phase_tolerance,
+        slip_window: args.slip_window,
+        cadence: gbs.0.cadence(),
+        strict_values: args.strict_values,
+        relax_duty: args.relax_duty,
+    });
+    // The diagnostics of the printed side that wait for the differences of the same ticks.
+    let mut sim_diags = Vec::new();
+    let mut report = StreamReport {
+        args,
+        symbols,
+        budget: report::Budget::new(args.max_reports, args.max_total_reports),
+        phases: report::PhaseTally::default(),
+        fingerprinter: fingerprint::Fingerprinter::default(),
+        first_difference: None,
+        tick: None,
+        ok: true,
+        nb_diagnostics: 0,
+    };
+    let is_printed = |side| match args.print_diagnostics {
+        BeforeOrAfter::Before => side == 0,
+        BeforeOrAfter::After => side == 1,
+        BeforeOrAfter::None => false,
+    };
+    // Takes a side's diagnostics out of its logs; its writes have been diffed already.
+    macro_rules! take_diagnostics {
+        ($logs:expr, $side:expr) => {{
+            let logs = $logs;
+            for diag in &logs.diagnostics {
+                if diag.level == DiagnosticLevel::Error {
+                    report.ok = false;
+                    report.phases.fail(Phase::of(diag.when.tick));
+                }
+            }
+            if is_printed($side) {
+                sim_diags.extend(logs.diagnostics);
+                // Stalls are only found once they end, after the ticks they started on.
+                sim_diags.sort_by_key(|diag| diag.when.tick);
+            }
+        }};
+    }
+    let kept = |access: &&run::IoAccess| !ignore_regs.contains(&access.addr);
+    // INIT's writes.
+    for (sim, side) in [(&mut sims.0, 0), (&mut sims.1, 1)] {
+        let logs = sim.take_logs();
+        if side == 0 {
+            lockstep.push_before(logs.io_log.iter().filter(kept));
+        } else {
+            lockstep.push_after(logs.io_log.iter().filter(kept));
+        }
+        take_diagnostics!(logs, side);
+    }
+    let mut nb_writes = (lockstep.len_before(), lockstep.len_after());
+
+    let mut tick = 0;
+    let mut failure = None;
+    // Runs a tick of one side, handing its writes over to be diffed.
+    macro_rules! step {
+        ($sim:expr, $side:tt, $push:ident, $len:ident) => {{
+            let start = Instant::now();
+            let held = lockstep.$len();
+            match $sim.run_tick() {
+                Ok(writes) => lockstep.$push(writes.iter().filter(kept)),
+                Err(err) => {
+                    failure = Some(($side, err));
+                    break;
+                }
+            }
+            nb_writes.$side += lockstep.$len() - held;
+            take_diagnostics!($sim.take_logs(), $side);
+            times.$side += start.elapsed();
+        }};
+    }
+    while sims.0.termination().is_none() || sims.1.termination().is_none() {
+        tick += 1;
+        step!(sims.0, 0, push_before, len_before);
+        step!(sims.1, 1, push_after, len_after);
+
+        // A side that is over has pushed all of its ticks.
+        let diff_diags = lockstep.diff_through(tick);
+        let settled = sim_diags.partition_point(|diag: &Diagnostic<run::DiagnosticKind>| {
+            diag.when.tick < lockstep.settled_ticks()
+        });
+        report.merged(reporter, sim_diags.drain(..settled), diff_diags);
+    }
+    if let Some((side, err)) = failure {
+        let (outcomes, path, song_id) = if side == 0 {
+            (&mut init_outcomes.0, paths.0, song_ids.0)
+        } else {
+            (&mut init_outcomes.1, paths.1, song_ids.1)
+        };
+        let failure = run::Failure::from(err);
+        outcomes.record(song_id, Some(&failure), args.early_init_cycles);
+        reporter.simulation_failed(path, song_id, &failure);
+        return None;
+    }
+    report.merged(reporter, sim_diags.drain(..), lockstep.finish());
+
+    if let Some(srams) = &mut srams {
+        *srams.0 = *sims.0.sram();
+        *srams.1 = *sims.1.sram();
+    }
+    let logs = (sims.0.finish(), sims.1.finish());
+    // The final stall, if any, is the only diagnostic left.
+    let last_diags = match args.print_diagnostics {
+        BeforeOrAfter::Before => logs.0.diagnostics.clone(),
+        BeforeOrAfter::After => logs.1.diagnostics.clone(),
+        BeforeOrAfter::None => Vec::new(),
+    };
+    for logbook in [&logs.0, &logs.1] {
+        for diag in &logbook.diagnostics {
+            if diag.level == DiagnosticLevel::Error {
+                report.ok = false;
+                report.phases.fail(Phase::of(diag.when.tick));
+            }
+        }
+        for tick in unrecorded_error_ticks(logbook) {
+            report.ok = false;
+            report.phases.fail(Phase::of(tick));
+        }
+    }
+    report.merged(reporter, last_diags.into_iter(), Vec::new());
+
+    for (logbook, gbs, path, outcomes, song_id, side_time, side_writes, side_stats) in [
+        (
+            &logs.0,
+            gbs.0,
+            paths.0,
+            &mut init_outcomes.0,
+            song_ids.0,
+            times.0,
+            nb_writes.0,
+            &mut stats.before,
+        ),
+        (
+            &logs.1,
+            gbs.1,
+            paths.1,
+            &mut init_outcomes.1,
+            song_ids.1,
+            times.1,
+            nb_writes.1,
+            &mut stats.after,
+        ),
+    ] {
+        outcomes.record(song_id, None, args.early_init_cycles);
+        side_stats.add(
+            logbook,
+            ticks_to_secs(logbook.ticks_simulated, gbs),
+            side_time,
+        );
+        // The logbook's writes were taken out as the song went.
+        side_stats.nb_writes += side_writes;
+        reporter.info(&format_args!(
+            "{}: song ran for {} ticks ({}) and ended due to {}",
+            path,
+            logbook.ticks_simulated,
+            format_secs(ticks_to_secs(logbook.ticks_simulated, gbs)),
+            logbook.termination,
+        ));
+    }
+    // Measured in the "before" file's ticks, in case the two don't tick at the same rate.
+    let after_length = (ticks_to_secs(logs.1.ticks_simulated, gbs.1) * cycles_per_sec(gbs.0) as f64
+        / f64::from(gbs.0.cycles_per_tick()))
+    .round() as u64;
+    let length_diff = logs.0.ticks_simulated.abs_diff(after_length);
+    if length_diff > args.length_tolerance && DiagnosticLevel::Warning <= args.max_level {
+        report.ok = false;
+        report.phases.fail(Phase::Play);
+        if report.budget.admit(DiagnosticLevel::Warning) {
+            reporter.finding(
+                DiagnosticLevel::Warning,
+                &format_args!(
+                    "the songs' lengths differ by {} ticks ({} before, {} after)",
+                    length_diff,
+                    format_secs(ticks_to_secs(logs.0.ticks_simulated, gbs.0)),
+                    format_secs(ticks_to_secs(logs.1.ticks_simulated, gbs.1)),
+                ),
+            );
+        }
+    }
+    match args.print_diagnostics {
+        BeforeOrAfter::Before => report_unrecorded(reporter, &logs.0.unrecorded_within(|_| true)),
+        BeforeOrAfter::After => report_unrecorded(reporter, &logs.1.unrecorded_within(|_| true)),
+        BeforeOrAfter::None => {}
+    }
+    report.budget.report_cuts(reporter);
+
+    if let Some(tick) = report.first_difference {
+        reporter.first_difference(tick);
+    }
+    if !report.ok {
+        report.phases.report(reporter);
+    }
+    let fingerprint = report.fingerprinter.finish();
+    if let Some(fingerprint) = &fingerprint {
+        reporter.fingerprint(fingerprint);
+    }
+    Some(Streamed {
+        ok: report.ok,
+        init_only: report.phases.init_only(),
+        fingerprint,
+        nb_diagnostics: report.nb_diagnostics,
+    })
+}
+
+/// The reporting state of a song compared with `--stream`, which is fed a few ticks at a time.
+struct StreamReport<'a> {
+    args: &'a Args,
+    symbols: Option<&'a sym::Symbols>,
+    budget: report::Budget,
+    phases: report::PhaseTally,
+    fingerprinter: fingerprint::Fingerprinter,
+    first_difference: Option<u64>,
+    /// The tick of the last diagnostic printed.
+    tick: Option<u64>,
+    ok: bool,
+    nb_diagnostics: usize,
+}
+
+impl StreamReport<'_> {
+    /// Reports simulation diagnostics and write differences, each in chronological order,
+    /// simulation diagnostics first within a cycle.
+    fn merged(
+        &mut self,
+        reporter: &mut dyn Reporter,
+        sim_diags: impl Iterator<Item = Diagnostic<run::DiagnosticKind>>,
+        diff_diags: Vec<Diagnostic<diff::DiagnosticKind>>,
+    ) {
+        let diff_diags = diff_diags
+            .into_iter()
+            .filter(|diag| diag.level <= self.args.max_level);
+        for diagnostic in merge::merge(
+            sim_diags.filter(|diag| diag.level <= self.args.max_level),
+            diff_diags,
+            |diag| diag.when.clone(),
+            |diag| diag.when.clone(),
+        ) {
+            match diagnostic {
+                merge::Either::Left(diag) => self.report(reporter, &diag),
+                merge::Either::Right(diag) => {
+                    self.first_difference.get_or_insert(diag.when.tick);
+                    self.ok = false;
+                    self.phases.fail(Phase::of(diag.when.tick));
+                    self.fingerprinter.add(&diag.kind);
+                    self.report(reporter, &diag);
+                }
+            }
+        }
+    }
+
+    /// Prints a diagnostic if the budget allows, after its tick's header if it is the first of
+    /// that tick to be.
+    fn report<K: Display>(&mut self, reporter: &mut dyn Reporter, diag: &Diagnostic<K>) {
+        self.nb_diagnostics += 1;
+        self.phases.count(diag.when.tick, diag.level);
+        if self.budget.admit(diag.level) {
+            if self.tick != Some(diag.when.tick) {
+                self.tick = Some(diag.when.tick);
+                reporter.tick(diag.when.tick);
+            }
+            reporter.diagnostic(
+                diag.level,
+                diag.when.cycle,
+                &sym::Pc(&diag.pc, self.symbols),
+                &diag.kind,
+            );
+        }
+    }
+}
+
+/// Simulates each song of a single file, without comparing it to anything.
+fn inspect(args: &Args, out: &report::Sink, options: &Options) -> Result<i32, Fatal> {
+    let Options {
+        sim_params,
+        presets,
+        symbols,
+        sym_warnings,
+        verbosity,
+        ..
+    } = options;
+    let symbols = symbols.as_ref();
+    let mut reporter = report::TextReporter {
+        verbosity: *verbosity,
+        out: report::Output::new(out, args.pager, args.spill_threshold),
+    };
+    let path = &args.before;
+    if path.ends_with(".expect") {
+        eprintln!(
+            "{}: {} is a script of expected writes, but no GBS file was given to check against it",
+            colorize!(Stderr, "Error", bright_red, bold),
+            path
+        );
+        return Err(Fatal);
+    }
+    for warning in sym_warnings {
+        reporter.warning(warning);
+    }
+    let data = read_file(path, &mut reporter)?;
+    let gbs = parse_gbs(&data, path, args, sim_params, &mut reporter)?;
+    let song_ids = match args.before_song {
+        Some(song_id) => song_id..=song_id,
+        None => gbs.songs(),
+    };
+
+    // One line per song, printed once they have all run.
+    let mut summary = Vec::new();
+    let mut failed = Vec::new();
+    for song_id in song_ids {
+        let song_ids = SongIDs::BeforeOnly(song_id);
+        reporter.progress(
+            "Simulating",
+            &format_args!("{} song #{}{}", path, song_id, presets),
+        );
+        reporter.heading(&format_args!("{} song #{}", path, song_id));
+        let logs = match run::simulate_song(&gbs, song_id, sim_params, None, None::<io::Sink>, None)
+        {
+            Ok(logs) => logs,
+            Err(err) => {
+                reporter.simulation_failed(path, song_id, &err);
+                summary.push(format!("#{}: simulation failed: {}", song_id, err));
+                failed.push(song_id);
+                continue;
+            }
+        };
+        reporter.song_start(&song_ids);
+        report_sim_diagnostics(&mut reporter, &logs, args, symbols);
+
+        let ok = !fails_at(&logs, args.fail_on);
+        reporter.song_end(&song_ids, ok, None);
+        if !ok {
+            failed.push(song_id);
+        }
+        let count = |level| {
+            logs.diagnostics
+                .iter()
+                .filter(|diag| diag.level == level)
+                .count()
+                + logs
+                    .unrecorded_within(|_| true)
+                    .iter()
+                    .filter(|((_, unrecorded), _)| *unrecorded == level)
+                    .map(|(_, count)| count)
+                    .sum::<usize>()
+        };
+        summary.push(format!(
+            "#{}: ran for {} ticks ({}), ended due to {}; {} errors, {} warnings, {} notes",
+            song_id,
+            logs.ticks_simulated,
+            format_secs(ticks_to_secs(logs.ticks_simulated, &gbs)),
+            logs.termination,
+            count(DiagnosticLevel::Error),
+            count(DiagnosticLevel::Warning),
+            count(DiagnosticLevel::Note),
+        ));
+    }
+
+    reporter.heading(&format_args!("Songs of {}", path));
+    for line in &summary {
+        reporter.line(line);
+    }
+    if failed.is_empty() {
+        reporter.line(&colorize!(
+            Stdout,
+            "All songs simulated cleanly!",
+            bright_green,
+            bold
+        ));
+        Ok(0)
+    } else {
+        let failed: Vec<_> = failed.iter().map(u8::to_string).collect();
+        reporter.line(&format_args!(
+            "{}: {}",
+            colorize!(Stdout, "Failing songs", bright_red, bold),
+            failed.join(", ")
+        ));
+        Ok(1)
+    }
+}
+
+/// Prints a song's simulation diagnostics, grouped by tick.
+fn report_sim_diagnostics(
+    reporter: &mut dyn Reporter,
+    logs: &run::Logbook,
+    args: &Args,
+    symbols: Option<&sym::Symbols>,
+) {
+    let mut budget = report::Budget::new(args.max_reports, args.max_total_reports);
+    let mut tick = None;
+    for diag in logs
+        .diagnostics
+        .iter()
+        .filter(|diag| diag.level <= args.max_level)
+    {
+        if budget.admit(diag.level) {
+            if tick != Some(diag.when.tick) {
+                tick = Some(diag.when.tick);
+                reporter.tick(diag.when.tick);
+            }
+            reporter.diagnostic(
+                diag.level,
+                diag.when.cycle,
+                &sym::Pc(&diag.pc, symbols),
+                &diag.kind,
+            );
+        }
+    }
+    report_unrecorded(reporter, &logs.unrecorded_within(|_| true));
+    budget.report_cuts(reporter);
+}
+
+/// Whether a song has simulation diagnostics of `level` or more severe, recorded or not.
+fn fails_at(logs: &run::Logbook, level: DiagnosticLevel) -> bool {
+    logs.diagnostics.iter().any(|diag| diag.level <= level)
+        || logs
+            .unrecorded
+            .keys()
+            .any(|(_, unrecorded)| *unrecorded <= level)
+}
+
+/// Those never went through the budget, so they are accounted for separately.
+fn report_unrecorded(
+    reporter: &mut dyn Reporter,
+    unrecorded: &BTreeMap<(&'static str, DiagnosticLevel), usize>,
+) {
+    for (&(kind, level), &count) in unrecorded {
+        reporter.cut(level, count);
+        reporter.line(&format_args!(
+            "...and {} further {} {}s were not recorded (see --max-recorded-diagnostics)",
+            count,
+            kind,
+            level.name().to_lowercase()
+        ));
+    }
+}
+
+/// Whether `--baseline` lists that difference, which is then no longer expected; either way, it is
+/// recorded for `--write-baseline`.
+fn known_difference(
+    args: &Args,
+    baseline: &mut Option<baseline::Baseline>,
+    new_baseline: &mut Vec<(baseline::BaselineKey, String)>,
+    song: u8,
+    diag: &Diagnostic<diff::DiagnosticKind>,
+) -> bool {
+    if args.baseline.is_none() && args.write_baseline.is_none() {
+        return false;
+    }
+    let key = baseline::BaselineKey::new(song, diag);
+    let known = baseline
+        .as_mut()
+        .is_some_and(|baseline| baseline.take(&key));
+    if args.write_baseline.is_some() {
+        new_baseline.push((key, diag.kind.to_string()));
+    }
+    known
+}
+
+/// The ticks during which errors went unrecorded.
+fn unrecorded_error_ticks(logbook: &run::Logbook) -> impl Iterator<Item = u64> + '_ {
+    logbook
+        .unrecorded
+        .iter()
+        .filter(|((_, level), _)| *level == DiagnosticLevel::Error)
+        .flat_map(|(_, ticks)| ticks.iter().map(|&(tick, _)| tick))
+}
+
+fn trace_write_fail(err: io::Error) {
+    eprintln!("Failed to write to trace file: {}", err);
+    report::exit(2);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic<K> {
+    when: Timestamp,
+    pc: Address,
+    level: DiagnosticLevel,
+    kind: K,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DiagnosticLevel {
+    Error,
+    Warning,
+    Note,
+}
+
+impl DiagnosticLevel {
+    /// In the same order as the discriminants.
+    const ALL: [Self; 3] = [Self::Error, Self::Warning, Self::Note];
+
+    /// Without any coloring.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Error => "Error",
+            Self::Warning => "Warning",
+            Self::Note => "Note",
+        }
+    }
+}
+
+impl FromStr for DiagnosticLevel {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("error") {
+            Ok(Self::Error)
+        } else if s.eq_ignore_ascii_case("warning") {
+            Ok(Self::Warning)
+        } else if s.eq_ignore_ascii_case("note") {
+            Ok(Self::Note)
+        } else {
+            Err("unknown diagnostic level")
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompareMode {
+    Writes,
+    State,
+    Both,
+}
+
+impl FromStr for CompareMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("writes") {
+            Ok(Self::Writes)
+        } else if s.eq_ignore_ascii_case("state") {
+            Ok(Self::State)
+        } else if s.eq_ignore_ascii_case("both") {
+            Ok(Self::Both)
+        } else {
+            Err("must be one of \"writes\", \"state\", or \"both\"")
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GroupBy {
+    Time,
+    Channel,
+}
+
+impl FromStr for GroupBy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("time") {
+            Ok(Self::Time)
+        } else if s.eq_ignore_ascii_case("channel") {
+            Ok(Self::Channel)
+        } else {
+            Err("must be either \"time\" or \"channel\"")
+        }
+    }
+}
+
+#[derive(Debug)]
+enum BeforeOrAfter {
+    Before,
+    After,
+    None,
+}
+
+impl FromStr for BeforeOrAfter {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("before") {
+            Ok(Self::Before)
+        } else if s.eq_ignore_ascii_case("after") {
+            Ok(Self::After)
+        } else if s.eq_ignore_ascii_case("none") {
+            Ok(Self::None)
+        } else {
+            Err("must be either \"before\" or \"after\"")
+        }
+    }
+}
+
+fn parse_addr_value_arg(arg: &str) -> Result<(AddrArg, u8), String> {
+    let (addr, value) = arg
+        .split_once('=')
+        .ok_or_else(|| "expected \"ADDR=VALUE\", e.g. \"CAFE=2A\"".to_string())?;
+    Ok((
+        addr.parse()
+            .map_err(|err| format!("invalid address: {}", err))?,
+        u8::from_str_radix(value.trim(), 16).map_err(|err| format!("invalid value: {}", err))?,
+    ))
+}
+
+/// Symbols can only be checked once they have been resolved.
+fn parse_stub_reg_arg(arg: &str) -> Result<(AddrArg, u8), String> {
+    let (addr, value) = parse_addr_value_arg(arg)?;
+    if let AddrArg::Addr(addr) = addr {
+        if !(0xFF00..=0xFF7F).contains(&addr) {
+            return Err(format!("${:04x} is not an I/O register", addr));
+        }
+    }
+    Ok((addr, value))
+}
+
+fn parse_tick_pattern(arg: &str) -> Result<Vec<u32>, String> {
+    if arg.trim().is_empty() {
+        return Err("the tick pattern cannot be empty".into());
+    }
+    let pattern = arg
+        .split(',')
+        .map(|cycles| {
+            let cycles = cycles.trim();
+            match cycles.parse() {
+                Ok(0) => Err("ticks cannot last 0 cycles".to_string()),
+                Ok(cycles) => Ok(cycles),
+                Err(err) => Err(format!("bad cycle count {:?}: {}", cycles, err)),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(pattern)
+}
+
+fn parse_shadow_arg(arg: &str) -> Result<(AddrArg, AddrArg), String> {
+    let (addr, reg) = arg
+        .split_once(':')
+        .ok_or_else(|| "expected \"ADDR:REG\", e.g. \"FF90:NR12\"".to_string())?;
+    Ok((
+        addr.parse()
+            .map_err(|err| format!("invalid address: {}", err))?,
+        parse_reg_arg(reg).map_err(|err| format!("invalid register: {}", err))?,
+    ))
+}
+
+/// Parses either a register's name (as displayed in diagnostics), its address in hex, or a symbol.
+fn parse_reg_arg(arg: &str) -> Result<AddrArg, String> {
+    let arg = arg.trim();
+    regs::parse(arg).map_or_else(
+        || arg.strip_prefix('$').unwrap_or(arg).parse(),
+        |addr| Ok(AddrArg::Addr(addr)),
+    )
+}
+
+/// A length of time given on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimeArg {
+    Millis(u64),
+    /// Taken as-is, regardless of the file's CPU speed.
+    Cycles(u64),
+}
+
+impl TimeArg {
+    /// `None` if that doesn't fit in a `u64`.
+    fn cycles(self, cycles_per_sec: u64) -> Option<u64> {
+        match self {
+            Self::Millis(millis) => millis.checked_mul(cycles_per_sec).map(|total| total / 1000),
+            Self::Cycles(cycles) => Some(cycles),
+        }
+    }
+
+    /// Cycles are converted at single speed.
+    fn real_time(self) -> Duration {
+        match self {
+            Self::Millis(millis) => Duration::from_millis(millis),
+            Self::Cycles(cycles) => {
+                Duration::from_secs_f64(cycles as f64 / f64::from(CYCLES_PER_SEC))
+            }
+        }
+    }
+}
+
+/// Accepts plain seconds, `M:SS`, exact cycles like `123456c`, or a combination of `m`, `s`, and
+/// `ms` units from largest to smallest, like `1m30s`.
+fn parse_duration_arg(arg: &str) -> Result<TimeArg, String> {
+    const UNITS: [(&str, u64); 3] = [("m", 60_000), ("s", 1000), ("ms", 1)];
+
+    let arg = arg.trim();
+    let number = |num: &str| {
+        num.parse::<u64>()
+            .map_err(|err| format!("invalid duration {:?}: {}", arg, err))
+    };
+    let too_long = || format!("duration {:?} is too long", arg);
+    if arg.starts_with('-') {
+        return Err("durations cannot be negative".to_string());
+    }
+    if arg.contains(['.', ',']) {
+        return Err(format!(
+            "invalid duration {:?}: fractions are not supported, use a smaller unit instead (e.g. `2500ms` rather than `2.5s`)",
+            arg
+        ));
+    }
+
+    if let Some(cycles) = arg.strip_suffix('c') {
+        return number(cycles).map(TimeArg::Cycles);
+    }
+    if let Some((minutes, seconds)) = arg.split_once(':') {
+        let seconds = number(seconds)?;
+        if seconds >= 60 {
+            return Err("expected seconds to be less than 60 in \"MM:SS\"".to_string());
+        }
+        return number(minutes)?
+            .checked_mul(60)
+            .and_then(|minutes| minutes.checked_add(seconds))
+            .and_then(|secs| secs.checked_mul(1000))
+            .map(TimeArg::Millis)
+            .ok_or_else(too_long);
+    }
+    if arg.bytes().all(|c| c.is_ascii_digit()) {
+        return number(arg)?
+            .checked_mul(1000)
+            .map(TimeArg::Millis)
+            .ok_or_else(too_long);
+    }
+
+    let mut rest = arg;
+    let mut millis = 0u64;
+    // Units must be in decreasing order, so each one is only allowed once.
+    let mut min_unit = 0;
+    while !rest.is_empty() {
+        let (num, tail) = rest.split_at(
+            rest.find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len()),
+        );
+        let (unit, tail) = tail.split_at(
+            tail.find(|c: char| c.is_ascii_digit())
+                .unwrap_or(tail.len()),
+        );
+        let Some(i) = UNITS.iter().position(|(name, _)| *name == unit) else {
+            return Err(format!(
+                "invalid duration {:?}: unknown unit {:?} (expected `m`, `s`, `ms`, or a lone `c`)",
+                arg, unit
+            ));
+        };
+        if num.is_empty() {
+            return Err(format!(
+                "invalid duration {:?}: missing number before `{}`",
+                arg, unit
+            ));
+        }
+        if i < min_unit {
+            return Err(format!(
+                "invalid duration {:?}: units must go from largest to smallest, each once",
+                arg
+            ));
+        }
+        min_unit = i + 1;
+        millis = number(num)?
+            .checked_mul(UNITS[i].1)
+            .and_then(|part| millis.checked_add(part))
+            .ok_or_else(too_long)?;
+        rest = tail;
+    }
+    Ok(TimeArg::Millis(millis))
+}
+
+/// What a `-` path argument becomes; it doubles as its name in messages.
+const STDIN_PATH: &str = "<stdin>";
+
+/// Like [`argh::from_env`], but lets `-` through as a path, since argh takes it for an option.
+/// How well each part of the GBS format is supported.
+const GBS_FEATURES: [(&str, &str); 7] = [
+    ("GBS version 1 header", "yes"),
+    (
+        "other GBS versions",
+        "parsed as version 1, extensions ignored",
+    ),
+    ("VBlank-driven PLAY", "yes"),
+    (
+        "timer-driven PLAY",
+        "yes (tick length only, DIV and TIMA are not simulated)",
+    ),
+    ("CGB double speed", "yes (tick length only)"),
+    ("ROM bank switching ($2000-$3FFF writes)", "yes"),
+    ("interrupts", "no (INIT and PLAY are called directly)"),
+];
+
+fn print_version() {
+    println!(
+        "{} {} (git {})",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        env!("GBSDIFF_GIT_HASH"),
+    );
+    println!(
+        "Built for {}-{}, {} profile",
+        std::env::consts::ARCH,
+        std::env::consts::OS,
+        if cfg!(debug_assertions) {
+            "debug"
+        } else {
+            "release"
+        },
+    );
+    println!("Input formats: GBS, gzipped GBS");
+    println!("GBS features:");
+    for (feature, support) in GBS_FEATURES {
+        println!("    {}: {}", feature, support);
+    }
+}
+
+/// Returns the exit code.
+fn decode_trace(path: &str) -> i32 {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!(
+                "{}: Failed to open {}: {}",
+                colorize!(Stderr, "Error", bright_red, bold),
+                path,
+                err
+            );
+            return 2;
+        }
+    };
+    match run::trace::decode(BufReader::new(file), BufWriter::new(io::stdout().lock())) {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!(
+                "{}: Failed to decode {}: {}",
+                colorize!(Stderr, "Error", bright_red, bold),
+                path,
+                err
+            );
+            2
+        }
+    }
+}
+
+/// Runs each of the manifest's comparisons with the options shared on the command line, and sums
+/// them all up at the end; the exit code is the worst of theirs.
+fn run_manifest(cmd: &str, path: &str, shared: &[&str], out: &report::Sink) -> Result<i32, Fatal> {
+    let text = read_text(path)?;
+    let mut entries = manifest::parse(&text).map_err(|err| parse_error(path, err))?;
+    manifest::resolve_paths(&mut entries, path);
+    let missing = manifest::missing_files(&entries);
+    for err in &missing {
+        eprintln!(
+            "{}: {}: {}",
+            colorize!(Stderr, "Error", bright_red, bold),
+            path,
+            err
+        );
+    }
+    if !missing.is_empty() {
+        return Err(Fatal);
+    }
+
+    let mut results = Vec::new();
+    for comparison in manifest::comparisons(&entries) {
+        let entry = comparison.entry;
+        let mut strs = shared.to_vec();
+        strs.extend([entry.before.as_str(), entry.after.as_str()]);
+        let mut args = match Args::from_args(&[cmd], &strs) {
+            Ok(args) => args,
+            Err(early_exit) if early_exit.status.is_ok() => {
+                out.line(&early_exit.output);
+                return Ok(0);
+            }
+            Err(early_exit) => {
+                eprintln!(
+                    "{}\nRun {} --help for more information.",
+                    early_exit.output, cmd
+                );
+                return Ok(1);
+            }
+        };
+        comparison.merge_into(&mut args);
+
+        out.line(&format_args!(
+            "{} {}: {} vs {}",
+            colorize!(Stdout, "###", bold),
+            comparison.label(),
+            entry.before,
+            entry.after
+        ));
+        results.push((comparison.label(), run(args, out)));
+    }
+
+    out.line(&format_args!(
+        "{} Manifest summary:",
+        colorize!(Stdout, "==>", bold)
+    ));
+    let (rows, worst) = manifest::summary(&results);
+    for (label, code) in rows {
+        let outcome = manifest::outcome(code);
+        if code == 0 {
+            out.line(&format_args!(
+                "    {}  {}",
+                label,
+                colorize!(Stdout, outcome, bright_green, bold)
+            ));
+        } else {
+            out.line(&format_args!(
+                "    {}  {}",
+                label,
+                colorize!(Stdout, outcome, bright_red, bold)
+            ));
+        }
+    }
+    Ok(worst)
+}
+
+fn parse_args() -> Args {
+    let strings: Vec<String> = std::env::args_os()
+        .map(|s| s.into_string())
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|arg| {
+            eprintln!("Invalid utf8: {}", arg.to_string_lossy());
+            std::process::exit(1)
+        });
+    let Some((cmd, strings)) = strings.split_first() else {
+        eprintln!("No program name, argv is empty");
+        std::process::exit(1)
+    };
+    let cmd = std::path::Path::new(cmd)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(cmd);
+
+    let mut options = strings
+        .iter()
+        .enumerate()
+        .take_while(|(_, arg)| *arg != "--");
+    while let Some((i, arg)) = options.next() {
+        match arg.as_str() {
+            "--version" => {
+                print_version();
+                std::process::exit(0);
+            }
+            "--self-test" => std::process::exit(if self_test::run() { 0 } else { 1 }),
+            "--trace-decode" => {
+                let Some((_, path)) = options.next() else {
+                    eprintln!("Missing value for option '--trace-decode'.");
+                    std::process::exit(1)
+                };
+                std::process::exit(decode_trace(path));
+            }
+            "--manifest" => {
+                let Some((_, path)) = options.next() else {
+                    eprintln!("Missing value for option '--manifest'.");
+                    std::process::exit(1)
+                };
+                let shared = manifest::shared_args(strings, i);
+                std::process::exit(
+                    run_manifest(cmd, path, &shared, &report::Sink::stdout()).unwrap_or(2),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    let mut options_ended = false;
+    let strs: Vec<&str> = strings
+        .iter()
+        .map(|arg| {
+            options_ended |= arg == "--";
+            if arg == "-" && !options_ended {
+                STDIN_PATH
+            } else {
+                arg.as_str()
+            }
+        })
+        .collect();
+    let args = Args::from_args(&[cmd], &strs).unwrap_or_else(|early_exit| {
+        std::process::exit(match early_exit.status {
+            Ok(()) => {
+                println!("{}", early_exit.output);
+                0
+            }
+            Err(()) => {
+                eprintln!(
+                    "{}\nRun {} --help for more information.",
+                    early_exit.output, cmd
+                );
+                1
+            }
+        })
+    });
+    args
+}
+
+/// Fails if the file is not a valid GBS, and warns about anything unusual in its header.
+fn parse_gbs<'a>(
+    data: &'a [u8],
+    path: &str,
+    args: &'a Args,
+    params: &run::SimParams,
+    reporter: &mut dyn Reporter,
+) -> Result<Gbs<'a>, Fatal> {
+    let gbs = Gbs::new(data).map_err(|err| parse_error(path, err))?;
+    let mut gbs = gbs.with_tick_pattern(args.tick_pattern.as_deref().unwrap_or_default());
+    for (kind, addr) in [
+        (gbs::AddressKind::Init, params.init_addr),
+        (gbs::AddressKind::Play, params.play_addr),
+    ] {
+        if let Some(addr) = addr {
+            gbs = gbs.with_entry(kind, addr).map_err(|err| {
+                eprintln!(
+                    "{}: --{}-addr: {}: {}",
+                    colorize!(Stderr, "Error", bright_red, bold),
+                    kind,
+                    path,
+                    err
+                );
+                Fatal
+            })?;
+        }
+    }
+    if gbs.version() != Gbs::KNOWN_VERSION {
+        reporter.warning(&format_args!(
+            "{}: GBS version {}: extensions ignored",
+            path,
+            gbs.version(),
+        ));
+    }
+    if gbs.addr(gbs::AddressKind::Init) == gbs.addr(gbs::AddressKind::Play) {
+        reporter.warning(&format_args!(
+            "{}: INIT and PLAY are both at ${:04x}, so every tick will re-run INIT unless the driver dispatches on its own state; --init-addr and --play-addr can point to the actual entry points",
+            path,
+            gbs.addr(gbs::AddressKind::Play),
+        ));
+    }
+    for kind in [gbs::AddressKind::Init, gbs::AddressKind::Play] {
+        if gbs.entry_in_ram(kind) {
+            reporter.warning(&format_args!(
+                "{}: {} address ${:04x} lies in RAM, so code must have been copied there beforehand",
+                path,
+                kind,
+                gbs.addr(kind),
+            ));
+        }
+    }
+    Ok(gbs)
+}
+
+/// Reads a file (or stdin), decompressing it if it's gzipped.
+fn read_file(path: &str, reporter: &mut dyn Reporter) -> Result<Vec<u8>, Fatal> {
+    reporter.progress("Reading", &path);
+
+    let data = if path == STDIN_PATH {
+        let mut data = Vec::new();
+        io::stdin().read_to_end(&mut data).map(|_| data)
+    } else {
+        fs::read(path)
+    }
+    .map_err(|err| {
+        eprintln!(
+            "{} while reading {}: {}",
+            colorize!(Stderr, "Error", bright_red, bold),
+            path,
+            err
+        );
+        Fatal
+    })?;
+    if !data.starts_with(&gzip::MAGIC) {
+        return Ok(data);
+    }
+
+    gzip::decompress(&data).map_err(|err| {
+        eprintln!(
+            "{} decompressing {}: {}",
+            colorize!(Stderr, "Error", bright_red, bold),
+            path,
+            err
+        );
+        Fatal
+    })
+}
+
+fn parse_color_arg(arg: &str) -> Result<Option<bool>, String> {
+    if arg.eq_ignore_ascii_case("auto") {
+        Ok(None)
+    } else if arg.eq_ignore_ascii_case("always") {
+        Ok(Some(true))
+    } else if arg.eq_ignore_ascii_case("never") {
+        Ok(Some(false))
+    } else {
+        Err("expected \"auto\", \"always\", or \"never\"".to_string())
+    }
+}
+
+fn cycles_per_sec(gbs: &Gbs) -> u64 {
+    u64::from(CYCLES_PER_SEC) * if gbs.double_speed() { 2 } else { 1 }
+}
+
+/// How many ticks the given duration spans, for the given GBS file; larger ones than can be
+/// represented saturate.
+fn time_to_ticks(time: TimeArg, gbs: &Gbs) -> u64 {
+    time.cycles(cycles_per_sec(gbs)).unwrap_or(u64::MAX) / u64::from(gbs.cycles_per_tick())
+}
+
+/// Converts a simulation time limit to cycles, erroring out if the simulator can't count that
+/// high.
+fn sim_cycles(time: TimeArg, option: &str) -> Result<u32, Fatal> {
+    time.cycles(CYCLES_PER_SEC.into())
+        .and_then(|cycles| cycles.try_into().ok())
+        .ok_or_else(|| {
+            eprintln!(
+                "{}: {} cannot be longer than {} cycles ({})",
+                colorize!(Stderr, "Error", bright_red, bold),
+                option,
+                u32::MAX,
+                format_secs(f64::from(u32::MAX) / f64::from(CYCLES_PER_SEC)),
+            );
+            Fatal
+        })
+}
+
+/// How many seconds the given amount of ticks spans, for the given GBS file.
+fn ticks_to_secs(ticks: u64, gbs: &Gbs) -> f64 {
+    ticks as f64 * f64::from(gbs.cycles_per_tick()) / cycles_per_sec(gbs) as f64
+}
+
+/// Formats a duration as `M:SS`, like `--from` and `--to` accept.
+fn format_secs(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// The ticks whose differences should be reported.
+#[derive(Debug, Clone)]
+struct TickWindow {
+    /// Ticks outside of this are not compared at all (`--from`, `--to`).
+    compared: Range<u64>,
+    /// Findings outside of this are not reported, but counted as skipped (`--skip-start`, `--skip-end`).
+    reported: Range<u64>,
+    /// Ticks within this are simulated, but not compared (`--skip-ticks`, `--skip-init`); it always
+    /// starts at tick 0 or 1.
+    skipped: Range<u64>,
+    /// Whether `--to` lies past the end of what was simulated.
+    truncated: bool,
+    /// How many ticks were simulated, INIT included.
+    nb_ticks: u64,
+}
+
+impl TickWindow {
+    fn new(args: &Args, gbs: &Gbs, logbook: &run::Logbook) -> Self {
+        let nb_ticks = logbook.tick_cycles.len() as u64;
+        // A `--from` or `--to` landing mid-tick includes that whole tick.
+        let compared_start = args
+            .from
+            .map_or(0, |time| time_to_ticks(time, gbs))
+            .max(logbook.first_tick);
+        let compared_end = args
+            .to
+            .map_or(u64::MAX, |time| time_to_ticks(time, gbs).saturating_add(1));
+        let reported_start = args.skip_start.map_or(0, |time| time_to_ticks(time, gbs));
+        let reported_end =
+            nb_ticks.saturating_sub(args.skip_end.map_or(0, |time| time_to_ticks(time, gbs)));
+
+        let skipped_start = if args.skip_init { 0 } else { 1 };
+
+        Self {
+            compared: compared_start..compared_end.min(nb_ticks),
+            reported: reported_start.max(compared_start)..reported_end.min(compared_end),
+            skipped: skipped_start..args.skip_ticks.saturating_add(1).max(skipped_start),
+            truncated: compared_end != u64::MAX && compared_end > nb_ticks,
+            nb_ticks,
+        }
+    }
+
+    fn contains(&self, tick: u64) -> bool {
+        self.reported.contains(&tick) && !self.skipped.contains(&tick)
+    }
+
+    /// Restricts a (chronologically sorted) log to the ticks being compared, which may leave a hole
+    /// after INIT.
+    fn compared_log<'a>(&self, io_log: &'a [run::IoAccess]) -> Cow<'a, [run::IoAccess]> {
+        let io_log = run::slice_ticks(io_log, &self.compared);
+        let before = run::slice_ticks(io_log, &(0..self.skipped.start));
+        let after = run::slice_ticks(io_log, &(self.skipped.end..u64::MAX));
+        if before.is_empty() {
+            Cow::Borrowed(after)
+        } else if after.is_empty() {
+            Cow::Borrowed(before)
+        } else {
+            Cow::Owned([before, after].concat())
+        }
+    }
+
+    /// Whether only part of the song is compared (`--from`, `--to`, or resuming a snapshot).
+    fn is_partial(&self) -> bool {
+        self.compared != (0..self.nb_ticks)
+    }
+
+    /// The first PLAY tick being compared, i.e. where [`Self::play_ticks`] starts (unless it's empty).
+    fn first_play_tick(&self) -> u64 {
+        self.compared.start.max(1).max(self.skipped.end)
+    }
+
+    /// Restricts a per-tick vector to the PLAY ticks being compared.
+    fn play_ticks<'a, T>(&self, per_tick: &'a [T]) -> &'a [T] {
+        let len = per_tick.len() as u64;
+        let end = self.compared.end.min(len);
+        let start = self.first_play_tick().min(end);
+        &per_tick[start as usize..end as usize]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    /// Tick 0 is the "init" phase.
+    tick: u64,
+    /// Can exceed the tick's budget, if PLAY overruns it.
+    cycle: u32,
+}
+
+impl Display for DiagnosticLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self.name();
+        match self {
+            Self::Error => write!(
+                f,
+                "{}",
+                name.if_supports_color(Stdout, |text| text.bright_red())
+            ),
+            Self::Warning => write!(
+                f,
+                "{}",
+                name.if_supports_color(Stdout, |text| text.bright_yellow())
+            ),
+            Self::Note => write!(
+                f,
+                "{}",
+                name.if_supports_color(Stdout, |text| text.bright_blue())
+            ),
+        }
+    }
+}
+
+/// How INIT went for the songs of one of the files, to spot headers claiming more songs than the
+/// driver has.
+#[derive(Debug, Default)]
+struct InitOutcomes {
+    ok: BTreeSet<u8>,
+    crashed_early: BTreeSet<u8>,
+}
+
+impl InitOutcomes {
+    fn record(&mut self, song_id: u8, failure: Option<&run::Failure>, max_cycles: u32) {
+        match failure {
+            Some(failure) if failure.is_early_init_crash(max_cycles) => {
+                self.crashed_early.insert(song_id);
+            }
+            Some(failure) if failure.init_cycles.is_some() => (),
+            _ => {
+                self.ok.insert(song_id);
+            }
+        }
+    }
+
+    /// The last songs of the file, if they all crashed early but some song before them didn't.
+    fn past_song_table(&self, gbs: &Gbs) -> Option<(u8, u8)> {
+        let songs = gbs.songs();
+        let last = *songs.end();
+        let first = songs
+            .rev()
+            .take_while(|id| self.crashed_early.contains(id))
+            .last()?;
+        self.ok
+            .iter()
+            .any(|&id| id < first)
+            .then_some((first, last))
+    }
+}
+
+/// The ID of a song in each file; surplus songs only exist in one of them.
+#[derive(Clone, Copy)]
+enum SongIDs {
+    Both(u8, u8),
+    BeforeOnly(u8),
+    AfterOnly(u8),
+}
+
+impl Display for SongIDs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Both(before, after) if before == after => write!(f, "{}", before),
+            Self::Both(before, after) => write!(f, "{} and {}", before, after),
+            Self::BeforeOnly(id) => write!(f, "{} (before only)", id),
+            Self::AfterOnly(id) => write!(f, "{} (after only)", id),
+        }
+    }
+}
+
+/// Which part of the memory map an address is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Region {
+    Rom,
+    Vram,
+    Sram,
+    Wram,
+    Echo,
+    Oam,
+    /// $FEA0-$FEFF.
+    Unusable,
+    /// IE included.
+    Io,
+    Hram,
+}
+
+impl Region {
+    fn of(addr: u16) -> Self {
+        match addr {
+            0x0000..=0x7FFF => Self::Rom,
+            0x8000..=0x9FFF => Self::Vram,
+            0xA000..=0xBFFF => Self::Sram,
+            0xC000..=0xDFFF => Self::Wram,
+            0xE000..=0xFDFF => Self::Echo,
+            0xFE00..=0xFE9F => Self::Oam,
+            0xFEA0..=0xFEFF => Self::Unusable,
+            0xFF80..=0xFFFE => Self::Hram,
+            0xFF00..=0xFF7F | 0xFFFF => Self::Io,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Rom => "ROM",
+            Self::Vram => "VRAM",
+            Self::Sram => "SRAM",
+            Self::Wram => "WRAM",
+            Self::Echo => "echo RAM",
+            Self::Oam => "OAM",
+            Self::Unusable => "unusable memory",
+            Self::Io => "I/O",
+            Self::Hram => "HRAM",
+        }
+    }
+}
+
+/// The bank is the one that was mapped in the address's region: the ROM bank for ROMX, and the
+/// WRAM bank for WRAMX. It is meaningless everywhere else.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Address(u8, u16);
+
+impl Address {
+    /// The (bank, address) pair, with the bank set to 0 outside of the banked areas.
+    fn canonical(&self) -> (u8, u16) {
+        match self.1 {
+            0x4000..=0x7FFF | 0xD000..=0xDFFF => (self.0, self.1),
+            _ => (0, self.1),
+        }
+    }
+}
+
+/// ROM addresses are printed as `bank:address`, since almost all PCs are there; anything else is
+/// followed by its region, e.g. `c123 (WRAM)`, so that it doesn't look like a ROM address.
+impl LowerHex for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (Region::of(self.1), self.canonical()) {
+            (Region::Rom, (bank, addr)) => write!(f, "{:02x}:{:04x}", bank, addr),
+            (region, (0, addr)) => write!(f, "{:04x} ({})", addr, region.name()),
+            (region, (bank, addr)) => write!(f, "{:02x}:{:04x} ({})", bank, addr, region.name()),
+        }
+    }
+}
@@ -0,0 +1,438 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with manifests (`--manifest`), which list several comparisons to run in a
+//! row, all with the options given on the command line, so that they only need to be kept in sync
+//! in one place.
+//!
+//! Each line describes one comparison, as comma-separated `key = value` pairs:
+//!
+//! ```text
+//! name = "projectA", before = "a/old.gbs", after = "a/new.gbs"
+//! name = "projectB", before = "b/old.gbs", after = "b/new.gbs", song = 2, jitter = 8
+//! name = "projectC", before = "c/old.gbs", after = "c/new.gbs", songs = "1-4"
+//! ```
+//!
+//! `name`, `before`, and `after` are required; `song`, `jitter`, and `watch` override the
+//! `--before-song`/`--after-song`, `--jitter`, and `--watch` options for that comparison only.
+//! `songs` runs one comparison per song of the (inclusive) range instead.
+//! Relative paths are relative to the manifest's directory.
+
+use std::{ops::RangeInclusive, path::Path};
+
+use crate::{sym::AddrArg, Args, STDIN_PATH};
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub line_no: usize,
+    pub name: String,
+    pub before: String,
+    pub after: String,
+    /// Set by either `song` or `songs`.
+    pub songs: Option<RangeInclusive<u8>>,
+    pub jitter: Option<u16>,
+    pub watch: Option<(AddrArg, u8)>,
+}
+
+const KEYS: [&str; 7] = [
+    "name", "before", "after", "song", "songs", "jitter", "watch",
+];
+
+/// Splits on the commas that aren't within quotes.
+fn split_pairs(line: &str) -> Result<Vec<&str>, String> {
+    let mut pairs = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                pairs.push(&line[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if quoted {
+        return Err("unterminated string".into());
+    }
+    pairs.push(&line[start..]);
+    Ok(pairs)
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+fn parse_entry(line_no: usize, line: &str) -> Result<Entry, String> {
+    let mut entry = Entry {
+        line_no,
+        name: String::new(),
+        before: String::new(),
+        after: String::new(),
+        songs: None,
+        jitter: None,
+        watch: None,
+    };
+    let mut seen = Vec::new();
+    for pair in split_pairs(line)? {
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("{:?} must be of the form `key = value`", pair.trim()))?;
+        let (key, value) = (key.trim(), unquote(value.trim()));
+        if !KEYS.contains(&key) {
+            return Err(format!(
+                "unknown key {:?} (expected one of {})",
+                key,
+                KEYS.join(", ")
+            ));
+        }
+        if seen.contains(&key) {
+            return Err(format!("{:?} is given more than once", key));
+        }
+        if let Some(other) = seen
+            .iter()
+            .find(|seen| ["song", "songs"].contains(seen) && ["song", "songs"].contains(&key))
+        {
+            return Err(format!("{:?} cannot be given along with {:?}", key, other));
+        }
+        seen.push(key);
+
+        let bad_value = |err: &dyn std::fmt::Display| format!("bad {}: {}", key, err);
+        match key {
+            "name" => entry.name = value.to_string(),
+            "before" => entry.before = value.to_string(),
+            "after" => entry.after = value.to_string(),
+            "song" => {
+                let song = value.parse().map_err(|err| bad_value(&err))?;
+                entry.songs = Some(song..=song);
+            }
+            "songs" => entry.songs = Some(parse_song_range(value).map_err(|err| bad_value(&err))?),
+            "jitter" => entry.jitter = Some(value.parse().map_err(|err| bad_value(&err))?),
+            "watch" => {
+                entry.watch =
+                    Some(crate::parse_addr_value_arg(value).map_err(|err| bad_value(&err))?)
+            }
+            _ => unreachable!(),
+        }
+    }
+    for key in ["name", "before", "after"] {
+        if !seen.contains(&key) {
+            return Err(format!("missing {:?}", key));
+        }
+    }
+    for path in [&entry.before, &entry.after] {
+        if path == "-" || path == STDIN_PATH {
+            return Err("the files of a manifest cannot be read from stdin".into());
+        }
+    }
+    Ok(entry)
+}
+
+/// Parses `first-last`, or a single song ID.
+fn parse_song_range(value: &str) -> Result<RangeInclusive<u8>, String> {
+    let parse = |id: &str| id.trim().parse::<u8>().map_err(|err| err.to_string());
+    let range = match value.split_once('-') {
+        Some((first, last)) => parse(first)?..=parse(last)?,
+        None => {
+            let song = parse(value)?;
+            song..=song
+        }
+    };
+    if range.is_empty() {
+        return Err(format!(
+            "song #{} comes after song #{}",
+            range.start(),
+            range.end()
+        ));
+    }
+    Ok(range)
+}
+
+pub fn parse(text: &str) -> Result<Vec<Entry>, String> {
+    let mut entries: Vec<Entry> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line.split_once('#').map_or(line, |(line, _)| line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let entry =
+            parse_entry(line_no, line).map_err(|err| format!("line {}: {}", line_no, err))?;
+        if let Some(prev) = entries.iter().find(|prev| prev.name == entry.name) {
+            return Err(format!(
+                "line {}: the name {:?} is already used on line {}",
+                line_no, entry.name, prev.line_no
+            ));
+        }
+        entries.push(entry);
+    }
+    if entries.is_empty() {
+        return Err("the manifest lists no comparisons".into());
+    }
+    Ok(entries)
+}
+
+/// Makes the entries' paths relative to the manifest's directory instead.
+pub fn resolve_paths(entries: &mut [Entry], manifest_path: &str) {
+    let Some(dir) = Path::new(manifest_path).parent() else {
+        return;
+    };
+    for entry in entries {
+        for path in [&mut entry.before, &mut entry.after] {
+            *path = dir.join(&*path).to_string_lossy().into_owned();
+        }
+    }
+}
+
+/// Lists every missing file at once, so that a typo doesn't only surface after the comparisons
+/// before it have run.
+pub fn missing_files(entries: &[Entry]) -> Vec<String> {
+    entries
+        .iter()
+        .flat_map(|entry| {
+            [&entry.before, &entry.after]
+                .into_iter()
+                .filter(|path| !Path::new(path).exists())
+                .map(move |path| format!("line {}: {} does not exist", entry.line_no, path))
+        })
+        .collect()
+}
+
+/// One of the comparisons that an entry stands for.
+#[derive(Debug, Clone)]
+pub struct Comparison<'a> {
+    pub entry: &'a Entry,
+    /// Only set if the entry lists several songs, since it's otherwise part of the overrides.
+    pub song: Option<u8>,
+}
+
+impl Comparison<'_> {
+    /// How the comparison is referred to in the summary.
+    pub fn label(&self) -> String {
+        match self.song {
+            Some(song) => format!("{} #{}", self.entry.name, song),
+            None => self.entry.name.clone(),
+        }
+    }
+
+    /// Overrides the options shared on the command line with the entry's.
+    pub fn merge_into(&self, args: &mut Args) {
+        let song = self
+            .song
+            .or_else(|| self.entry.songs.as_ref().map(|songs| *songs.start()));
+        if let Some(song) = song {
+            args.before_song = Some(song);
+            args.after_song = Some(song);
+        }
+        if let Some(jitter) = self.entry.jitter {
+            args.jitter = jitter;
+        }
+        if let Some(watch) = &self.entry.watch {
+            args.watch = Some(watch.clone());
+        }
+    }
+}
+
+/// Expands the entries whose `songs` is a range into one comparison per song.
+pub fn comparisons(entries: &[Entry]) -> Vec<Comparison<'_>> {
+    entries
+        .iter()
+        .flat_map(|entry| match &entry.songs {
+            Some(songs) if songs.start() != songs.end() => songs
+                .clone()
+                .map(|song| Comparison {
+                    entry,
+                    song: Some(song),
+                })
+                .collect(),
+            _ => vec![Comparison { entry, song: None }],
+        })
+        .collect()
+}
+
+/// The command line without `--manifest` and its value, which is at `flag_at`, for the options to
+/// be shared by all comparisons.
+pub fn shared_args(strings: &[String], flag_at: usize) -> Vec<&str> {
+    strings
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != flag_at && i != flag_at + 1)
+        .map(|(_, arg)| arg.as_str())
+        .collect()
+}
+
+/// What the summary says of a comparison, given its exit code.
+pub fn outcome(code: i32) -> &'static str {
+    match code {
+        0 => "OK",
+        1 => "failing",
+        _ => "error",
+    }
+}
+
+/// The summary's rows, with the labels padded to line up the outcomes, and the manifest's exit
+/// code, which is the worst of the comparisons'.
+pub fn summary(results: &[(String, i32)]) -> (Vec<(String, i32)>, i32) {
+    let width = results
+        .iter()
+        .map(|(label, _)| label.len())
+        .max()
+        .unwrap_or(0);
+    let rows = results
+        .iter()
+        .map(|(label, code)| (format!("{:width$}", label), *code))
+        .collect();
+    let worst = results.iter().map(|&(_, code)| code).max().unwrap_or(0);
+    (rows, worst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argh::FromArgs;
+
+    fn args(strs: &[&str]) -> Args {
+        Args::from_args(&["gbsdiff"], strs).unwrap()
+    }
+
+    #[test]
+    fn parses_entries() {
+        let entries = parse(
+            "# Two projects\n\
+             name = \"a\", before = \"a, old.gbs\", after = a.gbs, song = 2, jitter = 8\n\
+             \n\
+             name = \"b\", before = b1.gbs, after = b2.gbs, songs = \"1-4\" # All of them\n",
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].line_no, 2);
+        assert_eq!(entries[0].before, "a, old.gbs");
+        assert_eq!(entries[0].songs, Some(2..=2));
+        assert_eq!(entries[0].jitter, Some(8));
+        assert_eq!(entries[1].line_no, 4);
+        assert_eq!(entries[1].songs, Some(1..=4));
+        assert_eq!(entries[1].jitter, None);
+    }
+
+    #[test]
+    fn rejects_bad_entries() {
+        for (text, err) in [
+            ("", "the manifest lists no comparisons"),
+            ("name = a, before = b", "line 1: missing \"after\""),
+            (
+                "name = a, before = b, after = c, pitch = 3",
+                "line 1: unknown key \"pitch\"",
+            ),
+            (
+                "name = a, before = b, after = c, name = d",
+                "line 1: \"name\" is given more than once",
+            ),
+            ("name = \"a, before = b", "line 1: unterminated string"),
+            (
+                "name = a, before b",
+                "line 1: \"before b\" must be of the form",
+            ),
+            (
+                "name = a, before = b, after = c, song = 1, songs = 1-2",
+                "line 1: \"songs\" cannot be given along with \"song\"",
+            ),
+            (
+                "name = a, before = b, after = c, songs = 4-1",
+                "line 1: bad songs: song #4 comes after song #1",
+            ),
+            (
+                "name = a, before = b, after = c, songs = 1-x",
+                "line 1: bad songs: invalid digit",
+            ),
+            (
+                "name = a, before = -, after = c",
+                "line 1: the files of a manifest cannot be read from stdin",
+            ),
+            (
+                "name = a, before = b, after = c\nname = a, before = d, after = e",
+                "line 2: the name \"a\" is already used on line 1",
+            ),
+        ] {
+            let actual = parse(text).unwrap_err();
+            assert!(actual.starts_with(err), "{:?}: {:?}", text, actual);
+        }
+    }
+
+    #[test]
+    fn expands_song_ranges() {
+        let entries = parse(
+            "name = a, before = b, after = c\n\
+             name = d, before = e, after = f, songs = 2-4\n\
+             name = g, before = h, after = i, songs = 5-5",
+        )
+        .unwrap();
+        let labels: Vec<_> = comparisons(&entries)
+            .iter()
+            .map(Comparison::label)
+            .collect();
+        assert_eq!(labels, ["a", "d #2", "d #3", "d #4", "g"]);
+    }
+
+    #[test]
+    fn entries_override_shared_options() {
+        let entries = parse(
+            "name = a, before = b, after = c\n\
+             name = d, before = e, after = f, song = 3, jitter = 2, watch = C000=1\n\
+             name = g, before = h, after = i, songs = 1-2",
+        )
+        .unwrap();
+        let comparisons = comparisons(&entries);
+        let merged: Vec<_> = comparisons
+            .iter()
+            .map(|comparison| {
+                let mut args = args(&["--jitter", "7", "--before-song", "5", "x", "y"]);
+                comparison.merge_into(&mut args);
+                (args.before_song, args.after_song, args.jitter, args.watch)
+            })
+            .collect();
+        assert_eq!(merged[0], (Some(5), None, 7, None));
+        assert_eq!(
+            merged[1],
+            (Some(3), Some(3), 2, Some((AddrArg::Addr(0xC000), 1)))
+        );
+        assert_eq!(merged[2], (Some(1), Some(1), 7, None));
+        assert_eq!(merged[3], (Some(2), Some(2), 7, None));
+    }
+
+    #[test]
+    fn shares_everything_but_the_manifest() {
+        let strings: Vec<_> = ["-q", "--manifest", "-q", "--jitter", "4"]
+            .map(String::from)
+            .into();
+        // The manifest is also named like an option given on the command line.
+        assert_eq!(shared_args(&strings, 1), ["-q", "--jitter", "4"]);
+    }
+
+    #[test]
+    fn sums_up_comparisons() {
+        let (rows, worst) = summary(&[("a".into(), 0), ("long name".into(), 2), ("ab".into(), 1)]);
+        assert_eq!(
+            rows,
+            [
+                ("a        ".to_string(), 0),
+                ("long name".to_string(), 2),
+                ("ab       ".to_string(), 1)
+            ]
+        );
+        assert_eq!(worst, 2);
+        assert_eq!(
+            rows.iter()
+                .map(|&(_, code)| outcome(code))
+                .collect::<Vec<_>>(),
+            ["OK", "error", "failing"]
+        );
+        assert_eq!(summary(&[]), (vec![], 0));
+    }
+}
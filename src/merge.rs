@@ -0,0 +1,140 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with interleaving two sorted streams of items, e.g. the simulation's
+//! diagnostics and the comparison's, so that they can be reported in chronological order.
+
+use std::iter::Peekable;
+
+/// An item from either stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
+/// Yields the items of both iterators ordered by key, assuming that each of them already is.
+///
+/// On ties, the left item comes first.
+pub(crate) struct Merge<L: Iterator, R: Iterator, FL, FR> {
+    left: Peekable<L>,
+    right: Peekable<R>,
+    key_left: FL,
+    key_right: FR,
+}
+
+pub(crate) fn merge<L, R, K, FL, FR>(
+    left: L,
+    right: R,
+    key_left: FL,
+    key_right: FR,
+) -> Merge<L, R, FL, FR>
+where
+    L: Iterator,
+    R: Iterator,
+    K: Ord,
+    FL: Fn(&L::Item) -> K,
+    FR: Fn(&R::Item) -> K,
+{
+    Merge {
+        left: left.peekable(),
+        right: right.peekable(),
+        key_left,
+        key_right,
+    }
+}
+
+impl<L, R, K, FL, FR> Iterator for Merge<L, R, FL, FR>
+where
+    L: Iterator,
+    R: Iterator,
+    K: Ord,
+    FL: Fn(&L::Item) -> K,
+    FR: Fn(&R::Item) -> K,
+{
+    type Item = Either<L::Item, R::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let left_first = match (self.left.peek(), self.right.peek()) {
+            (None, None) => return None,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (Some(left), Some(right)) => (self.key_left)(left) <= (self.key_right)(right),
+        };
+        if left_first {
+            self.left.next().map(Either::Left)
+        } else {
+            self.right.next().map(Either::Right)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Timestamp;
+
+    /// Which stream each item came from, and its timestamp.
+    fn interleave(
+        left: &[(u64, u32)],
+        right: &[(u64, u32)],
+    ) -> Vec<Either<(u64, u32), (u64, u32)>> {
+        let when = |&(tick, cycle): &(u64, u32)| Timestamp { tick, cycle };
+        merge(left.iter().copied(), right.iter().copied(), when, when).collect()
+    }
+
+    #[test]
+    fn left_before_right() {
+        assert_eq!(
+            interleave(&[(1, 10), (1, 20)], &[(1, 30), (2, 0)]),
+            [
+                Either::Left((1, 10)),
+                Either::Left((1, 20)),
+                Either::Right((1, 30)),
+                Either::Right((2, 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn left_after_right() {
+        // The left stream being behind doesn't hold back the right one, nor the other way around.
+        assert_eq!(
+            interleave(&[(3, 0), (3, 5)], &[(1, 30), (2, 0)]),
+            [
+                Either::Right((1, 30)),
+                Either::Right((2, 0)),
+                Either::Left((3, 0)),
+                Either::Left((3, 5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn straddling_within_a_tick() {
+        assert_eq!(
+            interleave(&[(1, 10), (1, 40), (2, 5)], &[(1, 20), (1, 30), (2, 50)]),
+            [
+                Either::Left((1, 10)),
+                Either::Right((1, 20)),
+                Either::Right((1, 30)),
+                Either::Left((1, 40)),
+                Either::Left((2, 5)),
+                Either::Right((2, 50)),
+            ]
+        );
+    }
+
+    #[test]
+    fn ties_go_left_first() {
+        assert_eq!(
+            interleave(&[(1, 10)], &[(1, 10)]),
+            [Either::Left((1, 10)), Either::Right((1, 10))]
+        );
+        assert_eq!(interleave(&[], &[]), []);
+        assert_eq!(interleave(&[], &[(0, 0)]), [Either::Right((0, 0))]);
+    }
+}
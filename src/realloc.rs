@@ -0,0 +1,322 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with recognizing musical content that moved from one channel to another.
+//!
+//! Drivers with dynamic channel allocation may play the same part on another channel, e.g. when
+//! a sound effect steals the original one; this shows up as writes removed from a channel, and the
+//! same writes added to another. Such groups are collapsed into a single diagnostic.
+
+use crate::{
+    diff::{DiagnosticKind, TRIGGER_BIT},
+    Diagnostic, DiagnosticLevel,
+};
+
+/// Removed and added writes further apart than this many ticks are not considered related.
+const MAX_GAP: u64 = 16;
+
+/// The first register of each channel (CH2's first one is unused, but keeps the layout regular).
+const CHANNEL_BASES: [u16; 4] = [0xFF10, 0xFF15, 0xFF1A, 0xFF1F];
+
+/// Which of the 5 registers of a channel can be translated between which channels, by offset
+/// within the channel.
+/// CH1's sweep and CH3's DAC control, volume and wave are specific to their channels.
+fn translatable(ofs: u16, from: usize, to: usize) -> bool {
+    let (duty_length, envelope, period) = ([0, 1], [0, 1, 3], [0, 1, 2]);
+    match ofs {
+        1 => duty_length.contains(&from) && duty_length.contains(&to),
+        2 => envelope.contains(&from) && envelope.contains(&to),
+        3 => period.contains(&from) && period.contains(&to),
+        // The trigger bit is common to all channels, but only channels 1-3 have period bits there.
+        4 => true,
+        _ => false,
+    }
+}
+
+/// Returns the register's channel (0-based), and its offset within it.
+fn channel_of(reg: u16) -> Option<(usize, u16)> {
+    CHANNEL_BASES
+        .iter()
+        .position(|&base| (base..base + 5).contains(&reg))
+        .map(|channel| (channel, reg - CHANNEL_BASES[channel]))
+}
+
+/// Translates the write to the equivalent one on another channel, if there is one.
+fn translate(reg: u16, value: u8, to: usize) -> Option<(u16, u8)> {
+    let (from, ofs) = channel_of(reg)?;
+    if from == to || !translatable(ofs, from, to) {
+        return None;
+    }
+    let reg = CHANNEL_BASES[to] + ofs;
+    Some((reg, mask(reg, value, from, to)))
+}
+
+/// Only the trigger and length enable bits can be carried over to/from CH4's NR44.
+fn mask(reg: u16, value: u8, from: usize, to: usize) -> u8 {
+    match channel_of(reg) {
+        Some((_, 4)) if from == 3 || to == 3 => value & 0xC0,
+        _ => value,
+    }
+}
+
+/// Collapses groups of writes removed from a channel that match writes added to another, into a
+/// single warning; the constituents are kept, but demoted to notes.
+///
+/// Groups whose writes don't all match are left alone, since those are actual content changes.
+pub fn collapse(diagnostics: &mut Vec<Diagnostic<DiagnosticKind>>) {
+    let mut collapsed = Vec::new();
+
+    for from in 0..CHANNEL_BASES.len() {
+        for to in (0..CHANNEL_BASES.len()).filter(|&to| to != from) {
+            let is_candidate = |diag: &Diagnostic<DiagnosticKind>| match diag.kind {
+                DiagnosticKind::Removed(reg, value) => {
+                    channel_of(reg).map(|(channel, _)| channel) == Some(from)
+                        && translate(reg, value, to).is_some()
+                }
+                DiagnosticKind::Added(reg, _) => {
+                    channel_of(reg).map(|(channel, _)| channel) == Some(to)
+                }
+                DiagnosticKind::OtherReg(before, _, after) => {
+                    channel_of(before).map(|(channel, _)| channel) == Some(from)
+                        && channel_of(after).map(|(channel, _)| channel) == Some(to)
+                }
+                _ => false,
+            };
+            let candidates: Vec<_> = diagnostics
+                .iter()
+                .enumerate()
+                .filter(|(_, diag)| diag.level == DiagnosticLevel::Error && is_candidate(diag))
+                .map(|(i, _)| i)
+                .collect();
+
+            // Split the candidates into groups of writes close to each other.
+            let mut groups: Vec<Vec<usize>> = Vec::new();
+            for i in candidates {
+                let tick = diagnostics[i].when.tick;
+                match groups.last_mut() {
+                    Some(group)
+                        if tick <= diagnostics[*group.last().unwrap()].when.tick + MAX_GAP =>
+                    {
+                        group.push(i)
+                    }
+                    _ => groups.push(vec![i]),
+                }
+            }
+
+            for group in groups {
+                // The writes of each side, in order; a write moved to another register as-is
+                // (which the diff reports as such when the sequences line up) counts on both.
+                let (mut before, mut after) = (Vec::new(), Vec::new());
+                for &i in &group {
+                    match diagnostics[i].kind {
+                        DiagnosticKind::Removed(reg, value) => before.push((reg, value)),
+                        DiagnosticKind::Added(reg, value) => after.push((reg, value)),
+                        DiagnosticKind::OtherReg(before_reg, value, after_reg) => {
+                            before.push((before_reg, value));
+                            after.push((after_reg, value));
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+                let matches = before.len() == after.len()
+                    && before.iter().zip(&after).all(
+                        |(&(reg, value), &(after_reg, after_value))| {
+                            translate(reg, value, to)
+                                == Some((after_reg, mask(after_reg, after_value, from, to)))
+                        },
+                    );
+                if before.is_empty() || !matches {
+                    continue;
+                }
+
+                let notes = after
+                    .iter()
+                    .filter(|&&(reg, value)| {
+                        channel_of(reg).map(|(_, ofs)| ofs) == Some(4) && value & TRIGGER_BIT != 0
+                    })
+                    .count();
+                let first = &diagnostics[group[0]];
+                let last = &diagnostics[*group.last().unwrap()];
+                collapsed.push((
+                    group[0],
+                    Diagnostic {
+                        when: first.when.clone(),
+                        pc: first.pc.clone(),
+                        level: DiagnosticLevel::Warning,
+                        kind: DiagnosticKind::ChannelReallocation {
+                            from: from as u8 + 1,
+                            to: to as u8 + 1,
+                            ticks: (first.when.tick, last.when.tick),
+                            notes,
+                        },
+                    },
+                ));
+                for i in group {
+                    diagnostics[i].level = DiagnosticLevel::Note;
+                }
+            }
+        }
+    }
+
+    // Insert from the back, so that the indices stay valid.
+    collapsed.sort_by_key(|(i, _)| *i);
+    for (i, diag) in collapsed.into_iter().rev() {
+        diagnostics.insert(i, diag);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Address, Timestamp};
+
+    fn removed(tick: u64, reg: u16, value: u8) -> Diagnostic<DiagnosticKind> {
+        diag(tick, DiagnosticKind::Removed(reg, value))
+    }
+
+    fn added(tick: u64, reg: u16, value: u8) -> Diagnostic<DiagnosticKind> {
+        diag(tick, DiagnosticKind::Added(reg, value))
+    }
+
+    fn diag(tick: u64, kind: DiagnosticKind) -> Diagnostic<DiagnosticKind> {
+        Diagnostic {
+            when: Timestamp { tick, cycle: 0 },
+            pc: Address(1, 0x4000),
+            level: DiagnosticLevel::Error,
+            kind,
+        }
+    }
+
+    /// A [`DiagnosticKind::ChannelReallocation`]'s fields.
+    type Realloc = (u8, u8, (u64, u64), usize);
+
+    /// The reallocations, and the levels of the other diagnostics, in order.
+    fn collapsed(
+        mut diagnostics: Vec<Diagnostic<DiagnosticKind>>,
+    ) -> (Vec<Realloc>, Vec<DiagnosticLevel>) {
+        collapse(&mut diagnostics);
+        let mut reallocs = Vec::new();
+        let mut levels = Vec::new();
+        for diag in diagnostics {
+            match diag.kind {
+                DiagnosticKind::ChannelReallocation {
+                    from,
+                    to,
+                    ticks,
+                    notes,
+                } => reallocs.push((from, to, ticks, notes)),
+                _ => levels.push(diag.level),
+            }
+        }
+        (reallocs, levels)
+    }
+
+    #[test]
+    fn moved_parts_are_collapsed() {
+        use DiagnosticLevel::*;
+
+        let diagnostics = || {
+            vec![
+                removed(5, 0xFF12, 0xF0),
+                removed(5, 0xFF13, 0x40),
+                removed(5, 0xFF14, 0x87),
+                added(5, 0xFF17, 0xF0),
+                added(5, 0xFF18, 0x40),
+                added(5, 0xFF19, 0x87),
+                removed(9, 0xFF13, 0x50),
+                added(9, 0xFF18, 0x50),
+                // Written as-is to the other channel's register, which the diff reports as such.
+                diag(12, DiagnosticKind::OtherReg(0xFF13, 0x60, 0xFF18)),
+                removed(12, 0xFF14, 0x86),
+                added(12, 0xFF19, 0x86),
+            ]
+        };
+        assert_eq!(
+            collapsed(diagnostics()),
+            (vec![(1, 2, (5, 12), 2)], vec![Note; 11])
+        );
+        // The summary comes first.
+        let mut diagnostics = diagnostics();
+        collapse(&mut diagnostics);
+        assert!(matches!(
+            diagnostics[0].kind,
+            DiagnosticKind::ChannelReallocation { .. }
+        ));
+
+        // Only the trigger and length enable bits carry over to CH4.
+        assert_eq!(
+            collapsed(vec![
+                removed(1, 0xFF12, 0xF0),
+                removed(1, 0xFF14, 0x87),
+                added(1, 0xFF21, 0xF0),
+                added(1, 0xFF23, 0x80),
+            ]),
+            (vec![(1, 4, (1, 1), 1)], vec![Note; 4])
+        );
+    }
+
+    #[test]
+    fn content_changes_are_not_collapsed() {
+        use DiagnosticLevel::*;
+
+        let cases = [
+            // Other values.
+            vec![removed(5, 0xFF12, 0xF0), added(5, 0xFF17, 0xA0)],
+            // Other registers.
+            vec![removed(5, 0xFF12, 0xF0), added(5, 0xFF18, 0xF0)],
+            // Registers specific to their channel: CH1's sweep, CH3's volume.
+            vec![removed(5, 0xFF10, 0x00), added(5, 0xFF15, 0x00)],
+            vec![removed(5, 0xFF1C, 0x20), added(5, 0xFF12, 0x20)],
+            // Only additions, or only removals.
+            vec![added(5, 0xFF17, 0xF0), added(5, 0xFF19, 0x87)],
+            vec![removed(5, 0xFF12, 0xF0)],
+        ];
+        for diagnostics in cases {
+            let levels = vec![Error; diagnostics.len()];
+            assert_eq!(collapsed(diagnostics), (vec![], levels));
+        }
+
+        // Warnings and notes are left alone.
+        let mut warning = removed(5, 0xFF12, 0xF0);
+        warning.level = Warning;
+        assert_eq!(
+            collapsed(vec![warning, added(5, 0xFF17, 0xF0)]),
+            (vec![], vec![Warning, Error])
+        );
+    }
+
+    #[test]
+    fn partial_overlaps_are_not_collapsed() {
+        use DiagnosticLevel::*;
+
+        // Part of the writes moved, but another one was dropped along the way.
+        assert_eq!(
+            collapsed(vec![
+                removed(5, 0xFF12, 0xF0),
+                removed(5, 0xFF13, 0x40),
+                removed(5, 0xFF14, 0x87),
+                added(5, 0xFF17, 0xF0),
+                added(5, 0xFF19, 0x87),
+            ]),
+            (vec![], vec![Error; 5])
+        );
+        // Groups far enough apart are judged on their own.
+        let diagnostics = |gap| {
+            vec![
+                removed(5, 0xFF12, 0xF0),
+                added(5, 0xFF17, 0xF0),
+                removed(5 + gap, 0xFF12, 0xA0),
+                added(5 + gap, 0xFF17, 0xB0),
+            ]
+        };
+        assert_eq!(
+            collapsed(diagnostics(MAX_GAP + 1)),
+            (vec![(1, 2, (5, 5), 0)], vec![Note, Note, Error, Error])
+        );
+        // But not if they are close enough to be one group.
+        assert_eq!(collapsed(diagnostics(MAX_GAP)), (vec![], vec![Error; 4]));
+    }
+}
@@ -0,0 +1,214 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with the hardware registers' names, so that displaying them and parsing them
+//! back (`--ignore-reg`, `.expect` scripts...) agree, and with which channel each belongs to.
+
+use gb_cpu_sim::reg::HwReg;
+
+use crate::{channel::Channel, waves::WAVE_RAM};
+
+const WAVE_RAM_NAMES: [&str; 16] = [
+    "Wave RAM[0]",
+    "Wave RAM[1]",
+    "Wave RAM[2]",
+    "Wave RAM[3]",
+    "Wave RAM[4]",
+    "Wave RAM[5]",
+    "Wave RAM[6]",
+    "Wave RAM[7]",
+    "Wave RAM[8]",
+    "Wave RAM[9]",
+    "Wave RAM[10]",
+    "Wave RAM[11]",
+    "Wave RAM[12]",
+    "Wave RAM[13]",
+    "Wave RAM[14]",
+    "Wave RAM[15]",
+];
+
+/// The name diagnostics use for the register at this address, if it has one.
+pub fn name(addr: u16) -> Option<&'static str> {
+    let name = match HwReg::try_from(addr) {
+        Ok(HwReg::Ramg) => "RAMG",
+        Ok(HwReg::Romb0) => "ROMB0",
+        Ok(HwReg::Romb1) => "ROMB1",
+        Ok(HwReg::Ramb) => "RAMB",
+        Ok(HwReg::Rtclatch) => "RTCLATCH",
+        Ok(HwReg::P1) => "P1",
+        Ok(HwReg::Sb) => "SB",
+        Ok(HwReg::Sc) => "SC",
+        Ok(HwReg::Div) => "DIV",
+        Ok(HwReg::Tima) => "TIMA",
+        Ok(HwReg::Tma) => "TMA",
+        Ok(HwReg::Tac) => "TAC",
+        Ok(HwReg::If) => "IF",
+        Ok(HwReg::Nr10) => "NR10",
+        Ok(HwReg::Nr11) => "NR11",
+        Ok(HwReg::Nr12) => "NR12",
+        Ok(HwReg::Nr13) => "NR13",
+        Ok(HwReg::Nr14) => "NR14",
+        Ok(HwReg::Nr21) => "NR21",
+        Ok(HwReg::Nr22) => "NR22",
+        Ok(HwReg::Nr23) => "NR23",
+        Ok(HwReg::Nr24) => "NR24",
+        Ok(HwReg::Nr30) => "NR30",
+        Ok(HwReg::Nr31) => "NR31",
+        Ok(HwReg::Nr32) => "NR32",
+        Ok(HwReg::Nr33) => "NR33",
+        Ok(HwReg::Nr34) => "NR34",
+        Ok(HwReg::Nr41) => "NR41",
+        Ok(HwReg::Nr42) => "NR42",
+        Ok(HwReg::Nr43) => "NR43",
+        Ok(HwReg::Nr44) => "NR44",
+        Ok(HwReg::Nr50) => "NR50",
+        Ok(HwReg::Nr51) => "NR51",
+        Ok(HwReg::Nr52) => "NR52",
+        Ok(
+            reg @ (HwReg::Wave0
+            | HwReg::Wave1
+            | HwReg::Wave2
+            | HwReg::Wave3
+            | HwReg::Wave4
+            | HwReg::Wave5
+            | HwReg::Wave6
+            | HwReg::Wave7
+            | HwReg::Wave8
+            | HwReg::Wave9
+            | HwReg::WaveA
+            | HwReg::WaveB
+            | HwReg::WaveC
+            | HwReg::WaveD
+            | HwReg::WaveE
+            | HwReg::WaveF),
+        ) => WAVE_RAM_NAMES[usize::from(u16::from(reg) - HwReg::Wave0 as u16)],
+        Ok(HwReg::Lcdc) => "LCDC",
+        Ok(HwReg::Stat) => "STAT",
+        Ok(HwReg::Scy) => "SCY",
+        Ok(HwReg::Scx) => "SCX",
+        Ok(HwReg::Ly) => "LY",
+        Ok(HwReg::Lyc) => "LYC",
+        Ok(HwReg::Dma) => "DMA",
+        Ok(HwReg::Bgp) => "BGP",
+        Ok(HwReg::Obp0) => "OBP0",
+        Ok(HwReg::Obp1) => "OBP1",
+        Ok(HwReg::Wy) => "WY",
+        Ok(HwReg::Wx) => "WX",
+        Ok(HwReg::Key1) => "KEY1",
+        Ok(HwReg::Vbk) => "VBK",
+        Ok(HwReg::Hdma1) => "HDMA1",
+        Ok(HwReg::Hdma2) => "HDMA2",
+        Ok(HwReg::Hdma3) => "HDMA3",
+        Ok(HwReg::Hdma4) => "HDMA4",
+        Ok(HwReg::Hdma5) => "HDMA5",
+        Ok(HwReg::Rp) => "RP",
+        Ok(HwReg::Bcps) => "BCPS",
+        Ok(HwReg::Bcpd) => "BCPD",
+        Ok(HwReg::Ocps) => "OCPS",
+        Ok(HwReg::Ocpd) => "OCPD",
+        Ok(HwReg::Svbk) => "SVBK",
+        Ok(HwReg::Pcm12) => "PCM12",
+        Ok(HwReg::Pcm34) => "PCM34",
+        Ok(HwReg::Ie) => "IE",
+        // Not emulated, but still worth naming.
+        Err(0xFF50) => "BOOT",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// The I/O register with this [`name`], case-insensitively.
+pub fn parse(name: &str) -> Option<u16> {
+    (0xFF00..=0xFFFF)
+        .find(|&addr| self::name(addr).is_some_and(|reg| reg.eq_ignore_ascii_case(name)))
+}
+
+/// Whether the address is within the APU's register range, wave RAM included.
+pub fn is_apu(addr: u16) -> bool {
+    (0xFF10..=0xFF3F).contains(&addr)
+}
+
+/// The channel that the register at this address belongs to, if any.
+pub fn channel(addr: u16) -> Option<Channel> {
+    let channel = match addr {
+        0xFF10..=0xFF14 => Channel::Ch1,
+        // There is no NR20.
+        0xFF16..=0xFF19 => Channel::Ch2,
+        0xFF1A..=0xFF1E => Channel::Ch3,
+        // Nor NR40.
+        0xFF20..=0xFF23 => Channel::Ch4,
+        0xFF24..=0xFF26 => Channel::Global,
+        addr if WAVE_RAM.contains(&addr) => Channel::Ch3,
+        _ => return None,
+    };
+    Some(channel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{diff::RegDispl, tests::assert_golden};
+
+    #[test]
+    fn names_are_unchanged() {
+        let names: String = (0xFF00..=0xFFFF)
+            .map(|addr| format!("${:04x} {}\n", addr, RegDispl(addr)))
+            .collect();
+        assert_golden("regs", &names);
+    }
+
+    #[test]
+    fn names_parse_back() {
+        for addr in 0xFF00..=0xFFFF {
+            if let Some(name) = name(addr) {
+                assert_eq!(parse(name), Some(addr), "{}", name);
+            }
+        }
+        assert_eq!(parse("NR52"), Some(0xFF26));
+        assert_eq!(parse("nr52"), Some(0xFF26));
+        assert_eq!(parse("Boot"), Some(0xFF50));
+        assert_eq!(parse("NR20"), None);
+        // Only I/O registers are parsed, not the MBC's.
+        assert_eq!(parse("RAMG"), None);
+        assert_eq!(parse("ff26"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn wave_ram_parses() {
+        assert_eq!(parse("Wave RAM[0]"), Some(0xFF30));
+        assert_eq!(parse("Wave RAM[9]"), Some(0xFF39));
+        assert_eq!(parse("Wave RAM[10]"), Some(0xFF3A));
+        assert_eq!(parse("wave ram[15]"), Some(0xFF3F));
+        assert_eq!(parse("WAVE RAM[15]"), Some(0xFF3F));
+        assert_eq!(parse("Wave RAM[16]"), None);
+        assert_eq!(parse("Wave RAM[a]"), None);
+        assert_eq!(parse("Wave RAM[01]"), None);
+        assert_eq!(parse("Wave RAM 0"), None);
+        assert_eq!(parse("Wave RAM"), None);
+    }
+
+    #[test]
+    fn channels() {
+        assert_eq!(channel(0xFF10), Some(Channel::Ch1));
+        assert_eq!(channel(0xFF14), Some(Channel::Ch1));
+        assert_eq!(channel(0xFF15), None);
+        assert_eq!(channel(0xFF16), Some(Channel::Ch2));
+        assert_eq!(channel(0xFF1E), Some(Channel::Ch3));
+        assert_eq!(channel(0xFF1F), None);
+        assert_eq!(channel(0xFF23), Some(Channel::Ch4));
+        assert_eq!(channel(0xFF26), Some(Channel::Global));
+        assert_eq!(channel(0xFF27), None);
+        assert_eq!(channel(0xFF30), Some(Channel::Ch3));
+        assert_eq!(channel(0xFF3F), Some(Channel::Ch3));
+        assert_eq!(channel(0xFF40), None);
+        assert_eq!(channel(0xC000), None);
+        // Every channel register is an APU one.
+        assert!((0x0000..=0xFFFF)
+            .filter(|&addr| channel(addr).is_some())
+            .all(is_apu));
+    }
+}
@@ -0,0 +1,246 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with rendering a song as text, one line per tick, so that it can be
+//! "listened to" by eye, and two renders compared with ordinary diff tools.
+//!
+//! Each channel's cell shows its note (or the noise settings for CH4), its volume, and a `*` if it
+//! was triggered during that tick. Channels that are silenced by their DAC being off (or the APU
+//! being powered off) are shown as `---`; length counters and envelopes are not simulated.
+
+use std::fmt::Write;
+
+use gb_cpu_sim::reg::HwReg;
+
+use crate::{
+    run::IoAccess,
+    state::{self, Snapshot},
+};
+
+const NOTE_NAMES: [&str; 12] = [
+    "C-", "C#", "D-", "D#", "E-", "F-", "F#", "G-", "G#", "A-", "A#", "B-",
+];
+
+/// The name of the pitch closest to that frequency (in Hz), e.g. `A-4` for 440 Hz.
+fn note_name(freq: f64) -> String {
+    // MIDI note numbers, where A4 is 69.
+    let note = (69.0 + 12.0 * (freq / 440.0).log2()).round().max(0.0) as usize;
+    format!("{}{}", NOTE_NAMES[note % 12], note / 12 - 1)
+}
+
+/// Which registers drive a channel.
+#[derive(Debug, Clone, Copy)]
+enum Channel {
+    Pulse {
+        nrx2: HwReg,
+        nrx3: HwReg,
+        nrx4: HwReg,
+    },
+    Wave,
+    Noise,
+}
+
+impl Channel {
+    const ALL: [Self; 4] = [
+        Self::Pulse {
+            nrx2: HwReg::Nr12,
+            nrx3: HwReg::Nr13,
+            nrx4: HwReg::Nr14,
+        },
+        Self::Pulse {
+            nrx2: HwReg::Nr22,
+            nrx3: HwReg::Nr23,
+            nrx4: HwReg::Nr24,
+        },
+        Self::Wave,
+        Self::Noise,
+    ];
+
+    fn trigger_reg(self) -> HwReg {
+        match self {
+            Self::Pulse { nrx4, .. } => nrx4,
+            Self::Wave => HwReg::Nr34,
+            Self::Noise => HwReg::Nr44,
+        }
+    }
+}
+
+/// A channel's audible state at the end of a tick.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChannelState {
+    /// Set once the channel has been triggered with its DAC on, and until the DAC or APU is
+    /// turned off.
+    playing: bool,
+    /// Whether the channel was triggered during the tick.
+    triggered: bool,
+    /// The note name, or the noise settings.
+    pitch: String,
+    /// A single hex digit.
+    volume: u8,
+}
+
+impl ChannelState {
+    /// Updates the state from the end of a tick that contains writes.
+    fn fold(&mut self, channel: Channel, snapshot: &Snapshot) {
+        let reg = |reg: HwReg| snapshot.reg(reg as u16).unwrap_or(0);
+        let period = |nrx3: HwReg, nrx4: HwReg| {
+            f64::from(u16::from(reg(nrx3)) | u16::from(reg(nrx4) & 0x07) << 8)
+        };
+
+        let dac_on = match channel {
+            Channel::Pulse { nrx2, .. } => reg(nrx2) & 0xF8 != 0,
+            Channel::Wave => reg(HwReg::Nr30) & 0x80 != 0,
+            Channel::Noise => reg(HwReg::Nr42) & 0xF8 != 0,
+        } && snapshot
+            .reg(HwReg::Nr52 as u16)
+            .map_or(true, |nr52| nr52 & 0x80 != 0);
+        self.triggered = snapshot.retriggers(channel.trigger_reg() as u16) != 0;
+        self.playing = dac_on && (self.playing || self.triggered);
+
+        (self.pitch, self.volume) = match channel {
+            Channel::Pulse { nrx2, nrx3, nrx4 } => (
+                note_name(131072.0 / (2048.0 - period(nrx3, nrx4))),
+                reg(nrx2) >> 4,
+            ),
+            Channel::Wave => (
+                note_name(65536.0 / (2048.0 - period(HwReg::Nr33, HwReg::Nr34))),
+                // Full, half, or quarter volume.
+                [0x0, 0xF, 0x7, 0x3][usize::from(reg(HwReg::Nr32) >> 5 & 3)],
+            ),
+            Channel::Noise => (format!("N{:02x}", reg(HwReg::Nr43)), reg(HwReg::Nr42) >> 4),
+        };
+    }
+
+    fn cell(&self) -> String {
+        if self.playing {
+            format!(
+                "{:<4}{:x}{}",
+                self.pitch,
+                self.volume,
+                if self.triggered { '*' } else { ' ' }
+            )
+        } else {
+            format!("{:<6}", "---")
+        }
+    }
+}
+
+/// Renders the first `nb_ticks` ticks of the log, starting with a header line.
+pub fn render(io_log: &[IoAccess], nb_ticks: u64) -> String {
+    // Length expiries are not shown.
+    let snapshots = state::snapshots(io_log, &[]);
+    let tick_width = nb_ticks
+        .saturating_sub(1)
+        .to_string()
+        .len()
+        .max("tick".len());
+
+    let mut text = format!("{:>tick_width$}  CH1     CH2     CH3     CH4\n", "tick");
+    let mut channels: [ChannelState; 4] = Default::default();
+    for tick in 0..nb_ticks {
+        let snapshot = snapshots.get(&tick);
+        for (state, channel) in channels.iter_mut().zip(Channel::ALL) {
+            match snapshot {
+                Some(snapshot) => state.fold(channel, snapshot),
+                None => state.triggered = false,
+            }
+        }
+
+        write!(text, "{:>tick_width$}", tick).unwrap();
+        for state in &channels {
+            write!(text, "  {}", state.cell()).unwrap();
+        }
+        // Strip trailing padding, so that diff tools don't trip on it.
+        text.truncate(text.trim_end().len());
+        text.push('\n');
+    }
+    text
+}
+
+/// Fills in the `{song}` and `{side}` placeholders of a `--render` path.
+pub fn path(template: &str, song_id: u8, side: &str) -> String {
+    template
+        .replace("{song}", &song_id.to_string())
+        .replace("{side}", side)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Address, Timestamp};
+
+    #[test]
+    fn notes_are_named_after_the_closest_pitch() {
+        assert_eq!(note_name(440.0), "A-4");
+        assert_eq!(note_name(261.63), "C-4");
+        assert_eq!(note_name(277.18), "C#4");
+        assert_eq!(note_name(450.0), "A-4");
+        assert_eq!(note_name(32.70), "C-1");
+    }
+
+    #[test]
+    fn ticks_are_rendered_per_channel() {
+        let writes: &[(u64, &[(u16, u8)])] = &[
+            (
+                0,
+                &[
+                    (0xFF26, 0x80),
+                    // CH1 plays an A4 at full volume.
+                    (0xFF12, 0xF0),
+                    (0xFF13, 0xD6),
+                    (0xFF14, 0x86),
+                    // CH2's DAC is on, but it's never triggered.
+                    (0xFF17, 0xF0),
+                    // CH3's period is the same as CH1's, but that's an octave lower.
+                    (0xFF1A, 0x80),
+                    (0xFF1C, 0x20),
+                    (0xFF1D, 0xD6),
+                    (0xFF1E, 0x86),
+                    (0xFF21, 0x80),
+                    (0xFF22, 0x55),
+                    (0xFF23, 0x80),
+                ],
+            ),
+            // CH1's DAC is turned off, and CH4 is retriggered.
+            (2, &[(0xFF12, 0x00), (0xFF23, 0x80)]),
+            (3, &[(0xFF26, 0x00)]),
+        ];
+        let io_log: Vec<_> = writes
+            .iter()
+            .flat_map(|&(tick, writes)| {
+                writes.iter().map(move |&(addr, data)| IoAccess {
+                    when: Timestamp { tick, cycle: 0 },
+                    pc: Address(1, 0x4000),
+                    addr,
+                    data,
+                })
+            })
+            .collect();
+        assert_eq!(
+            render(&io_log, 4),
+            "tick  CH1     CH2     CH3     CH4\n   \
+                0  A-4 f*  ---     A-3 f*  N55 8*\n   \
+                1  A-4 f   ---     A-3 f   N55 8\n   \
+                2  ---     ---     A-3 f   N55 8*\n   \
+                3  ---     ---     ---     ---\n"
+        );
+        // The tick column grows as needed.
+        assert!(render(&io_log, 12_345)
+            .lines()
+            .nth(12_345)
+            .unwrap()
+            .starts_with("12344  ---"));
+    }
+
+    #[test]
+    fn paths_are_filled_in() {
+        assert_eq!(
+            path("out/{song}-{side}.txt", 12, "before"),
+            "out/12-before.txt"
+        );
+        assert_eq!(path("{side}{song}{song}", 3, "after"), "after33");
+    }
+}
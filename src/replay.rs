@@ -0,0 +1,129 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with feeding the register reads of one simulation into another.
+//!
+//! If the "after" build's writes match once it is fed the values that the "before" build read
+//! back, then the differences stem from read-back values (e.g. DIV); otherwise, they stem from the
+//! driver's own logic.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, VecDeque},
+    fmt::Display,
+};
+
+use crate::{diff::RegDispl, run::IoAccess};
+
+#[derive(Debug, Default)]
+struct Queue {
+    values: VecDeque<u8>,
+    recorded: usize,
+    requested: usize,
+}
+
+/// The recorded read values, handed out in order for each register.
+#[derive(Debug)]
+pub struct ReadQueues(RefCell<BTreeMap<u16, Queue>>);
+
+impl ReadQueues {
+    pub fn new(read_log: &[IoAccess]) -> Self {
+        let mut queues = BTreeMap::<_, Queue>::new();
+        for access in read_log {
+            let queue = queues.entry(access.addr).or_default();
+            queue.values.push_back(access.data);
+            queue.recorded += 1;
+        }
+        Self(RefCell::new(queues))
+    }
+
+    /// Returns `None` once the recording for that register has run out.
+    pub fn next(&self, addr: u16) -> Option<u8> {
+        let mut queues = self.0.borrow_mut();
+        let queue = queues.entry(addr).or_default();
+        queue.requested += 1;
+        queue.values.pop_front()
+    }
+
+    /// The registers that were not read as many times during the replay as during the recording.
+    pub fn count_mismatches(&self) -> Vec<CountMismatch> {
+        self.0
+            .borrow()
+            .iter()
+            .filter(|(_, queue)| queue.recorded != queue.requested)
+            .map(|(&reg, queue)| CountMismatch {
+                reg,
+                recorded: queue.recorded,
+                requested: queue.requested,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CountMismatch {
+    pub reg: u16,
+    pub recorded: usize,
+    pub requested: usize,
+}
+
+impl Display for CountMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} was read {} times by \"before\", but {} times during the replay",
+            RegDispl(self.reg),
+            self.recorded,
+            self.requested
+        )?;
+        if self.requested > self.recorded {
+            write!(
+                f,
+                " (the last {} got their simulated value)",
+                self.requested - self.recorded
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// What the replay says about the differences between the two builds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The builds' writes already matched.
+    NothingToExplain,
+    /// The writes match once "after" is fed "before"'s read values.
+    ReadBack,
+    /// The writes still differ, by this many findings.
+    Logic(usize),
+}
+
+impl Outcome {
+    pub fn new(differed: bool, nb_replay_findings: usize) -> Self {
+        match (differed, nb_replay_findings) {
+            (false, _) => Self::NothingToExplain,
+            (true, 0) => Self::ReadBack,
+            (true, n) => Self::Logic(n),
+        }
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NothingToExplain => write!(f, "no differences to explain"),
+            Self::ReadBack => write!(
+                f,
+                "writes match when fed \"before\"'s read values — the differences come from values read back"
+            ),
+            Self::Logic(n) => write!(
+                f,
+                "{} findings remain when fed \"before\"'s read values — the differences come from the driver's logic",
+                n
+            ),
+        }
+    }
+}
@@ -0,0 +1,260 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with rendering a run into a single, self-contained HTML file.
+
+use std::{
+    fmt::{Display, Write as _},
+    fs,
+    ops::Range,
+};
+
+use super::Reporter;
+use crate::{throughput::RunStats, DiagnosticLevel, SongIDs};
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; }
+details { border: 1px solid #ccc; border-radius: 4px; margin: 0.5em 0; padding: 0.5em; }
+summary { font-weight: bold; cursor: pointer; }
+summary.ok { color: #1a7f37; }
+summary.fail { color: #cf222e; }
+h3 { font-size: 1em; margin: 0.8em 0 0.2em; }
+.line { font-family: monospace; white-space: pre-wrap; }
+.error { color: #cf222e; }
+.warning { color: #9a6700; }
+.note { color: #0969da; }
+";
+
+/// Escapes text for use in HTML content and attribute values.
+fn escape(text: &dyn Display) -> String {
+    let mut escaped = String::new();
+    for c in text.to_string().chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn level_class(level: DiagnosticLevel) -> &'static str {
+    match level {
+        DiagnosticLevel::Error => "error",
+        DiagnosticLevel::Warning => "warning",
+        DiagnosticLevel::Note => "note",
+    }
+}
+
+#[derive(Debug)]
+struct Song {
+    title: String,
+    ok: bool,
+    fingerprint: Option<String>,
+    /// Already rendered.
+    body: String,
+}
+
+/// Buffers the whole run, and writes it out once the summary is known.
+#[derive(Debug)]
+pub(crate) struct HtmlReporter {
+    path: String,
+    title: String,
+    /// Warnings about the run as a whole, already rendered.
+    general: String,
+    songs: Vec<Song>,
+    /// Only known once the run is over.
+    stats: Vec<String>,
+}
+
+impl HtmlReporter {
+    pub fn new(path: String, before: &str, after: &str) -> Self {
+        Self {
+            path,
+            title: format!("gbsdiff: {} vs {}", before, after),
+            general: String::new(),
+            songs: Vec::new(),
+            stats: Vec::new(),
+        }
+    }
+
+    /// Where the next element goes.
+    fn body(&mut self) -> &mut String {
+        match self.songs.last_mut() {
+            Some(song) => &mut song.body,
+            None => &mut self.general,
+        }
+    }
+
+    fn push_line(&mut self, class: &str, text: &dyn Display) {
+        // `write!`ing to a `String` cannot fail.
+        writeln!(
+            self.body(),
+            "<div class=\"line {}\">{}</div>",
+            class,
+            escape(text)
+        )
+        .unwrap();
+    }
+
+    fn render(&self) -> String {
+        let mut html = String::new();
+        writeln!(
+            html,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">"
+        )
+        .unwrap();
+        writeln!(html, "<title>{}</title>", escape(&self.title)).unwrap();
+        writeln!(html, "<style>{}</style>\n</head>\n<body>", STYLE).unwrap();
+        writeln!(html, "<h1>{}</h1>", escape(&self.title)).unwrap();
+
+        let failing: Vec<_> = self
+            .songs
+            .iter()
+            .enumerate()
+            .filter(|(_, song)| !song.ok)
+            .collect();
+        if failing.is_empty() {
+            writeln!(html, "<p class=\"line\">All songs are OK!</p>").unwrap();
+        } else {
+            write!(html, "<p class=\"line error\">Failing songs:").unwrap();
+            for (i, song) in failing {
+                write!(html, " <a href=\"#song-{}\">{}</a>", i, escape(&song.title)).unwrap();
+            }
+            writeln!(html, "</p>").unwrap();
+        }
+        html.push_str(&self.general);
+
+        for (i, song) in self.songs.iter().enumerate() {
+            writeln!(
+                html,
+                "<details id=\"song-{}\"{}>\n<summary class=\"{}\">Songs {}: {}{}</summary>",
+                i,
+                if song.ok { "" } else { " open" },
+                if song.ok { "ok" } else { "fail" },
+                escape(&song.title),
+                if song.ok { "OK" } else { "failed" },
+                song.fingerprint
+                    .as_ref()
+                    .map_or_else(String::new, |fingerprint| format!(
+                        " (diff fingerprint {})",
+                        escape(fingerprint)
+                    )),
+            )
+            .unwrap();
+            html.push_str(&song.body);
+            writeln!(html, "</details>").unwrap();
+        }
+
+        writeln!(html, "<h2>Run statistics</h2>").unwrap();
+        for line in &self.stats {
+            writeln!(html, "<div class=\"line\">{}</div>", escape(line)).unwrap();
+        }
+
+        writeln!(html, "</body>\n</html>").unwrap();
+        html
+    }
+}
+
+impl Reporter for HtmlReporter {
+    fn warning(&mut self, message: &dyn Display) {
+        self.push_line("warning", &format_args!("warning: {}", message));
+    }
+
+    fn song_start(&mut self, songs: &SongIDs) {
+        self.songs.push(Song {
+            title: songs.to_string(),
+            ok: true,
+            fingerprint: None,
+            body: String::new(),
+        });
+    }
+
+    fn simulation_failed(&mut self, path: &str, song_id: u8, err: &dyn Display) {
+        if let Some(song) = self.songs.last_mut() {
+            song.ok = false;
+        }
+        self.push_line(
+            "error",
+            &format_args!("Failed to simulate {} song #{}: {}", path, song_id, err),
+        );
+    }
+
+    fn tick(&mut self, tick: u64) {
+        writeln!(self.body(), "<h3>Tick {}</h3>", tick).unwrap();
+    }
+
+    fn diagnostic(
+        &mut self,
+        level: DiagnosticLevel,
+        cycle: u32,
+        pc: &dyn Display,
+        message: &dyn Display,
+    ) {
+        self.push_line(
+            level_class(level),
+            &format_args!(
+                "{} on cycle {} (PC = {}): {}",
+                level.name(),
+                cycle,
+                pc,
+                message
+            ),
+        );
+    }
+
+    fn fingerprint(&mut self, fingerprint: &dyn Display) {
+        if let Some(song) = self.songs.last_mut() {
+            song.fingerprint = Some(fingerprint.to_string());
+        }
+    }
+
+    fn finding(&mut self, level: DiagnosticLevel, message: &dyn Display) {
+        self.push_line(
+            level_class(level),
+            &format_args!("{}: {}", level.name(), message),
+        );
+    }
+
+    fn heading(&mut self, title: &dyn Display) {
+        writeln!(self.body(), "<h3>{}</h3>", escape(title)).unwrap();
+    }
+
+    fn line(&mut self, message: &dyn Display) {
+        self.push_line("", message);
+    }
+
+    fn columns(&mut self, before: &str, after: &str, level: Option<DiagnosticLevel>) {
+        self.push_line(
+            level.map_or("", level_class),
+            &format_args!("  {} | {}", before, after),
+        );
+    }
+
+    fn song_end(&mut self, _songs: &SongIDs, ok: bool, partial: Option<Range<u64>>) {
+        if let Some(song) = self.songs.last_mut() {
+            song.ok = ok;
+        }
+        if let (true, Some(ticks)) = (ok, partial) {
+            self.line(&format_args!(
+                "OK! (only ticks {} to {} were compared)",
+                ticks.start,
+                ticks.end.saturating_sub(1)
+            ));
+        }
+    }
+
+    fn summary(&mut self, _failed: &[SongIDs], _init_only: &[SongIDs], stats: &RunStats) {
+        self.stats = stats.lines();
+        fs::write(&self.path, self.render()).unwrap_or_else(|err| {
+            eprintln!("Failed to write HTML report: {}", err);
+            super::exit(2);
+        });
+    }
+}
@@ -0,0 +1,343 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with rendering a run as GitHub-flavored Markdown, for pasting into pull
+//! requests.
+//!
+//! Like the HTML report, nothing here goes through `owo_colors`, so the output never contains
+//! escape sequences, whatever `--color` says.
+
+use std::{
+    fmt::{Display, Write as _},
+    fs,
+    ops::Range,
+};
+
+use super::Reporter;
+use crate::{throughput::RunStats, DiagnosticLevel, SongIDs};
+
+#[derive(Debug)]
+struct Song {
+    title: String,
+    ok: bool,
+    /// Indexed by level.
+    counts: [usize; DiagnosticLevel::ALL.len()],
+    first_tick: Option<u64>,
+    fingerprint: Option<String>,
+    /// Plain text, one entry per line.
+    lines: Vec<String>,
+}
+
+/// Buffers the whole run, and writes it out once the summary is known.
+#[derive(Debug)]
+pub(crate) struct MarkdownReporter {
+    path: String,
+    title: String,
+    secs_per_tick: f64,
+    /// Warnings about the run as a whole.
+    general: Vec<String>,
+    songs: Vec<Song>,
+}
+
+/// Table cells cannot contain pipes, nor span lines.
+fn cell(text: &dyn Display) -> String {
+    text.to_string().replace('|', "\\|").replace('\n', " ")
+}
+
+/// A fence that none of the lines can close early.
+fn fence(lines: &[String]) -> String {
+    let longest_run = lines
+        .iter()
+        .flat_map(|line| line.split(|c| c != '`'))
+        .map(str::len)
+        .max()
+        .unwrap_or(0);
+    "`".repeat(longest_run.max(2) + 1)
+}
+
+impl MarkdownReporter {
+    pub fn new(path: String, before: &str, after: &str, secs_per_tick: f64) -> Self {
+        Self {
+            path,
+            title: format!("gbsdiff: `{}` vs `{}`", before, after),
+            secs_per_tick,
+            general: Vec::new(),
+            songs: Vec::new(),
+        }
+    }
+
+    fn push_line(&mut self, text: &dyn Display) {
+        let line = text.to_string();
+        match self.songs.last_mut() {
+            Some(song) => song.lines.push(line),
+            None => self.general.push(line),
+        }
+    }
+
+    fn count(&mut self, level: DiagnosticLevel, count: usize) {
+        if let Some(song) = self.songs.last_mut() {
+            song.counts[level as usize] += count;
+        }
+    }
+
+    /// Formats as `M:SS.cc`, like `--stat`.
+    fn format_time(&self, tick: u64) -> String {
+        let centis = (tick as f64 * self.secs_per_tick * 100.0).round() as u64;
+        format!(
+            "{}:{:02}.{:02}",
+            centis / 6000,
+            centis / 100 % 60,
+            centis % 100
+        )
+    }
+
+    fn code_block(md: &mut String, lines: &[String]) {
+        let fence = fence(lines);
+        writeln!(md, "{}text", fence).unwrap();
+        for line in lines {
+            writeln!(md, "{}", line).unwrap();
+        }
+        writeln!(md, "{}", fence).unwrap();
+    }
+
+    fn render(&self, failed: &[SongIDs]) -> String {
+        // `write!`ing to a `String` cannot fail.
+        let mut md = String::new();
+        writeln!(md, "### {}\n", self.title).unwrap();
+        if failed.is_empty() {
+            writeln!(md, "All songs are OK.\n").unwrap();
+        } else {
+            writeln!(
+                md,
+                "{} of {} songs failing.\n",
+                failed.len(),
+                self.songs.len()
+            )
+            .unwrap();
+        }
+        if !self.general.is_empty() {
+            Self::code_block(&mut md, &self.general);
+            md.push('\n');
+        }
+
+        writeln!(
+            md,
+            "| Songs | Status | Errors | Warnings | Notes | First difference | Fingerprint |"
+        )
+        .unwrap();
+        writeln!(md, "|---|---|---:|---:|---:|---|---|").unwrap();
+        for song in &self.songs {
+            writeln!(
+                md,
+                "| {} | {} | {} | {} | {} | {} | {} |",
+                cell(&song.title),
+                if song.ok { "✅ OK" } else { "❌ failed" },
+                song.counts[DiagnosticLevel::Error as usize],
+                song.counts[DiagnosticLevel::Warning as usize],
+                song.counts[DiagnosticLevel::Note as usize],
+                song.first_tick.map_or_else(String::new, |tick| format!(
+                    "tick {} ({})",
+                    tick,
+                    self.format_time(tick)
+                )),
+                song.fingerprint
+                    .as_ref()
+                    .map_or_else(String::new, |fingerprint| format!("`{}`", fingerprint)),
+            )
+            .unwrap();
+        }
+
+        for song in self.songs.iter().filter(|song| !song.ok) {
+            writeln!(
+                md,
+                "\n<details>\n<summary>Songs {}</summary>\n",
+                cell(&song.title)
+            )
+            .unwrap();
+            Self::code_block(&mut md, &song.lines);
+            writeln!(md, "\n</details>").unwrap();
+        }
+        md
+    }
+}
+
+impl Reporter for MarkdownReporter {
+    fn warning(&mut self, message: &dyn Display) {
+        self.push_line(&format_args!("warning: {}", message));
+    }
+
+    fn song_start(&mut self, songs: &SongIDs) {
+        self.songs.push(Song {
+            title: songs.to_string(),
+            ok: true,
+            counts: Default::default(),
+            first_tick: None,
+            fingerprint: None,
+            lines: Vec::new(),
+        });
+    }
+
+    fn simulation_failed(&mut self, path: &str, song_id: u8, err: &dyn Display) {
+        if let Some(song) = self.songs.last_mut() {
+            song.ok = false;
+        }
+        self.push_line(&format_args!(
+            "Failed to simulate {} song #{}: {}",
+            path, song_id, err
+        ));
+    }
+
+    fn tick(&mut self, tick: u64) {
+        self.push_line(&format_args!("==== Tick {} ====", tick));
+    }
+
+    fn diagnostic(
+        &mut self,
+        level: DiagnosticLevel,
+        cycle: u32,
+        pc: &dyn Display,
+        message: &dyn Display,
+    ) {
+        self.count(level, 1);
+        self.push_line(&format_args!(
+            "{} on cycle {} (PC = {}): {}",
+            level.name(),
+            cycle,
+            pc,
+            message
+        ));
+    }
+
+    fn first_difference(&mut self, tick: u64) {
+        if let Some(song) = self.songs.last_mut() {
+            song.first_tick = Some(tick);
+        }
+    }
+
+    fn fingerprint(&mut self, fingerprint: &dyn Display) {
+        if let Some(song) = self.songs.last_mut() {
+            song.fingerprint = Some(fingerprint.to_string());
+        }
+    }
+
+    /// So that the table has the actual totals.
+    fn cut(&mut self, level: DiagnosticLevel, count: usize) {
+        self.count(level, count);
+    }
+
+    fn finding(&mut self, level: DiagnosticLevel, message: &dyn Display) {
+        self.count(level, 1);
+        self.push_line(&format_args!("{}: {}", level.name(), message));
+    }
+
+    fn heading(&mut self, title: &dyn Display) {
+        self.push_line(&format_args!("--- {} ---", title));
+    }
+
+    fn line(&mut self, message: &dyn Display) {
+        self.push_line(message);
+    }
+
+    fn columns(&mut self, before: &str, after: &str, _level: Option<DiagnosticLevel>) {
+        self.push_line(&format_args!("  {} | {}", before, after));
+    }
+
+    fn song_end(&mut self, _songs: &SongIDs, ok: bool, _partial: Option<Range<u64>>) {
+        if let Some(song) = self.songs.last_mut() {
+            song.ok = ok;
+        }
+    }
+
+    fn summary(&mut self, failed: &[SongIDs], _init_only: &[SongIDs], _stats: &RunStats) {
+        fs::write(&self.path, self.render(failed)).unwrap_or_else(|err| {
+            eprintln!("Failed to write Markdown report: {}", err);
+            super::exit(2);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cells_and_fences_cannot_be_broken_out_of() {
+        assert_eq!(cell(&"a | b\nc"), "a \\| b c");
+        assert_eq!(fence(&[]), "```");
+        assert_eq!(fence(&["no backticks".into(), "`one`".into()]), "```");
+        assert_eq!(fence(&["````".into(), "a ``` b".into()]), "`````");
+    }
+
+    #[test]
+    fn failing_songs_get_details() {
+        let path = std::env::temp_dir().join(format!("gbsdiff-test-{}.md", std::process::id()));
+        let mut md = MarkdownReporter::new(
+            path.to_str().unwrap().into(),
+            "before.gbs",
+            "after.gbs",
+            0.5,
+        );
+        md.warning(&"no symbols");
+
+        md.song_start(&SongIDs::Both(1, 1));
+        md.song_end(&SongIDs::Both(1, 1), true, None);
+
+        md.song_start(&SongIDs::Both(2, 3));
+        md.tick(5);
+        md.diagnostic(DiagnosticLevel::Error, 12, &"$01:4000", &"wrote ``` here");
+        // Cut diagnostics are counted, but not shown.
+        md.cut(DiagnosticLevel::Note, 4);
+        md.first_difference(5);
+        md.fingerprint(&"abc");
+        md.song_end(&SongIDs::Both(2, 3), false, None);
+
+        md.summary(&[SongIDs::Both(2, 3)], &[], &RunStats::default());
+        let report = fs::read_to_string(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(
+            report,
+            "### gbsdiff: `before.gbs` vs `after.gbs`\n\
+             \n\
+             1 of 2 songs failing.\n\
+             \n\
+             ```text\n\
+             warning: no symbols\n\
+             ```\n\
+             \n\
+             | Songs | Status | Errors | Warnings | Notes | First difference | Fingerprint |\n\
+             |---|---|---:|---:|---:|---|---|\n\
+             | 1 | ✅ OK | 0 | 0 | 0 |  |  |\n\
+             | 2 and 3 | ❌ failed | 1 | 0 | 4 | tick 5 (0:02.50) | `abc` |\n\
+             \n\
+             <details>\n\
+             <summary>Songs 2 and 3</summary>\n\
+             \n\
+             ````text\n\
+             ==== Tick 5 ====\n\
+             Error on cycle 12 (PC = $01:4000): wrote ``` here\n\
+             ````\n\
+             \n\
+             </details>\n"
+        );
+    }
+
+    #[test]
+    fn passing_runs_have_no_details() {
+        let mut md = MarkdownReporter::new(String::new(), "a.gbs", "b.gbs", 0.5);
+        md.song_start(&SongIDs::AfterOnly(4));
+        md.song_end(&SongIDs::AfterOnly(4), true, None);
+        assert_eq!(
+            md.render(&[]),
+            "### gbsdiff: `a.gbs` vs `b.gbs`\n\
+             \n\
+             All songs are OK.\n\
+             \n\
+             | Songs | Status | Errors | Warnings | Notes | First difference | Fingerprint |\n\
+             |---|---|---:|---:|---:|---|---|\n\
+             | 4 (after only) | ✅ OK | 0 | 0 | 0 |  |  |\n"
+        );
+    }
+}
@@ -0,0 +1,510 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with presenting the results of a run.
+
+use std::{fmt::Display, ops::Range};
+
+use owo_colors::{OwoColorize, Stream::Stdout};
+use slicedisplay::SliceDisplay;
+
+use crate::{throughput::RunStats, DiagnosticLevel, SongIDs};
+
+mod html;
+pub(crate) use html::HtmlReporter;
+mod markdown;
+pub(crate) use markdown::MarkdownReporter;
+mod output;
+pub(crate) use output::{exit, Output, Sink};
+mod stat;
+pub(crate) use stat::StatReporter;
+
+/// Receives everything that a run reports, in order.
+///
+/// Anything reported before the first [`Self::song_start`] is about the run as a whole.
+pub(crate) trait Reporter {
+    /// Something that is being done, only relevant while the run is in progress.
+    fn progress(&mut self, _verb: &str, _what: &dyn Display) {}
+    /// A problem with the inputs or the run itself, rather than a difference.
+    fn warning(&mut self, message: &dyn Display);
+
+    fn song_start(&mut self, songs: &SongIDs);
+    fn simulation_failed(&mut self, path: &str, song_id: u8, err: &dyn Display);
+    /// The following diagnostics belong to this tick.
+    fn tick(&mut self, tick: u64);
+    /// `pc` is already formatted, along with the symbol it belongs to (if any).
+    fn diagnostic(
+        &mut self,
+        level: DiagnosticLevel,
+        cycle: u32,
+        pc: &dyn Display,
+        message: &dyn Display,
+    );
+    /// The earliest tick at which the songs differ; reported at most once per song, before
+    /// [`Self::song_end`].
+    fn first_difference(&mut self, _tick: u64) {}
+    /// A digest of the song's write differences (see [`crate::fingerprint`]); reported at most
+    /// once per song, before [`Self::song_end`].
+    fn fingerprint(&mut self, fingerprint: &dyn Display) {
+        self.info(&format_args!("Diff fingerprint: {}", fingerprint));
+    }
+    /// How many items of that level were counted, but not rendered; see [`Budget`].
+    fn cut(&mut self, _level: DiagnosticLevel, _count: usize) {}
+    /// A diagnostic that is not tied to a particular point in the song.
+    fn finding(&mut self, level: DiagnosticLevel, message: &dyn Display);
+    fn heading(&mut self, title: &dyn Display);
+    fn line(&mut self, message: &dyn Display);
+    /// A write that matched, shown around the last diagnostic (`--context`).
+    fn context(&mut self, cycle: u32, write: &dyn Display) {
+        self.line(&format_args!("  cycle {}: {}", cycle, write));
+    }
+    /// Statistics and other information that isn't a finding.
+    fn info(&mut self, message: &dyn Display) {
+        self.line(message);
+    }
+    /// Extra information, mostly useful when debugging gbsdiff itself.
+    fn detail(&mut self, _message: &dyn Display) {}
+    /// A row of a two-column view of both files; `level` is set if the cells differ.
+    fn columns(&mut self, before: &str, after: &str, level: Option<DiagnosticLevel>);
+    /// `partial` is the range of ticks that were compared, if not the whole song.
+    fn song_end(&mut self, songs: &SongIDs, ok: bool, partial: Option<Range<u64>>);
+
+    /// `init_only` lists the failing songs whose PLAY stream matched (see [`PhaseTally`]).
+    fn summary(&mut self, failed: &[SongIDs], init_only: &[SongIDs], stats: &RunStats);
+}
+
+/// Limits how many items get rendered per song.
+///
+/// Every top-level item (a diagnostic, or a difference in tick state) counts as one, whatever the
+/// output mode; items past the limits are still counted, so that totals are never affected.
+#[derive(Debug)]
+pub(crate) struct Budget {
+    max_per_level: usize,
+    max_total: usize,
+    /// Indexed by level.
+    rendered: [usize; DiagnosticLevel::ALL.len()],
+    cut: [usize; DiagnosticLevel::ALL.len()],
+}
+
+impl Budget {
+    pub fn new(max_per_level: usize, max_total: usize) -> Self {
+        Self {
+            max_per_level,
+            max_total,
+            rendered: Default::default(),
+            cut: Default::default(),
+        }
+    }
+
+    /// Whether an item of this level should be rendered; either way, it is counted.
+    pub fn admit(&mut self, level: DiagnosticLevel) -> bool {
+        let level = level as usize;
+        let total: usize = self.rendered.iter().sum();
+        if total == self.max_total || self.rendered[level] == self.max_per_level {
+            self.cut[level] += 1;
+            false
+        } else {
+            self.rendered[level] += 1;
+            true
+        }
+    }
+
+    /// Reports how many items were counted but not rendered, if any.
+    pub fn report_cuts(&self, reporter: &mut dyn Reporter) {
+        if self.rendered.iter().sum::<usize>() == self.max_total {
+            reporter.line(&format_args!(
+                "...stopped at {} diagnostics. Go fix your code!",
+                self.max_total
+            ));
+        }
+        for (level, &cut) in DiagnosticLevel::ALL.iter().zip(&self.cut) {
+            if cut != 0 {
+                reporter.cut(*level, cut);
+                reporter.line(&format_args!("...{} more {}s not shown", cut, level.name()));
+            }
+        }
+    }
+}
+
+/// Which part of a song something belongs to: INIT's power-up sequence, or the PLAY calls after
+/// it. A difference in the former has very different implications (clicks on song start, NR52
+/// ordering...) than one in the middle of a song.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Phase {
+    Init,
+    Play,
+}
+
+impl Phase {
+    pub fn of(tick: u64) -> Self {
+        if tick == 0 {
+            Self::Init
+        } else {
+            Self::Play
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Init => "INIT sequence",
+            Self::Play => "PLAY stream",
+        }
+    }
+}
+
+/// A song's diagnostics counted per [`Phase`], and which phases made it fail.
+#[derive(Debug, Default)]
+pub(crate) struct PhaseTally {
+    /// Indexed by phase, then by level.
+    counts: [[usize; DiagnosticLevel::ALL.len()]; 2],
+    failed: [bool; 2],
+}
+
+impl PhaseTally {
+    pub fn count(&mut self, tick: u64, level: DiagnosticLevel) {
+        self.counts[Phase::of(tick) as usize][level as usize] += 1;
+    }
+
+    pub fn fail(&mut self, phase: Phase) {
+        self.failed[phase as usize] = true;
+    }
+
+    /// Whether the song failed only because of its INIT sequence.
+    pub fn init_only(&self) -> bool {
+        self.failed[Phase::Init as usize] && !self.failed[Phase::Play as usize]
+    }
+
+    /// Reports a line per phase, e.g. "INIT sequence: 2 errors".
+    pub fn report(&self, reporter: &mut dyn Reporter) {
+        for phase in [Phase::Init, Phase::Play] {
+            let counts: Vec<_> = DiagnosticLevel::ALL
+                .iter()
+                .zip(&self.counts[phase as usize])
+                .filter(|(_, &count)| count != 0)
+                .map(|(level, &count)| {
+                    format!(
+                        "{} {}{}",
+                        count,
+                        level.name().to_lowercase(),
+                        if count == 1 { "" } else { "s" }
+                    )
+                })
+                .collect();
+            let verdict = match (counts.is_empty(), self.failed[phase as usize]) {
+                (true, false) => "OK".to_string(),
+                (true, true) => "failed".to_string(),
+                (false, _) => counts.join(", "),
+            };
+            reporter.info(&format_args!("{}: {}", phase.name(), verdict));
+        }
+    }
+}
+
+/// How much the terminal output says, besides the diagnostics (which are always printed).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Verbosity {
+    /// No progress banners, statistics, nor "OK!" lines.
+    Quiet,
+    Normal,
+    /// Also print details.
+    Verbose,
+}
+
+/// Prints to the terminal.
+#[derive(Debug)]
+pub(crate) struct TextReporter {
+    pub verbosity: Verbosity,
+    pub out: Output,
+}
+
+impl TextReporter {
+    pub fn new(verbosity: Verbosity, out: &Sink) -> Self {
+        Self {
+            verbosity,
+            out: Output::Stdout(out.clone()),
+        }
+    }
+}
+
+impl Reporter for TextReporter {
+    fn progress(&mut self, verb: &str, what: &dyn Display) {
+        if self.verbosity == Verbosity::Quiet {
+            return;
+        }
+        self.out.progress(&format_args!(
+            "{} {} {}...",
+            colorize!(Stdout, "==>", bold),
+            colorize!(Stdout, verb, bright_cyan, bold),
+            what
+        ));
+    }
+
+    fn warning(&mut self, message: &dyn Display) {
+        self.out.line(&format_args!(
+            "{}: {}",
+            colorize!(Stdout, "warning", bright_yellow, bold),
+            message
+        ));
+    }
+
+    fn song_start(&mut self, songs: &SongIDs) {
+        self.out.song_start(songs);
+    }
+
+    fn simulation_failed(&mut self, path: &str, song_id: u8, err: &dyn Display) {
+        self.out.line(&format_args!(
+            "{} to simulate {} song #{}: {}",
+            colorize!(Stdout, "Failed", bold, bright_red),
+            path,
+            song_id,
+            err
+        ));
+    }
+
+    fn tick(&mut self, tick: u64) {
+        self.out.line(&format_args!(
+            "{} Tick {} {}",
+            colorize!(Stdout, "====", bold),
+            tick,
+            colorize!(Stdout, "====", bold)
+        ))
+    }
+
+    fn diagnostic(
+        &mut self,
+        level: DiagnosticLevel,
+        cycle: u32,
+        pc: &dyn Display,
+        message: &dyn Display,
+    ) {
+        self.out.diagnostic(level);
+        self.out.line(&format_args!(
+            "{} on cycle {} (PC = {}): {}",
+            level, cycle, pc, message
+        ));
+    }
+
+    fn finding(&mut self, level: DiagnosticLevel, message: &dyn Display) {
+        self.out.diagnostic(level);
+        self.out.line(&format_args!("{}: {}", level, message));
+    }
+
+    fn heading(&mut self, title: &dyn Display) {
+        self.out.line(&format_args!("--- {} ---", title));
+    }
+
+    fn line(&mut self, message: &dyn Display) {
+        self.out.line(message);
+    }
+
+    fn context(&mut self, cycle: u32, write: &dyn Display) {
+        self.out.line(&format_args!(
+            "{}",
+            colorize!(Stdout, format!("  cycle {}: {}", cycle, write), dimmed)
+        ));
+    }
+
+    fn info(&mut self, message: &dyn Display) {
+        if self.verbosity != Verbosity::Quiet {
+            self.out.line(message);
+        }
+    }
+
+    fn detail(&mut self, message: &dyn Display) {
+        if self.verbosity == Verbosity::Verbose {
+            self.out.line(message);
+        }
+    }
+
+    fn columns(&mut self, before: &str, after: &str, level: Option<DiagnosticLevel>) {
+        // The padding is part of the cell, so coloring it is harmless.
+        let paint = |cell: &str| match level {
+            _ if cell.trim().is_empty() => cell.to_string(),
+            None => cell.to_string(),
+            Some(DiagnosticLevel::Error) => colorize!(Stdout, cell, bright_red).to_string(),
+            Some(DiagnosticLevel::Warning) => colorize!(Stdout, cell, bright_yellow).to_string(),
+            Some(DiagnosticLevel::Note) => colorize!(Stdout, cell, bright_blue).to_string(),
+        };
+        self.out.line(&format_args!(
+            "{}",
+            format!("  {} | {}", paint(before), paint(after)).trim_end()
+        ));
+    }
+
+    fn song_end(&mut self, _songs: &SongIDs, ok: bool, partial: Option<Range<u64>>) {
+        self.out.song_end();
+        match (ok, partial) {
+            (false, _) => (),
+            (true, _) if self.verbosity == Verbosity::Quiet => (),
+            (true, None) => self.out.line(&colorize!(Stdout, "OK!", bright_green, bold)),
+            (true, Some(ticks)) => self.out.line(&format_args!(
+                "{} (only ticks {} to {} were compared)",
+                colorize!(Stdout, "OK!", bright_green, bold),
+                ticks.start,
+                ticks.end.saturating_sub(1),
+            )),
+        }
+    }
+
+    fn summary(&mut self, failed: &[SongIDs], init_only: &[SongIDs], stats: &RunStats) {
+        if failed.is_empty() {
+            self.out.line(&format_args!(
+                "{} {}",
+                colorize!(Stdout, "==>", bold),
+                colorize!(Stdout, "All songs are OK!", bright_green, bold)
+            ));
+        } else if failed.len() == 1 {
+            self.out.line(&format_args!(
+                "{} song: {}",
+                colorize!(Stdout, "Failing", bright_red, bold),
+                failed[0]
+            ));
+        } else {
+            self.out.line(&format_args!(
+                "{} songs: {}",
+                colorize!(Stdout, "Failing", bright_red, bold),
+                failed.display()
+            ));
+        }
+        if !init_only.is_empty() {
+            self.out.line(&format_args!(
+                "{} (usually quick to fix): {}",
+                colorize!(Stdout, "Only INIT differs", bright_yellow, bold),
+                init_only.display()
+            ));
+        }
+
+        if self.verbosity != Verbosity::Quiet {
+            self.out.line(&format_args!(
+                "{} {}",
+                colorize!(Stdout, "==>", bold),
+                colorize!(Stdout, "Run statistics", bold)
+            ));
+            for line in stats.lines() {
+                self.out.line(&line);
+            }
+        }
+    }
+}
+
+/// Forwards everything to several reporters.
+pub(crate) struct Reporters(pub Vec<Box<dyn Reporter>>);
+
+impl Reporter for Reporters {
+    fn progress(&mut self, verb: &str, what: &dyn Display) {
+        for reporter in &mut self.0 {
+            reporter.progress(verb, what);
+        }
+    }
+
+    fn warning(&mut self, message: &dyn Display) {
+        for reporter in &mut self.0 {
+            reporter.warning(message);
+        }
+    }
+
+    fn song_start(&mut self, songs: &SongIDs) {
+        for reporter in &mut self.0 {
+            reporter.song_start(songs);
+        }
+    }
+
+    fn simulation_failed(&mut self, path: &str, song_id: u8, err: &dyn Display) {
+        for reporter in &mut self.0 {
+            reporter.simulation_failed(path, song_id, err);
+        }
+    }
+
+    fn tick(&mut self, tick: u64) {
+        for reporter in &mut self.0 {
+            reporter.tick(tick);
+        }
+    }
+
+    fn diagnostic(
+        &mut self,
+        level: DiagnosticLevel,
+        cycle: u32,
+        pc: &dyn Display,
+        message: &dyn Display,
+    ) {
+        for reporter in &mut self.0 {
+            reporter.diagnostic(level, cycle, pc, message);
+        }
+    }
+
+    fn first_difference(&mut self, tick: u64) {
+        for reporter in &mut self.0 {
+            reporter.first_difference(tick);
+        }
+    }
+
+    fn fingerprint(&mut self, fingerprint: &dyn Display) {
+        for reporter in &mut self.0 {
+            reporter.fingerprint(fingerprint);
+        }
+    }
+
+    fn cut(&mut self, level: DiagnosticLevel, count: usize) {
+        for reporter in &mut self.0 {
+            reporter.cut(level, count);
+        }
+    }
+
+    fn finding(&mut self, level: DiagnosticLevel, message: &dyn Display) {
+        for reporter in &mut self.0 {
+            reporter.finding(level, message);
+        }
+    }
+
+    fn heading(&mut self, title: &dyn Display) {
+        for reporter in &mut self.0 {
+            reporter.heading(title);
+        }
+    }
+
+    fn line(&mut self, message: &dyn Display) {
+        for reporter in &mut self.0 {
+            reporter.line(message);
+        }
+    }
+
+    fn context(&mut self, cycle: u32, write: &dyn Display) {
+        for reporter in &mut self.0 {
+            reporter.context(cycle, write);
+        }
+    }
+
+    fn info(&mut self, message: &dyn Display) {
+        for reporter in &mut self.0 {
+            reporter.info(message);
+        }
+    }
+
+    fn detail(&mut self, message: &dyn Display) {
+        for reporter in &mut self.0 {
+            reporter.detail(message);
+        }
+    }
+
+    fn columns(&mut self, before: &str, after: &str, level: Option<DiagnosticLevel>) {
+        for reporter in &mut self.0 {
+            reporter.columns(before, after, level);
+        }
+    }
+
+    fn song_end(&mut self, songs: &SongIDs, ok: bool, partial: Option<Range<u64>>) {
+        for reporter in &mut self.0 {
+            reporter.song_end(songs, ok, partial.clone());
+        }
+    }
+
+    fn summary(&mut self, failed: &[SongIDs], init_only: &[SongIDs], stats: &RunStats) {
+        for reporter in &mut self.0 {
+            reporter.summary(failed, init_only, stats);
+        }
+    }
+}
@@ -0,0 +1,493 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with where the terminal output goes.
+//!
+//! A diff that blows up scrolls thousands of lines past faster than they can be read, so when
+//! stdout is a terminal, the songs with too many diagnostics get their output written to a file of
+//! their own, and only a summary is printed; or with `--pager`, everything goes through a pager,
+//! like git does. When stdout is not a terminal, everything is printed as-is.
+
+use std::{
+    cell::RefCell,
+    fmt::{Debug, Display},
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, IsTerminal, Write},
+    path::PathBuf,
+    process::{Child, ChildStdin, Command, Stdio},
+    rc::{Rc, Weak},
+};
+
+use crate::{DiagnosticLevel, SongIDs};
+
+/// Whatever stands for stdout: the process's own, or a buffer in tests. Every reporter printing to
+/// it holds a handle of its own.
+#[derive(Clone)]
+pub(crate) struct Sink {
+    out: Rc<RefCell<dyn Write>>,
+    is_terminal: bool,
+}
+
+impl Sink {
+    pub fn stdout() -> Self {
+        Self {
+            out: Rc::new(RefCell::new(io::stdout())),
+            is_terminal: io::stdout().is_terminal(),
+        }
+    }
+
+    /// Never a terminal; also returns the buffer that everything gets written to.
+    #[cfg(test)]
+    pub fn buffer() -> (Self, Rc<RefCell<Vec<u8>>>) {
+        let buffer = Rc::new(RefCell::new(Vec::new()));
+        let sink = Self {
+            out: buffer.clone(),
+            is_terminal: false,
+        };
+        (sink, buffer)
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        self.is_terminal
+    }
+
+    /// Like `println!`, including panicking if stdout is gone.
+    pub fn line(&self, line: &dyn Display) {
+        writeln!(self.out.borrow_mut(), "{}", line)
+            .unwrap_or_else(|err| panic!("failed printing to stdout: {}", err));
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.borrow_mut().flush()
+    }
+}
+
+impl Debug for Sink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sink")
+            .field("is_terminal", &self.is_terminal)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Output {
+    Stdout(Sink),
+    Spill(Spill),
+    Pager(Rc<RefCell<Pager>>),
+}
+
+thread_local! {
+    /// The running pager, if any, so that [`exit`] can wait for it.
+    static PAGER: RefCell<Weak<RefCell<Pager>>> = RefCell::new(Weak::new());
+}
+
+/// Like [`std::process::exit`], but lets the pager (if any) finish first, as it would have if the
+/// [`Output`] had been dropped; exiting from under it would leave the terminal in a mess.
+pub(crate) fn exit(code: i32) -> ! {
+    if let Some(pager) = PAGER.with(|pager| pager.borrow().upgrade()) {
+        // If it's being written to, this is a panic from within, so there's no waiting for it.
+        if let Ok(mut pager) = pager.try_borrow_mut() {
+            pager.close();
+        }
+    }
+    std::process::exit(code);
+}
+
+impl Output {
+    /// Plain `out`, unless it is a terminal: then everything goes through `$PAGER` if `pager` is
+    /// set, or else songs with more than `spill_threshold` diagnostics are spilled to a temporary
+    /// file (unless it's 0).
+    pub fn new(out: &Sink, pager: bool, spill_threshold: usize) -> Self {
+        if !out.is_terminal() {
+            Self::Stdout(out.clone())
+        } else if pager {
+            Pager::spawn().map_or_else(
+                || Self::Stdout(out.clone()),
+                |pager| {
+                    let pager = Rc::new(RefCell::new(pager));
+                    PAGER.with(|active| *active.borrow_mut() = Rc::downgrade(&pager));
+                    Self::Pager(pager)
+                },
+            )
+        } else if spill_threshold != 0 {
+            Self::Spill(Spill::new(
+                out.clone(),
+                std::env::temp_dir(),
+                spill_threshold,
+            ))
+        } else {
+            Self::Stdout(out.clone())
+        }
+    }
+
+    pub fn line(&mut self, line: &dyn Display) {
+        match self {
+            Self::Stdout(out) => out.line(line),
+            Self::Spill(spill) => spill.line(line),
+            Self::Pager(pager) => pager.borrow_mut().line(line),
+        }
+    }
+
+    /// Unlike other lines, progress banners are never held back, since they are only relevant
+    /// while the run is in progress.
+    pub fn progress(&mut self, line: &dyn Display) {
+        match self {
+            Self::Stdout(out) => out.line(line),
+            Self::Spill(spill) => spill.out.line(line),
+            Self::Pager(pager) => pager.borrow_mut().line(line),
+        }
+    }
+
+    pub fn song_start(&mut self, songs: &SongIDs) {
+        if let Self::Spill(spill) = self {
+            spill.song_start(songs);
+        }
+    }
+
+    /// Must be called before the diagnostic's line is output.
+    pub fn diagnostic(&mut self, level: DiagnosticLevel) {
+        if let Self::Spill(spill) = self {
+            spill.diagnostic(level);
+        }
+    }
+
+    pub fn song_end(&mut self) {
+        match self {
+            Self::Stdout(_) => {}
+            Self::Spill(spill) => spill.song_end(),
+            Self::Pager(pager) => pager.borrow_mut().flush(),
+        }
+    }
+}
+
+/// Holds each song's lines back until it's known whether the song has more than `threshold`
+/// diagnostics; if it does, they go to a file in `dir` instead.
+#[derive(Debug)]
+pub(crate) struct Spill {
+    out: Sink,
+    dir: PathBuf,
+    threshold: usize,
+    /// The (first) ID of the song being reported, if any; lines outside of songs are printed
+    /// straight away.
+    song: Option<u8>,
+    held: Vec<String>,
+    /// Indexed by level.
+    counts: [usize; DiagnosticLevel::ALL.len()],
+    file: Option<(PathBuf, BufWriter<File>)>,
+}
+
+impl Spill {
+    pub fn new(out: Sink, dir: PathBuf, threshold: usize) -> Self {
+        Self {
+            out,
+            dir,
+            threshold,
+            song: None,
+            held: Vec::new(),
+            counts: Default::default(),
+            file: None,
+        }
+    }
+
+    fn line(&mut self, line: &dyn Display) {
+        match (&self.song, &mut self.file) {
+            (None, _) => self.out.line(line),
+            (Some(_), Some((path, file))) => {
+                writeln!(file, "{}", strip_colors(&line.to_string()))
+                    .unwrap_or_else(|err| spill_write_fail(path, err));
+            }
+            (Some(_), None) => self.held.push(line.to_string()),
+        }
+    }
+
+    fn song_start(&mut self, songs: &SongIDs) {
+        let (SongIDs::Both(id, _) | SongIDs::BeforeOnly(id) | SongIDs::AfterOnly(id)) = *songs;
+        self.song = Some(id);
+        self.counts = Default::default();
+    }
+
+    fn diagnostic(&mut self, level: DiagnosticLevel) {
+        self.counts[level as usize] += 1;
+        let Some(song) = self.song else {
+            return;
+        };
+        if self.file.is_some() || self.counts.iter().sum::<usize>() <= self.threshold {
+            return;
+        }
+
+        let (path, file) = self.create_file(song).unwrap_or_else(|err| {
+            eprintln!(
+                "Failed to create a file to write the song's output to: {}",
+                err
+            );
+            exit(2);
+        });
+        let mut file = BufWriter::new(file);
+        for line in self.held.drain(..) {
+            writeln!(file, "{}", strip_colors(&line))
+                .unwrap_or_else(|err| spill_write_fail(&path, err));
+        }
+        self.file = Some((path, file));
+    }
+
+    /// Named after the song, and the process so that concurrent runs don't clash.
+    fn create_file(&self, song: u8) -> io::Result<(PathBuf, File)> {
+        let mut suffix = String::new();
+        for n in 1.. {
+            let path = self.dir.join(format!(
+                "gbsdiff-song{}-{}{}.txt",
+                song,
+                std::process::id(),
+                suffix
+            ));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((path, file)),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    suffix = format!("-{}", n);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        unreachable!()
+    }
+
+    fn song_end(&mut self) {
+        self.song = None;
+        match self.file.take() {
+            Some((path, mut file)) => {
+                file.flush()
+                    .unwrap_or_else(|err| spill_write_fail(&path, err));
+                let count = |level: DiagnosticLevel| self.counts[level as usize];
+                self.out.line(&format_args!(
+                    "{} diagnostics ({} errors, {} warnings, {} notes); full diff written to {}",
+                    self.counts.iter().sum::<usize>(),
+                    count(DiagnosticLevel::Error),
+                    count(DiagnosticLevel::Warning),
+                    count(DiagnosticLevel::Note),
+                    path.display(),
+                ));
+            }
+            None => {
+                for line in self.held.drain(..) {
+                    self.out.line(&line);
+                }
+            }
+        }
+    }
+}
+
+fn spill_write_fail(path: &std::path::Path, err: io::Error) -> ! {
+    eprintln!("Failed to write to {}: {}", path.display(), err);
+    exit(2);
+}
+
+/// Colors are only meant for the terminal; this removes the escape sequences that set them, e.g.
+/// `\x1b[1;31m`.
+fn strip_colors(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.find(|c| c.is_ascii_alphabetic());
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+/// A pager process, which gets all of the output.
+#[derive(Debug)]
+pub(crate) struct Pager {
+    child: Child,
+    /// `None` once the pager has been quit, after which the output is dropped.
+    stdin: Option<ChildStdin>,
+}
+
+impl Pager {
+    /// `None` if `$PAGER` is empty or `cat`, or if the pager could not be started.
+    fn spawn() -> Option<Self> {
+        let pager = std::env::var("PAGER").unwrap_or_else(|_| "less".into());
+        if matches!(pager.trim(), "" | "cat") {
+            return None;
+        }
+
+        // `$PAGER` may contain arguments.
+        let mut command = if cfg!(windows) {
+            let mut command = Command::new("cmd");
+            command.arg("/C");
+            command
+        } else {
+            let mut command = Command::new("sh");
+            command.arg("-c");
+            command
+        };
+        command.arg(&pager).stdin(Stdio::piped());
+        // Like git: quit if everything fits on one screen, let colors through, and don't clear the
+        // screen when quitting.
+        if std::env::var_os("LESS").is_none() {
+            command.env("LESS", "FRX");
+        }
+        match command.spawn() {
+            Ok(mut child) => {
+                let stdin = child.stdin.take();
+                Some(Self { child, stdin })
+            }
+            Err(err) => {
+                eprintln!("Failed to start the pager ({}): {}", pager, err);
+                None
+            }
+        }
+    }
+
+    fn line(&mut self, line: &dyn Display) {
+        let Some(stdin) = self.stdin.as_mut() else {
+            return;
+        };
+        // Any error means that the pager was quit before reading everything (SIGPIPE is ignored,
+        // so that's `EPIPE`), which is fine.
+        if writeln!(stdin, "{}", line).is_err() {
+            self.stdin = None;
+        }
+    }
+
+    fn flush(&mut self) {
+        if self
+            .stdin
+            .as_mut()
+            .is_some_and(|stdin| stdin.flush().is_err())
+        {
+            self.stdin = None;
+        }
+    }
+
+    /// The pager must see the end of its input and be waited for, or the shell would take the
+    /// terminal back from under it.
+    fn close(&mut self) {
+        self.stdin = None;
+        // Nothing can be done about it at this point.
+        let _ = self.child.wait();
+    }
+}
+
+impl Drop for Pager {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reports a song with one line per diagnostic (the first being an error, the rest notes),
+    /// surrounded by lines outside of any song.
+    fn report_song(spill: &mut Spill, song: u8, nb_diags: usize) {
+        spill.line(&"before");
+        spill.song_start(&SongIDs::Both(song, song));
+        spill.line(&"header");
+        for i in 0..nb_diags {
+            let level = if i == 0 {
+                DiagnosticLevel::Error
+            } else {
+                DiagnosticLevel::Note
+            };
+            spill.diagnostic(level);
+            spill.line(&format_args!("\x1b[1;31mdiag\x1b[0m {}", i));
+        }
+        spill.song_end();
+        spill.line(&"after");
+    }
+
+    /// A fresh directory for the spilled files, which is emptied by the test.
+    fn spill_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("gbsdiff-test-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn spilled_files(dir: &std::path::Path) -> Vec<PathBuf> {
+        let mut paths: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn songs_within_the_threshold_are_printed() {
+        let dir = spill_dir("within");
+        let (sink, buffer) = Sink::buffer();
+        let mut spill = Spill::new(sink, dir.clone(), 3);
+        report_song(&mut spill, 1, 3);
+        report_song(&mut spill, 2, 0);
+
+        assert_eq!(
+            String::from_utf8(buffer.take()).unwrap(),
+            "before\nheader\n\x1b[1;31mdiag\x1b[0m 0\n\x1b[1;31mdiag\x1b[0m 1\n\
+             \x1b[1;31mdiag\x1b[0m 2\nafter\nbefore\nheader\nafter\n"
+        );
+        assert_eq!(spilled_files(&dir), Vec::<PathBuf>::new());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn songs_past_the_threshold_are_spilled() {
+        let dir = spill_dir("past");
+        let (sink, buffer) = Sink::buffer();
+        let mut spill = Spill::new(sink, dir.clone(), 3);
+        report_song(&mut spill, 7, 4);
+        // A later song is spilled again, to a file of its own.
+        report_song(&mut spill, 7, 5);
+
+        let paths = spilled_files(&dir);
+        let pid = std::process::id();
+        assert_eq!(
+            paths,
+            [
+                dir.join(format!("gbsdiff-song7-{}-1.txt", pid)),
+                dir.join(format!("gbsdiff-song7-{}.txt", pid)),
+            ]
+        );
+        assert_eq!(
+            String::from_utf8(buffer.take()).unwrap(),
+            format!(
+                "before\n\
+                 4 diagnostics (1 errors, 0 warnings, 3 notes); full diff written to {}\n\
+                 after\nbefore\n\
+                 5 diagnostics (1 errors, 0 warnings, 4 notes); full diff written to {}\n\
+                 after\n",
+                paths[1].display(),
+                paths[0].display(),
+            )
+        );
+        // Held lines are written too, and the colors are gone.
+        assert_eq!(
+            std::fs::read_to_string(&paths[1]).unwrap(),
+            "header\ndiag 0\ndiag 1\ndiag 2\ndiag 3\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&paths[0]).unwrap(),
+            "header\ndiag 0\ndiag 1\ndiag 2\ndiag 3\ndiag 4\n"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn colors_are_stripped() {
+        assert_eq!(strip_colors("\x1b[1;31mred\x1b[0m plain"), "red plain");
+        assert_eq!(strip_colors("no colors"), "no colors");
+    }
+}
@@ -0,0 +1,269 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with summarizing a run as one line per song, like `git diff --stat`.
+
+use std::{fmt::Display, ops::Range};
+
+use owo_colors::{OwoColorize, Stream::Stdout};
+
+use super::{Reporter, Sink};
+use crate::{throughput::RunStats, DiagnosticLevel, SongIDs};
+
+#[derive(Debug)]
+struct Row {
+    songs: String,
+    /// Indexed by level.
+    counts: [usize; DiagnosticLevel::ALL.len()],
+    first_tick: Option<u64>,
+    fingerprint: Option<String>,
+    /// Why the songs could not even be compared.
+    failure: Option<String>,
+    ok: bool,
+}
+
+/// Only counts the diagnostics, and prints a table once all songs have been compared.
+#[derive(Debug)]
+pub(crate) struct StatReporter {
+    out: Sink,
+    secs_per_tick: f64,
+    rows: Vec<Row>,
+}
+
+impl StatReporter {
+    pub fn new(out: &Sink, secs_per_tick: f64) -> Self {
+        Self {
+            out: out.clone(),
+            secs_per_tick,
+            rows: Vec::new(),
+        }
+    }
+
+    fn row(&mut self) -> &mut Row {
+        self.rows.last_mut().expect("Reporting outside of a song")
+    }
+
+    fn format_counts(counts: &[usize; DiagnosticLevel::ALL.len()]) -> [String; 3] {
+        let plural = |count: usize, what: &str| {
+            format!("{} {}{}", count, what, if count == 1 { "" } else { "s" })
+        };
+        [
+            plural(counts[DiagnosticLevel::Error as usize], "error"),
+            plural(counts[DiagnosticLevel::Warning as usize], "warning"),
+            plural(counts[DiagnosticLevel::Note as usize], "note"),
+        ]
+    }
+
+    /// Formats as `M:SS.cc`.
+    fn format_time(&self, tick: u64) -> String {
+        let centis = (tick as f64 * self.secs_per_tick * 100.0).round() as u64;
+        format!(
+            "{}:{:02}.{:02}",
+            centis / 6000,
+            centis / 100 % 60,
+            centis % 100
+        )
+    }
+}
+
+impl Reporter for StatReporter {
+    fn warning(&mut self, message: &dyn Display) {
+        self.out.line(&format_args!(
+            "{}: {}",
+            colorize!(Stdout, "warning", bright_yellow, bold),
+            message
+        ));
+    }
+
+    fn song_start(&mut self, songs: &SongIDs) {
+        self.rows.push(Row {
+            songs: songs.to_string(),
+            counts: Default::default(),
+            first_tick: None,
+            fingerprint: None,
+            failure: None,
+            ok: true,
+        });
+    }
+
+    fn simulation_failed(&mut self, path: &str, song_id: u8, err: &dyn Display) {
+        // `song_end` is not called for songs that failed to simulate.
+        let row = self.row();
+        row.failure = Some(format!(
+            "failed to simulate {} song #{}: {}",
+            path, song_id, err
+        ));
+        row.ok = false;
+    }
+
+    fn tick(&mut self, _tick: u64) {}
+
+    fn diagnostic(
+        &mut self,
+        level: DiagnosticLevel,
+        _cycle: u32,
+        _pc: &dyn Display,
+        _message: &dyn Display,
+    ) {
+        self.row().counts[level as usize] += 1;
+    }
+
+    fn cut(&mut self, level: DiagnosticLevel, count: usize) {
+        self.row().counts[level as usize] += count;
+    }
+
+    fn first_difference(&mut self, tick: u64) {
+        self.row().first_tick = Some(tick);
+    }
+
+    fn fingerprint(&mut self, fingerprint: &dyn Display) {
+        self.row().fingerprint = Some(fingerprint.to_string());
+    }
+
+    fn finding(&mut self, level: DiagnosticLevel, _message: &dyn Display) {
+        self.row().counts[level as usize] += 1;
+    }
+
+    fn heading(&mut self, _title: &dyn Display) {}
+
+    fn line(&mut self, _message: &dyn Display) {}
+
+    fn columns(&mut self, _before: &str, _after: &str, _level: Option<DiagnosticLevel>) {}
+
+    fn song_end(&mut self, _songs: &SongIDs, ok: bool, _partial: Option<Range<u64>>) {
+        self.row().ok = ok;
+    }
+
+    fn summary(&mut self, failed: &[SongIDs], _init_only: &[SongIDs], _stats: &RunStats) {
+        let cells: Vec<_> = self
+            .rows
+            .iter()
+            .map(|row| Self::format_counts(&row.counts))
+            .collect();
+        let mut total = [0; DiagnosticLevel::ALL.len()];
+        for row in &self.rows {
+            for (total, count) in total.iter_mut().zip(&row.counts) {
+                *total += count;
+            }
+        }
+        let total_cells = Self::format_counts(&total);
+
+        let labels: Vec<_> = self
+            .rows
+            .iter()
+            .map(|row| format!("song {}:", row.songs))
+            .collect();
+        let total_label = "total:";
+        let label_width = labels
+            .iter()
+            .map(String::len)
+            .chain([total_label.len()])
+            .max()
+            .unwrap();
+        let mut widths = [0; 3];
+        for row_cells in cells.iter().chain([&total_cells]) {
+            for (width, cell) in widths.iter_mut().zip(row_cells) {
+                *width = (*width).max(cell.len());
+            }
+        }
+
+        for ((row, label), row_cells) in self.rows.iter().zip(&labels).zip(&cells) {
+            let marker = if row.ok {
+                colorize!(Stdout, "✓", bright_green, bold).to_string()
+            } else {
+                colorize!(Stdout, "✗", bright_red, bold).to_string()
+            };
+            let mut details = match (&row.failure, row.first_tick) {
+                (Some(failure), _) => format!("  {}", failure),
+                (None, Some(tick)) => {
+                    format!("  first diff at tick {} ({})", tick, self.format_time(tick))
+                }
+                (None, None) => String::new(),
+            };
+            if let Some(fingerprint) = &row.fingerprint {
+                details.push_str(&format!("  fingerprint {}", fingerprint));
+            }
+            self.out.line(&format_args!(
+                "{:<label_width$} {:>w0$}, {:>w1$}, {:>w2$}  {}{}",
+                label,
+                row_cells[0],
+                row_cells[1],
+                row_cells[2],
+                marker,
+                details,
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2],
+            ));
+        }
+        self.out.line(&format_args!(
+            "{:<label_width$} {:>w0$}, {:>w1$}, {:>w2$}  {} of {} songs failing",
+            total_label,
+            total_cells[0],
+            total_cells[1],
+            total_cells[2],
+            failed.len(),
+            self.rows.len(),
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_line_is_printed_per_song() {
+        owo_colors::set_override(false);
+        let (sink, buffer) = Sink::buffer();
+        let mut stat = StatReporter::new(&sink, 0.5);
+
+        stat.song_start(&SongIDs::Both(1, 1));
+        stat.diagnostic(DiagnosticLevel::Error, 0, &"$4000", &"error");
+        stat.tick(3);
+        for _ in 0..2 {
+            stat.diagnostic(DiagnosticLevel::Warning, 0, &"$4000", &"warning");
+        }
+        stat.finding(DiagnosticLevel::Note, &"note");
+        // Diagnostics that were cut count too.
+        stat.cut(DiagnosticLevel::Note, 5);
+        stat.first_difference(10);
+        stat.fingerprint(&"abc");
+        stat.song_end(&SongIDs::Both(1, 1), false, None);
+
+        stat.song_start(&SongIDs::Both(2, 3));
+        stat.simulation_failed("after.gbs", 3, &"boom");
+
+        stat.song_start(&SongIDs::Both(4, 4));
+        stat.song_end(&SongIDs::Both(4, 4), true, None);
+
+        assert!(buffer.borrow().is_empty());
+        stat.summary(
+            &[SongIDs::Both(1, 1), SongIDs::Both(2, 3)],
+            &[],
+            &RunStats::default(),
+        );
+        assert_eq!(
+            String::from_utf8(buffer.take()).unwrap(),
+            "song 1:        1 error, 2 warnings, 6 notes  ✗  first diff at tick 10 (0:05.00)  fingerprint abc\n\
+             song 2 and 3: 0 errors, 0 warnings, 0 notes  ✗  failed to simulate after.gbs song #3: boom\n\
+             song 4:       0 errors, 0 warnings, 0 notes  ✓\n\
+             total:         1 error, 2 warnings, 6 notes  2 of 3 songs failing\n"
+        );
+    }
+
+    #[test]
+    fn times_are_formatted_in_minutes() {
+        let stat = StatReporter::new(&Sink::buffer().0, 1.0 / 60.0);
+        assert_eq!(stat.format_time(0), "0:00.00");
+        assert_eq!(stat.format_time(30), "0:00.50");
+        assert_eq!(stat.format_time(3_601), "1:00.02");
+        assert_eq!(stat.format_time(60 * 60 * 10), "10:00.00");
+    }
+}
@@ -1,62 +1,401 @@
-use std::cell::{Cell, RefCell};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
 
 use gb_cpu_sim::{memory::AddressSpace, reg::HwReg};
 
 use crate::{
-    gbs::{AddressKind, Gbs},
+    gbs::{AddressKind, Cadence, Gbs},
+    replay::ReadQueues,
     Address,
 };
 
-use super::{DiagnosticKind, DiagnosticLevel, LogbookWriter};
+use super::{
+    hooks::{Hooks, SimHooks},
+    trace::TraceEvent,
+    Accessed, DiagnosticKind, DiagnosticLevel, LogbookWriter, Profile, SimParams, Sram,
+    WaveReadMode,
+};
+use crate::Timestamp;
+
+/// Past this many bank switches within a single tick, the driver is most likely stuck in a loop,
+/// so further switches stop being logged.
+const MAX_BANK_SWITCHES_PER_TICK: u32 = 1000;
+
+/// Registers that drivers ripped from CGB games commonly write to, but that don't matter to a GBS
+/// player (KEY1, BOOT), or that only need minimal support (SVBK).
+const INERT_REGS: [u16; 3] = [0xFF4D, 0xFF50, 0xFF70];
+
+/// How many stale wave RAM reads get a diagnostic, per song; the rest are only counted.
+const MAX_STALE_WAVE_READ_WARNINGS: usize = 10;
+
+/// One bit per byte of RAM: SRAM, then all WRAM banks, then HRAM (padded to a round size).
+#[derive(Debug, Clone)]
+struct RamBitmap([u64; RamBitmap::LEN / 64]);
+
+impl RamBitmap {
+    const LEN: usize = 0x2000 + 0x8000 + 0x80;
+    const SRAM: usize = 0;
+    const WRAM: usize = 0x2000;
+    const HRAM: usize = 0xA000;
+
+    fn get(&self, index: usize) -> bool {
+        self.0[index / 64] & 1 << (index % 64) != 0
+    }
+
+    fn set(&mut self, index: usize) {
+        self.0[index / 64] |= 1 << (index % 64);
+    }
+
+    fn set_range(&mut self, indices: std::ops::Range<usize>) {
+        for index in indices {
+            self.set(index);
+        }
+    }
+}
+
+impl Default for RamBitmap {
+    fn default() -> Self {
+        Self([0; Self::LEN / 64])
+    }
+}
+
+/// Which bytes of RAM have been written during the song, and which of the others have been read.
+#[derive(Debug, Default)]
+struct InitTracking {
+    written: RamBitmap,
+    reported: RamBitmap,
+}
 
 #[derive(Debug)]
 pub struct GbsAddrSpace<'a> {
     rom: &'a [u8],
     load_addr: u16,
+    /// How many ROM banks the file covers.
+    nb_banks: usize,
+    /// The tick during which the last bank switch happened, and how many happened during it.
+    bank_switches: (u64, u32),
 
-    sram: [u8; 0x2000],
-    wram: [u8; 0x2000],
+    sram: Sram,
+    /// All 8 banks, as selectable through SVBK; bank 0 is the one always mapped at $C000.
+    wram: [u8; 0x8000],
     hram: [u8; 0x7F],
 
     apu: Apu<'a>,
+    forced_reads: Option<&'a ReadQueues>,
+    hooks: Rc<RefCell<Hooks<'a>>>,
+    stub_regs: &'a [(u16, u8)],
+    profile: &'a Profile,
+    /// The values last written to the profile's extra registers.
+    extra_regs: HashMap<u16, u8>,
+    /// Which I/O registers' stubbed reads have been reported yet, indexed by `address - $FF00`.
+    stub_reads_noted: Cell<u128>,
+    /// Only the select bits (4 and 5) matter, which affect what reads return.
+    p1: u8,
+    /// Only the speed switch prep bit (0) is kept; no speed switch is ever performed.
+    key1: u8,
+    svbk: u8,
+    /// Which of the inert registers' writes have been reported yet, see [`INERT_REGS`].
+    inert_writes_noted: u8,
+    /// `None` if reads of uninitialized RAM aren't checked for.
+    init_tracking: Option<RefCell<Box<InitTracking>>>,
+    watchpoints: &'a [u16],
+    read_watchpoints: &'a [u16],
+    shadow_addrs: &'a [u16],
 
-    logger: &'a RefCell<LogbookWriter<'a>>,
+    logger: Rc<RefCell<LogbookWriter<'a>>>,
 }
 
 impl<'a> GbsAddrSpace<'a> {
     pub(super) fn new(
         gbs: &'a Gbs<'_>,
-        logger: &'a RefCell<LogbookWriter<'a>>,
-        silence_timer: &'a Cell<u32>,
+        logger: Rc<RefCell<LogbookWriter<'a>>>,
+        forced_reads: Option<&'a ReadQueues>,
+        params: &'a SimParams,
+        hooks: Rc<RefCell<Hooks<'a>>>,
+        sram: Option<&Sram>,
     ) -> Self {
         let rom = gbs.rom();
         let load_addr = gbs.addr(AddressKind::Load);
+        // Bank 1 is always considered present, since it is the one mapped by default.
+        let nb_banks = ((usize::from(load_addr) + rom.len() + 0x3FFF) / 0x4000).max(2);
 
         Self {
             rom,
             load_addr,
+            nb_banks,
+            bank_switches: (0, 0),
 
-            sram: [0; 0x2000],
-            wram: [0; 0x2000],
+            sram: sram.copied().unwrap_or([0; 0x2000]),
+            wram: [0; 0x8000],
             hram: [0; 0x7F],
 
-            apu: Apu::new(logger, silence_timer),
+            apu: Apu::new(
+                Rc::clone(&logger),
+                params.wave_read_mode,
+                gbs.cadence(),
+                gbs.double_speed(),
+            ),
+            forced_reads,
+            hooks,
+            stub_regs: &params.stub_regs,
+            profile: &params.profile,
+            extra_regs: HashMap::new(),
+            stub_reads_noted: Cell::new(0),
+            p1: 0xFF,
+            key1: 0,
+            svbk: 0,
+            inert_writes_noted: 0,
+            init_tracking: params.uninit_check.then(|| {
+                let mut tracking = Box::<InitTracking>::default();
+                // SRAM carried over from the previous song has been written by it, as far as
+                // this one is concerned.
+                if sram.is_some() {
+                    tracking
+                        .written
+                        .set_range(RamBitmap::SRAM..RamBitmap::SRAM + 0x2000);
+                }
+                RefCell::new(tracking)
+            }),
+            watchpoints: &params.watchpoints,
+            read_watchpoints: &params.read_watchpoints,
+            shadow_addrs: &params.shadow_addrs,
 
             logger,
         }
     }
 
+    pub(super) fn sram(&self) -> &Sram {
+        &self.sram
+    }
+
+    /// Everything that a snapshot needs to resume from; see [`MemoryState`].
+    pub(super) fn state(&self) -> MemoryState {
+        MemoryState {
+            sram: self.sram,
+            wram: Box::new(self.wram),
+            hram: self.hram,
+            apu_regs: self.apu.regs(),
+            wave_ram: self.apu.wave_ram,
+            ch3_trigger: self.apu.ch3_trigger.clone(),
+            channels_on: self.apu.channels_on,
+            length_timers: self.apu.length_timers,
+            length_phase: self.apu.length_phase,
+            bank_switches: self.bank_switches,
+            p1: self.p1,
+            key1: self.key1,
+            svbk: self.svbk,
+            extra_regs: self.extra_regs.clone(),
+        }
+    }
+
+    /// Which bytes were initialized is not part of the state, so all of them are assumed to be.
+    pub(super) fn restore(&mut self, state: &MemoryState) {
+        if let Some(tracking) = self.init_tracking.as_mut() {
+            tracking.get_mut().written.set_range(0..RamBitmap::LEN);
+        }
+        self.sram = state.sram;
+        self.wram = *state.wram;
+        self.hram = state.hram;
+        self.apu.set_regs(state.apu_regs);
+        self.apu.wave_ram = state.wave_ram;
+        self.apu.ch3_trigger = state.ch3_trigger.clone();
+        self.apu.channels_on = state.channels_on;
+        self.apu.length_timers = state.length_timers;
+        self.apu.length_phase = state.length_phase;
+        self.bank_switches = state.bank_switches;
+        self.p1 = state.p1;
+        self.key1 = state.key1;
+        self.svbk = state.svbk;
+        self.extra_regs = state.extra_regs.clone();
+    }
+
+    /// Where an address in WRAM (or echo RAM) points to within `wram`.
+    fn wram_offset(&self, address: u16) -> usize {
+        let ofs = usize::from(address & 0x1FFF);
+        if ofs < 0x1000 {
+            ofs
+        } else {
+            // Selecting bank 0 selects bank 1 instead.
+            usize::from((self.svbk & 7).max(1)) * 0x1000 + ofs - 0x1000
+        }
+    }
+
+    /// Advances the APU by a tick; once past the song's last write, a channel cut by its length
+    /// timer is when the song actually goes silent.
+    pub(super) fn end_tick(&mut self) {
+        let expired = self.apu.tick_frame();
+        if expired == 0 {
+            return;
+        }
+        self.hooks.borrow_mut().end.silence_timer = 0;
+        let mut logger = self.logger.borrow_mut();
+        let tick = logger.tick;
+        logger.logbook.length_expiries.extend(
+            (0..4)
+                .filter(|channel| expired & 1 << channel != 0)
+                .map(|channel| (tick, channel)),
+        );
+    }
+
+    /// Reads memory without checking that it was initialized, for observers.
+    pub(super) fn peek(&self, address: u16) -> u8 {
+        match address {
+            0xA000..=0xBFFF => self.sram[usize::from(address - 0xA000)],
+            0xC000..=0xFDFF => self.wram[self.wram_offset(address)],
+            0xFF80..=0xFFFE => self.hram[usize::from(address - 0xFF80)],
+            _ => self.read_unwatched(address),
+        }
+    }
+
+    /// Considers the return address that the GBS player pushes before calling INIT or PLAY to be
+    /// there, even though it isn't simulated.
+    pub(super) fn assume_return_addr(&mut self, sp: u16) {
+        for address in [sp, sp.wrapping_add(1)] {
+            let index = match address {
+                0xA000..=0xBFFF => RamBitmap::SRAM + usize::from(address - 0xA000),
+                0xC000..=0xFDFF => RamBitmap::WRAM + self.wram_offset(address),
+                0xFF80..=0xFFFE => RamBitmap::HRAM + usize::from(address - 0xFF80),
+                _ => continue,
+            };
+            self.mark_written(index);
+        }
+    }
+
+    /// `index` is in [`RamBitmap`]'s layout.
+    fn mark_written(&mut self, index: usize) {
+        if let Some(tracking) = self.init_tracking.as_mut() {
+            tracking.get_mut().written.set(index);
+        }
+    }
+
+    /// Reports the first read of each byte that hasn't been written yet, since it would hold
+    /// garbage on hardware instead of zero.
+    fn check_init(&self, index: usize, address: u16) {
+        let Some(tracking) = &self.init_tracking else {
+            return;
+        };
+        let mut tracking = tracking.borrow_mut();
+        if !tracking.written.get(index) && !tracking.reported.get(index) {
+            tracking.reported.set(index);
+            drop(tracking);
+            self.diagnose(
+                DiagnosticLevel::Warning,
+                DiagnosticKind::UninitializedRead(self.cur_bank_addr(address)),
+            );
+        }
+    }
+
+    fn inert_read(&self, address: u16) -> Option<u8> {
+        match address {
+            0xFF4D => Some(0x7E | self.key1),
+            0xFF50 => Some(0xFF),
+            0xFF70 => Some(0xF8 | self.svbk),
+            _ => None,
+        }
+    }
+
+    fn hook_write(&self, address: u16, data: u8) {
+        self.hooks.borrow_mut().on_write(address, data);
+    }
+
     fn diagnose(&self, level: DiagnosticLevel, kind: DiagnosticKind) {
         self.logger.borrow_mut().diagnose(level, kind);
     }
 
     fn cur_bank_addr(&self, addr: u16) -> Address {
-        Address(self.logger.borrow().rom_bank, addr)
+        self.logger.borrow().bank_addr(addr)
+    }
+
+    /// The value returned by reading an I/O register that is not emulated, if it is stubbed.
+    ///
+    /// P1 is stubbed by default to no buttons being pressed, as some drivers pause or mute when
+    /// they see a press.
+    fn stub_read(&self, address: u16) -> Option<u8> {
+        let value = self
+            .stub_regs
+            .iter()
+            .rev() // Later options override earlier ones.
+            .find(|(addr, _)| *addr == address)
+            .map(|(_, value)| *value)
+            .or_else(|| (address == 0xFF00).then_some(0xC0 | self.p1 & 0x30 | 0x0F))?;
+
+        let bit = 1 << (address - 0xFF00);
+        let noted = self.stub_reads_noted.get();
+        if noted & bit == 0 {
+            self.stub_reads_noted.set(noted | bit);
+            self.diagnose(
+                DiagnosticLevel::Note,
+                DiagnosticKind::StubbedRead(Accessed(address), value),
+            );
+        }
+        Some(value)
+    }
+
+    /// Unwritten extra registers read as $FF, like unsupported ones.
+    fn extra_read(&self, address: u16) -> Option<u8> {
+        self.profile
+            .is_extra(address)
+            .then(|| self.extra_regs.get(&address).copied().unwrap_or(0xFF))
+    }
+
+    fn extra_write(&mut self, address: u16, data: u8) -> Option<()> {
+        self.profile.is_extra(address).then(|| {
+            self.extra_regs.insert(address, data);
+        })
+    }
+
+    fn check_watchpoint(&self, address: u16, data: u8, is_write: bool) {
+        let watchpoints = if is_write {
+            self.watchpoints
+        } else {
+            self.read_watchpoints
+        };
+        if !watchpoints.contains(&address) {
+            return;
+        }
+        let recent_pcs = self.logger.borrow().recent_pcs();
+        let kind = if is_write {
+            DiagnosticKind::WatchedWrite(Accessed(address), data, recent_pcs)
+        } else {
+            DiagnosticKind::WatchedRead(Accessed(address), data, recent_pcs)
+        };
+        self.diagnose(DiagnosticLevel::Note, kind);
+    }
+
+    fn trace_io_read(&self, address: u16, data: u8) {
+        self.logger
+            .borrow_mut()
+            .trace_io(TraceEvent::IoRead(address, data));
+    }
+
+    fn trace_io_write(&self, address: u16, data: u8) {
+        self.logger
+            .borrow_mut()
+            .trace_io(TraceEvent::IoWrite(address, data));
     }
 }
 
 impl AddressSpace for GbsAddrSpace<'_> {
     fn read(&self, address: u16) -> u8 {
+        let data = self.read_unwatched(address);
+        self.check_watchpoint(address, data, false);
+        data
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.check_watchpoint(address, data, true);
+        if self.shadow_addrs.contains(&address) {
+            self.logger.borrow_mut().log_shadow(address, data);
+        }
+        self.write_unwatched(address, data);
+    }
+}
+
+impl GbsAddrSpace<'_> {
+    fn read_unwatched(&self, address: u16) -> u8 {
         match address {
             0x0000..=0x3FFF => {
                 // If the address is in the loaded area, output it; otherwise, fall back to $FF
@@ -67,7 +406,7 @@ impl AddressSpace for GbsAddrSpace<'_> {
                     .unwrap_or_else(|| {
                         self.diagnose(
                             DiagnosticLevel::Warning,
-                            DiagnosticKind::UnsupportedRead(self.cur_bank_addr(address)),
+                            DiagnosticKind::UnsupportedRead(Accessed(address)),
                         );
                         0xFF
                     })
@@ -80,7 +419,7 @@ impl AddressSpace for GbsAddrSpace<'_> {
                     .unwrap_or_else(|| {
                         self.diagnose(
                             DiagnosticLevel::Warning,
-                            DiagnosticKind::UnsupportedRead(self.cur_bank_addr(address)),
+                            DiagnosticKind::UnsupportedRead(Accessed(address)),
                         );
                         0x00 // The spec says "should"...
                     })
@@ -88,99 +427,238 @@ impl AddressSpace for GbsAddrSpace<'_> {
             0x8000..=0x9FFF => {
                 self.diagnose(
                     DiagnosticLevel::Warning,
-                    DiagnosticKind::UnsupportedRead(self.cur_bank_addr(address)),
+                    DiagnosticKind::UnsupportedRead(Accessed(address)),
                 );
                 0xFF
             }
-            0xA000..=0xBFFF => self.sram[usize::from(address - 0xA000)],
-            0xC000..=0xDFFF => self.wram[usize::from(address - 0xC000)],
+            0xA000..=0xBFFF => {
+                let ofs = usize::from(address - 0xA000);
+                self.check_init(RamBitmap::SRAM + ofs, address);
+                self.sram[ofs]
+            }
+            0xC000..=0xDFFF => {
+                let ofs = self.wram_offset(address);
+                self.check_init(RamBitmap::WRAM + ofs, address);
+                self.wram[ofs]
+            }
             0xE000..=0xFDFF => {
                 self.diagnose(
                     DiagnosticLevel::Note,
-                    DiagnosticKind::EchoRamRead(self.cur_bank_addr(address)),
+                    DiagnosticKind::EchoRamRead(Accessed(address)),
                 );
-                self.wram[usize::from(address - 0xE000)]
+                let ofs = self.wram_offset(address);
+                self.check_init(RamBitmap::WRAM + ofs, address);
+                self.wram[ofs]
             }
             0xFE00..=0xFEFF => {
                 self.diagnose(
                     DiagnosticLevel::Warning,
-                    DiagnosticKind::UnsupportedRead(self.cur_bank_addr(address)),
+                    DiagnosticKind::UnsupportedRead(Accessed(address)),
                 );
                 0xFF
             }
-            0xFF00..=0xFF7F => self.apu.read(address).unwrap_or_else(|| {
-                self.diagnose(
-                    DiagnosticLevel::Warning,
-                    DiagnosticKind::UnsupportedRead(self.cur_bank_addr(address)),
-                );
-                0xFF
-            }),
-            0xFF80..=0xFFFE => self.hram[usize::from(address - 0xFF80)],
+            0xFF00..=0xFF7F => {
+                let data = self
+                    .apu
+                    .read(address)
+                    .or_else(|| self.inert_read(address))
+                    .or_else(|| self.stub_read(address))
+                    .or_else(|| self.extra_read(address))
+                    .unwrap_or_else(|| {
+                        self.diagnose(
+                            DiagnosticLevel::Warning,
+                            DiagnosticKind::UnsupportedRead(Accessed(address)),
+                        );
+                        0xFF
+                    });
+                // Once the recording runs out, fall back to the simulated value.
+                let data = self
+                    .forced_reads
+                    .and_then(|reads| reads.next(address))
+                    .unwrap_or(data);
+                self.logger.borrow_mut().log_read(address, data);
+                self.trace_io_read(address, data);
+                data
+            }
+            0xFF80..=0xFFFE => {
+                let ofs = usize::from(address - 0xFF80);
+                self.check_init(RamBitmap::HRAM + ofs, address);
+                self.hram[ofs]
+            }
             0xFFFF => {
                 self.diagnose(
                     DiagnosticLevel::Warning,
-                    DiagnosticKind::UnsupportedRead(self.cur_bank_addr(address)),
+                    DiagnosticKind::UnsupportedRead(Accessed(address)),
                 );
+                self.trace_io_read(address, 0xFF);
                 0xFF
             }
         }
     }
 
-    fn write(&mut self, address: u16, data: u8) {
+    fn write_unwatched(&mut self, address: u16, data: u8) {
+        self.logger.borrow_mut().side_effects += 1;
         match address {
             0x2000..=0x3FFF => {
-                self.logger.borrow_mut().rom_bank = data;
-                if data == 0 {
+                let tick = self.logger.borrow().tick;
+                if self.bank_switches.0 != tick {
+                    self.bank_switches = (tick, 0);
+                }
+                self.bank_switches.1 += 1;
+                let quiet = self.bank_switches.1 > MAX_BANK_SWITCHES_PER_TICK;
+                if self.bank_switches.1 == MAX_BANK_SWITCHES_PER_TICK + 1 {
                     self.diagnose(
                         DiagnosticLevel::Warning,
-                        DiagnosticKind::UnsupportedWrite(self.cur_bank_addr(address), data),
+                        DiagnosticKind::BankSwitchFlood(MAX_BANK_SWITCHES_PER_TICK),
                     );
                 }
+
+                if !quiet {
+                    self.logger
+                        .borrow_mut()
+                        .trace_io(TraceEvent::BankSwitch(data));
+                    if data == 0 {
+                        self.diagnose(
+                            DiagnosticLevel::Warning,
+                            DiagnosticKind::UnsupportedWrite(Accessed(address), data),
+                        );
+                    } else if usize::from(data) >= self.nb_banks {
+                        self.diagnose(
+                            DiagnosticLevel::Warning,
+                            DiagnosticKind::BankOutOfRange(data, self.nb_banks),
+                        );
+                    }
+                }
+                // Like a real MBC, ignore the bits that don't select any existing bank.
+                let mask = self.nb_banks.next_power_of_two() - 1;
+                self.logger.borrow_mut().rom_bank = data & mask as u8;
             }
             0x0000..=0x7FFF => {
                 self.diagnose(
                     DiagnosticLevel::Warning,
-                    DiagnosticKind::UnsupportedWrite(self.cur_bank_addr(address), data),
+                    DiagnosticKind::UnsupportedWrite(Accessed(address), data),
                 );
             }
             0x8000..=0x9FFF => {
                 self.diagnose(
                     DiagnosticLevel::Warning,
-                    DiagnosticKind::UnsupportedWrite(self.cur_bank_addr(address), data),
+                    DiagnosticKind::UnsupportedWrite(Accessed(address), data),
                 );
             }
-            0xA000..=0xBFFF => self.sram[usize::from(address - 0xA000)] = data,
-            0xC000..=0xDFFF => self.wram[usize::from(address - 0xC000)] = data,
+            0xA000..=0xBFFF => {
+                self.hook_write(address, data);
+                let ofs = usize::from(address - 0xA000);
+                self.mark_written(RamBitmap::SRAM + ofs);
+                self.sram[ofs] = data
+            }
+            0xC000..=0xDFFF => {
+                self.hook_write(address, data);
+                let ofs = self.wram_offset(address);
+                self.mark_written(RamBitmap::WRAM + ofs);
+                self.wram[ofs] = data
+            }
             0xE000..=0xFDFF => {
                 self.diagnose(
                     DiagnosticLevel::Note,
-                    DiagnosticKind::EchoRamWrite(self.cur_bank_addr(address), data),
+                    DiagnosticKind::EchoRamWrite(Accessed(address), data),
                 );
-                self.wram[usize::from(address - 0xE000)] = data
+                self.hook_write(address, data);
+                let ofs = self.wram_offset(address);
+                self.mark_written(RamBitmap::WRAM + ofs);
+                self.wram[ofs] = data
             }
             0xFE00..=0xFEFF => {
                 self.diagnose(
                     DiagnosticLevel::Warning,
-                    DiagnosticKind::UnsupportedWrite(self.cur_bank_addr(address), data),
+                    DiagnosticKind::UnsupportedWrite(Accessed(address), data),
                 );
             }
-            0xFF00..=0xFF7F => self.apu.write(address, data).unwrap_or_else(|| {
-                self.diagnose(
-                    DiagnosticLevel::Warning,
-                    DiagnosticKind::UnsupportedWrite(self.cur_bank_addr(address), data),
-                )
-            }),
-            0xFF80..=0xFFFE => self.hram[usize::from(address - 0xFF80)] = data,
+            0xFF00 => {
+                self.trace_io_write(address, data);
+                self.hook_write(address, data);
+                self.p1 = data;
+            }
+            // Resets the timer's phase, which matters to timer-driven drivers; the divider itself
+            // isn't simulated, though.
+            0xFF04 => {
+                self.trace_io_write(address, data);
+                self.hook_write(address, data);
+                self.logger.borrow_mut().log(address, data);
+            }
+            0xFF4D | 0xFF50 | 0xFF70 => {
+                self.trace_io_write(address, data);
+                self.hook_write(address, data);
+                self.logger.borrow_mut().log(address, data);
+                let bit = 1 << INERT_REGS.iter().position(|&reg| reg == address).unwrap();
+                if self.inert_writes_noted & bit == 0 {
+                    self.inert_writes_noted |= bit;
+                    self.diagnose(
+                        DiagnosticLevel::Note,
+                        DiagnosticKind::InertRegWrite(Accessed(address), data),
+                    );
+                }
+                match address {
+                    0xFF4D => self.key1 = data & 1,
+                    0xFF70 => {
+                        self.svbk = data & 7;
+                        self.logger.borrow_mut().wram_bank = self.svbk.max(1);
+                    }
+                    _ => {} // Nothing to boot into.
+                }
+            }
+            0xFF01..=0xFF7F => {
+                self.trace_io_write(address, data);
+                self.apu
+                    .write(address, data)
+                    .or_else(|| self.extra_write(address, data))
+                    .map(|()| self.hook_write(address, data))
+                    .unwrap_or_else(|| {
+                        self.diagnose(
+                            DiagnosticLevel::Warning,
+                            DiagnosticKind::UnsupportedWrite(Accessed(address), data),
+                        )
+                    })
+            }
+            0xFF80..=0xFFFE => {
+                self.hook_write(address, data);
+                let ofs = usize::from(address - 0xFF80);
+                self.mark_written(RamBitmap::HRAM + ofs);
+                self.hram[ofs] = data
+            }
             0xFFFF => {
+                self.trace_io_write(address, data);
                 self.diagnose(
                     DiagnosticLevel::Warning,
-                    DiagnosticKind::UnsupportedWrite(self.cur_bank_addr(address), data),
+                    DiagnosticKind::UnsupportedWrite(Accessed(address), data),
                 );
             }
         }
     }
 }
 
+/// The contents of memory and of the registers that reads depend on.
+///
+/// Which diagnostics have been reported already is not part of it, so a resumed song may report
+/// them once more.
+#[derive(Debug, Clone)]
+pub(super) struct MemoryState {
+    pub sram: Sram,
+    pub wram: Box<[u8; 0x8000]>,
+    pub hram: [u8; 0x7F],
+    /// NR10 to NR52, in address order, skipping the unused ones.
+    pub apu_regs: [u8; 21],
+    pub wave_ram: [u8; 16],
+    pub ch3_trigger: Option<Timestamp>,
+    pub channels_on: u8,
+    pub length_timers: [u16; 4],
+    pub length_phase: u32,
+    pub bank_switches: (u64, u32),
+    pub p1: u8,
+    pub key1: u8,
+    pub svbk: u8,
+    pub extra_regs: HashMap<u16, u8>,
+}
+
 #[derive(Debug)]
 /// The APU as modelled by the GBS spec.
 struct Apu<'a> {
@@ -211,13 +689,48 @@ struct Apu<'a> {
     nr52: u8,
 
     wave_ram: [u8; 16],
+    /// When CH3 was last triggered, if it is still playing.
+    ch3_trigger: Option<Timestamp>,
+    wave_read_mode: WaveReadMode,
+    cadence: Cadence,
+    /// Which channels have been triggered with their DAC on, and not turned off (or cut by their
+    /// length timer) since; bit 0 is CH1.
+    channels_on: u8,
+    /// How many length clocks each channel has left to play, or 0 if its length isn't enabled.
+    length_timers: [u16; 4],
+    /// CPU cycles elapsed since the last length clock.
+    length_phase: u32,
+    /// CPU cycles between length clocks.
+    cycles_per_length_clock: u32,
+    /// Whether the approximation of PCM12 and PCM34 (respectively) has been reported yet.
+    pcm_reads_noted: [Cell<bool>; 2],
 
-    silence_timer: &'a Cell<u32>,
-    logger: &'a RefCell<LogbookWriter<'a>>,
+    logger: Rc<RefCell<LogbookWriter<'a>>>,
 }
 
 impl<'a> Apu<'a> {
-    fn new(logger: &'a RefCell<LogbookWriter<'a>>, silence_timer: &'a Cell<u32>) -> Self {
+    fn regs(&self) -> [u8; 21] {
+        [
+            self.nr10, self.nr11, self.nr12, self.nr13, self.nr14, self.nr21, self.nr22, self.nr23,
+            self.nr24, self.nr30, self.nr31, self.nr32, self.nr33, self.nr34, self.nr41, self.nr42,
+            self.nr43, self.nr44, self.nr50, self.nr51, self.nr52,
+        ]
+    }
+
+    fn set_regs(&mut self, regs: [u8; 21]) {
+        [
+            self.nr10, self.nr11, self.nr12, self.nr13, self.nr14, self.nr21, self.nr22, self.nr23,
+            self.nr24, self.nr30, self.nr31, self.nr32, self.nr33, self.nr34, self.nr41, self.nr42,
+            self.nr43, self.nr44, self.nr50, self.nr51, self.nr52,
+        ] = regs;
+    }
+
+    fn new(
+        logger: Rc<RefCell<LogbookWriter<'a>>>,
+        wave_read_mode: WaveReadMode,
+        cadence: Cadence,
+        double_speed: bool,
+    ) -> Self {
         Self {
             nr10: 0,
             nr11: 0,
@@ -241,7 +754,15 @@ impl<'a> Apu<'a> {
             nr51: 0,
             nr52: 0,
             wave_ram: Default::default(),
-            silence_timer,
+            ch3_trigger: None,
+            wave_read_mode,
+            cadence,
+            channels_on: 0,
+            length_timers: [0; 4],
+            length_phase: 0,
+            // The frame sequencer clocks lengths at 256 Hz, regardless of the CPU speed.
+            cycles_per_length_clock: if double_speed { 8192 } else { 4096 },
+            pcm_reads_noted: Default::default(),
             logger,
         }
     }
@@ -254,8 +775,144 @@ impl<'a> Apu<'a> {
         self.logger.borrow_mut().log(addr, data);
     }
 
-    fn cur_bank_addr(&self, addr: u16) -> Address {
-        Address(self.logger.borrow().rom_bank, addr)
+    /// The index of the wave RAM byte CH3 is (approximately) playing.
+    fn ch3_position(&self, trigger: &Timestamp) -> usize {
+        let now = self.logger.borrow().now();
+        let elapsed = self.cadence.cycles_before(now.tick) + u64::from(now.cycle)
+            - self.cadence.cycles_before(trigger.tick)
+            - u64::from(trigger.cycle);
+        // CH3 advances by one sample (half a byte) every `2048 - freq` APU cycles, which are twice as fast as CPU ones.
+        let freq = u64::from(self.nr34 & 7) << 8 | u64::from(self.nr33);
+        let samples = elapsed * 2 / (2048 - freq);
+        (samples / 2 % 16) as usize
+    }
+
+    fn read_wave_ram(&self, address: u16) -> u8 {
+        let stored = self.wave_ram[usize::from(address - 0xFF30)];
+        let Some(trigger) = &self.ch3_trigger else {
+            return stored;
+        };
+
+        let nb_stale = {
+            let mut logger = self.logger.borrow_mut();
+            logger.logbook.stale_wave_reads += 1;
+            logger.logbook.stale_wave_reads
+        };
+        if nb_stale <= MAX_STALE_WAVE_READ_WARNINGS {
+            self.diagnose(
+                DiagnosticLevel::Warning,
+                DiagnosticKind::StaleWaveRead(Accessed(address)),
+            );
+        }
+
+        match self.wave_read_mode {
+            WaveReadMode::Stored => stored,
+            WaveReadMode::Ff => 0xFF,
+            WaveReadMode::CurrentSampleApprox => self.wave_ram[self.ch3_position(trigger)],
+        }
+    }
+
+    fn dac_on(&self, channel: usize) -> bool {
+        match channel {
+            0 => self.nr12 & 0xF8 != 0,
+            1 => self.nr22 & 0xF8 != 0,
+            2 => self.nr30 & 0x80 != 0,
+            _ => self.nr42 & 0xF8 != 0,
+        }
+    }
+
+    /// To be called after any write to one of the channels' NRx4 or DAC control registers.
+    fn update_channel(&mut self, channel: usize, triggered: bool) {
+        if !self.dac_on(channel) || self.nr52 & 0x80 == 0 {
+            self.channels_on &= !(1 << channel);
+            self.length_timers[channel] = 0;
+        } else if triggered {
+            self.channels_on |= 1 << channel;
+        }
+    }
+
+    /// How many length clocks the channel plays for, per its NRx1.
+    fn length(&self, channel: usize) -> u16 {
+        match channel {
+            0 => 64 - u16::from(self.nr11 & 0x3F),
+            1 => 64 - u16::from(self.nr21 & 0x3F),
+            2 => 256 - u16::from(self.nr31),
+            _ => 64 - u16::from(self.nr41 & 0x3F),
+        }
+    }
+
+    /// To be called after any write to one of the channels' NRx4, after [`Self::update_channel`].
+    ///
+    /// This is approximate: the length is taken from NRx1 when the channel is triggered, instead of
+    /// being reloaded only once it runs out, and it counts whole ticks' worth of length clocks.
+    fn update_length(&mut self, channel: usize, nrx4: u8) {
+        if nrx4 & 0x40 == 0 || self.channels_on & 1 << channel == 0 {
+            self.length_timers[channel] = 0;
+        } else if nrx4 & 0x80 != 0 || self.length_timers[channel] == 0 {
+            self.length_timers[channel] = self.length(channel);
+        }
+    }
+
+    /// To be called after any write to one of the channels' NRx1, which reloads its length.
+    fn reload_length(&mut self, channel: usize) {
+        if self.length_timers[channel] != 0 {
+            self.length_timers[channel] = self.length(channel);
+        }
+    }
+
+    /// Advances the length timers by a tick's worth of length clocks, and returns which channels
+    /// they cut (bit 0 is CH1).
+    fn tick_frame(&mut self) -> u8 {
+        let tick = self.logger.borrow().tick;
+        self.length_phase += self.cadence.tick_cycles(tick);
+        let nb_clocks = self.length_phase / self.cycles_per_length_clock;
+        self.length_phase %= self.cycles_per_length_clock;
+
+        let mut expired = 0;
+        for channel in 0..4 {
+            let timer = &mut self.length_timers[channel];
+            if *timer == 0 {
+                continue;
+            }
+            match timer.checked_sub(nb_clocks.try_into().unwrap_or(u16::MAX)) {
+                Some(left) if left != 0 => *timer = left,
+                _ => {
+                    *timer = 0;
+                    self.channels_on &= !(1 << channel);
+                    if channel == 2 {
+                        self.ch3_trigger = None;
+                    }
+                    expired |= 1 << channel;
+                }
+            }
+        }
+        expired
+    }
+
+    /// Since the APU's output isn't simulated, a channel's output is approximated by its envelope's initial
+    /// volume (or CH3's output level) while it is on; this at least keeps the reads deterministic.
+    fn amplitude(&self, channel: usize) -> u8 {
+        if self.channels_on & 1 << channel == 0 {
+            return 0;
+        }
+        match channel {
+            0 => self.nr12 >> 4,
+            1 => self.nr22 >> 4,
+            2 => [0, 15, 7, 3][usize::from(self.nr32 >> 5 & 3)],
+            _ => self.nr42 >> 4,
+        }
+    }
+
+    /// `channels` is the first of the two channels, 0-based.
+    fn read_pcm(&self, channels: usize) -> u8 {
+        let noted = &self.pcm_reads_noted[channels / 2];
+        if !noted.replace(true) {
+            self.diagnose(
+                DiagnosticLevel::Warning,
+                DiagnosticKind::ApproximatePcmRead(if channels == 0 { 12 } else { 34 }),
+            );
+        }
+        self.amplitude(channels) | self.amplitude(channels + 1) << 4
     }
 
     fn read(&self, address: u16) -> Option<u8> {
@@ -269,7 +926,7 @@ impl<'a> Apu<'a> {
             Err(0xFF15) => {
                 self.diagnose(
                     DiagnosticLevel::Note,
-                    DiagnosticKind::UnsupportedRead(self.cur_bank_addr(address)),
+                    DiagnosticKind::UnsupportedRead(Accessed(address)),
                 );
                 0xFF
             }
@@ -287,7 +944,7 @@ impl<'a> Apu<'a> {
             Err(0xFF1F) => {
                 self.diagnose(
                     DiagnosticLevel::Note,
-                    DiagnosticKind::UnsupportedRead(self.cur_bank_addr(address)),
+                    DiagnosticKind::UnsupportedRead(Accessed(address)),
                 );
                 0xFF
             }
@@ -298,7 +955,8 @@ impl<'a> Apu<'a> {
 
             Ok(HwReg::Nr50) => self.nr50,
             Ok(HwReg::Nr51) => self.nr51,
-            Ok(HwReg::Nr52) => self.nr52 | 0x70,
+            // The low bits are read-only, and tell which channels are on.
+            Ok(HwReg::Nr52) => self.nr52 & 0x80 | 0x70 | self.channels_on,
 
             Ok(
                 HwReg::Wave0
@@ -317,7 +975,10 @@ impl<'a> Apu<'a> {
                 | HwReg::WaveD
                 | HwReg::WaveE
                 | HwReg::WaveF,
-            ) => self.wave_ram[usize::from(address - 0xFF30)], // TODO: implement wave RAM locking
+            ) => self.read_wave_ram(address),
+
+            Ok(HwReg::Pcm12) => self.read_pcm(0),
+            Ok(HwReg::Pcm34) => self.read_pcm(2),
 
             _ => return None,
         })
@@ -326,42 +987,104 @@ impl<'a> Apu<'a> {
     fn write(&mut self, address: u16, data: u8) -> Option<()> {
         self.log(address, data);
 
-        // TODO: the APU is currently never ticked. Any reads back may be wrong...
+        // TODO: the APU is currently only ticked for length timers. Any reads back may be wrong...
 
         match HwReg::try_from(address) {
             Ok(HwReg::Nr10) => self.nr10 = data,
-            Ok(HwReg::Nr11) => self.nr11 = data,
-            Ok(HwReg::Nr12) => self.nr12 = data,
+            Ok(HwReg::Nr11) => {
+                self.nr11 = data;
+                self.reload_length(0);
+            }
+            Ok(HwReg::Nr12) => {
+                self.nr12 = data;
+                self.update_channel(0, false);
+            }
             Ok(HwReg::Nr13) => self.nr13 = data,
-            Ok(HwReg::Nr14) => self.nr14 = data,
+            Ok(HwReg::Nr14) => {
+                self.nr14 = data;
+                self.update_channel(0, data & 0x80 != 0);
+                self.update_length(0, data);
+            }
             Err(0xFF15) => self.diagnose(
                 DiagnosticLevel::Note,
-                DiagnosticKind::UnsupportedWrite(self.cur_bank_addr(address), data),
+                DiagnosticKind::UnsupportedWrite(Accessed(address), data),
             ),
 
-            Ok(HwReg::Nr21) => self.nr21 = data,
-            Ok(HwReg::Nr22) => self.nr22 = data,
+            Ok(HwReg::Nr21) => {
+                self.nr21 = data;
+                self.reload_length(1);
+            }
+            Ok(HwReg::Nr22) => {
+                self.nr22 = data;
+                self.update_channel(1, false);
+            }
             Ok(HwReg::Nr23) => self.nr23 = data,
-            Ok(HwReg::Nr24) => self.nr24 = data,
+            Ok(HwReg::Nr24) => {
+                self.nr24 = data;
+                self.update_channel(1, data & 0x80 != 0);
+                self.update_length(1, data);
+            }
 
-            Ok(HwReg::Nr30) => self.nr30 = data,
-            Ok(HwReg::Nr31) => self.nr31 = data,
+            Ok(HwReg::Nr30) => {
+                self.nr30 = data;
+                if data & 0x80 == 0 {
+                    self.ch3_trigger = None;
+                }
+                self.update_channel(2, false);
+            }
+            Ok(HwReg::Nr31) => {
+                self.nr31 = data;
+                self.reload_length(2);
+            }
             Ok(HwReg::Nr32) => self.nr32 = data,
             Ok(HwReg::Nr33) => self.nr33 = data,
-            Ok(HwReg::Nr34) => self.nr34 = data,
+            Ok(HwReg::Nr34) => {
+                self.nr34 = data;
+                if data & 0x80 != 0 && self.nr30 & 0x80 != 0 && self.nr52 & 0x80 != 0 {
+                    self.ch3_trigger = Some(self.logger.borrow().now());
+                }
+                self.update_channel(2, data & 0x80 != 0);
+                self.update_length(2, data);
+            }
 
             Err(0xFF1F) => self.diagnose(
                 DiagnosticLevel::Note,
-                DiagnosticKind::UnsupportedWrite(self.cur_bank_addr(address), data),
+                DiagnosticKind::UnsupportedWrite(Accessed(address), data),
             ),
-            Ok(HwReg::Nr41) => self.nr41 = data,
-            Ok(HwReg::Nr42) => self.nr42 = data,
+            Ok(HwReg::Nr41) => {
+                self.nr41 = data;
+                self.reload_length(3);
+            }
+            Ok(HwReg::Nr42) => {
+                self.nr42 = data;
+                self.update_channel(3, false);
+            }
             Ok(HwReg::Nr43) => self.nr43 = data,
-            Ok(HwReg::Nr44) => self.nr44 = data,
+            Ok(HwReg::Nr44) => {
+                self.nr44 = data;
+                self.update_channel(3, data & 0x80 != 0);
+                self.update_length(3, data);
+            }
 
             Ok(HwReg::Nr50) => self.nr50 = data,
             Ok(HwReg::Nr51) => self.nr51 = data,
-            Ok(HwReg::Nr52) => self.nr52 = data,
+            Ok(HwReg::Nr52) => {
+                self.nr52 = data;
+                if data & 0x80 == 0 {
+                    // Turning the APU off clears all of its registers, which is rarely intended
+                    // outside of INIT.
+                    let tick = self.logger.borrow().tick;
+                    if tick != 0 {
+                        self.diagnose(
+                            DiagnosticLevel::Warning,
+                            DiagnosticKind::ApuPoweredOff(tick),
+                        );
+                    }
+                    self.ch3_trigger = None;
+                    self.channels_on = 0;
+                    self.length_timers = [0; 4];
+                }
+            }
 
             Ok(
                 HwReg::Wave0
@@ -385,7 +1108,6 @@ impl<'a> Apu<'a> {
             _ => return None,
         };
 
-        self.silence_timer.set(0);
         Some(())
     }
 }
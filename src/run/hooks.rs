@@ -0,0 +1,150 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with observing the simulation as it runs, and deciding when a song is over.
+//!
+//! gbsdiff's own end conditions (silence, the watched address, and the timeout) are implemented as
+//! hooks as well, and always run before any others.
+
+use std::ops::ControlFlow;
+
+use gb_cpu_sim::cpu::State;
+
+use super::{GbsAddrSpace, Profile, SimParams, Termination};
+use crate::{gbs::Cadence, Address};
+
+pub trait SimHooks {
+    /// Called after each PLAY tick (but not after INIT); breaking ends the song.
+    ///
+    /// `Termination::Timeout` is an error unless the parameters allow timeouts.
+    fn on_tick_end(&mut self, _tick: u64, _cpu: &CpuView) -> ControlFlow<Termination> {
+        ControlFlow::Continue(())
+    }
+
+    /// Called for each write that lands in RAM or a simulated I/O register.
+    fn on_write(&mut self, _addr: u16, _data: u8) {}
+
+    /// Called before each instruction is executed.
+    fn on_instruction(&mut self, _pc: &Address) {}
+}
+
+/// No additional hooks.
+impl SimHooks for () {}
+
+/// Read-only access to the CPU and memory, from the end of a tick.
+pub struct CpuView<'c, 'a> {
+    pub(super) cpu: &'c State<GbsAddrSpace<'a>>,
+}
+
+impl CpuView<'_, '_> {
+    pub fn read(&self, addr: u16) -> u8 {
+        self.cpu.address_space.peek(addr)
+    }
+}
+
+/// The end conditions configured through the parameters.
+#[derive(Debug)]
+pub(super) struct EndConditions {
+    /// In cycles.
+    pub silence_timer: u32,
+    silence_timeout: u32,
+    watch: Option<(u16, u8)>,
+    /// Set when the watched address gets written the watched value, even if it's overwritten
+    /// before the end of the tick.
+    pub watch_hit: bool,
+    /// In cycles, how long until the song times out.
+    pub timeout: u32,
+    cadence: Cadence,
+    profile: Profile,
+}
+
+impl EndConditions {
+    pub fn new(params: &SimParams, cadence: Cadence) -> Self {
+        Self {
+            silence_timer: 0,
+            silence_timeout: params.silence_timeout,
+            watch: params.watch,
+            watch_hit: false,
+            timeout: params.timeout,
+            cadence,
+            profile: params.profile.clone(),
+        }
+    }
+}
+
+impl SimHooks for EndConditions {
+    fn on_tick_end(&mut self, tick: u64, cpu: &CpuView) -> ControlFlow<Termination> {
+        let cycles = self.cadence.tick_cycles(tick);
+        let termination = if self.silence_timer >= self.silence_timeout {
+            Some(Termination::Silence)
+        } else if self.watch_hit
+            || self
+                .watch
+                .is_some_and(|(addr, value)| cpu.read(addr) == value)
+        {
+            Some(Termination::Watch)
+        } else {
+            None
+        };
+        self.silence_timer += cycles;
+        if let Some(termination) = termination {
+            return ControlFlow::Break(termination);
+        }
+        match self.timeout.checked_sub(cycles) {
+            Some(timeout) => {
+                self.timeout = timeout;
+                ControlFlow::Continue(())
+            }
+            None => ControlFlow::Break(Termination::Timeout),
+        }
+    }
+
+    fn on_write(&mut self, addr: u16, data: u8) {
+        // The watch only catches RAM writes this way; I/O registers can't be written as-is, so the
+        // end-of-tick check reads them instead.
+        if self.profile.is_audio(addr) {
+            self.silence_timer = 0;
+        } else if !(0xFF00..0xFF80).contains(&addr) {
+            // Echo RAM writes are reported with their WRAM address as well.
+            if self.watch == Some((addr, data))
+                || (0xE000..=0xFDFF).contains(&addr) && self.watch == Some((addr - 0x2000, data))
+            {
+                self.watch_hit = true;
+            }
+        }
+    }
+}
+
+/// The built-in end conditions, followed by the caller's hooks.
+pub(super) struct Hooks<'h> {
+    pub end: EndConditions,
+    pub extra: &'h mut dyn SimHooks,
+}
+
+impl std::fmt::Debug for Hooks<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks")
+            .field("end", &self.end)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SimHooks for Hooks<'_> {
+    fn on_tick_end(&mut self, tick: u64, cpu: &CpuView) -> ControlFlow<Termination> {
+        self.end.on_tick_end(tick, cpu)?;
+        self.extra.on_tick_end(tick, cpu)
+    }
+
+    fn on_write(&mut self, addr: u16, data: u8) {
+        self.end.on_write(addr, data);
+        self.extra.on_write(addr, data);
+    }
+
+    fn on_instruction(&mut self, pc: &Address) {
+        self.end.on_instruction(pc);
+        self.extra.on_instruction(pc);
+    }
+}
@@ -7,8 +7,14 @@
 //! This module deals with running the CPU simulator for a particular GBS file.
 
 use std::{
-    cell::{Cell, RefCell},
+    cell::{Ref, RefCell},
+    collections::{BTreeMap, VecDeque},
+    fmt::Display,
     io::Write,
+    ops::{ControlFlow, Range, RangeInclusive},
+    rc::Rc,
+    str::FromStr,
+    time::Instant,
 };
 
 use gb_cpu_sim::{
@@ -18,121 +24,1220 @@ use gb_cpu_sim::{
 use parse_display::Display;
 
 use crate::{
+    diff::RegDispl,
     gbs::{AddressKind, Gbs},
-    Address, Diagnostic, DiagnosticLevel, Timestamp,
+    replay::ReadQueues,
+    Address, Diagnostic, DiagnosticLevel, Region, Timestamp,
 };
 
 mod addr_space;
 use addr_space::*;
+mod hooks;
+pub use hooks::{CpuView, SimHooks};
+pub(crate) mod snapshot;
+use snapshot::Snapshot;
+pub(crate) mod trace;
+use hooks::{EndConditions, Hooks};
+use trace::{TraceEvent, TraceFormat, TraceWriter};
+
+/// The parameters that affect how a song is simulated; shared by both GBS files.
+#[derive(Debug, Clone)]
+pub struct SimParams {
+    pub max_level: DiagnosticLevel,
+    /// Past this many diagnostics, a song's are only counted, so that a broken build looping over
+    /// something diagnosed doesn't exhaust memory.
+    pub max_recorded_diagnostics: usize,
+    /// In cycles.
+    pub timeout: u32,
+    pub allow_timeout: bool,
+    /// In cycles.
+    pub silence_timeout: u32,
+    pub watch: Option<(u16, u8)>,
+    /// The song only starts once this holds after INIT or a tick; see [`SongSimulation::warm_up`].
+    pub wait_for: Option<(u16, u8)>,
+    pub trace_filter: TraceFilter,
+    pub trace_format: TraceFormat,
+    pub wave_read_mode: WaveReadMode,
+    pub debug_markers: DebugMarkers,
+    /// A single INIT or PLAY call running longer than this is considered locked up.
+    pub max_func_cycles: u32,
+    /// Warn if PLAY stops writing to the APU for more ticks than this.
+    pub max_stall_ticks: u64,
+    /// Whether to tell when a single call takes long to simulate.
+    pub show_progress: bool,
+    /// Past this point in real time, songs are aborted (`--wall-timeout`).
+    pub deadline: Option<Instant>,
+    /// Written to the address space after LOAD, before INIT.
+    pub pokes: Vec<(u16, u8)>,
+    /// Applied just before INIT, after the pokes and the song ID.
+    pub init_regs: InitRegs,
+    /// Where INIT starts instead of the header's address (`--init-addr`).
+    pub init_addr: Option<u16>,
+    /// Where PLAY starts instead of the header's address (`--play-addr`).
+    pub play_addr: Option<u16>,
+    /// Whether to warn about reads of RAM that hasn't been written yet.
+    pub uninit_check: bool,
+    /// Values returned by reads of otherwise unsupported I/O registers, overriding the built-in ones.
+    pub stub_regs: Vec<(u16, u8)>,
+    pub profile: Profile,
+    /// Applied in order, so the last one for a given kind wins.
+    pub promotions: Vec<Promotion>,
+    /// Writes to these addresses are reported, along with the instructions that led to them.
+    pub watchpoints: Vec<u16>,
+    /// Like `watchpoints`, but for reads.
+    pub read_watchpoints: Vec<u16>,
+    /// RAM addresses whose writes are logged to [`Logbook::shadow_log`] (`--shadow`).
+    pub shadow_addrs: Vec<u16>,
+    /// Whether I/O reads are logged to [`Logbook::read_log`], which few options need.
+    pub log_reads: bool,
+}
+
+impl SimParams {
+    /// Settings that report everything and don't depend on the command line, so that the outcome
+    /// only depends on the simulator; the self-test and the unit tests use them.
+    pub fn new(timeout: u32) -> Self {
+        Self {
+            max_level: DiagnosticLevel::Warning,
+            max_recorded_diagnostics: usize::MAX,
+            timeout,
+            allow_timeout: true,
+            silence_timeout: u32::MAX,
+            watch: None,
+            wait_for: None,
+            trace_filter: TraceFilter::All,
+            trace_format: TraceFormat::Text,
+            wave_read_mode: WaveReadMode::Stored,
+            debug_markers: DebugMarkers::Note,
+            max_func_cycles: 1000,
+            max_stall_ticks: u64::MAX,
+            show_progress: false,
+            deadline: None,
+            pokes: Vec::new(),
+            init_regs: InitRegs::default(),
+            init_addr: None,
+            play_addr: None,
+            uninit_check: true,
+            stub_regs: Vec::new(),
+            profile: Profile::Apu,
+            promotions: Vec::new(),
+            watchpoints: Vec::new(),
+            read_watchpoints: Vec::new(),
+            shadow_addrs: Vec::new(),
+            log_reads: false,
+        }
+    }
+}
+
+/// Overrides the level of a kind of diagnostic, before `max_level` filters them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Promotion {
+    /// One of [`DiagnosticKind::NAMES`].
+    pub kind: &'static str,
+    /// `None` drops them entirely.
+    pub level: Option<DiagnosticLevel>,
+}
+
+impl FromStr for Promotion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, level) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected \"KIND=LEVEL\", got {:?}", s))?;
+        let kind = DiagnosticKind::NAMES
+            .into_iter()
+            .find(|name| kind.trim().eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                format!(
+                    "unknown diagnostic kind {:?} (must be one of {})",
+                    kind.trim(),
+                    DiagnosticKind::NAMES.join(", ")
+                )
+            })?;
+        let level = match level.trim() {
+            level if level.eq_ignore_ascii_case("ignore") => None,
+            level => Some(level.parse().map_err(|_| {
+                "level must be one of \"error\", \"warning\", \"note\", or \"ignore\""
+            })?),
+        };
+        Ok(Self { kind, level })
+    }
+}
+
+/// The level a diagnostic ends up with, if it isn't dropped.
+fn promote(
+    promotions: &[Promotion],
+    kind: &DiagnosticKind,
+    level: DiagnosticLevel,
+) -> Option<DiagnosticLevel> {
+    promotions
+        .iter()
+        .rev()
+        .find(|promotion| promotion.kind == kind.name())
+        .map_or(Some(level), |promotion| promotion.level)
+}
+
+/// Which lines get written to the trace file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFilter {
+    /// CPU state before each instruction, and I/O annotations.
+    All,
+    /// Only I/O accesses and bank switches.
+    Io,
+}
+
+impl FromStr for TraceFilter {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("all") {
+            Ok(Self::All)
+        } else if s.eq_ignore_ascii_case("io") {
+            Ok(Self::Io)
+        } else {
+            Err("must be either \"all\" or \"io\"")
+        }
+    }
+}
+
+/// What reading wave RAM returns while CH3 is playing.
+///
+/// On hardware, such reads return the byte currently being played (or $FF on DMG, most of the time);
+/// since the APU is not actually simulated, this can only be approximated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaveReadMode {
+    /// The byte last written there.
+    Stored,
+    Ff,
+    /// The byte at an estimate of the playback position, derived from the cycles elapsed since
+    /// the trigger and the channel's frequency; pitch changes mid-note are not accounted for.
+    CurrentSampleApprox,
+}
+
+impl FromStr for WaveReadMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("stored") {
+            Ok(Self::Stored)
+        } else if s.eq_ignore_ascii_case("ff") {
+            Ok(Self::Ff)
+        } else if s.eq_ignore_ascii_case("current-sample-approx") {
+            Ok(Self::CurrentSampleApprox)
+        } else {
+            Err("must be one of \"stored\", \"ff\", or \"current-sample-approx\"")
+        }
+    }
+}
+
+/// Which registers count as audio output, on top of the APU's: like them, writes to them reset the
+/// silence timer and count against stalls, and they read back what was written to them instead of
+/// being reported as unsupported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Profile {
+    Apu,
+    /// SB and SC as well, for drivers that stream data out of the link port.
+    ApuSerial,
+    Custom(Vec<RangeInclusive<u16>>),
+}
+
+impl Profile {
+    /// Whether the register is one of the extra ones.
+    pub fn is_extra(&self, addr: u16) -> bool {
+        match self {
+            Self::Apu => false,
+            Self::ApuSerial => (0xFF01..=0xFF02).contains(&addr),
+            Self::Custom(ranges) => ranges.iter().any(|range| range.contains(&addr)),
+        }
+    }
+
+    pub fn is_audio(&self, addr: u16) -> bool {
+        crate::regs::is_apu(addr) || self.is_extra(addr)
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("apu") {
+            return Ok(Self::Apu);
+        } else if s.eq_ignore_ascii_case("apu+serial") {
+            return Ok(Self::ApuSerial);
+        }
+        let Some(ranges) = s
+            .get(..7)
+            .filter(|prefix| prefix.eq_ignore_ascii_case("custom:"))
+            .map(|_| &s[7..])
+        else {
+            return Err(
+                "must be either \"apu\", \"apu+serial\", or \"custom:\" followed by address ranges (e.g. \"custom:ff01-ff02,ff4d\")"
+                    .into(),
+            );
+        };
+
+        let addr = |addr: &str| {
+            let addr = addr.trim();
+            u16::from_str_radix(addr.strip_prefix('$').unwrap_or(addr), 16)
+                .ok()
+                .filter(|addr| (0xFF01..=0xFF7F).contains(addr))
+                .ok_or_else(|| {
+                    format!(
+                        "{:?} is not the address of an I/O register between ff01 and ff7f",
+                        addr
+                    )
+                })
+        };
+        ranges
+            .split(',')
+            .map(|range| {
+                let (start, end) = range.split_once('-').unwrap_or((range, range));
+                let (start, end) = (addr(start)?, addr(end)?);
+                if start > end {
+                    return Err(format!("{:?} ends before it starts", range));
+                }
+                Ok(start..=end)
+            })
+            .collect::<Result<_, _>>()
+            .map(Self::Custom)
+    }
+}
+
+/// What to do about debug opcodes (`ld b, b` and `ld d, d`), which RGBDS users sprinkle as
+/// breakpoints and message markers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugMarkers {
+    /// Record them, so that their positions can be compared between the two files.
+    Anchor,
+    /// Emit a note for each.
+    Note,
+    Ignore,
+}
+
+impl FromStr for DebugMarkers {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("anchor") {
+            Ok(Self::Anchor)
+        } else if s.eq_ignore_ascii_case("note") {
+            Ok(Self::Note)
+        } else if s.eq_ignore_ascii_case("ignore") {
+            Ok(Self::Ignore)
+        } else {
+            Err("must be one of \"anchor\", \"note\", or \"ignore\"")
+        }
+    }
+}
+
+/// A CPU register that can be preset before INIT.
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+#[display(style = "lowercase")]
+pub enum CpuReg {
+    A,
+    F,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    Af,
+    Bc,
+    De,
+    Hl,
+}
+
+impl CpuReg {
+    const ALL: [Self; 12] = [
+        Self::A,
+        Self::F,
+        Self::B,
+        Self::C,
+        Self::D,
+        Self::E,
+        Self::H,
+        Self::L,
+        Self::Af,
+        Self::Bc,
+        Self::De,
+        Self::Hl,
+    ];
+
+    fn is_pair(self) -> bool {
+        matches!(self, Self::Af | Self::Bc | Self::De | Self::Hl)
+    }
+
+    fn set<S: AddressSpace>(self, cpu: &mut State<S>, value: u16) {
+        // 8-bit registers' values are checked to fit when parsing.
+        let byte = value as u8;
+        match self {
+            Self::A => cpu.a = byte,
+            Self::F => cpu.f.value = byte,
+            Self::B => cpu.b = byte,
+            Self::C => cpu.c = byte,
+            Self::D => cpu.d = byte,
+            Self::E => cpu.e = byte,
+            Self::H => cpu.h = byte,
+            Self::L => cpu.l = byte,
+            Self::Af => cpu.set_af(value),
+            Self::Bc => cpu.set_bc(value),
+            Self::De => cpu.set_de(value),
+            Self::Hl => cpu.set_hl(value),
+        }
+    }
+}
+
+/// Register assignments like `b=01,c=80,hl=c123` (hex values), applied in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InitRegs(pub Vec<(CpuReg, u16)>);
+
+impl InitRegs {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl FromStr for InitRegs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|assignment| {
+                let (name, value) = assignment
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected \"REG=VALUE\", got {:?}", assignment))?;
+                let name = name.trim();
+                let reg = CpuReg::ALL
+                    .into_iter()
+                    .find(|reg| name.eq_ignore_ascii_case(&reg.to_string()))
+                    .ok_or_else(|| format!("unknown register {:?}", name))?;
+                let value = if reg.is_pair() {
+                    u16::from_str_radix(value.trim(), 16)
+                } else {
+                    u8::from_str_radix(value.trim(), 16).map(u16::from)
+                }
+                .map_err(|err| format!("invalid value for {}: {}", reg, err))?;
+                Ok((reg, value))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl Display for InitRegs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (reg, value)) in self.0.iter().enumerate() {
+            if i != 0 {
+                write!(f, ",")?;
+            }
+            if reg.is_pair() {
+                write!(f, "{}={:04x}", reg, value)?;
+            } else {
+                write!(f, "{}={:02x}", reg, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Cartridge RAM, which GBS players may keep from one song to the next.
+pub(crate) type Sram = [u8; 0x2000];
 
 /// Note: `song_id` is 0-based.
+///
+/// If `forced_reads` is given, I/O register reads return the recorded values instead of the simulated ones.
+///
+/// If `sram` is given, the song starts with those SRAM contents instead of zeros, and they are
+/// replaced with the song's final ones (unless the simulation fails).
 pub(crate) fn simulate_song<T: Write>(
     gbs: &Gbs<'_>,
     song_id: u8,
-    max_level: DiagnosticLevel,
-    mut timeout: u32,
-    allow_timeout: bool,
-    silence_timeout: u32,
-    watch: Option<(u16, u8)>,
-    mut trace_file: Option<T>,
-) -> Result<Logbook, Error> {
-    let mut logbook = Default::default();
-    let logger = RefCell::new(LogbookWriter::new(&mut logbook, max_level));
-    let cycles_per_tick: u16 = if gbs.use_timer() {
-        (1u16 << gbs.timer_div_bit()) * (256u16 - u16::from(gbs.timer_mod()))
-    } else {
-        114 * 154 // 114 cycles/scanline times 154 scanlines
-    };
-    let silence_timer = Cell::new(0);
+    params: &SimParams,
+    forced_reads: Option<&ReadQueues>,
+    trace_file: Option<T>,
+    sram: Option<&mut Sram>,
+) -> Result<Logbook, Failure> {
+    simulate_song_with_hooks(
+        gbs,
+        song_id,
+        params,
+        forced_reads,
+        trace_file,
+        sram,
+        &mut (),
+    )
+}
 
-    if let Some(ref mut trace_file) = trace_file {
-        writeln!(trace_file, "==== SONG {} ====", song_id).unwrap_or_else(crate::trace_write_fail);
+/// Like [`simulate_song`], but `hooks` get to observe the simulation, and may end the song early.
+pub fn simulate_song_with_hooks<'a, T: Write + 'a>(
+    gbs: &'a Gbs<'_>,
+    song_id: u8,
+    params: &'a SimParams,
+    forced_reads: Option<&'a ReadQueues>,
+    trace_file: Option<T>,
+    sram: Option<&mut Sram>,
+    hooks: &'a mut dyn SimHooks,
+) -> Result<Logbook, Failure> {
+    let mut simulation = SongSimulation::new(
+        gbs,
+        song_id,
+        params,
+        forced_reads,
+        trace_file.map(|file| Box::new(file) as Box<dyn Write>),
+        sram.as_deref(),
+        hooks,
+    )?;
+    while simulation.termination().is_none() {
+        simulation.run_tick()?;
+    }
+    if let Some(sram) = sram {
+        *sram = *simulation.sram();
+    }
+    Ok(simulation.finish())
+}
+
+/// Like [`simulate_song`], but the song resumes from `resume` if given, instead of running "LOAD"
+/// and "INIT"; and if it gets past tick `save_at` (0 being INIT), a snapshot of that point is
+/// returned as well.
+pub(crate) fn simulate_song_resumable<T: Write>(
+    gbs: &Gbs<'_>,
+    song_id: u8,
+    params: &SimParams,
+    trace_file: Option<T>,
+    sram: Option<&mut Sram>,
+    resume: Option<&Snapshot>,
+    save_at: Option<u64>,
+) -> Result<(Logbook, Option<Snapshot>), Failure> {
+    let mut hooks = ();
+    let trace_file = trace_file.map(|file| Box::new(file) as Box<dyn Write>);
+    let mut simulation = match resume {
+        Some(snapshot) => {
+            SongSimulation::resume(gbs, song_id, params, trace_file, snapshot, &mut hooks)
+        }
+        None => SongSimulation::new(
+            gbs,
+            song_id,
+            params,
+            None,
+            trace_file,
+            sram.as_deref(),
+            &mut hooks,
+        )?,
+    };
+    let mut snapshot = None;
+    loop {
+        if save_at == Some(simulation.logger.borrow().tick) {
+            snapshot = Some(simulation.snapshot());
+        }
+        simulation.run_tick()?;
+        if simulation.termination().is_some() {
+            break;
+        }
     }
+    if let Some(sram) = sram {
+        *sram = *simulation.sram();
+    }
+    Ok((simulation.finish(), snapshot))
+}
 
-    // "LOAD" step.
-    let mut cpu = State::new(GbsAddrSpace::new(gbs, &logger, &silence_timer));
+/// A song being simulated, one tick at a time.
+pub(crate) struct SongSimulation<'a> {
+    gbs: &'a Gbs<'a>,
+    params: &'a SimParams,
+    cpu: State<GbsAddrSpace<'a>>,
+    logger: Rc<RefCell<LogbookWriter<'a>>>,
+    hooks: Rc<RefCell<Hooks<'a>>>,
+    /// Set once the song is over.
+    termination: Option<Termination>,
+    stalls: StallTracker,
+}
 
-    // "INIT" step.
-    cpu.a = song_id;
-    cpu.sp = gbs.stack_ptr();
-    cpu.pc = gbs.addr(AddressKind::Init);
-    run_func(&mut cpu, trace_file.as_mut(), &logger)?;
+impl<'a> SongSimulation<'a> {
+    /// Runs the "LOAD" and "INIT" steps.
+    pub fn new(
+        gbs: &'a Gbs<'_>,
+        song_id: u8,
+        params: &'a SimParams,
+        forced_reads: Option<&'a ReadQueues>,
+        trace_file: Option<Box<dyn Write + 'a>>,
+        sram: Option<&Sram>,
+        hooks: &'a mut dyn SimHooks,
+    ) -> Result<Self, Failure> {
+        let (mut cpu, logger, hooks) =
+            Self::load(gbs, song_id, params, forced_reads, trace_file, sram, hooks);
 
-    // "PLAY" step.
-    loop {
-        logger.borrow_mut().next_tick();
-        if let Some(ref mut trace_file) = trace_file {
-            writeln!(trace_file, "--- TICK {} ---", logger.borrow().tick)
-                .unwrap_or_else(crate::trace_write_fail);
+        // Pokes go first, so that they may not clobber the registers.
+        for &(addr, value) in &params.pokes {
+            cpu.write(addr, value);
         }
 
+        // "INIT" step.
+        crate::bug_report::set_phase(crate::bug_report::Phase::Init);
+        crate::bug_report::set_tick(0);
+        cpu.a = song_id;
+        for &(reg, value) in &params.init_regs.0 {
+            reg.set(&mut cpu, value);
+        }
         cpu.sp = gbs.stack_ptr();
-        cpu.pc = gbs.addr(AddressKind::Play);
-        let cycles = run_func(&mut cpu, trace_file.as_mut(), &logger)?;
+        cpu.address_space.assume_return_addr(cpu.sp);
+        cpu.pc = gbs.addr(AddressKind::Init);
+        let run = run_func(&mut cpu, &logger, params, &hooks, None).map_err(|error| Failure {
+            error,
+            init_cycles: Some(logger.borrow().cycle),
+        })?;
+        logger.borrow_mut().end_tick(run.cycles, run.stack_depth);
+        // Only PLAY's writes may end the song, like the end-of-tick check.
+        hooks.borrow_mut().end.watch_hit = false;
 
-        if let Some(_diff) = cycles_per_tick.checked_sub(cycles) {
-            // TODO: tick DIV etc.
-        } else {
-            logger.borrow_mut().diagnose(
-                DiagnosticLevel::Warning,
-                DiagnosticKind::TooLong(cycles, cycles_per_tick),
-            );
+        let mut simulation = Self {
+            gbs,
+            params,
+            cpu,
+            logger,
+            hooks,
+            termination: None,
+            stalls: StallTracker::default(),
+        };
+        if let Some(condition) = params.wait_for {
+            simulation.warm_up(condition)?;
         }
+        Ok(simulation)
+    }
 
-        // Check termination conditions.
-        if silence_timer.get() >= silence_timeout {
-            break;
+    /// Runs PLAY until `addr` holds `value` after a tick, like a player waiting for the driver to
+    /// be ready (e.g. done decompressing samples) would, then forgets about those ticks: the next
+    /// one is tick 1, and their writes and reads are discarded. Their diagnostics are kept, but
+    /// moved to tick 0, as the warm-up is really an extension of INIT.
+    ///
+    /// The warm-up may last as long as the timeout, which then starts over for the song itself, so
+    /// that songs waiting for different lengths don't end at different points; the silence timeout
+    /// and the watched address don't apply to it.
+    fn warm_up(&mut self, (addr, value): (u16, u8)) -> Result<(), Error> {
+        let cadence = self.gbs.cadence();
+        while self.cpu.address_space.peek(addr) != value {
+            {
+                let next_tick = self.logger.borrow().tick + 1;
+                let end = &mut self.hooks.borrow_mut().end;
+                end.timeout = end
+                    .timeout
+                    .checked_sub(cadence.tick_cycles(next_tick))
+                    .ok_or(Error::WaitTimeout(addr, value))?;
+            }
+            self.play()?;
+        }
+        {
+            let end = &mut self.hooks.borrow_mut().end;
+            end.silence_timer = 0;
+            end.watch_hit = false;
+            end.timeout = self.params.timeout;
+        }
+
+        let mut logger = self.logger.borrow_mut();
+        let warmup_ticks = logger.tick;
+        let logbook = &mut logger.logbook;
+        logbook.warmup_ticks = warmup_ticks;
+        logbook.io_log.retain(|access| access.when.tick == 0);
+        logbook.read_log.retain(|access| access.when.tick == 0);
+        logbook.shadow_log.retain(|access| access.when.tick == 0);
+        logbook.debug_markers.retain(|marker| marker.when.tick == 0);
+        logbook.length_expiries.clear();
+        for diag in &mut logbook.diagnostics {
+            diag.when.tick = 0;
         }
-        silence_timer.set(silence_timer.get() + u32::from(cycles_per_tick));
-        if let Some((addr, value)) = watch {
-            if cpu.read(addr) == value {
-                break;
+        for ticks in logbook.unrecorded.values_mut() {
+            let count = ticks.iter().map(|(_, count)| count).sum();
+            *ticks = vec![(0, count)];
+        }
+        // Keep INIT's entries.
+        logbook.tick_cycles.truncate(1);
+        logbook.last_write_cycles.truncate(1);
+        logbook.stack_depths.truncate(1);
+        logbook.exit_banks.truncate(1);
+        logger.tick = 0;
+        Ok(())
+    }
+
+    /// Picks up where [`Self::snapshot`] left off.
+    ///
+    /// The ticks up to the snapshot's are recorded as empty, see [`Logbook::first_tick`].
+    pub fn resume(
+        gbs: &'a Gbs<'_>,
+        song_id: u8,
+        params: &'a SimParams,
+        trace_file: Option<Box<dyn Write + 'a>>,
+        snapshot: &Snapshot,
+        hooks: &'a mut dyn SimHooks,
+    ) -> Self {
+        let (mut cpu, logger, hooks) =
+            Self::load(gbs, song_id, params, None, trace_file, None, hooks);
+
+        cpu.address_space.restore(&snapshot.memory);
+        [cpu.a, cpu.f.value, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l] = snapshot.regs;
+        cpu.ime = snapshot.ime;
+        {
+            let mut logger = logger.borrow_mut();
+            logger.rom_bank = snapshot.rom_bank;
+            logger.wram_bank = snapshot.memory.svbk.max(1);
+            for tick in 0..=snapshot.tick {
+                logger.tick = tick;
+                logger.end_tick(0, 0);
             }
+            logger.logbook.total_cycles = snapshot.total_cycles;
+            logger.logbook.first_tick = snapshot.tick + 1;
+        }
+        {
+            let end = &mut hooks.borrow_mut().end;
+            end.silence_timer = snapshot.silence_timer;
+            end.timeout = snapshot.timeout;
         }
-        timeout = match timeout.checked_sub(cycles_per_tick.into()) {
-            Some(timeout) => timeout,
-            None if allow_timeout => break,
-            None => return Err(Error::Timeout),
+
+        Self {
+            gbs,
+            params,
+            cpu,
+            logger,
+            hooks,
+            termination: None,
+            stalls: StallTracker::default(),
+        }
+    }
+
+    /// The "LOAD" step, which [`Self::resume`] then overwrites most of.
+    #[allow(clippy::type_complexity)]
+    fn load(
+        gbs: &'a Gbs<'_>,
+        song_id: u8,
+        params: &'a SimParams,
+        forced_reads: Option<&'a ReadQueues>,
+        trace_file: Option<Box<dyn Write + 'a>>,
+        sram: Option<&Sram>,
+        hooks: &'a mut dyn SimHooks,
+    ) -> (
+        State<GbsAddrSpace<'a>>,
+        Rc<RefCell<LogbookWriter<'a>>>,
+        Rc<RefCell<Hooks<'a>>>,
+    ) {
+        let logger = Rc::new(RefCell::new(LogbookWriter::new(
+            params.max_level,
+            params.max_recorded_diagnostics,
+            &params.promotions,
+            trace_file.map(|file| TraceWriter::new(file, params.trace_format)),
+            params.trace_filter,
+            params.log_reads,
+        )));
+        let hooks = Rc::new(RefCell::new(Hooks {
+            end: EndConditions::new(params, gbs.cadence()),
+            extra: hooks,
+        }));
+
+        logger.borrow_mut().trace_header(TraceEvent::Song(song_id));
+
+        let cpu = State::new(GbsAddrSpace::new(
+            gbs,
+            Rc::clone(&logger),
+            forced_reads,
+            params,
+            Rc::clone(&hooks),
+            sram,
+        ));
+        (cpu, logger, hooks)
+    }
+
+    /// The state after the last tick that ran, from which [`Self::resume`] can continue.
+    pub fn snapshot(&self) -> Snapshot {
+        let logger = self.logger.borrow();
+        let hooks = self.hooks.borrow();
+        let cpu = &self.cpu;
+        Snapshot {
+            tick: logger.tick,
+            total_cycles: logger.logbook.total_cycles,
+            regs: [cpu.a, cpu.f.value, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l],
+            ime: cpu.ime,
+            rom_bank: logger.rom_bank,
+            silence_timer: hooks.end.silence_timer,
+            timeout: hooks.end.timeout,
+            memory: cpu.address_space.state(),
+        }
+    }
+
+    /// Runs a single "PLAY" step, and returns the writes it made; [`Self::termination`] then
+    /// tells whether the song is over. Once it is, no more ticks run, and no writes are returned.
+    pub fn run_tick(&mut self) -> Result<Ref<'_, [IoAccess]>, Error> {
+        let start = self.logger.borrow().logbook.io_log.len();
+        if self.termination.is_none() {
+            let tick = self.play()?;
+            {
+                let mut logger = self.logger.borrow_mut();
+                if let Some(stall) =
+                    self.stalls
+                        .on_tick(tick, &logger.logbook.io_log[start..], self.params)
+                {
+                    insert_diagnostic(&mut logger.logbook, stall);
+                }
+            }
+
+            let flow = self
+                .hooks
+                .borrow_mut()
+                .on_tick_end(tick, &CpuView { cpu: &self.cpu });
+            match flow {
+                ControlFlow::Continue(()) => {}
+                ControlFlow::Break(Termination::Timeout) if !self.params.allow_timeout => {
+                    return Err(Error::Timeout);
+                }
+                ControlFlow::Break(termination) => self.termination = Some(termination),
+            }
+        }
+        let logger = self.logger.borrow();
+        Ok(Ref::map(logger, |logger| {
+            &logger.logbook.io_log[start.min(logger.logbook.io_log.len())..]
+        }))
+    }
+
+    /// Why the song is over, if it is.
+    pub fn termination(&self) -> Option<Termination> {
+        self.termination
+    }
+
+    /// Takes everything logged so far out of the logbook, leaving only the counts and totals; for
+    /// callers that process each tick as it is run, so that the logs don't grow with the song.
+    pub fn take_logs(&mut self) -> Logbook {
+        let logbook = &mut self.logger.borrow_mut().logbook;
+        Logbook {
+            diagnostics: std::mem::take(&mut logbook.diagnostics),
+            io_log: std::mem::take(&mut logbook.io_log),
+            read_log: std::mem::take(&mut logbook.read_log),
+            shadow_log: std::mem::take(&mut logbook.shadow_log),
+            tick_cycles: std::mem::take(&mut logbook.tick_cycles),
+            last_write_cycles: std::mem::take(&mut logbook.last_write_cycles),
+            stack_depths: std::mem::take(&mut logbook.stack_depths),
+            exit_banks: std::mem::take(&mut logbook.exit_banks),
+            debug_markers: std::mem::take(&mut logbook.debug_markers),
+            length_expiries: std::mem::take(&mut logbook.length_expiries),
+            ..Default::default()
+        }
+    }
+
+    /// Calls PLAY once, and returns which tick that was.
+    fn play(&mut self) -> Result<u64, Error> {
+        check_deadline(self.params)?;
+        crate::bug_report::set_phase(crate::bug_report::Phase::Play);
+        self.logger.borrow_mut().next_tick();
+        let tick = self.logger.borrow().tick;
+        let cycles_per_tick = self.gbs.cadence().tick_cycles(tick);
+        crate::bug_report::set_tick(tick);
+        self.logger
+            .borrow_mut()
+            .trace_header(TraceEvent::Tick(tick));
+
+        self.cpu.sp = self.gbs.stack_ptr();
+        self.cpu.address_space.assume_return_addr(self.cpu.sp);
+        self.cpu.pc = self.gbs.addr(AddressKind::Play);
+        let run = run_func(
+            &mut self.cpu,
+            &self.logger,
+            self.params,
+            &self.hooks,
+            Some(cycles_per_tick),
+        )?;
+        self.logger
+            .borrow_mut()
+            .end_tick(run.cycles, run.stack_depth);
+        self.cpu.address_space.end_tick();
+
+        if let Some(overrun_pc) = run.overrun_pc {
+            self.logger.borrow_mut().diagnose(
+                DiagnosticLevel::Warning,
+                DiagnosticKind::TooLong(
+                    run.cycles,
+                    cycles_per_tick,
+                    overrun_pc,
+                    BankCycles::top(&run.bank_cycles),
+                ),
+            );
+        } else {
+            // TODO: tick DIV etc.
+        }
+        Ok(tick)
+    }
+
+    /// Collects the results; the song is considered to have timed out if it isn't over yet.
+    /// The current SRAM contents.
+    pub fn sram(&self) -> &Sram {
+        self.cpu.address_space.sram()
+    }
+
+    pub fn finish(self) -> Logbook {
+        let mut logbook = std::mem::take(&mut self.logger.borrow_mut().logbook);
+        logbook.ticks_simulated = self.logger.borrow().tick;
+        logbook.termination = self.termination.unwrap_or(Termination::Timeout);
+        if let Some(stall) =
+            self.stalls
+                .finish(logbook.ticks_simulated, logbook.termination, self.params)
+        {
+            insert_diagnostic(&mut logbook, stall);
+        }
+        logbook
+    }
+}
+
+/// Keeps the diagnostics in chronological order.
+fn insert_diagnostic(logbook: &mut Logbook, diag: Diagnostic<DiagnosticKind>) {
+    let i = logbook
+        .diagnostics
+        .partition_point(|other| other.when.tick < diag.when.tick);
+    logbook.diagnostics.insert(i, diag);
+}
+
+/// Reports stretches of PLAY ticks without any APU writes, once PLAY has written to the APU at least
+/// once (so that silent intros don't count); each is reported once it ends.
+///
+/// A stretch that ends the song through the silence timeout is only a note, since songs that end
+/// normally look the same.
+#[derive(Debug, Default)]
+struct StallTracker {
+    /// The last audio write made by PLAY, if any.
+    last_write: Option<(u64, Address)>,
+}
+
+impl StallTracker {
+    /// `writes` are those of PLAY tick `tick`.
+    fn on_tick(
+        &mut self,
+        tick: u64,
+        writes: &[IoAccess],
+        params: &SimParams,
+    ) -> Option<Diagnostic<DiagnosticKind>> {
+        let mut audio_writes = writes
+            .iter()
+            .filter(|access| params.profile.is_audio(access.addr));
+        let first = audio_writes.next()?;
+        let last = audio_writes.next_back().unwrap_or(first);
+        let stall = self.stall(tick, DiagnosticLevel::Warning, params);
+        self.last_write = Some((tick, last.pc.clone()));
+        stall
+    }
+
+    /// `ticks_simulated` PLAY ticks ran in total.
+    fn finish(
+        &self,
+        ticks_simulated: u64,
+        termination: Termination,
+        params: &SimParams,
+    ) -> Option<Diagnostic<DiagnosticKind>> {
+        let level = if termination == Termination::Silence {
+            DiagnosticLevel::Note
+        } else {
+            DiagnosticLevel::Warning
         };
+        self.stall(ticks_simulated + 1, level, params)
     }
 
-    Ok(logbook)
+    /// The stall from the last write up to `next_tick`, if it's long enough.
+    fn stall(
+        &self,
+        next_tick: u64,
+        level: DiagnosticLevel,
+        params: &SimParams,
+    ) -> Option<Diagnostic<DiagnosticKind>> {
+        let (tick, pc) = self.last_write.as_ref()?;
+        let first_silent = tick + 1;
+        if next_tick.saturating_sub(first_silent) <= params.max_stall_ticks {
+            return None;
+        }
+        let kind = DiagnosticKind::AudioStall(first_silent, next_tick - first_silent);
+        let level =
+            promote(&params.promotions, &kind, level).filter(|&level| level <= params.max_level)?;
+        Some(Diagnostic {
+            when: Timestamp {
+                tick: first_silent,
+                cycle: 0,
+            },
+            pc: pc.clone(),
+            level,
+            kind,
+        })
+    }
 }
 
 #[derive(Debug, Default)]
-pub(crate) struct Logbook {
+pub struct Logbook {
     pub diagnostics: Vec<Diagnostic<DiagnosticKind>>,
+    /// How many diagnostics of each kind and level were dropped past
+    /// [`SimParams::max_recorded_diagnostics`], per tick (in order, and only those with any).
+    pub unrecorded: BTreeMap<(&'static str, DiagnosticLevel), Vec<(u64, usize)>>,
     pub io_log: Vec<IoAccess>,
+    /// The values returned by I/O register reads, if [`SimParams::log_reads`] is set.
+    pub read_log: Vec<IoAccess>,
+    /// Writes to the RAM copies of audio registers given by [`SimParams::shadow_addrs`].
+    pub shadow_log: Vec<IoAccess>,
+    /// How many times wave RAM was read while CH3 was playing, whose result may not match hardware.
+    pub stale_wave_reads: usize,
+    /// How many cycles each tick took, indexed by tick (so the first entry is INIT's).
+    pub tick_cycles: Vec<u32>,
+    /// The cycle of each tick's last I/O write (if any), indexed by tick.
+    pub last_write_cycles: Vec<Option<u32>>,
+    /// How many bytes of stack each tick used at most, indexed by tick.
+    pub stack_depths: Vec<u16>,
+    /// Which ROM bank was mapped when each tick's function returned, indexed by tick.
+    pub exit_banks: Vec<u8>,
+    /// Only recorded with [`DebugMarkers::Anchor`].
+    pub debug_markers: Vec<DebugMarker>,
+    /// The end of each tick at which a channel was cut by its length timer, and the channel
+    /// (0-based), in order.
+    pub length_expiries: Vec<(u64, u8)>,
+    /// How many PLAY ticks ran before the song was considered over.
+    pub ticks_simulated: u64,
+    /// How many PLAY ticks ran before [`SimParams::wait_for`] held; they are not part of the
+    /// others.
+    pub warmup_ticks: u64,
+    /// The first tick that was actually simulated, which is past 0 if the song was resumed from a
+    /// snapshot; the per-tick vectors are zeroed before it.
+    pub first_tick: u64,
+    /// CPU cycles executed by INIT and every PLAY tick.
+    pub total_cycles: u64,
+    pub termination: Termination,
 }
 
-#[derive(Debug, Display)]
-pub(crate) enum DiagnosticKind {
-    #[display("unsupported read from ${0:x}")]
-    UnsupportedRead(Address),
-    #[display("unsupported write of ${1:02x} to ${0:04x}")]
-    UnsupportedWrite(Address, u8),
-    #[display("read from echo RAM at ${0:x}")]
-    EchoRamRead(Address),
-    #[display("write of ${0:02x} to echo RAM at ${1:x}")]
-    EchoRamWrite(Address, u8),
-    #[display("tick took {0} cycles, over the budget of {1} cycles")]
-    TooLong(u16, u16),
+impl Logbook {
+    /// How many diagnostics of each kind and level were dropped during the ticks that `within`
+    /// accepts; kinds without any are left out.
+    pub fn unrecorded_within(
+        &self,
+        within: impl Fn(u64) -> bool,
+    ) -> BTreeMap<(&'static str, DiagnosticLevel), usize> {
+        self.unrecorded
+            .iter()
+            .filter_map(|(&key, ticks)| {
+                let count = ticks
+                    .iter()
+                    .filter(|(tick, _)| within(*tick))
+                    .map(|(_, count)| count)
+                    .sum();
+                (count != 0).then_some((key, count))
+            })
+            .collect()
+    }
+}
+
+/// Why a song was considered over.
+#[derive(Debug, Display, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Termination {
+    #[display("silence")]
+    Silence,
+    #[display("the watched address")]
+    Watch,
+    #[default]
+    #[display("timeout")]
+    Timeout,
+}
+
+/// The ROM banks that an over-budget tick spent the most cycles in, and how many.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BankCycles(pub Vec<(u8, u32)>);
+
+impl BankCycles {
+    /// How many banks are worth listing.
+    const MAX_LEN: usize = 3;
+
+    fn top(bank_cycles: &[u32; 256]) -> Self {
+        let mut banks: Vec<_> = (0..=u8::MAX)
+            .zip(bank_cycles.iter().copied())
+            .filter(|&(_, cycles)| cycles != 0)
+            .collect();
+        // Ties are broken by bank number, to keep the output stable.
+        banks.sort_by_key(|&(bank, cycles)| (std::cmp::Reverse(cycles), bank));
+        banks.truncate(Self::MAX_LEN);
+        Self(banks)
+    }
+}
+
+impl Display for BankCycles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (bank, cycles)) in self.0.iter().enumerate() {
+            let sep = if i == 0 { " (cycles per bank: " } else { ", " };
+            write!(f, "{}${:02x}: {}", sep, bank, cycles)?;
+        }
+        if !self.0.is_empty() {
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Display, Clone, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    #[display("unsupported read from {0}")]
+    UnsupportedRead(Accessed),
+    #[display("unsupported write of ${1:02x} to {0}")]
+    UnsupportedWrite(Accessed, u8),
+    #[display("read from {0}")]
+    EchoRamRead(Accessed),
+    #[display("write of ${1:02x} to {0}")]
+    EchoRamWrite(Accessed, u8),
+    #[display("tick took {0} cycles, over the budget of {1} cycles; budget exceeded while executing ${2:x}{3}")]
+    TooLong(u32, u32, Address, BankCycles),
     #[display("executed a debug opcode at ${0:x}")]
     DebugOp(Address),
+    #[display("switched to ROM bank ${0:02x}, but the file only contains {1} banks")]
+    BankOutOfRange(u8, usize),
+    #[display("more than {0} ROM bank switches in a single tick, not reporting any more of them")]
+    BankSwitchFlood(u32),
+    #[display("executing code from ${0:x}")]
+    HramExecution(Address),
+    #[display("read from {0} while CH3 is playing; hardware would not return the stored byte")]
+    StaleWaveRead(Accessed),
+    #[display(
+        "PCM{0} read: returning approximated value (the initial volume of each channel that is on)"
+    )]
+    ApproximatePcmRead(u8),
+    #[display("read from {0}, which is not emulated; returning ${1:02x} (see --stub-reg)")]
+    StubbedRead(Accessed, u8),
+    #[display("driver stopped writing audio registers at tick {0}, for {1} ticks")]
+    AudioStall(u64, u64),
+    #[display("APU powered off mid-song at tick {0}")]
+    ApuPoweredOff(u64),
+    /// Only reported for the first write to each such register in a song.
+    #[display(
+        "write of ${1:02x} to {0}, a CGB or boot ROM register that GBS players may not support"
+    )]
+    InertRegWrite(Accessed, u8),
+    /// Only reported for the first read of each byte in a song. Unlike the other accesses, this
+    /// one is an [`Address`], since the read may be from WRAMX, whose bank then matters.
+    #[display("read from ${0:x} before anything was written there; it would contain garbage on hardware (see --no-uninit-check)")]
+    UninitializedRead(Address),
+    #[display("watched write of ${1:02x} to {0}{2}")]
+    WatchedWrite(Accessed, u8, RecentPcs),
+    #[display("watched read of ${1:02x} from {0}{2}")]
+    WatchedRead(Accessed, u8, RecentPcs),
+}
+
+/// An address being accessed, as opposed to one that code runs from (the diagnostic's PC already
+/// says where the access was made from). I/O registers are printed by name, and other addresses
+/// along with their memory region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Accessed(pub u16);
+
+impl Display for Accessed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match Region::of(self.0) {
+            Region::Io => write!(f, "{}", RegDispl(self.0)),
+            region => write!(f, "${:04x} ({})", self.0, region.name()),
+        }
+    }
+}
+
+/// The instructions executed (within the same INIT or PLAY call) before a watchpoint was hit,
+/// oldest first; at most [`PC_HISTORY_LEN`] of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecentPcs(pub Vec<Address>);
+
+impl Display for RecentPcs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "\n    after executing:")?;
+        if self.0.is_empty() {
+            write!(f, " nothing (first instruction)")?;
+        }
+        for pc in &self.0 {
+            write!(f, " ${:x}", pc)?;
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-/// Currently only writes, but reads may also be interesting in the future
-pub(crate) struct IoAccess {
+/// A debug opcode being executed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugMarker {
+    pub when: Timestamp,
+    pub pc: Address,
+}
+
+impl DiagnosticKind {
+    /// The names by which `--promote` refers to each kind, in declaration order.
+    pub const NAMES: [&'static str; 18] = [
+        "unsupported-read",
+        "unsupported-write",
+        "echo-ram-read",
+        "echo-ram-write",
+        "too-long",
+        "debug-op",
+        "bank-out-of-range",
+        "bank-switch-flood",
+        "hram-execution",
+        "stale-wave-read",
+        "approximate-pcm-read",
+        "stubbed-read",
+        "audio-stall",
+        "apu-powered-off",
+        "inert-reg-write",
+        "uninitialized-read",
+        "watched-write",
+        "watched-read",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        Self::NAMES[match self {
+            Self::UnsupportedRead(..) => 0,
+            Self::UnsupportedWrite(..) => 1,
+            Self::EchoRamRead(..) => 2,
+            Self::EchoRamWrite(..) => 3,
+            Self::TooLong(..) => 4,
+            Self::DebugOp(..) => 5,
+            Self::BankOutOfRange(..) => 6,
+            Self::BankSwitchFlood(..) => 7,
+            Self::HramExecution(..) => 8,
+            Self::StaleWaveRead(..) => 9,
+            Self::ApproximatePcmRead(..) => 10,
+            Self::StubbedRead(..) => 11,
+            Self::AudioStall(..) => 12,
+            Self::ApuPoweredOff(..) => 13,
+            Self::InertRegWrite(..) => 14,
+            Self::UninitializedRead(..) => 15,
+            Self::WatchedWrite(..) => 16,
+            Self::WatchedRead(..) => 17,
+        }]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A write, or in the read log, a read
+pub struct IoAccess {
     pub when: Timestamp,
     pub pc: Address,
     pub addr: u16,
     pub data: u8,
 }
 
+/// Returns the part of a (chronologically sorted) log that belongs to the given ticks.
+pub(crate) fn slice_ticks<'a>(io_log: &'a [IoAccess], ticks: &Range<u64>) -> &'a [IoAccess] {
+    let start = io_log.partition_point(|access| access.when.tick < ticks.start);
+    let end = io_log.partition_point(|access| access.when.tick < ticks.end);
+    &io_log[start..end.max(start)]
+}
+
+/// Converts a log's timestamps from one tick length to another, going through absolute cycle counts
+/// (`tick * cycles_per_tick + cycle`).
+pub(crate) fn rebase_ticks(io_log: &[IoAccess], from_cycles: u32, to_cycles: u32) -> Vec<IoAccess> {
+    io_log
+        .iter()
+        .map(|access| {
+            let absolute = access.when.tick * u64::from(from_cycles) + u64::from(access.when.cycle);
+            IoAccess {
+                when: Timestamp {
+                    tick: absolute / u64::from(to_cycles),
+                    // This is always less than `to_cycles`, which is a `u32`.
+                    cycle: (absolute % u64::from(to_cycles)) as u32,
+                },
+                ..access.clone()
+            }
+        })
+        .collect()
+}
+
 #[derive(Debug, Display)]
 /// Errors that immediately stop the execution.
-pub(crate) enum Error {
+pub enum Error {
     #[display("executed a `halt` at ${0:x}")]
     Halted(Address),
     #[display("executed a `stop` at ${0:x}")]
@@ -143,56 +1248,194 @@ pub(crate) enum Error {
     PoppedTooDeep(u16, u16),
     #[display("CPU seemingly locked up at ${0:x}")]
     LockedUp(Address),
+    #[display("stuck in an infinite loop at ${0:x}, which nothing can break out of")]
+    InfiniteLoop(Address),
     #[display("timed out")]
     Timeout,
+    #[display("timed out waiting for ${0:04x} to become ${1:02x} (--wait-for)")]
+    WaitTimeout(u16, u8),
+    #[display("ran out of real time (--wall-timeout)")]
+    WallClockTimeout,
     #[display("execution has gone haywire: PC = ${0:x}")]
     PcHaywire(Address),
     #[display("stack has gone haywire: SP = ${0:x} (PC = ${1:x})")]
     SpHaywire(Address, Address),
 }
 
+/// An [`Error`], and whether it happened during INIT.
+#[derive(Debug, Display)]
+#[display("{error}")]
+pub struct Failure {
+    pub error: Error,
+    /// If INIT is what failed, how many cycles it had run for.
+    pub init_cycles: Option<u32>,
+}
+
+impl From<Error> for Failure {
+    fn from(error: Error) -> Self {
+        Self {
+            error,
+            init_cycles: None,
+        }
+    }
+}
+
+impl Failure {
+    /// Whether INIT failed in a way that suggests it was given a song ID past the end of the
+    /// driver's song table, within `max_cycles`.
+    pub fn is_early_init_crash(&self, max_cycles: u32) -> bool {
+        matches!(
+            self.error,
+            Error::PcHaywire(_) | Error::SpHaywire(..) | Error::InvalidOpcode(..)
+        ) && self.init_cycles.is_some_and(|cycles| cycles <= max_cycles)
+    }
+}
+
+/// What running a function to completion measured.
+struct FuncRun {
+    cycles: u32,
+    stack_depth: u16,
+    /// The instruction during which the budget was exceeded, if it was.
+    overrun_pc: Option<Address>,
+    /// Cycles spent in each ROM bank; code outside of ROMX counts as bank 0.
+    bank_cycles: [u32; 256],
+}
+
+/// How many instructions [`run_func`] runs between checks of the deadline, since reading the clock
+/// is slow compared to simulating an instruction.
+const DEADLINE_CHECK_INTERVAL: usize = 4096;
+
+fn check_deadline(params: &SimParams) -> Result<(), Error> {
+    match params.deadline {
+        Some(deadline) if Instant::now() >= deadline => Err(Error::WallClockTimeout),
+        _ => Ok(()),
+    }
+}
+
+/// How many instructions back [`run_func`] looks for a repeated CPU state; this catches `jr @`,
+/// two-instruction ping-pongs, and other tight loops.
+const SPIN_WINDOW: usize = 8;
+
+/// How many of the last instructions [`RecentPcs`] lists.
+const PC_HISTORY_LEN: usize = 8;
+
+/// Everything that determines what the CPU does next, save for memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SpinState {
+    pc: Address,
+    regs: [u8; 8],
+    sp: u16,
+    /// [`LogbookWriter::side_effects`]
+    side_effects: u64,
+}
+
 /// Run the CPU simulator until a `ret` is executed.
 ///
 /// The function will also return if the pseudo-return-address is popped, or if the stack appears to become less deep than on entry; this is considered an error.
 ///
 /// Note that this function returns *after* the `ret` is executed.
-fn run_func<S: AddressSpace, T: Write>(
+///
+/// Returns how many cycles the function took, and how deep below the initial SP the stack went.
+/// (No return address is pushed before the call, so it isn't counted either.)
+///
+/// If the CPU state repeats within a few instructions without any memory write or I/O read in
+/// between, nothing can break out of the loop (there are no interrupts), so this errors out
+/// immediately instead of waiting for `max_func_cycles` to run out.
+fn run_func<S: AddressSpace>(
     cpu: &mut State<S>,
-    mut trace_file: Option<T>,
     logger: &RefCell<LogbookWriter>,
-) -> Result<u16, Error> {
-    let mut total_cycles = 0u16;
+    params: &SimParams,
+    hooks: &RefCell<Hooks>,
+    budget: Option<u32>,
+) -> Result<FuncRun, Error> {
+    let mut total_cycles = 0u32;
+    let mut overrun_pc = None;
+    let mut bank_cycles = [0u32; 256];
 
     let orig_sp = cpu.sp;
+    let mut min_sp = orig_sp;
+    let mut in_hram = false;
+    let mut recent_states: [Option<SpinState>; SPIN_WINDOW] = Default::default();
+    let mut nb_instructions = 0usize;
+    // Whatever ran before belongs to another call.
+    logger.borrow_mut().recent_pcs.clear();
     // SP in ROM does not make sense
     while cpu.sp >= 0x8000 && cpu.sp <= orig_sp {
-        let prev_pc = Address(logger.borrow().rom_bank, cpu.pc);
+        let prev_pc = logger.borrow().bank_addr(cpu.pc);
+        let prev_sp = cpu.sp;
+        // Anything pushed is the stack's state before some later instruction, possibly the `ret`.
+        min_sp = min_sp.min(cpu.sp);
         logger.borrow_mut().pc = cpu.pc;
 
         // Check that the state is valid
         if (0xFF00..=0xFF7F).contains(&cpu.pc) {
             return Err(Error::PcHaywire(prev_pc));
         }
-        if (0xFF00..=0xFF7F).contains(&cpu.sp) {
-            return Err(Error::SpHaywire(Address(prev_pc.0, cpu.sp), prev_pc));
+        // Some drivers copy a small routine to HRAM; that's fine, but worth knowing about.
+        let was_in_hram = std::mem::replace(&mut in_hram, (0xFF80..=0xFFFE).contains(&cpu.pc));
+        if in_hram && !was_in_hram {
+            logger.borrow_mut().diagnose(
+                DiagnosticLevel::Note,
+                DiagnosticKind::HramExecution(prev_pc.clone()),
+            );
         }
 
-        if let Some(ref mut trace_file) = trace_file {
-            writeln!(trace_file, "pc=${:04x} b=${:02x} c=${:02x} d=${:02x} e=${:02x} h=${:02x} l=${:02x} a=${:02x} f={}{}{}{} sp=${:04x}",
-                cpu.pc, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l, cpu.a,
-                if cpu.f.get_z() { "Z" } else {"z"},
-                if cpu.f.get_n() { "N" } else {"n"},
-                if cpu.f.get_h() { "H" } else {"h"},
-                if cpu.f.get_c() { "C" } else {"c"},
-                cpu.sp).unwrap_or_else(crate::trace_write_fail);
+        {
+            let mut logger = logger.borrow_mut();
+            let cycle = logger.cycle;
+            logger.trace_cpu(TraceEvent::Cpu {
+                pc: cpu.pc,
+                sp: cpu.sp,
+                a: cpu.a,
+                f: cpu.f.value,
+                bc: u16::from_be_bytes([cpu.b, cpu.c]),
+                de: u16::from_be_bytes([cpu.d, cpu.e]),
+                hl: u16::from_be_bytes([cpu.h, cpu.l]),
+                cycle,
+            });
         }
+        hooks.borrow_mut().on_instruction(&prev_pc);
 
+        let state = SpinState {
+            pc: prev_pc.clone(),
+            regs: [cpu.a, cpu.f.value, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l],
+            sp: cpu.sp,
+            side_effects: logger.borrow().side_effects,
+        };
+        if recent_states.contains(&Some(state.clone())) {
+            return Err(Error::InfiniteLoop(prev_pc));
+        }
+        recent_states[nb_instructions % SPIN_WINDOW] = Some(state);
+        if nb_instructions % DEADLINE_CHECK_INTERVAL == 0 {
+            check_deadline(params)?;
+        }
+        nb_instructions += 1;
+
+        let nb_accesses = {
+            let logbook = &logger.borrow().logbook;
+            (
+                logbook.io_log.len(),
+                logbook.read_log.len(),
+                logbook.shadow_log.len(),
+            )
+        };
         match cpu.tick() {
             TickResult::Ok => (), // The easy case, just keep trying
-            TickResult::Debug | TickResult::Break => logger.borrow_mut().diagnose(
-                DiagnosticLevel::Note,
-                DiagnosticKind::DebugOp(prev_pc.clone()),
-            ),
+            TickResult::Debug | TickResult::Break => match params.debug_markers {
+                DebugMarkers::Anchor => {
+                    let mut logger = logger.borrow_mut();
+                    let when = logger.now();
+                    logger.logbook.debug_markers.push(DebugMarker {
+                        when,
+                        pc: prev_pc.clone(),
+                    });
+                }
+                DebugMarkers::Note => logger.borrow_mut().diagnose(
+                    DiagnosticLevel::Note,
+                    DiagnosticKind::DebugOp(prev_pc.clone()),
+                ),
+                DebugMarkers::Ignore => {}
+            },
             TickResult::Halt => return Err(Error::Halted(prev_pc)),
             TickResult::Stop => return Err(Error::Stopped(prev_pc)),
             TickResult::InvalidOpcode => {
@@ -200,42 +1443,152 @@ fn run_func<S: AddressSpace, T: Write>(
             }
         }
 
-        let elapsed = cpu.cycles_elapsed.try_into().unwrap();
-        total_cycles = total_cycles
-            .checked_add(elapsed)
-            .ok_or(Error::LockedUp(prev_pc))?;
-        logger.borrow_mut().cycle += elapsed;
+        // SP may transiently point to I/O space (e.g. through `add sp`); only the stack actually
+        // being accessed there is a problem.
+        let stack_access = if cpu.sp == prev_sp.wrapping_sub(2) {
+            Some(cpu.sp) // Push, `call`, or `rst`
+        } else if cpu.sp == prev_sp.wrapping_add(2) {
+            Some(prev_sp) // Pop, or `ret`
+        } else {
+            None
+        };
+        if let Some(addr) = stack_access {
+            if (0xFF00..=0xFF7F).contains(&addr)
+                || (0xFF00..=0xFF7F).contains(&addr.wrapping_add(1))
+            {
+                // `add sp, 2`, `add sp, -2`, and `ld sp, hl` may also do this, without accessing memory.
+                if !matches!(cpu.read(prev_pc.1), 0xE8 | 0xF9) {
+                    return Err(Error::SpHaywire(Address(prev_pc.0, addr), prev_pc));
+                }
+            }
+        }
+
+        let elapsed: u16 = cpu.cycles_elapsed.try_into().unwrap();
+        let prev_total = total_cycles;
+        total_cycles = total_cycles.saturating_add(elapsed.into());
+        if total_cycles > params.max_func_cycles {
+            return Err(Error::LockedUp(prev_pc));
+        }
+        let bank = &mut bank_cycles[usize::from(prev_pc.canonical().0)];
+        *bank = bank.saturating_add(elapsed.into());
+        if overrun_pc.is_none() && budget.is_some_and(|budget| total_cycles > budget) {
+            overrun_pc = Some(prev_pc.clone());
+        }
+        // Long INIT routines (clearing RAM, decompressing...) may take a while to simulate.
+        if params.show_progress
+            && total_cycles / crate::CYCLES_PER_SEC != prev_total / crate::CYCLES_PER_SEC
+        {
+            let tick = logger.borrow().tick;
+            eprintln!(
+                "...{} has been running for {}s of simulated time (PC = ${:x})",
+                if tick == 0 {
+                    "INIT".to_string()
+                } else {
+                    format!("PLAY (tick {})", tick)
+                },
+                total_cycles / crate::CYCLES_PER_SEC,
+                prev_pc,
+            );
+        }
+        // `max_func_cycles` stops the function long before this could saturate.
+        let mut logger = logger.borrow_mut();
+        logger.settle_accesses(nb_accesses, elapsed);
+        logger.cycle = logger.cycle.saturating_add(elapsed.into());
+        logger.executed(prev_pc);
         cpu.cycles_elapsed = 0;
     }
 
     if cpu.sp == orig_sp.wrapping_add(2) {
-        Ok(total_cycles)
+        logger.borrow_mut().logbook.total_cycles += u64::from(total_cycles);
+        Ok(FuncRun {
+            cycles: total_cycles,
+            stack_depth: orig_sp - min_sp,
+            overrun_pc,
+            bank_cycles,
+        })
     } else {
         Err(Error::PoppedTooDeep(cpu.sp, orig_sp))
     }
 }
 
-#[derive(Debug)]
 struct LogbookWriter<'a> {
-    logbook: &'a mut Logbook,
+    logbook: Logbook,
     max_level: DiagnosticLevel,
+    max_recorded: usize,
+    promotions: &'a [Promotion],
+    trace: Option<TraceWriter<'a>>,
+    trace_filter: TraceFilter,
+    log_reads: bool,
 
     rom_bank: u8, // This is the canonical copy, and yes that's ugly af.
+    /// Unlike `rom_bank`, a mirror of SVBK, only used to label WRAMX addresses.
+    wram_bank: u8,
     pc: u16,
     tick: u64,
-    cycle: u16,
+    cycle: u32,
+    /// Bumped by every memory write and I/O read, i.e. whatever may make a loop behave differently
+    /// from one iteration to the next.
+    side_effects: u64,
+    /// The last instructions of the current call, for [`RecentPcs`]; the one being executed is
+    /// only added once it's done.
+    recent_pcs: VecDeque<Address>,
+}
+
+impl std::fmt::Debug for LogbookWriter<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LogbookWriter")
+            .field("logbook", &self.logbook)
+            .field("max_level", &self.max_level)
+            .field("max_recorded", &self.max_recorded)
+            .field("promotions", &self.promotions)
+            .field("trace_filter", &self.trace_filter)
+            .field("log_reads", &self.log_reads)
+            .field("rom_bank", &self.rom_bank)
+            .field("wram_bank", &self.wram_bank)
+            .field("pc", &self.pc)
+            .field("tick", &self.tick)
+            .field("cycle", &self.cycle)
+            .field("side_effects", &self.side_effects)
+            .field("recent_pcs", &self.recent_pcs)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The trace is buffered, so it must be flushed by the end of each song at the latest, in case the
+/// process exits afterwards.
+impl Drop for LogbookWriter<'_> {
+    fn drop(&mut self) {
+        if let Some(trace) = self.trace.as_mut() {
+            trace.flush().unwrap_or_else(crate::trace_write_fail);
+        }
+    }
 }
 
 impl<'a> LogbookWriter<'a> {
-    fn new(logbook: &'a mut Logbook, max_level: DiagnosticLevel) -> Self {
+    fn new(
+        max_level: DiagnosticLevel,
+        max_recorded: usize,
+        promotions: &'a [Promotion],
+        trace: Option<TraceWriter<'a>>,
+        trace_filter: TraceFilter,
+        log_reads: bool,
+    ) -> Self {
         Self {
-            logbook,
+            logbook: Logbook::default(),
             max_level,
+            max_recorded,
+            promotions,
+            trace,
+            trace_filter,
+            log_reads,
 
             rom_bank: 1,
+            wram_bank: 1,
             pc: 0,
             tick: 0,
             cycle: 0,
+            side_effects: 0,
+            recent_pcs: VecDeque::with_capacity(PC_HISTORY_LEN),
         }
     }
 
@@ -244,6 +1597,51 @@ impl<'a> LogbookWriter<'a> {
         self.cycle = 0;
     }
 
+    /// Records the per-tick bookkeeping once the tick's function has returned.
+    fn end_tick(&mut self, cycles: u32, stack_depth: u16) {
+        let last_write = self
+            .logbook
+            .io_log
+            .last()
+            .filter(|access| access.when.tick == self.tick)
+            .map(|access| access.when.cycle);
+        self.logbook.tick_cycles.push(cycles);
+        self.logbook.last_write_cycles.push(last_write);
+        self.logbook.stack_depths.push(stack_depth);
+        self.logbook.exit_banks.push(self.rom_bank);
+    }
+
+    /// Accesses are logged while their instruction runs, thus with the cycle it started on; once
+    /// its length is known, this moves those logged since the logs were `nb_accesses` long to the
+    /// cycles they land on.
+    ///
+    /// Every instruction writes on its last cycles, one write per cycle (e.g. `ldh [$12], a` writes
+    /// on its 3rd cycle, but `ld [$ff12], a` on its 4th), so that writes are compared by when they
+    /// actually take effect, and not by when the instructions before them happened to end. Reads
+    /// are placed the same way, which is only approximate for the few instructions that do
+    /// something after reading (`ret`, `inc [hl]`...).
+    fn settle_accesses(&mut self, nb_accesses: (usize, usize, usize), elapsed: u16) {
+        let logbook = &mut self.logbook;
+        for accesses in [
+            &mut logbook.io_log[nb_accesses.0..],
+            &mut logbook.read_log[nb_accesses.1..],
+            &mut logbook.shadow_log[nb_accesses.2..],
+        ] {
+            let nb_new = accesses.len() as u32;
+            for (i, access) in (0..).zip(accesses.iter_mut()) {
+                access.when.cycle += u32::from(elapsed).saturating_sub(nb_new - i);
+            }
+        }
+    }
+
+    /// `addr`, with whichever bank is currently mapped in its region.
+    fn bank_addr(&self, addr: u16) -> Address {
+        match addr {
+            0xD000..=0xDFFF => Address(self.wram_bank, addr),
+            _ => Address(self.rom_bank, addr),
+        }
+    }
+
     fn now(&self) -> Timestamp {
         Timestamp {
             tick: self.tick,
@@ -254,20 +1652,1103 @@ impl<'a> LogbookWriter<'a> {
     fn log(&mut self, addr: u16, data: u8) {
         self.logbook.io_log.push(IoAccess {
             when: self.now(),
-            pc: Address(self.rom_bank, self.pc),
+            pc: self.bank_addr(self.pc),
             addr,
             data,
         })
     }
 
+    fn log_shadow(&mut self, addr: u16, data: u8) {
+        self.logbook.shadow_log.push(IoAccess {
+            when: self.now(),
+            pc: self.bank_addr(self.pc),
+            addr,
+            data,
+        })
+    }
+
+    fn log_read(&mut self, addr: u16, data: u8) {
+        self.side_effects += 1;
+        if !self.log_reads {
+            return;
+        }
+        self.logbook.read_log.push(IoAccess {
+            when: self.now(),
+            pc: self.bank_addr(self.pc),
+            addr,
+            data,
+        })
+    }
+
+    fn executed(&mut self, pc: Address) {
+        if self.recent_pcs.len() == PC_HISTORY_LEN {
+            self.recent_pcs.pop_front();
+        }
+        self.recent_pcs.push_back(pc);
+    }
+
+    fn recent_pcs(&self) -> RecentPcs {
+        RecentPcs(self.recent_pcs.iter().cloned().collect())
+    }
+
+    fn trace(&mut self, event: TraceEvent) {
+        if let Some(trace) = self.trace.as_mut() {
+            trace.write(&event).unwrap_or_else(crate::trace_write_fail);
+        }
+    }
+
+    /// Song and tick separators are always written, so that any kind of trace can be navigated.
+    fn trace_header(&mut self, event: TraceEvent) {
+        self.trace(event);
+    }
+
+    fn trace_cpu(&mut self, event: TraceEvent) {
+        if self.trace_filter == TraceFilter::All {
+            self.trace(event);
+        }
+    }
+
+    fn trace_io(&mut self, event: TraceEvent) {
+        self.trace(event);
+    }
+
     fn diagnose(&mut self, level: DiagnosticLevel, kind: DiagnosticKind) {
-        if level <= self.max_level {
+        let Some(level) = promote(self.promotions, &kind, level) else {
+            return;
+        };
+        if level > self.max_level {
+            return;
+        }
+        if self.logbook.diagnostics.len() >= self.max_recorded {
+            let tick = self.now().tick;
+            let ticks = self
+                .logbook
+                .unrecorded
+                .entry((kind.name(), level))
+                .or_default();
+            match ticks.last_mut() {
+                Some((last, count)) if *last == tick => *count += 1,
+                _ => ticks.push((tick, 1)),
+            }
+        } else {
             self.logbook.diagnostics.push(Diagnostic {
                 when: self.now(),
-                pc: Address(self.rom_bank, self.pc),
+                pc: self.bank_addr(self.pc),
                 level,
                 kind,
             });
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gbs::{Code, Gbs, GbsBuilder};
+
+    #[test]
+    fn diagnostics_past_the_cap_are_counted() {
+        // Each tick writes to echo RAM 8 times and reads it back 4 times, which are all notes.
+        let mut play = Code::default();
+        for i in 0..8 {
+            play = play.ld_a(i).raw(&[0xEA, i, 0xE0]); // `ld [$e0xx], a`
+        }
+        for _ in 0..4 {
+            play = play.raw(&[0xFA, 0x00, 0xE0]); // `ld a, [$e000]`
+        }
+        // Out of the way of IE, whose reads would be diagnosed too.
+        let data = GbsBuilder::default()
+            .stack_ptr(0xDFFE)
+            .play(play.ret())
+            .build();
+        let gbs = Gbs::new(&data).unwrap();
+        let simulate = |max_recorded_diagnostics| {
+            let mut params = SimParams::new(gbs.cycles_per_tick() * 50);
+            params.max_level = DiagnosticLevel::Note;
+            params.max_recorded_diagnostics = max_recorded_diagnostics;
+            simulate_song(&gbs, 1, &params, None, None::<std::io::Sink>, None).unwrap()
+        };
+        let (all, capped) = (simulate(usize::MAX), simulate(10));
+        assert!(all.diagnostics.len() >= 40 * 12);
+        assert_eq!(capped.diagnostics.len(), 10);
+        assert_eq!(capped.diagnostics, all.diagnostics[..10]);
+
+        // Together, the recorded and unrecorded diagnostics are all of them, tick by tick.
+        let count = |logbook: &Logbook| {
+            let mut counts = BTreeMap::<_, usize>::new();
+            for diag in &logbook.diagnostics {
+                *counts
+                    .entry((diag.kind.name(), diag.level, diag.when.tick))
+                    .or_default() += 1;
+            }
+            for (&(kind, level), ticks) in &logbook.unrecorded {
+                for &(tick, count) in ticks {
+                    *counts.entry((kind, level, tick)).or_default() += count;
+                }
+            }
+            counts
+        };
+        assert!(all.unrecorded.is_empty());
+        assert_eq!(count(&capped), count(&all));
+        assert_eq!(
+            capped.unrecorded_within(|tick| tick == 3),
+            BTreeMap::from([
+                (("echo-ram-read", DiagnosticLevel::Note), 4),
+                (("echo-ram-write", DiagnosticLevel::Note), 8),
+            ])
+        );
+        assert_eq!(
+            capped.unrecorded_within(|_| true).values().sum::<usize>(),
+            all.diagnostics.len() - 10
+        );
+        assert!(capped.unrecorded_within(|_| false).is_empty());
+    }
+
+    #[test]
+    fn tick_patterns_set_each_ticks_budget() {
+        // About 4 cycles per iteration.
+        let play = Code::default()
+            .raw(&[0x06, 250]) // `ld b, 250`
+            .raw(&[0x05, 0x20, 0xFD]) // `.loop: dec b; jr nz, .loop`
+            .ret();
+        let data = GbsBuilder::default().stack_ptr(0xDFFE).play(play).build();
+        let pattern = [2000, 2000, 800];
+        let gbs = Gbs::new(&data).unwrap().with_tick_pattern(&pattern);
+        let mut params = SimParams::new(2000 * 9);
+        params.max_func_cycles = 10_000;
+        let logbook = simulate_song(&gbs, 1, &params, None, None::<std::io::Sink>, None).unwrap();
+        let too_long: Vec<_> = logbook
+            .diagnostics
+            .iter()
+            .filter_map(|diag| match diag.kind {
+                DiagnosticKind::TooLong(_, budget, ..) => Some((diag.when.tick, budget)),
+                _ => None,
+            })
+            .collect();
+        // Only every third tick is too short for PLAY.
+        assert!(logbook.ticks_simulated >= 6);
+        let expected: Vec<_> = (1..=logbook.ticks_simulated)
+            .filter(|tick| tick % 3 == 0)
+            .map(|tick| (tick, 800))
+            .collect();
+        assert_eq!(too_long, expected);
+    }
+
+    /// Ends the song after a given tick, counting the ticks and writes it sees.
+    #[derive(Default)]
+    struct StopAt {
+        tick: u64,
+        ticks_seen: Vec<u64>,
+        nb_writes: usize,
+    }
+
+    impl SimHooks for StopAt {
+        fn on_tick_end(&mut self, tick: u64, _cpu: &CpuView) -> ControlFlow<Termination> {
+            self.ticks_seen.push(tick);
+            if tick == self.tick {
+                ControlFlow::Break(Termination::Watch)
+            } else {
+                ControlFlow::Continue(())
+            }
+        }
+
+        fn on_write(&mut self, _addr: u16, _data: u8) {
+            self.nb_writes += 1;
+        }
+    }
+
+    #[test]
+    fn breaking_hooks_end_the_song() {
+        let data = GbsBuilder::default()
+            .stack_ptr(0xDFFE)
+            .play(Code::default().write(0xFF12, 0xF0).ret())
+            .build();
+        let gbs = Gbs::new(&data).unwrap();
+        let params = SimParams::new(gbs.cycles_per_tick() * 100);
+        let mut hooks = StopAt {
+            tick: 7,
+            ..Default::default()
+        };
+        let logbook = simulate_song_with_hooks(
+            &gbs,
+            1,
+            &params,
+            None,
+            None::<std::io::Sink>,
+            None,
+            &mut hooks,
+        )
+        .unwrap();
+        assert_eq!(logbook.termination, Termination::Watch);
+        assert_eq!(logbook.ticks_simulated, 7);
+        // INIT is not a tick that can end the song.
+        assert_eq!(hooks.ticks_seen, (1..=7).collect::<Vec<_>>());
+        assert_eq!(hooks.nb_writes, 7);
+        assert_eq!(logbook.io_log.len(), 7);
+
+        // Without breaking, the song runs until the timeout.
+        let mut hooks = StopAt {
+            tick: u64::MAX,
+            ..Default::default()
+        };
+        let logbook = simulate_song_with_hooks(
+            &gbs,
+            1,
+            &params,
+            None,
+            None::<std::io::Sink>,
+            None,
+            &mut hooks,
+        )
+        .unwrap();
+        assert_eq!(logbook.termination, Termination::Timeout);
+        assert!(logbook.ticks_simulated >= 99);
+    }
+
+    #[test]
+    fn hooks_read_memory_at_the_end_of_ticks() {
+        struct Peeks(Vec<u8>);
+        impl SimHooks for Peeks {
+            fn on_tick_end(&mut self, _tick: u64, cpu: &CpuView) -> ControlFlow<Termination> {
+                self.0.push(cpu.read(0xC000));
+                ControlFlow::Continue(())
+            }
+        }
+
+        // `ld hl, $c000; inc [hl]`
+        let play = Code::default().raw(&[0x21, 0x00, 0xC0, 0x34]).ret();
+        let data = GbsBuilder::default()
+            .stack_ptr(0xDFFE)
+            .init(Code::default().ld_a(10).raw(&[0xEA, 0x00, 0xC0]).ret()) // `ld [$c000], a`
+            .play(play)
+            .build();
+        let gbs = Gbs::new(&data).unwrap();
+        let params = SimParams::new(gbs.cycles_per_tick() * 5);
+        let mut hooks = Peeks(Vec::new());
+        simulate_song_with_hooks(
+            &gbs,
+            1,
+            &params,
+            None,
+            None::<std::io::Sink>,
+            None,
+            &mut hooks,
+        )
+        .unwrap();
+        assert_eq!(hooks.0[..4], [11, 12, 13, 14]);
+    }
+
+    /// The writes made by a PLAY routine, over a few ticks.
+    #[test]
+    fn long_inits_keep_their_cycle_count() {
+        let init = Code::default()
+            .raw(&[0x01, 0x00, 0x38]) // `ld bc, $3800`
+            .raw(&[0x0B, 0x78, 0xB1, 0x20, 0xFB]) // `.loop: dec bc; ld a, b; or c; jr nz, .loop`
+            .ret();
+        let data = GbsBuilder::default()
+            .stack_ptr(0xDFFE)
+            .init(init)
+            .play(Code::default().ret())
+            .build();
+        let gbs = Gbs::new(&data).unwrap();
+        let mut params = SimParams::new(200_000);
+        params.max_func_cycles = u32::MAX;
+        let logbook = simulate_song(&gbs, 1, &params, None, None::<std::io::Sink>, None).unwrap();
+        // 7 cycles per iteration, 14336 iterations.
+        assert!(
+            (100_352..100_400).contains(&logbook.tick_cycles[0]),
+            "{}",
+            logbook.tick_cycles[0]
+        );
+        assert!(logbook.tick_cycles[1..].iter().all(|&cycles| cycles < 100));
+    }
+
+    #[test]
+    fn stack_depths_are_measured_per_tick() {
+        let play = |sub: u16| {
+            let [lo, hi] = sub.to_le_bytes();
+            Code::default()
+                .raw(&[0xC5, 0xD5]) // `push bc; push de`
+                .raw(&[0xCD, lo, hi]) // `call sub`
+                .raw(&[0xD1, 0xC1]) // `pop de; pop bc`
+                .ret()
+        };
+        let builder = GbsBuilder::default()
+            .stack_ptr(0xDFFE)
+            .play(play(0))
+            .extra(&[0xE5, 0xE1, 0xC9]); // `push hl; pop hl; ret`
+        let data = builder.clone().play(play(builder.extra_addr())).build();
+        let gbs = Gbs::new(&data).unwrap();
+        let params = SimParams::new(gbs.cycles_per_tick() * 3);
+        let logbook = simulate_song(&gbs, 1, &params, None, None::<std::io::Sink>, None).unwrap();
+        // INIT doesn't touch the stack; PLAY pushes 2 registers, then the call's return address,
+        // and another register within the call.
+        assert_eq!(logbook.stack_depths[0], 0);
+        assert!(logbook.stack_depths[1..].iter().all(|&depth| depth == 8));
+    }
+
+    fn play_writes(play: Code) -> Vec<IoAccess> {
+        let data = GbsBuilder::default()
+            .stack_ptr(0xDFFE)
+            .play(play.ret())
+            .build();
+        let gbs = Gbs::new(&data).unwrap();
+        let params = SimParams::new(gbs.cycles_per_tick() * 3);
+        let logbook = simulate_song(&gbs, 1, &params, None, None::<std::io::Sink>, None).unwrap();
+        logbook.io_log
+    }
+
+    #[test]
+    fn moves_match_cycle_differences() {
+        use crate::diff::{DiagnosticKind, DiffGenerator};
+
+        let ldh = |code: Code| code.ld_a(0xF0).ldh_to(0xFF12);
+        let cases = [
+            // `nop` takes 1 cycle, `ld [$c000], a` takes 4.
+            (
+                ldh(Code::default().raw(&[0x00])),
+                ldh(Code::default().raw(&[0xEA, 0x00, 0xC0])),
+                3,
+            ),
+            // `ldh [$12], a` writes on its 3rd cycle, `ld [$ff12], a` on its 4th.
+            (
+                ldh(Code::default()),
+                Code::default().ld_a(0xF0).raw(&[0xEA, 0x12, 0xFF]),
+                1,
+            ),
+        ];
+        for (i, (before, after, delta)) in cases.into_iter().enumerate() {
+            let logs = (play_writes(before), play_writes(after));
+            let diags: Vec<_> = DiffGenerator::new(&logs.0, &logs.1, 20, false).collect();
+            assert!(!diags.is_empty(), "case {}", i);
+            for diag in &diags {
+                assert!(
+                    matches!(diag.kind, DiagnosticKind::Moved(0xFF12, 0xF0, moved) if moved == delta),
+                    "case {}: {:#?}",
+                    i,
+                    diag
+                );
+            }
+        }
+    }
+
+    /// How a PLAY routine fails, if it does.
+    fn play_error(play: Code) -> Option<Error> {
+        let data = GbsBuilder::default().stack_ptr(0xDFFE).play(play).build();
+        let gbs = Gbs::new(&data).unwrap();
+        let params = SimParams::new(gbs.cycles_per_tick() * 3);
+        simulate_song(&gbs, 1, &params, None, None::<std::io::Sink>, None)
+            .err()
+            .map(|failure| failure.error)
+    }
+
+    #[test]
+    fn spinning_is_caught_early() {
+        // PLAY starts right after INIT's `ret`.
+        const PLAY: u16 = Gbs::MIN_ROM_ADDR + 1;
+
+        // `jr @`
+        let error = play_error(Code::default().raw(&[0x18, 0xFE]));
+        assert!(
+            matches!(error, Some(Error::InfiniteLoop(Address(_, PLAY)))),
+            "{:?}",
+            error
+        );
+        // `.a: jr .b; .b: jr .a`
+        let error = play_error(Code::default().raw(&[0x18, 0x00, 0x18, 0xFC]));
+        assert!(
+            matches!(error, Some(Error::InfiniteLoop(Address(_, PLAY)))),
+            "{:?}",
+            error
+        );
+        // `.loop: swap a; swap a; jr .loop`, whose state only repeats every three instructions.
+        let error = play_error(Code::default().raw(&[0xCB, 0x37, 0xCB, 0x37, 0x18, 0xFA]));
+        assert!(
+            matches!(error, Some(Error::InfiniteLoop(Address(_, PLAY)))),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn changing_loops_are_not_spinning() {
+        // `ld b, 200; .loop: dec b; jr nz, .loop`, whose counter changes.
+        let error = play_error(Code::default().raw(&[0x06, 200, 0x05, 0x20, 0xFD]).ret());
+        assert!(error.is_none(), "{:?}", error);
+        // `ld hl, $c000; .loop: ld [hl], a; jr .loop`, whose writes may change what it reads.
+        let error = play_error(Code::default().raw(&[0x21, 0x00, 0xC0, 0x77, 0x18, 0xFD]));
+        assert!(matches!(error, Some(Error::LockedUp(_))), "{:?}", error);
+        // `.loop: ldh a, [rNR52]; jr .loop`, polling an I/O register.
+        let error = play_error(Code::default().raw(&[0xF0, 0x26, 0x18, 0xFC]));
+        assert!(matches!(error, Some(Error::LockedUp(_))), "{:?}", error);
+    }
+
+    /// Runs `play` with a wall-clock limit of 50 ms, but no limit on simulated time.
+    fn run_against_the_clock(play: Code) -> (Option<Error>, std::time::Duration) {
+        let data = GbsBuilder::default().stack_ptr(0xDFFE).play(play).build();
+        let gbs = Gbs::new(&data).unwrap();
+        let mut params = SimParams::new(u32::MAX);
+        params.max_func_cycles = u32::MAX;
+        let start = Instant::now();
+        params.deadline = Some(start + std::time::Duration::from_millis(50));
+        let result = simulate_song(&gbs, 1, &params, None, None::<std::io::Sink>, None);
+        (result.err().map(|failure| failure.error), start.elapsed())
+    }
+
+    #[test]
+    fn wall_timeouts_abort_songs() {
+        // Many short ticks, each checking the deadline once it's over.
+        let (error, elapsed) = run_against_the_clock(Code::default().write(0xFF12, 0xF0).ret());
+        assert!(
+            matches!(error, Some(Error::WallClockTimeout)),
+            "{:?}",
+            error
+        );
+        assert!(elapsed.as_secs() < 10, "{:?}", elapsed);
+
+        // A single call that would take minutes to run out of cycles: `ld hl, $c000; .loop: ld
+        // [hl], a; jr .loop`.
+        let (error, elapsed) =
+            run_against_the_clock(Code::default().raw(&[0x21, 0x00, 0xC0, 0x77, 0x18, 0xFD]));
+        assert!(
+            matches!(error, Some(Error::WallClockTimeout)),
+            "{:?}",
+            error
+        );
+        assert!(elapsed.as_secs() < 10, "{:?}", elapsed);
+    }
+
+    #[test]
+    fn slicing_ticks_handles_boundaries() {
+        let access = |tick| IoAccess {
+            when: Timestamp { tick, cycle: 0 },
+            pc: Address(0, 0x4000),
+            addr: 0xFF12,
+            data: tick as u8,
+        };
+        // Tick 2 has no writes, tick 3 has two.
+        let log: Vec<_> = [0, 1, 3, 3, 4].into_iter().map(access).collect();
+        let ticks = |ticks: Range<u64>| -> Vec<_> {
+            slice_ticks(&log, &ticks)
+                .iter()
+                .map(|access| access.when.tick)
+                .collect()
+        };
+        assert_eq!(ticks(0..5), [0, 1, 3, 3, 4]);
+        assert_eq!(ticks(1..4), [1, 3, 3]);
+        assert_eq!(ticks(3..4), [3, 3]);
+        assert_eq!(ticks(4..5), [4]);
+        assert_eq!(ticks(2..3), []);
+        assert_eq!(ticks(3..3), []);
+        // Skipping more ticks than were simulated.
+        assert_eq!(ticks(5..u64::MAX), []);
+        assert_eq!(ticks(100..200), []);
+        assert_eq!(ticks(0..u64::MAX), [0, 1, 3, 3, 4]);
+        // Backwards ranges (e.g. `--from` past the end) are empty, rather than panicking.
+        assert_eq!(ticks(Range { start: 4, end: 1 }), []);
+        assert!(slice_ticks(&[], &(0..u64::MAX)).is_empty());
+        assert!(slice_ticks(&[], &Range { start: 5, end: 1 }).is_empty());
+    }
+
+    #[test]
+    fn watchpoints_capture_recent_pcs() {
+        const PLAY: u16 = Gbs::MIN_ROM_ADDR + 1;
+        // `ld [$c000], a` right away, then after 10 `nop`s and `ld a, $42`; then `ld a, [$c000]`.
+        let play = Code::default()
+            .raw(&[0xEA, 0x00, 0xC0])
+            .raw(&[0x00; 10])
+            .ld_a(0x42)
+            .raw(&[0xEA, 0x00, 0xC0, 0xFA, 0x00, 0xC0])
+            .ret();
+        let data = GbsBuilder::default().stack_ptr(0xDFFE).play(play).build();
+        let gbs = Gbs::new(&data).unwrap();
+        let mut params = SimParams::new(gbs.cycles_per_tick() * 3);
+        params.max_level = DiagnosticLevel::Note;
+        params.watchpoints = vec![0xC000];
+        params.read_watchpoints = vec![0xC000];
+        let logbook = simulate_song(&gbs, 1, &params, None, None::<std::io::Sink>, None).unwrap();
+
+        let watched: Vec<_> = logbook
+            .diagnostics
+            .iter()
+            .filter(|diag| diag.when.tick == 1)
+            .filter_map(|diag| match &diag.kind {
+                DiagnosticKind::WatchedWrite(_, data, pcs) => Some((true, *data, pcs)),
+                DiagnosticKind::WatchedRead(_, data, pcs) => Some((false, *data, pcs)),
+                _ => None,
+            })
+            .collect();
+        let pcs = |pcs: &RecentPcs| -> Vec<_> { pcs.0.iter().map(|pc| pc.1).collect() };
+        assert_eq!(watched.len(), 3, "{:#?}", logbook.diagnostics);
+        // Nothing ran before the first instruction, not even the previous call's.
+        assert_eq!((watched[0].0, pcs(watched[0].2)), (true, vec![]));
+        // Only the last instructions are kept, oldest first.
+        let ld_a = PLAY + 3 + 10;
+        let expected: Vec<_> = (ld_a - 7..=ld_a).collect();
+        assert_eq!((watched[1].0, watched[1].1), (true, 0x42));
+        assert_eq!(pcs(watched[1].2), expected);
+        assert_eq!((watched[2].0, watched[2].1), (false, 0x42));
+        assert_eq!(
+            pcs(watched[2].2)[..],
+            [&expected[1..], &[ld_a + 2]].concat()
+        );
+
+        assert_eq!(
+            watched[0].2.to_string(),
+            "\n    after executing: nothing (first instruction)"
+        );
+        assert_eq!(
+            RecentPcs(vec![Address(1, 0x4000), Address(0, 0x0401)]).to_string(),
+            "\n    after executing: $01:4000 $00:0401"
+        );
+    }
+
+    #[test]
+    fn rebased_ticks_keep_absolute_cycles() {
+        let log: Vec<_> = [(0, 5), (1, 0), (1, 17_555), (2, 20_000), (1 << 40, 3)]
+            .iter()
+            .enumerate()
+            .map(|(i, &(tick, cycle))| IoAccess {
+                when: Timestamp { tick, cycle },
+                pc: Address(1, 0x4000 + i as u16),
+                addr: 0xFF12,
+                data: i as u8,
+            })
+            .collect();
+        let when = |log: &[IoAccess]| -> Vec<_> {
+            log.iter()
+                .map(|access| (access.when.tick, access.when.cycle))
+                .collect()
+        };
+
+        // Cycles past the tick's budget (i.e. PLAY overrunning it) carry over into the next one,
+        // even if the length doesn't change.
+        assert_eq!(
+            when(&rebase_ticks(&log, 17_556, 17_556)),
+            [(0, 5), (1, 0), (1, 17_555), (3, 2_444), (1 << 40, 3)]
+        );
+        assert_eq!(
+            when(&rebase_ticks(&log, 17_556, 8_778)),
+            [(0, 5), (2, 0), (3, 8_777), (6, 2_444), (1 << 41, 3)]
+        );
+        assert_eq!(
+            when(&rebase_ticks(&log, 10_000, 30_000)),
+            [
+                (0, 5),
+                (0, 10_000),
+                (0, 27_555),
+                (1, 10_000),
+                (
+                    (10_000 << 40) / 30_000,
+                    ((10_000u64 << 40) % 30_000) as u32 + 3
+                )
+            ]
+        );
+        // Only the timestamps change, and the log stays sorted.
+        let rebased = rebase_ticks(&log, 17_556, 1_000);
+        assert!(rebased.windows(2).all(|pair| pair[0].when < pair[1].when));
+        for (before, after) in log.iter().zip(&rebased) {
+            assert_eq!(
+                (&before.pc, before.addr, before.data),
+                (&after.pc, after.addr, after.data)
+            );
+        }
+    }
+
+    #[test]
+    fn init_regs_parse() {
+        use CpuReg::*;
+
+        let parse = |arg: &str| arg.parse::<InitRegs>();
+        assert_eq!(
+            parse("b=01,c=80,hl=c123"),
+            Ok(InitRegs(vec![(B, 0x01), (C, 0x80), (Hl, 0xC123)]))
+        );
+        // Names are case-insensitive, and assignments are kept in order, even if they overlap.
+        assert_eq!(
+            parse(" A=FF , Af = 1234,a=0"),
+            Ok(InitRegs(vec![(A, 0xFF), (Af, 0x1234), (A, 0x00)]))
+        );
+        assert_eq!(parse("de=0042,f=b0").unwrap().to_string(), "de=0042,f=b0");
+
+        let err = |arg| parse(arg).unwrap_err();
+        assert_eq!(err(""), "expected \"REG=VALUE\", got \"\"");
+        assert_eq!(err("b=01,c"), "expected \"REG=VALUE\", got \"c\"");
+        assert_eq!(
+            err("b=01;c=02"),
+            "invalid value for b: invalid digit found in string"
+        );
+        assert_eq!(err("sp=dffe"), "unknown register \"sp\"");
+        assert_eq!(err("bh=0000"), "unknown register \"bh\"");
+        assert_eq!(
+            err("b=100"),
+            "invalid value for b: number too large to fit in target type"
+        );
+        assert_eq!(
+            err("hl=10000"),
+            "invalid value for hl: number too large to fit in target type"
+        );
+        assert_eq!(
+            err("c=-1"),
+            "invalid value for c: invalid digit found in string"
+        );
+        assert_eq!(
+            err("c="),
+            "invalid value for c: cannot parse integer from empty string"
+        );
+    }
+
+    #[test]
+    fn pokes_and_init_regs_precede_init() {
+        let init = Code::default()
+            .raw(&[0xFA, 0x00, 0xC0]) // `ld a, [$c000]`
+            .ldh_to(0xFF12)
+            .raw(&[0x78]) // `ld a, b`
+            .ldh_to(0xFF13)
+            .raw(&[0x7D]) // `ld a, l`
+            .ldh_to(0xFF14)
+            .ret();
+        let data = GbsBuilder::default()
+            .stack_ptr(0xDFFE)
+            .init(init)
+            .play(Code::default().ret())
+            .build();
+        let gbs = Gbs::new(&data).unwrap();
+        let mut params = SimParams::new(gbs.cycles_per_tick());
+        params.pokes = vec![(0xC000, 0x5A)];
+        params.init_regs = "b=42,hl=1234".parse().unwrap();
+        let logbook = simulate_song(&gbs, 1, &params, None, None::<std::io::Sink>, None).unwrap();
+        let writes: Vec<_> = logbook
+            .io_log
+            .iter()
+            .map(|access| (access.addr, access.data))
+            .collect();
+        assert_eq!(writes, [(0xFF12, 0x5A), (0xFF13, 0x42), (0xFF14, 0x34)]);
+    }
+
+    #[test]
+    fn pcm_reads_follow_the_channels_volumes() {
+        let init = Code::default()
+            .write(0xFF26, 0x80)
+            // CH1 and CH2 triggered with volumes 10 and 5, CH3 at full volume, CH4 never triggered.
+            .write(0xFF12, 0xA0)
+            .write(0xFF14, 0x80)
+            .write(0xFF17, 0x50)
+            .write(0xFF19, 0x80)
+            .write(0xFF1A, 0x80)
+            .write(0xFF1C, 0x20)
+            .write(0xFF1E, 0x80)
+            .raw(&[0xF0, 0x76]) // `ldh a, [rPCM12]`
+            .ldh_to(0xFF13)
+            .raw(&[0xF0, 0x77]) // `ldh a, [rPCM34]`
+            .ldh_to(0xFF18)
+            // Turning CH1's DAC off silences it.
+            .write(0xFF12, 0x00)
+            .raw(&[0xF0, 0x76]) // `ldh a, [rPCM12]`
+            .ldh_to(0xFF1D)
+            .ret();
+        let data = GbsBuilder::default()
+            .stack_ptr(0xDFFE)
+            .init(init)
+            .play(Code::default().ret())
+            .build();
+        let gbs = Gbs::new(&data).unwrap();
+        let params = SimParams::new(gbs.cycles_per_tick() * 3);
+        let logbook = simulate_song(&gbs, 1, &params, None, None::<std::io::Sink>, None).unwrap();
+        let read = |addr| {
+            logbook
+                .io_log
+                .iter()
+                .find(|access| access.addr == addr)
+                .map(|access| access.data)
+        };
+        assert_eq!(read(0xFF13), Some(0x5A));
+        assert_eq!(read(0xFF18), Some(0x0F));
+        assert_eq!(read(0xFF1D), Some(0x50));
+
+        // Each register's approximation is only reported once.
+        let approximated: Vec<_> = logbook
+            .diagnostics
+            .iter()
+            .filter_map(|diag| match diag.kind {
+                DiagnosticKind::ApproximatePcmRead(reg) => Some((reg, diag.level)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            approximated,
+            [
+                (12, DiagnosticLevel::Warning),
+                (34, DiagnosticLevel::Warning)
+            ]
+        );
+    }
+
+    #[test]
+    fn watched_writes_end_the_song_even_if_cleared() {
+        // Where the counter gets written, before being cleared within the same call.
+        let data = |[lo, hi]: [u8; 2]| {
+            let flash = |code: Code| {
+                code.raw(&[0xEA, lo, hi]) // `ld [flag], a`
+                    .raw(&[0xAF, 0xEA, lo, hi]) // `xor a; ld [flag], a`
+                    .ret()
+            };
+            GbsBuilder::default()
+                .stack_ptr(0xDFFE)
+                // INIT writing the watched value doesn't end the song.
+                .init(flash(Code::default().ld_a(3)))
+                // `ld hl, $c000; inc [hl]; ld a, [hl]`
+                .play(flash(Code::default().raw(&[0x21, 0x00, 0xC0, 0x34, 0x7E])))
+                .build()
+        };
+        for flag in [0xC001u16, 0xE001] {
+            let data = data(flag.to_le_bytes());
+            let gbs = Gbs::new(&data).unwrap();
+            let mut params = SimParams::new(gbs.cycles_per_tick() * 20);
+            params.watch = Some((0xC001, 3));
+            let logbook =
+                simulate_song(&gbs, 1, &params, None, None::<std::io::Sink>, None).unwrap();
+            assert_eq!(logbook.termination, Termination::Watch, "${:04x}", flag);
+            assert_eq!(logbook.ticks_simulated, 3, "${:04x}", flag);
+        }
+    }
+
+    #[test]
+    fn stubbed_registers_read_as_configured() {
+        let init = Code::default()
+            .write(0xFF00, 0x20) // Selecting the D-pad.
+            .raw(&[0xF0, 0x00]) // `ldh a, [rP1]`
+            .ldh_to(0xFF13)
+            .raw(&[0xF0, 0x56]) // `ldh a, [rRP]`
+            .ldh_to(0xFF18)
+            .raw(&[0xF0, 0x57]) // `ldh a, [$ff57]`
+            .ldh_to(0xFF1D)
+            .ret();
+        let data = GbsBuilder::default()
+            .stack_ptr(0xDFFE)
+            .init(init)
+            .play(Code::default().raw(&[0xF0, 0x56]).ret()) // `ldh a, [rRP]`
+            .build();
+        let gbs = Gbs::new(&data).unwrap();
+        let mut params = SimParams::new(gbs.cycles_per_tick() * 3);
+        params.max_level = DiagnosticLevel::Note;
+        // Later stubs override earlier ones.
+        params.stub_regs = vec![(0xFF56, 0x01), (0xFF56, 0x3C)];
+        let logbook = simulate_song(&gbs, 1, &params, None, None::<std::io::Sink>, None).unwrap();
+        let written = |addr| {
+            logbook
+                .io_log
+                .iter()
+                .find(|access| access.addr == addr)
+                .map(|access| access.data)
+        };
+        // No buttons are pressed, and the unused bits read as 1.
+        assert_eq!(written(0xFF13), Some(0xEF));
+        assert_eq!(written(0xFF18), Some(0x3C));
+        // Registers that aren't stubbed still read as $FF, with a warning.
+        assert_eq!(written(0xFF1D), Some(0xFF));
+
+        // Each stubbed register is only reported once, even across ticks.
+        let reads: Vec<_> = logbook
+            .diagnostics
+            .iter()
+            .filter_map(|diag| match diag.kind {
+                DiagnosticKind::StubbedRead(Accessed(addr), value) => {
+                    Some((diag.level, addr, value))
+                }
+                DiagnosticKind::UnsupportedRead(Accessed(addr)) => Some((diag.level, addr, 0xFF)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            reads,
+            [
+                (DiagnosticLevel::Note, 0xFF00, 0xEF),
+                (DiagnosticLevel::Note, 0xFF56, 0x3C),
+                (DiagnosticLevel::Warning, 0xFF57, 0xFF),
+            ]
+        );
+    }
+
+    #[test]
+    fn exit_banks_are_recorded_per_tick() {
+        // `ld a, bank; ld [$2000], a`
+        let map = |bank| Code::default().ld_a(bank).raw(&[0xEA, 0x00, 0x20]);
+        let exit_banks = |play: Code| {
+            let data = GbsBuilder::default()
+                .stack_ptr(0xDFFE)
+                .init(map(2).ret())
+                .play(play.ret())
+                .extra(&[0; 0xC000]) // For the ROM to span 4 banks.
+                .build();
+            let gbs = Gbs::new(&data).unwrap();
+            let params = SimParams::new(gbs.cycles_per_tick() * 3);
+            simulate_song(&gbs, 1, &params, None, None::<std::io::Sink>, None)
+                .unwrap()
+                .exit_banks
+        };
+        let restoring = exit_banks(Code::default());
+        assert_eq!(restoring[..4], [2, 2, 2, 2]);
+        let switching = exit_banks(map(3));
+        assert_eq!(switching[..4], [2, 3, 3, 3]);
+        // Banks past the end of the ROM wrap around, like a real MBC's.
+        assert_eq!(exit_banks(map(7))[..2], [2, 3]);
+
+        assert_eq!(
+            crate::diff::compare_exit_banks(&restoring, &switching, |_| true)
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            ["PLAY now returns with bank 3 mapped instead of bank 2 (first differing tick: 1)"]
+        );
+    }
+
+    #[test]
+    fn wram_is_banked_by_svbk() {
+        // `ld a, value; ld [$d000], a`
+        let store = |code: Code, value| code.ld_a(value).raw(&[0xEA, 0x00, 0xD0]);
+        let load = |code: Code, reg| code.raw(&[0xFA, 0x00, 0xD0]).ldh_to(reg); // `ld a, [$d000]`
+        let init = Code::default().write(0xFF4D, 0x01).write(0xFF70, 0x02);
+        let init = store(init, 0x11).write(0xFF70, 0x03);
+        let init = store(init, 0x22).write(0xFF70, 0x00); // Selects bank 1.
+        let init = store(init, 0x33).write(0xFF70, 0x02);
+        let init = load(init, 0xFF13).write(0xFF70, 0x01);
+        let init = load(init, 0xFF18)
+            .raw(&[0xF0, 0x4D]) // `ldh a, [rKEY1]`
+            .ldh_to(0xFF1D)
+            .raw(&[0xF0, 0x70]) // `ldh a, [rSVBK]`
+            .ldh_to(0xFF22)
+            .write(0xFF50, 0x01)
+            .ret();
+        let data = GbsBuilder::default()
+            .stack_ptr(0xDFFE)
+            .init(init)
+            .play(Code::default().ret())
+            .build();
+        let gbs = Gbs::new(&data).unwrap();
+        let mut params = SimParams::new(gbs.cycles_per_tick() * 3);
+        params.max_level = DiagnosticLevel::Note;
+        let logbook = simulate_song(&gbs, 1, &params, None, None::<std::io::Sink>, None).unwrap();
+        let written = |addr| {
+            logbook
+                .io_log
+                .iter()
+                .find(|access| access.addr == addr)
+                .map(|access| access.data)
+        };
+        assert_eq!(written(0xFF13), Some(0x11));
+        assert_eq!(written(0xFF18), Some(0x33));
+        // The speed switch is armed, but never performed; only the selected bank is kept.
+        assert_eq!(written(0xFF1D), Some(0x7F));
+        assert_eq!(written(0xFF22), Some(0xF9));
+
+        // Each register is only reported once, and as a note.
+        let inert: Vec<_> = logbook
+            .diagnostics
+            .iter()
+            .filter_map(|diag| match diag.kind {
+                DiagnosticKind::InertRegWrite(Accessed(addr), data) => {
+                    Some((diag.level, addr, data))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            inert,
+            [
+                (DiagnosticLevel::Note, 0xFF4D, 0x01),
+                (DiagnosticLevel::Note, 0xFF70, 0x02),
+                (DiagnosticLevel::Note, 0xFF50, 0x01),
+            ]
+        );
+    }
+
+    #[test]
+    fn profiles_parse() {
+        assert_eq!("APU".parse(), Ok(Profile::Apu));
+        assert_eq!("apu+Serial".parse(), Ok(Profile::ApuSerial));
+        assert_eq!(
+            "custom:ff01-ff02, $ff4d".parse(),
+            Ok(Profile::Custom(vec![0xFF01..=0xFF02, 0xFF4D..=0xFF4D]))
+        );
+
+        let err = |arg: &str| arg.parse::<Profile>().unwrap_err();
+        assert!(err("serial").starts_with("must be either"));
+        assert!(err("custom").starts_with("must be either"));
+        assert_eq!(
+            err("custom:ff02-ff01"),
+            "\"ff02-ff01\" ends before it starts"
+        );
+        assert_eq!(
+            err("custom:ff00"),
+            "\"ff00\" is not the address of an I/O register between ff01 and ff7f"
+        );
+        assert_eq!(
+            err("custom:ff01,c000"),
+            "\"c000\" is not the address of an I/O register between ff01 and ff7f"
+        );
+        assert_eq!(
+            err("custom:"),
+            "\"\" is not the address of an I/O register between ff01 and ff7f"
+        );
+
+        let custom = Profile::Custom(vec![0xFF4D..=0xFF4D, 0xFF60..=0xFF6F]);
+        assert!(custom.is_extra(0xFF65) && custom.is_audio(0xFF65));
+        assert!(!custom.is_extra(0xFF01) && !custom.is_audio(0xFF01));
+        assert!(!custom.is_extra(0xFF12) && custom.is_audio(0xFF12));
+        assert!(Profile::ApuSerial.is_audio(0xFF02) && !Profile::Apu.is_audio(0xFF02));
+    }
+
+    #[test]
+    fn extra_registers_count_as_audio() {
+        let play = Code::default()
+            .write(0xFF01, 0x42)
+            .raw(&[0xF0, 0x01]) // `ldh a, [rSB]`
+            .ldh_to(0xFF01);
+        let data = GbsBuilder::default()
+            .stack_ptr(0xDFFE)
+            .play(play.ret())
+            .build();
+        let gbs = Gbs::new(&data).unwrap();
+        let simulate = |profile| {
+            let mut params = SimParams::new(gbs.cycles_per_tick() * 20);
+            params.silence_timeout = gbs.cycles_per_tick() * 5;
+            params.profile = profile;
+            simulate_song(&gbs, 1, &params, None, None::<std::io::Sink>, None).unwrap()
+        };
+
+        // SB's writes keep the song from going silent, and read back without being reported.
+        let logbook = simulate(Profile::ApuSerial);
+        assert_eq!(logbook.termination, Termination::Timeout);
+        assert!(logbook.io_log.len() >= 2 * 20);
+        assert!(logbook
+            .io_log
+            .iter()
+            .all(|access| (access.addr, access.data) == (0xFF01, 0x42)));
+        assert!(logbook.diagnostics.is_empty(), "{:#?}", logbook.diagnostics);
+
+        let logbook = simulate(Profile::Apu);
+        assert_eq!(logbook.termination, Termination::Silence);
+        let reported = |kind: &dyn Fn(&DiagnosticKind) -> bool| {
+            logbook.diagnostics.iter().any(|diag| kind(&diag.kind))
+        };
+        assert!(reported(&|kind| matches!(
+            kind,
+            DiagnosticKind::UnsupportedWrite(Accessed(0xFF01), 0x42)
+        )));
+        assert!(reported(&|kind| matches!(
+            kind,
+            DiagnosticKind::UnsupportedRead(Accessed(0xFF01))
+        )));
+    }
+
+    #[test]
+    fn wramx_pcs_are_labelled_with_their_bank() {
+        // Copies `ld a, $f0; ldh [rNR12], a; ret` to $d000 in WRAM bank 2, and calls it.
+        let mut init = Code::default().write(0xFF70, 0x02);
+        for (i, byte) in [0x3E, 0xF0, 0xE0, 0x12, 0xC9].into_iter().enumerate() {
+            init = init.ld_a(byte).raw(&[0xEA, i as u8, 0xD0]); // `ld [$d00i], a`
+        }
+        let init = init.raw(&[0xCD, 0x00, 0xD0]).ret(); // `call $d000`
+        let data = GbsBuilder::default()
+            // Out of the way of WRAM banking.
+            .stack_ptr(0xFFFE)
+            .init(init)
+            .play(Code::default().ret())
+            .build();
+        let gbs = Gbs::new(&data).unwrap();
+        let params = SimParams::new(gbs.cycles_per_tick() * 3);
+        let logbook = simulate_song(&gbs, 1, &params, None, None::<std::io::Sink>, None).unwrap();
+        let write = logbook
+            .io_log
+            .iter()
+            .find(|access| access.addr == 0xFF12)
+            .unwrap();
+        assert_eq!(write.pc, Address(2, 0xD002));
+        assert_eq!(format!("{:x}", write.pc), "02:d002 (WRAM)");
+    }
+
+    #[test]
+    fn warm_up_ticks_are_skipped() {
+        // `ldh a, [$ff80]; inc a; ldh [$ff80], a`, then writes the count to NR12.
+        let play = Code::default()
+            .raw(&[0xF0, 0x80, 0x3C, 0xE0, 0x80])
+            .ldh_to(0xFF12)
+            .ret();
+        let data = GbsBuilder::default()
+            .stack_ptr(0xDFFE)
+            .init(Code::default().write(0xFF80, 0x00).ret())
+            .play(play)
+            .build();
+        let gbs = Gbs::new(&data).unwrap();
+        let simulate = |wait_for| {
+            let mut params = SimParams::new(gbs.cycles_per_tick() * 10);
+            params.wait_for = Some(wait_for);
+            simulate_song(&gbs, 1, &params, None, None::<std::io::Sink>, None)
+        };
+
+        let logbook = simulate((0xFF80, 3)).unwrap();
+        assert_eq!(logbook.warmup_ticks, 3);
+        // The song starts right after the warm-up, with a fresh timeout.
+        let writes: Vec<_> = logbook
+            .io_log
+            .iter()
+            .map(|access| (access.when.tick, access.data))
+            .collect();
+        assert_eq!(writes[..3], [(1, 4), (2, 5), (3, 6)]);
+        assert!(logbook.ticks_simulated >= 9, "{}", logbook.ticks_simulated);
+        assert_eq!(
+            logbook.tick_cycles.len() as u64,
+            logbook.ticks_simulated + 1
+        );
+
+        // Waiting for something that is already true doesn't skip anything.
+        let logbook = simulate((0xFF80, 0)).unwrap();
+        assert_eq!(logbook.warmup_ticks, 0);
+        assert_eq!(
+            (logbook.io_log[0].when.tick, logbook.io_log[0].data),
+            (1, 1)
+        );
+
+        // The counter only reaches $ff after 255 ticks, well past the timeout.
+        let failure = simulate((0xFF80, 0xFF)).unwrap_err();
+        assert!(
+            matches!(failure.error, Error::WaitTimeout(0xFF80, 0xFF)),
+            "{:?}",
+            failure.error
+        );
+    }
+
+    #[test]
+    fn length_counters_cut_channels() {
+        let init = Code::default()
+            .write(0xFF26, 0x80)
+            // CH1 plays for a single length clock.
+            .write(0xFF12, 0xF0)
+            .write(0xFF11, 0x3F)
+            .write(0xFF14, 0xC0)
+            // CH2 plays for 64.
+            .write(0xFF17, 0xF0)
+            .write(0xFF16, 0x00)
+            .write(0xFF19, 0xC0)
+            // CH3's DAC is off, so it never plays.
+            .write(0xFF1B, 0xFF)
+            .write(0xFF1E, 0xC0)
+            // CH4's length is not enabled.
+            .write(0xFF21, 0xF0)
+            .write(0xFF20, 0x3F)
+            .write(0xFF23, 0x80)
+            .ret();
+        let data = GbsBuilder::default()
+            .stack_ptr(0xDFFE)
+            .init(init)
+            .play(Code::default().ret())
+            .build();
+        let gbs = Gbs::new(&data).unwrap();
+        let params = SimParams::new(gbs.cycles_per_tick() * 30);
+        let logbook = simulate_song(&gbs, 1, &params, None, None::<std::io::Sink>, None).unwrap();
+        // Lengths are clocked at 256 Hz, from the first PLAY tick on.
+        let ch2_tick = (64 * 4096u64).div_ceil(gbs.cycles_per_tick().into());
+        assert_eq!(logbook.length_expiries, [(1, 0), (ch2_tick, 1)]);
+    }
+}
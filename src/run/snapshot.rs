@@ -0,0 +1,312 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with saving a song's simulation state mid-song, so that it can later be
+//! resumed from there instead of simulated from the start (`--save-state`, `--load-state`).
+//!
+//! Snapshot files start with [`MAGIC`], then gbsdiff's version, a hash of the GBS file, and the song
+//! ID; a snapshot is refused unless all of them match, since resuming it would give meaningless
+//! results otherwise. All multi-byte fields are little-endian.
+
+use std::{fs, io, path::Path};
+
+use super::addr_space::MemoryState;
+use crate::Timestamp;
+
+/// Identifies snapshot files, and their format version.
+const MAGIC: &[u8; 8] = b"GBSDSS03";
+
+fn version() -> String {
+    format!("{} {}", env!("CARGO_PKG_VERSION"), env!("GBSDIFF_GIT_HASH"))
+}
+
+/// Everything needed to resume a song right after one of its ticks.
+///
+/// PC and SP are not part of it, since they are reset before each PLAY call anyway.
+#[derive(Debug, Clone)]
+pub(crate) struct Snapshot {
+    /// The last tick that ran; 0 is INIT.
+    pub(super) tick: u64,
+    pub(super) total_cycles: u64,
+    /// A, F, B, C, D, E, H, L.
+    pub(super) regs: [u8; 8],
+    pub(super) ime: bool,
+    pub(super) rom_bank: u8,
+    pub(super) silence_timer: u32,
+    pub(super) timeout: u32,
+    pub(super) memory: MemoryState,
+}
+
+impl Snapshot {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let memory = &self.memory;
+        out.extend_from_slice(&self.tick.to_le_bytes());
+        out.extend_from_slice(&self.total_cycles.to_le_bytes());
+        out.extend_from_slice(&self.regs);
+        out.extend_from_slice(&[self.ime.into(), self.rom_bank]);
+        out.extend_from_slice(&self.silence_timer.to_le_bytes());
+        out.extend_from_slice(&self.timeout.to_le_bytes());
+        out.extend_from_slice(&memory.sram);
+        out.extend_from_slice(&*memory.wram);
+        out.extend_from_slice(&memory.hram);
+        out.extend_from_slice(&memory.apu_regs);
+        out.extend_from_slice(&memory.wave_ram);
+        match &memory.ch3_trigger {
+            Some(when) => {
+                out.push(1);
+                out.extend_from_slice(&when.tick.to_le_bytes());
+                out.extend_from_slice(&when.cycle.to_le_bytes());
+            }
+            None => out.push(0),
+        }
+        out.push(memory.channels_on);
+        for timer in memory.length_timers {
+            out.extend_from_slice(&timer.to_le_bytes());
+        }
+        out.extend_from_slice(&memory.length_phase.to_le_bytes());
+        out.extend_from_slice(&memory.bank_switches.0.to_le_bytes());
+        out.extend_from_slice(&memory.bank_switches.1.to_le_bytes());
+        out.extend_from_slice(&[memory.p1, memory.key1, memory.svbk]);
+        // There are fewer than 128 I/O registers, and sorting keeps snapshots reproducible.
+        let mut extra_regs: Vec<_> = memory.extra_regs.iter().collect();
+        extra_regs.sort();
+        out.push(extra_regs.len() as u8);
+        for (addr, value) in extra_regs {
+            out.extend_from_slice(&addr.to_le_bytes());
+            out.push(*value);
+        }
+    }
+
+    fn decode(input: &mut Reader) -> Option<Self> {
+        let tick = u64::from_le_bytes(input.bytes()?);
+        let total_cycles = u64::from_le_bytes(input.bytes()?);
+        let regs = input.bytes()?;
+        let [ime, rom_bank] = input.bytes()?;
+        let silence_timer = u32::from_le_bytes(input.bytes()?);
+        let timeout = u32::from_le_bytes(input.bytes()?);
+        let sram = input.bytes()?;
+        let wram = Box::new(input.bytes()?);
+        let hram = input.bytes()?;
+        let apu_regs = input.bytes()?;
+        let wave_ram = input.bytes()?;
+        let ch3_trigger = match input.bytes()? {
+            [0] => None,
+            [1] => Some(Timestamp {
+                tick: u64::from_le_bytes(input.bytes()?),
+                cycle: u32::from_le_bytes(input.bytes()?),
+            }),
+            _ => return None,
+        };
+        let [channels_on] = input.bytes()?;
+        let mut length_timers = [0; 4];
+        for timer in &mut length_timers {
+            *timer = u16::from_le_bytes(input.bytes()?);
+        }
+        let length_phase = u32::from_le_bytes(input.bytes()?);
+        let bank_switches = (
+            u64::from_le_bytes(input.bytes()?),
+            u32::from_le_bytes(input.bytes()?),
+        );
+        let [p1, key1, svbk] = input.bytes()?;
+        let [nb_extra_regs] = input.bytes()?;
+        let extra_regs = (0..nb_extra_regs)
+            .map(|_| Some((u16::from_le_bytes(input.bytes()?), input.bytes::<1>()?[0])))
+            .collect::<Option<_>>()?;
+
+        Some(Self {
+            tick,
+            total_cycles,
+            regs,
+            ime: ime != 0,
+            rom_bank,
+            silence_timer,
+            timeout,
+            memory: MemoryState {
+                sram,
+                wram,
+                hram,
+                apu_regs,
+                wave_ram,
+                ch3_trigger,
+                channels_on,
+                length_timers,
+                length_phase,
+                bank_switches,
+                p1,
+                key1,
+                svbk,
+                extra_regs,
+            },
+        })
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn slice(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (bytes, rest) = (self.0.len() >= len).then(|| self.0.split_at(len))?;
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.slice(N)?.try_into().ok()
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub(crate) fn save(
+    path: &Path,
+    gbs_data: &[u8],
+    song_id: u8,
+    snapshot: &Snapshot,
+) -> io::Result<()> {
+    let version = version();
+    let mut data = MAGIC.to_vec();
+    data.push(version.len() as u8);
+    data.extend_from_slice(version.as_bytes());
+    data.extend_from_slice(&crate::fnv1a(gbs_data).to_le_bytes());
+    data.push(song_id);
+    snapshot.encode(&mut data);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, data)
+}
+
+/// Errors out if the snapshot was saved by another version of gbsdiff, or from another GBS file or
+/// song.
+pub(crate) fn load(path: &Path, gbs_data: &[u8], song_id: u8) -> io::Result<Snapshot> {
+    let data = fs::read(path)?;
+    let mut input = Reader(data.strip_prefix(MAGIC).ok_or_else(|| {
+        invalid("not a gbsdiff snapshot, or from an incompatible version".into())
+    })?);
+    let truncated = || invalid("the snapshot is truncated".into());
+
+    let [len] = input.bytes().ok_or_else(truncated)?;
+    let version = input.slice(len.into()).ok_or_else(truncated)?;
+    if version != self::version().as_bytes() {
+        return Err(invalid(format!(
+            "saved by gbsdiff {}, but this is gbsdiff {}",
+            String::from_utf8_lossy(version),
+            self::version()
+        )));
+    }
+    let hash = u64::from_le_bytes(input.bytes().ok_or_else(truncated)?);
+    if hash != crate::fnv1a(gbs_data) {
+        return Err(invalid("saved from a different GBS file".into()));
+    }
+    let [saved_id] = input.bytes().ok_or_else(truncated)?;
+    if saved_id != song_id {
+        return Err(invalid(format!(
+            "saved from song {}, not song {}",
+            saved_id, song_id
+        )));
+    }
+
+    let snapshot = Snapshot::decode(&mut input).ok_or_else(truncated)?;
+    if input.0.is_empty() {
+        Ok(snapshot)
+    } else {
+        Err(invalid("unexpected data after the snapshot".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        gbs::{Code, Gbs, GbsBuilder},
+        run::{simulate_song_resumable, IoAccess, SimParams},
+    };
+
+    fn snapshot_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "gbsdiff-test-{}-{}.snapshot",
+            name,
+            std::process::id()
+        ))
+    }
+
+    /// Each tick, bumps a counter in WRAM, writes it to NR13, and flips `a` (which carries over
+    /// from one PLAY call to the next).
+    fn song() -> Vec<u8> {
+        let play = Code::default()
+            .raw(&[0x21, 0x00, 0xC0, 0x34]) // `ld hl, $c000; inc [hl]`
+            .raw(&[0x2F, 0x47, 0x7E]) // `cpl; ld b, a; ld a, [hl]`
+            .ldh_to(0xFF13)
+            .raw(&[0x78]) // `ld a, b`
+            .ldh_to(0xFF12)
+            .ret();
+        GbsBuilder::default().stack_ptr(0xDFFE).play(play).build()
+    }
+
+    #[test]
+    fn resuming_matches_a_straight_run() {
+        let data = song();
+        let gbs = Gbs::new(&data).unwrap();
+        let params = SimParams::new(gbs.cycles_per_tick() * 1000);
+        let simulate = |resume, save_at| {
+            simulate_song_resumable(&gbs, 1, &params, None::<io::Sink>, None, resume, save_at)
+                .unwrap()
+        };
+
+        let (straight, snapshot) = simulate(None, Some(500));
+        let path = snapshot_path("resume");
+        save(&path, &data, 1, &snapshot.unwrap()).unwrap();
+        let snapshot = load(&path, &data, 1).unwrap();
+        fs::remove_file(&path).unwrap();
+        let (resumed, _) = simulate(Some(&snapshot), None);
+
+        assert!(straight.ticks_simulated >= 1000);
+        assert_eq!(resumed.ticks_simulated, straight.ticks_simulated);
+        assert_eq!(resumed.termination, straight.termination);
+        let after_snapshot = |log: &[IoAccess]| -> Vec<_> {
+            log.iter()
+                .filter(|access| access.when.tick > 500)
+                .cloned()
+                .collect()
+        };
+        assert_eq!(resumed.io_log, after_snapshot(&straight.io_log));
+        assert_eq!(
+            resumed.io_log.len(),
+            2 * (straight.ticks_simulated as usize - 500)
+        );
+    }
+
+    #[test]
+    fn mismatched_snapshots_are_refused() {
+        let data = song();
+        let gbs = Gbs::new(&data).unwrap();
+        let params = SimParams::new(gbs.cycles_per_tick() * 10);
+        let (_, snapshot) =
+            simulate_song_resumable(&gbs, 1, &params, None::<io::Sink>, None, None, Some(5))
+                .unwrap();
+        let path = snapshot_path("refused");
+        save(&path, &data, 1, &snapshot.unwrap()).unwrap();
+
+        let error = |data: &[u8], song_id| load(&path, data, song_id).unwrap_err().to_string();
+        let other = GbsBuilder::default().build();
+        assert_eq!(error(&other, 1), "saved from a different GBS file");
+        assert_eq!(error(&data, 2), "saved from song 1, not song 2");
+
+        let saved = fs::read(&path).unwrap();
+        fs::write(&path, &saved[..saved.len() - 1]).unwrap();
+        assert_eq!(error(&data, 1), "the snapshot is truncated");
+        fs::write(&path, [&saved[..], &[0]].concat()).unwrap();
+        assert_eq!(error(&data, 1), "unexpected data after the snapshot");
+        fs::write(&path, b"GBSDSS00").unwrap();
+        assert_eq!(
+            error(&data, 1),
+            "not a gbsdiff snapshot, or from an incompatible version"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
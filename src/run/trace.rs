@@ -0,0 +1,353 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with the trace file's contents, either as text or as binary records.
+//!
+//! Binary traces start with [`MAGIC`], followed by records made of a type byte and fixed-size
+//! little-endian fields; `--trace-decode` turns them back into the text format.
+
+use std::{
+    fmt::Display,
+    io::{self, Read, Write},
+    str::FromStr,
+};
+
+use crate::diff::RegDispl;
+
+/// Identifies binary trace files, and their format version.
+const MAGIC: &[u8; 8] = b"GBSDTR01";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,
+    Binary,
+}
+
+impl FromStr for TraceFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("text") {
+            Ok(Self::Text)
+        } else if s.eq_ignore_ascii_case("binary") {
+            Ok(Self::Binary)
+        } else {
+            Err("must be either \"text\" or \"binary\"")
+        }
+    }
+}
+
+/// Anything that gets written to the trace file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum TraceEvent {
+    /// The start of a song, with its ID.
+    Song(u8),
+    /// The start of a PLAY tick.
+    Tick(u64),
+    /// The CPU state before an instruction; `cycle` counts from the start of the tick.
+    Cpu {
+        pc: u16,
+        sp: u16,
+        a: u8,
+        f: u8,
+        bc: u16,
+        de: u16,
+        hl: u16,
+        cycle: u32,
+    },
+    IoRead(u16, u8),
+    IoWrite(u16, u8),
+    BankSwitch(u8),
+}
+
+impl Display for TraceEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Song(song_id) => write!(f, "==== SONG {} ====", song_id),
+            Self::Tick(tick) => write!(f, "--- TICK {} ---", tick),
+            Self::Cpu {
+                pc,
+                sp,
+                a,
+                f: flags,
+                bc,
+                de,
+                hl,
+                cycle: _,
+            } => {
+                let flag = |mask: u8, set: char| {
+                    if flags & mask != 0 {
+                        set
+                    } else {
+                        set.to_ascii_lowercase()
+                    }
+                };
+                write!(
+                    f,
+                    "pc=${:04x} b=${:02x} c=${:02x} d=${:02x} e=${:02x} h=${:02x} l=${:02x} a=${:02x} f={}{}{}{} sp=${:04x}",
+                    pc,
+                    bc >> 8,
+                    bc & 0xFF,
+                    de >> 8,
+                    de & 0xFF,
+                    hl >> 8,
+                    hl & 0xFF,
+                    a,
+                    flag(0x80, 'Z'),
+                    flag(0x40, 'N'),
+                    flag(0x20, 'H'),
+                    flag(0x10, 'C'),
+                    sp,
+                )
+            }
+            Self::IoRead(addr, data) => write!(
+                f,
+                "io: read ${:02x} <- {} ({:04x})",
+                data,
+                RegDispl(*addr),
+                addr
+            ),
+            Self::IoWrite(addr, data) => write!(
+                f,
+                "io: write ${:02x} -> {} ({:04x})",
+                data,
+                RegDispl(*addr),
+                addr
+            ),
+            Self::BankSwitch(bank) => write!(f, "bank: switch to ${:02x}", bank),
+        }
+    }
+}
+
+impl TraceEvent {
+    fn encode<W: Write + ?Sized>(&self, out: &mut W) -> io::Result<()> {
+        match self {
+            Self::Song(song_id) => out.write_all(&[0, *song_id]),
+            Self::Tick(tick) => {
+                out.write_all(&[1])?;
+                out.write_all(&tick.to_le_bytes())
+            }
+            Self::Cpu {
+                pc,
+                sp,
+                a,
+                f,
+                bc,
+                de,
+                hl,
+                cycle,
+            } => {
+                let mut record = [0; 17];
+                record[0] = 2;
+                record[1..3].copy_from_slice(&pc.to_le_bytes());
+                record[3..5].copy_from_slice(&sp.to_le_bytes());
+                record[5] = *a;
+                record[6] = *f;
+                record[7..9].copy_from_slice(&bc.to_le_bytes());
+                record[9..11].copy_from_slice(&de.to_le_bytes());
+                record[11..13].copy_from_slice(&hl.to_le_bytes());
+                record[13..17].copy_from_slice(&cycle.to_le_bytes());
+                out.write_all(&record)
+            }
+            Self::IoRead(addr, data) => {
+                let [lo, hi] = addr.to_le_bytes();
+                out.write_all(&[3, lo, hi, *data])
+            }
+            Self::IoWrite(addr, data) => {
+                let [lo, hi] = addr.to_le_bytes();
+                out.write_all(&[4, lo, hi, *data])
+            }
+            Self::BankSwitch(bank) => out.write_all(&[5, *bank]),
+        }
+    }
+
+    /// Returns `None` at the end of the input, and an error for truncated or unknown records.
+    fn decode<R: Read>(input: &mut R) -> io::Result<Option<Self>> {
+        let mut kind = [0];
+        if input.read(&mut kind)? == 0 {
+            return Ok(None);
+        }
+        fn bytes<const N: usize, R: Read>(input: &mut R) -> io::Result<[u8; N]> {
+            let mut bytes = [0; N];
+            input.read_exact(&mut bytes).map_err(|err| {
+                if err.kind() == io::ErrorKind::UnexpectedEof {
+                    io::Error::new(io::ErrorKind::InvalidData, "the last record is truncated")
+                } else {
+                    err
+                }
+            })?;
+            Ok(bytes)
+        }
+        let u16 = |[lo, hi]: [u8; 2]| u16::from_le_bytes([lo, hi]);
+
+        Ok(Some(match kind[0] {
+            0 => Self::Song(bytes::<1, _>(input)?[0]),
+            1 => Self::Tick(u64::from_le_bytes(bytes(input)?)),
+            2 => {
+                let record: [u8; 16] = bytes(input)?;
+                Self::Cpu {
+                    pc: u16([record[0], record[1]]),
+                    sp: u16([record[2], record[3]]),
+                    a: record[4],
+                    f: record[5],
+                    bc: u16([record[6], record[7]]),
+                    de: u16([record[8], record[9]]),
+                    hl: u16([record[10], record[11]]),
+                    cycle: u32::from_le_bytes([record[12], record[13], record[14], record[15]]),
+                }
+            }
+            3 | 4 => {
+                let [lo, hi, data] = bytes(input)?;
+                if kind[0] == 3 {
+                    Self::IoRead(u16([lo, hi]), data)
+                } else {
+                    Self::IoWrite(u16([lo, hi]), data)
+                }
+            }
+            5 => Self::BankSwitch(bytes::<1, _>(input)?[0]),
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown record type {}", kind),
+                ))
+            }
+        }))
+    }
+}
+
+/// Writes events to the trace file, in either format.
+pub(super) struct TraceWriter<'a> {
+    out: Box<dyn Write + 'a>,
+    format: TraceFormat,
+}
+
+impl<'a> TraceWriter<'a> {
+    pub fn new(out: Box<dyn Write + 'a>, format: TraceFormat) -> Self {
+        Self { out, format }
+    }
+
+    pub fn write(&mut self, event: &TraceEvent) -> io::Result<()> {
+        match self.format {
+            TraceFormat::Text => writeln!(self.out, "{}", event),
+            TraceFormat::Binary => event.encode(&mut self.out),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Must be written once at the start of a binary trace file, which may then span several songs.
+pub(crate) fn write_header<W: Write>(file: &mut W) -> io::Result<()> {
+    file.write_all(MAGIC)
+}
+
+/// Prints a binary trace in the text format.
+pub(crate) fn decode<R: Read, W: Write>(mut input: R, mut output: W) -> io::Result<()> {
+    let mut magic = [0; MAGIC.len()];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a binary gbsdiff trace, or from an incompatible version",
+        ));
+    }
+    while let Some(event) = TraceEvent::decode(&mut input)? {
+        writeln!(output, "{}", event)?;
+    }
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One of each kind of event, with values that use every byte of their fields.
+    fn events() -> Vec<TraceEvent> {
+        let events = vec![
+            TraceEvent::Song(0xFF),
+            TraceEvent::Tick(0x0123_4567_89AB_CDEF),
+            TraceEvent::Cpu {
+                pc: 0x4567,
+                sp: 0xDFFC,
+                a: 0x12,
+                f: 0xA0,
+                bc: 0x3456,
+                de: 0x789A,
+                hl: 0xBCDE,
+                cycle: 0x0102_0304,
+            },
+            TraceEvent::IoRead(0xFF26, 0xF1),
+            TraceEvent::IoWrite(0xFF14, 0x87),
+            TraceEvent::BankSwitch(0x1F),
+        ];
+        // Make sure that new kinds of events get added above.
+        for event in &events {
+            match event {
+                TraceEvent::Song(_)
+                | TraceEvent::Tick(_)
+                | TraceEvent::Cpu { .. }
+                | TraceEvent::IoRead(..)
+                | TraceEvent::IoWrite(..)
+                | TraceEvent::BankSwitch(_) => {}
+            }
+        }
+        events
+    }
+
+    fn write_all(format: TraceFormat) -> Vec<u8> {
+        let mut out = vec![];
+        if format == TraceFormat::Binary {
+            write_header(&mut out).unwrap();
+        }
+        let mut writer = TraceWriter::new(Box::new(&mut out), format);
+        for event in &events() {
+            writer.write(event).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+        out
+    }
+
+    #[test]
+    fn every_event_round_trips() {
+        let binary = write_all(TraceFormat::Binary);
+        let mut input = &binary[MAGIC.len()..];
+        let mut decoded = vec![];
+        while let Some(event) = TraceEvent::decode(&mut input).unwrap() {
+            decoded.push(event);
+        }
+        assert_eq!(decoded, events());
+
+        // Decoding yields the same as tracing as text in the first place.
+        let mut text = vec![];
+        decode(&binary[..], &mut text).unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            String::from_utf8(write_all(TraceFormat::Text)).unwrap()
+        );
+    }
+
+    #[test]
+    fn bad_traces_are_rejected() {
+        let binary = write_all(TraceFormat::Binary);
+        let err = |input: &[u8]| decode(input, io::sink()).unwrap_err();
+
+        assert_eq!(
+            err(&binary[1..]).to_string(),
+            "not a binary gbsdiff trace, or from an incompatible version"
+        );
+        for len in [binary.len() - 1, MAGIC.len() + 3] {
+            let err = err(&binary[..len]);
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), "the last record is truncated");
+        }
+        let mut unknown = binary.clone();
+        unknown.push(6);
+        assert_eq!(err(&unknown).to_string(), "unknown record type 6");
+    }
+}
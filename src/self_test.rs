@@ -0,0 +1,95 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with checking that the simulator works at all, using a tiny hand-assembled
+//! GBS file, so that a broken build can be told apart from a broken driver.
+
+use std::io;
+
+use crate::{
+    gbs::{Code, Gbs, GbsBuilder},
+    run::{self, SimParams},
+};
+
+/// INIT turns the APU on and routes all channels to both speakers; PLAY then starts a note on CH1.
+const INIT_WRITES: [(u16, u8); 2] = [(0xFF26, 0x80), (0xFF25, 0xFF)];
+const PLAY_WRITES: [(u16, u8); 2] = [(0xFF12, 0xF0), (0xFF14, 0x87)];
+/// INIT, then this many PLAY calls.
+const NB_PLAY_TICKS: u64 = 2;
+
+/// Each write, then `ret`.
+fn assemble(writes: &[(u16, u8)]) -> Code {
+    writes
+        .iter()
+        .fold(Code::default(), |code, &(reg, value)| {
+            code.write(reg, value)
+        })
+        .ret()
+}
+
+fn build_gbs() -> Vec<u8> {
+    GbsBuilder::default()
+        .init(assemble(&INIT_WRITES))
+        .play(assemble(&PLAY_WRITES))
+        .build()
+}
+
+/// Runs the self-test, printing the outcome; returns whether it succeeded.
+pub fn run() -> bool {
+    let data = build_gbs();
+    let gbs = match Gbs::new(&data) {
+        Ok(gbs) => gbs,
+        Err(err) => {
+            println!("Self-test: FAIL (the test GBS does not parse: {})", err);
+            return false;
+        }
+    };
+    let params = SimParams::new(gbs.cycles_per_tick() * NB_PLAY_TICKS as u32);
+    let log = match run::simulate_song(
+        &gbs,
+        gbs.first_song(),
+        &params,
+        None,
+        None::<io::Sink>,
+        None,
+    ) {
+        Ok(log) => log,
+        Err(err) => {
+            println!("Self-test: FAIL (simulation error: {})", err);
+            return false;
+        }
+    };
+
+    let expected: Vec<_> = INIT_WRITES
+        .iter()
+        .map(|&(reg, value)| (0, reg, value))
+        .chain((1..=NB_PLAY_TICKS).flat_map(|tick| {
+            PLAY_WRITES
+                .iter()
+                .map(move |&(reg, value)| (tick, reg, value))
+        }))
+        .collect();
+    let got: Vec<_> = log
+        .io_log
+        .iter()
+        .map(|access| (access.when.tick, access.addr, access.data))
+        .collect();
+    if got.len() < expected.len() || got[..expected.len()] != expected[..] {
+        let format = |writes: &[(u64, u16, u8)]| {
+            writes
+                .iter()
+                .map(|(tick, reg, value)| format!("{}:{:04x}=${:02x}", tick, reg, value))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        println!("Self-test: FAIL");
+        println!("    expected writes: {}", format(&expected));
+        println!("    got writes:      {}", format(&got));
+        return false;
+    }
+    println!("Self-test: OK");
+    true
+}
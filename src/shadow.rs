@@ -0,0 +1,237 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with the RAM copies of audio registers (`--shadow`).
+//!
+//! Many drivers compute every register's value into a "shadow" variable first, and only copy the
+//! ones that changed to the hardware; a bug can corrupt a shadow without the hardware writes
+//! changing (yet). The shadows' writes are logged alongside the I/O writes, so that they can be
+//! compared between both files, and checked against the hardware writes within each file.
+
+use std::fmt::Display;
+
+use crate::{
+    diff::RegDispl,
+    run::{slice_ticks, IoAccess},
+    AddrArg,
+};
+
+/// A RAM address that shadows an I/O register.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shadow {
+    pub addr: u16,
+    pub reg: u16,
+    /// How the address was given on the command line.
+    pub name: String,
+}
+
+impl Shadow {
+    /// `addr` and `reg` are the resolved addresses; `addr_arg` is how the former was given.
+    pub fn new(addr_arg: &AddrArg, addr: u16, reg: u16) -> Result<Self, String> {
+        if !(0xFF00..=0xFF7F).contains(&reg) {
+            return Err(format!("${:04x} is not an I/O register", reg));
+        }
+        if !matches!(addr, 0xA000..=0xDFFF | 0xFF80..=0xFFFE) {
+            return Err(format!("${:04x} is not in RAM", addr));
+        }
+        let name = match addr_arg {
+            AddrArg::Addr(addr) => format!("${:04x}", addr),
+            AddrArg::Name(name) => name.clone(),
+        };
+        Ok(Self { addr, reg, name })
+    }
+}
+
+/// A write to a register that doesn't match its shadow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch<'a> {
+    pub shadow: &'a Shadow,
+    pub tick: u64,
+    pub written: u8,
+    /// The value last written to the shadow earlier in the same tick, if any.
+    pub shadowed: Option<u8>,
+}
+
+impl Display for Mismatch<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} written with ${:02x} at tick {} but shadow {} ",
+            RegDispl(self.shadow.reg),
+            self.written,
+            self.tick,
+            self.shadow.name,
+        )?;
+        match self.shadowed {
+            Some(value) => write!(f, "holds ${:02x}", value),
+            None => write!(f, "was not written earlier in that tick"),
+        }
+    }
+}
+
+/// Checks that every write to a shadowed register was preceded, within the same tick, by a write
+/// of the same value to its shadow.
+pub fn check<'a>(
+    io_log: &[IoAccess],
+    shadow_log: &[IoAccess],
+    shadows: &'a [Shadow],
+) -> Vec<Mismatch<'a>> {
+    let mut mismatches = Vec::new();
+    for access in io_log {
+        for shadow in shadows.iter().filter(|shadow| shadow.reg == access.addr) {
+            let tick = access.when.tick;
+            let shadowed = slice_ticks(shadow_log, &(tick..tick + 1))
+                .iter()
+                .take_while(|write| write.when < access.when)
+                .filter(|write| write.addr == shadow.addr)
+                .last()
+                .map(|write| write.data);
+            if shadowed != Some(access.data) {
+                mismatches.push(Mismatch {
+                    shadow,
+                    tick,
+                    written: access.data,
+                    shadowed,
+                });
+            }
+        }
+    }
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        diff::{DiagnosticKind, DiffGenerator},
+        gbs::{Code, Gbs, GbsBuilder},
+        run, Address, Timestamp,
+    };
+
+    fn shadow(addr: u16, reg: u16) -> Shadow {
+        Shadow::new(&AddrArg::Addr(addr), addr, reg).unwrap()
+    }
+
+    fn access(tick: u64, cycle: u32, addr: u16, data: u8) -> IoAccess {
+        IoAccess {
+            when: Timestamp { tick, cycle },
+            pc: Address(0, 0x4000),
+            addr,
+            data,
+        }
+    }
+
+    #[test]
+    fn maps_ram_to_registers() {
+        let by_name = Shadow::new(&AddrArg::Name("wNR12".into()), 0xC012, 0xFF12).unwrap();
+        assert_eq!(
+            by_name,
+            Shadow {
+                addr: 0xC012,
+                reg: 0xFF12,
+                name: "wNR12".into()
+            }
+        );
+        assert_eq!(shadow(0xFF90, 0xFF30).name, "$ff90");
+        assert_eq!(shadow(0xA000, 0xFF7F).addr, 0xA000);
+
+        let err = |addr, reg| Shadow::new(&AddrArg::Addr(addr), addr, reg).unwrap_err();
+        assert_eq!(err(0xC000, 0xFF80), "$ff80 is not an I/O register");
+        assert_eq!(err(0xC000, 0xC001), "$c001 is not an I/O register");
+        assert_eq!(err(0x4000, 0xFF12), "$4000 is not in RAM");
+        assert_eq!(err(0xFF12, 0xFF12), "$ff12 is not in RAM");
+        assert_eq!(err(0xFFFF, 0xFF12), "$ffff is not in RAM");
+    }
+
+    #[test]
+    fn checks_writes_against_shadows() {
+        let shadows = [shadow(0xC012, 0xFF12), shadow(0xC013, 0xFF13)];
+        let shadow_log = [
+            access(1, 10, 0xC012, 0xF0),
+            access(1, 20, 0xC013, 0x40),
+            access(1, 30, 0xC012, 0xA0),
+            // Too late for tick 1's NR13 write.
+            access(1, 60, 0xC013, 0x41),
+            // Only counts for tick 3.
+            access(3, 10, 0xC012, 0x77),
+        ];
+        let io_log = [
+            access(1, 40, 0xFF12, 0xA0),
+            access(1, 50, 0xFF13, 0x41),
+            access(2, 10, 0xFF12, 0xA0),
+            access(3, 20, 0xFF12, 0x77),
+            // Not shadowed.
+            access(3, 30, 0xFF14, 0x87),
+        ];
+        let mismatches = check(&io_log, &shadow_log, &shadows);
+        assert_eq!(
+            mismatches,
+            [
+                Mismatch {
+                    shadow: &shadows[1],
+                    tick: 1,
+                    written: 0x41,
+                    shadowed: Some(0x40),
+                },
+                Mismatch {
+                    shadow: &shadows[0],
+                    tick: 2,
+                    written: 0xA0,
+                    shadowed: None,
+                },
+            ]
+        );
+        assert_eq!(
+            mismatches[0].to_string(),
+            "NR13 written with $41 at tick 1 but shadow $c013 holds $40"
+        );
+        assert_eq!(
+            mismatches[1].to_string(),
+            "NR12 written with $a0 at tick 2 but shadow $c012 was not written earlier in that tick"
+        );
+    }
+
+    #[test]
+    fn compares_shadows_across_builds() {
+        // Both builds write the same value to NR12, but "after" computes a different shadow first.
+        let play = |shadowed: u8| {
+            Code::default()
+                .raw(&[0x3E, shadowed, 0xEA, 0x12, 0xC0]) // `ld a, shadowed; ld [$c012], a`
+                .write(0xFF12, 0xF0)
+                .ret()
+        };
+        let data = (
+            GbsBuilder::default().play(play(0xF0)).build(),
+            GbsBuilder::default().play(play(0xF1)).build(),
+        );
+        let gbs = (Gbs::new(&data.0).unwrap(), Gbs::new(&data.1).unwrap());
+        let mut params = run::SimParams::new(gbs.0.cycles_per_tick() * 3);
+        params.shadow_addrs = vec![0xC012];
+        let simulate =
+            |gbs| run::simulate_song(gbs, 1, &params, None, None::<std::io::Sink>, None).unwrap();
+        let logs = (simulate(&gbs.0), simulate(&gbs.1));
+
+        assert!(logs.0.shadow_log.iter().all(|write| write.addr == 0xC012));
+        assert!(!logs.0.shadow_log.is_empty());
+        let io_diffs: Vec<_> =
+            DiffGenerator::new(&logs.0.io_log, &logs.1.io_log, 0, false).collect();
+        assert!(io_diffs.is_empty(), "{:#?}", io_diffs);
+        let shadow_diffs: Vec<_> =
+            DiffGenerator::new(&logs.0.shadow_log, &logs.1.shadow_log, 0, false).collect();
+        assert_eq!(shadow_diffs.len(), logs.0.shadow_log.len());
+        assert!(shadow_diffs
+            .iter()
+            .all(|diag| matches!(diag.kind, DiagnosticKind::OtherValue(0xC012, 0xF0, 0xF1))));
+
+        // Only "after" holds values that it doesn't write.
+        let shadows = [shadow(0xC012, 0xFF12)];
+        assert!(check(&logs.0.io_log, &logs.0.shadow_log, &shadows).is_empty());
+        assert_eq!(
+            check(&logs.1.io_log, &logs.1.shadow_log, &shadows).len(),
+            logs.1.shadow_log.len()
+        );
+    }
+}
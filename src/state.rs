@@ -0,0 +1,365 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with comparing the APU state at the end of each tick, rather than the writes
+//! that led to it.
+//!
+//! Retriggers are audible even if the final state is the same, so they are counted separately; so
+//! are channels being cut by their length timer, which no register reflects.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+};
+
+use gb_cpu_sim::reg::HwReg;
+
+use crate::{diff::RegDispl, run::IoAccess};
+
+/// The APU state at the end of a tick.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Every register written so far, with its latest value.
+    regs: BTreeMap<u16, u8>,
+    /// How many times each NRx4 register triggered its channel during this tick.
+    retriggers: BTreeMap<u16, usize>,
+    /// Which channels (0-based) were cut by their length timer at the end of this tick.
+    expiries: BTreeSet<u8>,
+}
+
+impl Snapshot {
+    /// The register's latest value, or `None` if it was never written.
+    pub fn reg(&self, reg: u16) -> Option<u8> {
+        self.regs.get(&reg).copied()
+    }
+
+    /// How many times that NRx4 register triggered its channel during the snapshot's tick.
+    pub fn retriggers(&self, reg: u16) -> usize {
+        self.retriggers.get(&reg).copied().unwrap_or(0)
+    }
+}
+
+fn is_trigger(access: &IoAccess) -> bool {
+    [HwReg::Nr14, HwReg::Nr24, HwReg::Nr34, HwReg::Nr44]
+        .iter()
+        .any(|&reg| access.addr == reg as u16)
+        && access.data & 0x80 != 0
+}
+
+/// Folds the log into one snapshot per tick that contains writes or length expiries (see
+/// [`crate::run::Logbook::length_expiries`]); the state of other ticks is that of the latest
+/// snapshot before them.
+pub fn snapshots(io_log: &[IoAccess], length_expiries: &[(u64, u8)]) -> BTreeMap<u64, Snapshot> {
+    let mut ticks: Vec<_> = io_log
+        .iter()
+        .map(|access| access.when.tick)
+        .chain(length_expiries.iter().map(|&(tick, _)| tick))
+        .collect();
+    ticks.sort_unstable();
+    ticks.dedup();
+
+    let mut snapshots = BTreeMap::new();
+    let mut state = Snapshot::default();
+    let (mut accesses, mut expiries) =
+        (io_log.iter().peekable(), length_expiries.iter().peekable());
+    for tick in ticks {
+        while let Some(access) = accesses.next_if(|access| access.when.tick == tick) {
+            state.regs.insert(access.addr, access.data);
+            if is_trigger(access) {
+                *state.retriggers.entry(access.addr).or_default() += 1;
+            }
+        }
+        while let Some(&(_, channel)) = expiries.next_if(|&&(expiry_tick, _)| expiry_tick == tick) {
+            state.expiries.insert(channel);
+        }
+
+        snapshots.insert(tick, state.clone());
+        state.retriggers.clear();
+        state.expiries.clear();
+    }
+
+    snapshots
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateDiff {
+    /// The register ends the tick with different values (`None` if never written).
+    Value(u16, Option<u8>, Option<u8>),
+    /// The channel was triggered a different number of times during the tick.
+    Retriggers(u16, usize, usize),
+    /// The channel (0-based) was cut by its length timer at the end of the tick in only one of
+    /// the logs; whether it was in "after".
+    Expiry(u8, bool),
+}
+
+impl Display for StateDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = |value: &Option<u8>| match value {
+            Some(value) => format!("${:02x}", value),
+            None => "never written".to_string(),
+        };
+        match self {
+            Self::Value(reg, before, after) => write!(
+                f,
+                "{} ends the tick as {} instead of {}",
+                RegDispl(*reg),
+                value(after),
+                value(before)
+            ),
+            Self::Retriggers(reg, before, after) => write!(
+                f,
+                "{} triggers its channel {} times instead of {}",
+                RegDispl(*reg),
+                after,
+                before
+            ),
+            Self::Expiry(channel, true) => write!(
+                f,
+                "CH{}'s length timer runs out, but didn't before",
+                channel + 1
+            ),
+            Self::Expiry(channel, false) => {
+                write!(f, "CH{}'s length timer no longer runs out", channel + 1)
+            }
+        }
+    }
+}
+
+/// Compares the state of both logs at every tick where either changes.
+///
+/// A value difference is only reported when it appears or changes, not for every tick it persists.
+pub fn compare(
+    before: &BTreeMap<u64, Snapshot>,
+    after: &BTreeMap<u64, Snapshot>,
+) -> Vec<(u64, StateDiff)> {
+    fn state_at<'a>(
+        snapshots: &'a BTreeMap<u64, Snapshot>,
+        tick: u64,
+        empty: &'a Snapshot,
+    ) -> &'a Snapshot {
+        snapshots
+            .range(..=tick)
+            .next_back()
+            .map_or(empty, |(_, snapshot)| snapshot)
+    }
+    let empty = Snapshot::default();
+    let retriggers_at = |snapshots: &BTreeMap<u64, Snapshot>, tick, reg| {
+        snapshots
+            .get(&tick)
+            .and_then(|snapshot| snapshot.retriggers.get(&reg))
+            .copied()
+            .unwrap_or(0)
+    };
+    let expired_at = |snapshots: &BTreeMap<u64, Snapshot>, tick, channel| {
+        snapshots
+            .get(&tick)
+            .is_some_and(|snapshot| snapshot.expiries.contains(&channel))
+    };
+
+    let mut ticks: Vec<_> = before.keys().chain(after.keys()).copied().collect();
+    ticks.sort_unstable();
+    ticks.dedup();
+
+    let mut diffs = Vec::new();
+    let mut reported = BTreeMap::new();
+    for tick in ticks {
+        let (state_before, state_after) = (
+            state_at(before, tick, &empty),
+            state_at(after, tick, &empty),
+        );
+
+        let mut regs: Vec<_> = state_before
+            .regs
+            .keys()
+            .chain(state_after.regs.keys())
+            .copied()
+            .collect();
+        regs.sort_unstable();
+        regs.dedup();
+        for reg in regs {
+            let values = (
+                state_before.regs.get(&reg).copied(),
+                state_after.regs.get(&reg).copied(),
+            );
+            let previous = reported.insert(reg, values);
+            if values.0 != values.1 && previous != Some(values) {
+                diffs.push((tick, StateDiff::Value(reg, values.0, values.1)));
+            }
+        }
+
+        for reg in [HwReg::Nr14, HwReg::Nr24, HwReg::Nr34, HwReg::Nr44] {
+            let reg = reg as u16;
+            let counts = (
+                retriggers_at(before, tick, reg),
+                retriggers_at(after, tick, reg),
+            );
+            if counts.0 != counts.1 {
+                diffs.push((tick, StateDiff::Retriggers(reg, counts.0, counts.1)));
+            }
+        }
+
+        for channel in 0..4 {
+            let expired = (
+                expired_at(before, tick, channel),
+                expired_at(after, tick, channel),
+            );
+            if expired.0 != expired.1 {
+                diffs.push((tick, StateDiff::Expiry(channel, expired.1)));
+            }
+        }
+    }
+
+    diffs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Address, Timestamp};
+
+    /// (tick, register, value)
+    type Writes = &'static [(u64, u16, u8)];
+    type Diffs = &'static [(u64, StateDiff)];
+
+    fn log(writes: &[(u64, u16, u8)]) -> Vec<IoAccess> {
+        writes
+            .iter()
+            .enumerate()
+            .map(|(i, &(tick, addr, data))| IoAccess {
+                when: Timestamp {
+                    tick,
+                    cycle: i as u32,
+                },
+                pc: Address(1, 0x4000),
+                addr,
+                data,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn state_is_folded_per_tick() {
+        // Each case is the writes, then for a tick: a register's value and NR14's retriggers.
+        let cases: &[(Writes, u64, u16, Option<u8>, usize)] = &[
+            // The latest write wins.
+            (
+                &[(1, 0xFF12, 0xF0), (1, 0xFF12, 0xA0)],
+                1,
+                0xFF12,
+                Some(0xA0),
+                0,
+            ),
+            // Values carry over to later snapshots.
+            (
+                &[(1, 0xFF12, 0xF0), (3, 0xFF13, 0x40)],
+                3,
+                0xFF12,
+                Some(0xF0),
+                0,
+            ),
+            (&[(3, 0xFF13, 0x40)], 3, 0xFF12, None, 0),
+            // Only NRx4 writes with bit 7 set are triggers, and each one counts.
+            (&[(1, 0xFF14, 0x87)], 1, 0xFF14, Some(0x87), 1),
+            (&[(1, 0xFF14, 0x07)], 1, 0xFF14, Some(0x07), 0),
+            (
+                &[(1, 0xFF14, 0x87), (1, 0xFF14, 0xC7)],
+                1,
+                0xFF14,
+                Some(0xC7),
+                2,
+            ),
+            (
+                &[(1, 0xFF13, 0x87), (1, 0xFF26, 0x80)],
+                1,
+                0xFF13,
+                Some(0x87),
+                0,
+            ),
+            // Retriggers don't carry over, unlike the value.
+            (
+                &[(1, 0xFF14, 0x87), (2, 0xFF12, 0xF0)],
+                2,
+                0xFF14,
+                Some(0x87),
+                0,
+            ),
+        ];
+        for &(writes, tick, reg, value, retriggers) in cases {
+            let snapshots = snapshots(&log(writes), &[]);
+            let snapshot = &snapshots[&tick];
+            assert_eq!(
+                (snapshot.reg(reg), snapshot.retriggers(0xFF14)),
+                (value, retriggers),
+                "{:x?}",
+                writes
+            );
+        }
+
+        // Ticks without writes or expiries get no snapshot.
+        let snapshots = snapshots(&log(&[(1, 0xFF12, 0xF0), (5, 0xFF12, 0)]), &[(3, 1)]);
+        assert_eq!(snapshots.keys().copied().collect::<Vec<_>>(), [1, 3, 5]);
+        assert_eq!(snapshots[&3].expiries, BTreeSet::from([1]));
+        assert!(snapshots[&5].expiries.is_empty());
+    }
+
+    #[test]
+    fn only_changes_are_reported() {
+        // Each case is the writes before and after, and the differences they should yield.
+        let cases: &[(Writes, Writes, Diffs)] = &[
+            (&[(1, 0xFF12, 0xF0)], &[(1, 0xFF12, 0xF0)], &[]),
+            // Ending on the same value is fine, whatever the path there.
+            (
+                &[(1, 0xFF12, 0xF0)],
+                &[(1, 0xFF12, 0xA0), (1, 0xFF12, 0xF0)],
+                &[],
+            ),
+            // A lasting difference is only reported when it appears, or changes.
+            (
+                &[(1, 0xFF12, 0xF0), (2, 0xFF13, 0), (4, 0xFF12, 0xA0)],
+                &[(1, 0xFF12, 0xA0), (2, 0xFF13, 0), (3, 0xFF12, 0xB0)],
+                &[
+                    (1, StateDiff::Value(0xFF12, Some(0xF0), Some(0xA0))),
+                    (3, StateDiff::Value(0xFF12, Some(0xF0), Some(0xB0))),
+                    (4, StateDiff::Value(0xFF12, Some(0xA0), Some(0xB0))),
+                ],
+            ),
+            (
+                &[(1, 0xFF12, 0xF0)],
+                &[(2, 0xFF12, 0xF0)],
+                &[(1, StateDiff::Value(0xFF12, Some(0xF0), None))],
+            ),
+            // Retriggering is audible, even if the registers end up the same.
+            (
+                &[(1, 0xFF14, 0x87)],
+                &[(1, 0xFF14, 0x87), (1, 0xFF14, 0x87)],
+                &[(1, StateDiff::Retriggers(0xFF14, 1, 2))],
+            ),
+            (
+                &[(1, 0xFF19, 0x87), (2, 0xFF12, 0)],
+                &[(1, 0xFF19, 0x87), (2, 0xFF12, 0), (2, 0xFF19, 0x87)],
+                &[(2, StateDiff::Retriggers(0xFF19, 0, 1))],
+            ),
+        ];
+        for (before, after, expected) in cases {
+            assert_eq!(
+                compare(&snapshots(&log(before), &[]), &snapshots(&log(after), &[])),
+                *expected,
+                "{:x?} vs {:x?}",
+                before,
+                after
+            );
+        }
+
+        let expiries = |expiries: &[(u64, u8)]| snapshots(&[], expiries);
+        assert_eq!(
+            compare(&expiries(&[(2, 0), (5, 3)]), &expiries(&[(2, 0), (6, 3)])),
+            [
+                (5, StateDiff::Expiry(3, false)),
+                (6, StateDiff::Expiry(3, true))
+            ]
+        );
+    }
+}
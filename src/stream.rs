@@ -0,0 +1,325 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with diffing both songs while they are being simulated (`--stream`), so that
+//! only a few ticks' worth of writes are ever held in memory instead of both songs' whole logs.
+//!
+//! Writes are only ever paired within their tick, so each tick can be diffed as soon as both sides
+//! have run it; but its differences are only handed out once the next tick has been diffed as
+//! well, since writes that slipped into the next tick are paired with those missing from the end
+//! of this one.
+
+use crate::{
+    diff::{self, DiagnosticKind, DiffGenerator, DivPhase},
+    gbs::Cadence,
+    run::IoAccess,
+    Diagnostic,
+};
+
+/// How the writes are diffed, mirroring the corresponding options.
+#[derive(Debug, Clone)]
+pub struct DiffParams {
+    pub jitter: u16,
+    pub song_wide_div_phase: bool,
+    pub slip_window: usize,
+    pub cadence: Cadence,
+    pub strict_values: bool,
+    pub relax_duty: bool,
+}
+
+#[derive(Debug)]
+pub struct Lockstep {
+    params: DiffParams,
+    /// The writes of the ticks from the one before [`Self::next_tick`] on, for either side.
+    logs: (Vec<IoAccess>, Vec<IoAccess>),
+    /// The first tick that hasn't been diffed yet.
+    next_tick: u64,
+    div_phase: DivPhase,
+    /// The differences of the tick before `next_tick`, which may still be paired with the next
+    /// tick's.
+    pending: Vec<Diagnostic<DiagnosticKind>>,
+}
+
+impl Lockstep {
+    pub fn new(params: DiffParams) -> Self {
+        Self {
+            params,
+            logs: Default::default(),
+            next_tick: 0,
+            div_phase: DivPhase::default(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Appends writes made by the "before" song, which must come after all of those pushed so far.
+    pub fn push_before<'a>(&mut self, writes: impl IntoIterator<Item = &'a IoAccess>) {
+        self.logs.0.extend(writes.into_iter().cloned());
+    }
+
+    /// Like [`Self::push_before`], for the "after" song.
+    pub fn push_after<'a>(&mut self, writes: impl IntoIterator<Item = &'a IoAccess>) {
+        self.logs.1.extend(writes.into_iter().cloned());
+    }
+
+    /// How many of the "before" song's writes are being held.
+    pub fn len_before(&self) -> usize {
+        self.logs.0.len()
+    }
+
+    /// How many of the "after" song's writes are being held.
+    pub fn len_after(&self) -> usize {
+        self.logs.1.len()
+    }
+
+    /// All differences before this tick have been handed out.
+    pub fn settled_ticks(&self) -> u64 {
+        self.next_tick.saturating_sub(1)
+    }
+
+    /// Diffs the ticks up to `complete` (included), for which both sides must have pushed all of
+    /// their writes, and returns the differences that are final, in order.
+    pub fn diff_through(&mut self, complete: u64) -> Vec<Diagnostic<DiagnosticKind>> {
+        let mut ready = Vec::new();
+        while self.next_tick <= complete {
+            ready.append(&mut self.diff_next_tick());
+        }
+        ready
+    }
+
+    /// Diffs the remaining ticks, once both sides are over.
+    pub fn finish(mut self) -> Vec<Diagnostic<DiagnosticKind>> {
+        let last_tick = |log: &[IoAccess]| log.last().map(|access| access.when.tick);
+        let mut ready = Vec::new();
+        while let Some(last) = last_tick(&self.logs.0).max(last_tick(&self.logs.1)) {
+            if self.next_tick > last {
+                break;
+            }
+            ready.append(&mut self.diff_next_tick());
+        }
+        ready.append(&mut self.pending);
+        ready
+    }
+
+    /// Returns the differences of the tick before the one diffed, which are now final.
+    fn diff_next_tick(&mut self) -> Vec<Diagnostic<DiagnosticKind>> {
+        let tick = self.next_tick;
+        // The previous tick's writes are still there, but have been diffed already.
+        let this_tick = |log: &[IoAccess]| {
+            let start = log.partition_point(|access| access.when.tick < tick);
+            (
+                start,
+                log.partition_point(|access| access.when.tick <= tick),
+            )
+        };
+        let ((start0, end0), (start1, end1)) = (this_tick(&self.logs.0), this_tick(&self.logs.1));
+        let mut generator = DiffGenerator::resume(
+            &self.logs.0[start0..end0],
+            &self.logs.1[start1..end1],
+            self.div_phase.clone(),
+            self.params.jitter,
+            self.params.song_wide_div_phase,
+        );
+        let mut diags: Vec<_> = generator.by_ref().collect();
+        self.div_phase = generator.div_phase();
+
+        let logs = (&*self.logs.0, &*self.logs.1);
+        diff::pair_freq_writes(&mut diags, logs);
+        if !self.params.strict_values {
+            diff::relax_field_changes(&mut diags, self.params.relax_duty);
+        }
+        let mut diags = std::mem::take(&mut self.pending)
+            .into_iter()
+            .chain(diags)
+            .collect();
+        diff::pair_slipped_writes(
+            &mut diags,
+            logs,
+            self.params.slip_window,
+            self.params.jitter,
+            &self.params.cadence,
+        );
+        let split = diags.partition_point(|diag| diag.when.tick < tick);
+        self.pending = diags.split_off(split);
+
+        // Only this tick's writes are needed from now on, for pairing the next one's.
+        self.logs.0.drain(..start0);
+        self.logs.1.drain(..start1);
+        self.next_tick += 1;
+        diags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        gbs::{Code, Gbs, GbsBuilder},
+        run::{SimParams, SongSimulation},
+        Address, Timestamp,
+    };
+
+    fn params(cadence: Cadence) -> DiffParams {
+        DiffParams {
+            jitter: 20,
+            song_wide_div_phase: false,
+            slip_window: 4,
+            cadence,
+            strict_values: false,
+            relax_duty: false,
+        }
+    }
+
+    /// A small xorshift, so that the random inputs are the same on every run.
+    fn random(seed: &mut u32, below: u32) -> u32 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 17;
+        *seed ^= *seed << 5;
+        *seed % below
+    }
+
+    fn write(tick: u64, cycle: u32, addr: u16, data: u8) -> IoAccess {
+        IoAccess {
+            when: Timestamp { tick, cycle },
+            pc: Address(0, 0x4000 + cycle as u16),
+            addr,
+            data,
+        }
+    }
+
+    /// A pair of logs whose writes were randomly changed, dropped, delayed, or moved to the next
+    /// tick.
+    fn random_logs(seed: &mut u32, nb_ticks: u64) -> (Vec<IoAccess>, Vec<IoAccess>) {
+        const REGS: [u16; 6] = [0xFF04, 0xFF12, 0xFF13, 0xFF14, 0xFF22, 0xFF30];
+        let (mut before, mut after) = (Vec::new(), Vec::new());
+        for tick in 0..nb_ticks {
+            let mut cycle = 0;
+            let mut moved = Vec::new();
+            for _ in 0..random(seed, 6) {
+                cycle += 4 + random(seed, 40);
+                let access = write(
+                    tick,
+                    cycle,
+                    REGS[random(seed, REGS.len() as u32) as usize],
+                    random(seed, 4) as u8,
+                );
+                before.push(access.clone());
+                match random(seed, 8) {
+                    0 => {}
+                    1 => after.push(IoAccess {
+                        data: access.data ^ 0x10,
+                        ..access
+                    }),
+                    2 => after.push(IoAccess {
+                        when: Timestamp {
+                            tick,
+                            cycle: cycle + random(seed, 30),
+                        },
+                        ..access
+                    }),
+                    3 => moved.push(access),
+                    _ => after.push(access),
+                }
+            }
+            after.sort_by(|a, b| a.when.cmp(&b.when));
+            for (i, access) in moved.into_iter().enumerate() {
+                after.push(write(tick + 1, i as u32, access.addr, access.data));
+            }
+        }
+        after.sort_by(|a, b| a.when.cmp(&b.when));
+        (before, after)
+    }
+
+    /// What the whole-song pipeline in `main` reports, minus the collapsing of reallocations.
+    fn diff_whole(logs: (&[IoAccess], &[IoAccess]), params: &DiffParams) -> String {
+        let mut diags: Vec<_> =
+            DiffGenerator::new(logs.0, logs.1, params.jitter, params.song_wide_div_phase).collect();
+        diff::pair_freq_writes(&mut diags, logs);
+        diff::pair_slipped_writes(
+            &mut diags,
+            logs,
+            params.slip_window,
+            params.jitter,
+            &params.cadence,
+        );
+        diff::relax_field_changes(&mut diags, params.relax_duty);
+        format!("{:#?}", diags)
+    }
+
+    #[test]
+    fn streaming_matches_whole_songs() {
+        let data = GbsBuilder::default().build();
+        let cadence = Gbs::new(&data).unwrap().cadence();
+        let mut seed = 0x1329;
+        for _ in 0..200 {
+            let nb_ticks = 1 + u64::from(random(&mut seed, 12));
+            let logs = random_logs(&mut seed, nb_ticks);
+            let params = params(cadence.clone());
+            let expected = diff_whole((&logs.0, &logs.1), &params);
+
+            let mut lockstep = Lockstep::new(params);
+            let mut diags = Vec::new();
+            for tick in 0..=nb_ticks {
+                let of_tick = |access: &&IoAccess| access.when.tick == tick;
+                lockstep.push_before(logs.0.iter().filter(of_tick));
+                lockstep.push_after(logs.1.iter().filter(of_tick));
+                diags.append(&mut lockstep.diff_through(tick));
+                assert!(diags
+                    .iter()
+                    .all(|diag| diag.when.tick < lockstep.settled_ticks()));
+            }
+            diags.append(&mut lockstep.finish());
+            assert_eq!(format!("{:#?}", diags), expected, "logs: {:#?}", logs);
+        }
+    }
+
+    #[test]
+    fn long_songs_take_constant_memory() {
+        const NB_TICKS: u64 = 20_000;
+        // Every tick writes the same notes, except that "after" plays a different one.
+        let play = |freq| {
+            Code::default()
+                .write(0xFF12, 0xF0)
+                .write(0xFF13, freq)
+                .write(0xFF14, 0x87)
+                .ret()
+        };
+        let data = (
+            GbsBuilder::default().play(play(0x40)).build(),
+            GbsBuilder::default().play(play(0x41)).build(),
+        );
+        let gbs = (Gbs::new(&data.0).unwrap(), Gbs::new(&data.1).unwrap());
+        let sim_params = SimParams::new(gbs.0.cycles_per_tick() * NB_TICKS as u32);
+        let mut hooks = ((), ());
+        let mut sims = (
+            SongSimulation::new(&gbs.0, 1, &sim_params, None, None, None, &mut hooks.0).unwrap(),
+            SongSimulation::new(&gbs.1, 1, &sim_params, None, None, None, &mut hooks.1).unwrap(),
+        );
+        let mut lockstep = Lockstep::new(params(gbs.0.cadence()));
+        lockstep.push_before(&sims.0.take_logs().io_log);
+        lockstep.push_after(&sims.1.take_logs().io_log);
+
+        let mut nb_diags = 0;
+        let mut tick = 0;
+        while sims.0.termination().is_none() {
+            tick += 1;
+            lockstep.push_before(sims.0.run_tick().unwrap().iter());
+            lockstep.push_after(sims.1.run_tick().unwrap().iter());
+            for sim in [&mut sims.0, &mut sims.1] {
+                let logs = sim.take_logs();
+                assert!(logs.io_log.len() <= 3);
+                assert!(logs.tick_cycles.len() <= 1);
+            }
+            nb_diags += lockstep.diff_through(tick).len();
+            // This tick's writes, and the previous one's for pairing.
+            assert!(lockstep.len_before() + lockstep.len_after() <= 2 * 2 * 3);
+        }
+        nb_diags += lockstep.finish().len();
+        let ticks_simulated = sims.0.finish().ticks_simulated;
+        assert!(ticks_simulated >= NB_TICKS);
+        // One per PLAY tick, for the different note.
+        assert_eq!(nb_diags as u64, ticks_simulated);
+    }
+}
@@ -0,0 +1,242 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with RGBDS symbol files, so that addresses can be given and shown by name.
+//!
+//! Symbol files consist of `BB:AAAA name` lines (both hex numbers); empty lines and comments
+//! (starting with `;`) are ignored. A name may only be defined once.
+
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+use crate::Address;
+
+#[derive(Debug, Default)]
+pub struct Symbols {
+    by_name: HashMap<String, (u8, u16)>,
+    /// Sorted by canonical location, for lookups by address.
+    by_addr: Vec<((u8, u16), String)>,
+}
+
+pub fn parse(text: &str) -> Result<Symbols, String> {
+    let mut symbols = Symbols::default();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split_once(';').map_or(line, |(line, _)| line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |msg: &str| format!("line {}: {}", i + 1, msg);
+
+        let (location, name) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| error("expected `BB:AAAA name`"))?;
+        let (bank, addr) = location
+            .split_once(':')
+            .ok_or_else(|| error("expected `BB:AAAA name`"))?;
+        let bank = u8::from_str_radix(bank, 16).map_err(|err| error(&err.to_string()))?;
+        let addr = u16::from_str_radix(addr, 16).map_err(|err| error(&err.to_string()))?;
+        let name = name.trim().to_string();
+
+        // Repeated lines are harmless, but a name can't be in two places.
+        match symbols.by_name.get(&name) {
+            Some(&location) if location == (bank, addr) => continue,
+            Some((bank, addr)) => {
+                return Err(error(&format!(
+                    "{} is already defined at {:02x}:{:04x}",
+                    name, bank, addr
+                )))
+            }
+            None => {}
+        }
+        symbols
+            .by_addr
+            .push((Address(bank, addr).canonical(), name.clone()));
+        symbols.by_name.insert(name, (bank, addr));
+    }
+
+    symbols.by_addr.sort();
+    Ok(symbols)
+}
+
+impl Symbols {
+    /// The symbol's bank and address.
+    pub fn get(&self, name: &str) -> Option<(u8, u16)> {
+        self.by_name.get(name).copied()
+    }
+
+    /// The closest symbol at or before `addr`, in the same bank and memory region, and how far
+    /// past it the address is.
+    pub fn nearest(&self, addr: &Address) -> Option<(&str, u16)> {
+        let location = addr.canonical();
+        let i = self.by_addr.partition_point(|(sym, _)| *sym <= location);
+        let ((bank, sym_addr), name) = self.by_addr.get(i.checked_sub(1)?)?;
+        (*bank == location.0 && region(*sym_addr) == region(location.1))
+            .then(|| (name.as_str(), location.1 - sym_addr))
+    }
+}
+
+/// Symbols can only be looked up within a single memory region, since e.g. the last label in ROM0
+/// has nothing to do with the start of ROMX.
+fn region(addr: u16) -> u8 {
+    match addr {
+        0x0000..=0x3FFF => 0,
+        0x4000..=0x7FFF => 1,
+        0x8000..=0x9FFF => 2,
+        0xA000..=0xBFFF => 3,
+        0xC000..=0xDFFF => 4,
+        0xE000..=0xFDFF => 5,
+        0xFE00..=0xFEFF => 6,
+        0xFF00..=0xFF7F => 7,
+        0xFF80..=0xFFFE => 8,
+        0xFFFF => 9,
+    }
+}
+
+/// The bank that is always mapped at that address in a GBS player, if any.
+fn fixed_bank(addr: u16) -> Option<u8> {
+    match addr {
+        0x4000..=0x7FFF => None,
+        // WRAMX is always bank 1 on DMG.
+        0xD000..=0xDFFF => Some(1),
+        _ => Some(0),
+    }
+}
+
+/// An address given on the command line, either as a hex number or as a symbol's name.
+///
+/// Names that are also valid hex numbers are taken as the latter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddrArg {
+    Addr(u16),
+    Name(String),
+}
+
+impl FromStr for AddrArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(addr) = u16::from_str_radix(s, 16) {
+            return Ok(Self::Addr(addr));
+        }
+        let is_name = s
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.')
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_.@#$".contains(c));
+        if is_name {
+            Ok(Self::Name(s.to_string()))
+        } else {
+            Err(format!(
+                "{:?} is neither a hex address nor a symbol name",
+                s
+            ))
+        }
+    }
+}
+
+impl AddrArg {
+    /// Also returns a warning if the symbol's bank doesn't match what's mapped at its address.
+    pub fn resolve(&self, symbols: Option<&Symbols>) -> Result<(u16, Option<String>), String> {
+        let name = match self {
+            Self::Addr(addr) => return Ok((*addr, None)),
+            Self::Name(name) => name,
+        };
+        let (bank, addr) = symbols
+            .ok_or_else(|| format!("{:?} is a symbol name, but no --sym file was given", name))?
+            .get(name)
+            .ok_or_else(|| format!("unknown symbol {:?}", name))?;
+        let warning = fixed_bank(addr)
+            .filter(|&mapped| mapped != bank)
+            .map(|mapped| {
+                format!(
+                    "symbol {} is in bank {}, but bank {} is always mapped at ${:04x}; only using its address",
+                    name, bank, mapped, addr,
+                )
+            });
+        Ok((addr, warning))
+    }
+}
+
+/// A code location, followed by the symbol it belongs to if any.
+pub struct Pc<'a>(pub &'a Address, pub Option<&'a Symbols>);
+
+impl Display for Pc<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "${:04x}", self.0)?;
+        match self.1.and_then(|symbols| symbols.nearest(self.0)) {
+            Some((name, 0)) => write!(f, " ({})", name),
+            Some((name, ofs)) => write!(f, " ({}+${:x})", name, ofs),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bank_and_address_are_parsed() {
+        let symbols = parse(
+            "; File generated by rgblink\n\
+             \n\
+             00:0150 Init\n\
+             01:4000 Play  ; trailing comment\n\
+             01:4010 Play.loop\n\
+             02:4000 OtherBank\n\
+             00:d000   wChannels\n",
+        )
+        .unwrap();
+        assert_eq!(symbols.get("Init"), Some((0, 0x0150)));
+        assert_eq!(symbols.get("Play.loop"), Some((1, 0x4010)));
+        assert_eq!(symbols.get("wChannels"), Some((0, 0xD000)));
+        assert_eq!(symbols.get("Missing"), None);
+
+        let nearest = |bank, addr| symbols.nearest(&Address(bank, addr));
+        assert_eq!(nearest(1, 0x4000), Some(("Play", 0)));
+        assert_eq!(nearest(1, 0x4012), Some(("Play.loop", 2)));
+        assert_eq!(nearest(2, 0x4F00), Some(("OtherBank", 0xF00)));
+        // The bank doesn't matter in ROM0, but symbols don't carry over to another region.
+        assert_eq!(nearest(3, 0x0152), Some(("Init", 2)));
+        assert_eq!(nearest(3, 0x4000), None);
+        assert_eq!(nearest(0, 0x3FFF), Some(("Init", 0x3EAF)));
+        assert_eq!(nearest(1, 0x0100), None);
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        let err = |text| parse(text).unwrap_err();
+        assert_eq!(err("\n01:4000"), "line 2: expected `BB:AAAA name`");
+        assert_eq!(err("014000 Play"), "line 1: expected `BB:AAAA name`");
+        assert_eq!(
+            err("00:0150 Init\nzz:4000 Play"),
+            "line 2: invalid digit found in string"
+        );
+        assert_eq!(
+            err("01:10000 Play"),
+            "line 1: number too large to fit in target type"
+        );
+    }
+
+    #[test]
+    fn labels_must_be_defined_once() {
+        // E.g. from concatenating symbol files.
+        let symbols = parse("01:4000 Play\n01:4000 Play").unwrap();
+        assert_eq!(symbols.get("Play"), Some((1, 0x4000)));
+        assert_eq!(symbols.nearest(&Address(1, 0x4001)), Some(("Play", 1)));
+
+        assert_eq!(
+            parse("01:4000 Play\n00:0150 Init\n02:4000 Play").unwrap_err(),
+            "line 3: Play is already defined at 01:4000"
+        );
+
+        // Several names for the same address are fine, though.
+        let symbols = parse("01:4000 Play\n01:4000 Music_Play").unwrap();
+        assert_eq!(symbols.get("Play"), symbols.get("Music_Play"));
+    }
+}
@@ -0,0 +1,186 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with detecting that a song's tempo changed between both files, which would
+//! otherwise show up as an avalanche of differences once the write streams drift apart by a tick.
+//!
+//! Whether the driver reloads TMA or derives its tempo from counters in software, the notes it
+//! plays are what move, so their timelines are compared: the Nth note event of "after" is matched
+//! with the Nth of "before", and a line is fit through those pairs of ticks. Only triggers count as
+//! note events, since how many NRx3 writes a note takes (vibrato, slides...) can change for other
+//! reasons than tempo.
+
+use std::fmt::Display;
+
+use crate::{diff::TRIGGER_BIT, run::IoAccess};
+
+/// Fewer note events than this can't tell a tempo change apart from a few notes being moved.
+const MIN_EVENTS: usize = 16;
+/// Below this, the tempo is deemed unchanged.
+const MIN_RATE_CHANGE: f64 = 0.005;
+/// How well the fit must explain the pairs (its R²); timelines that are not related by a tempo
+/// change usually fit a line much worse than this.
+const MIN_FIT: f64 = 0.999;
+/// How many ticks off the fit any single note event may be; with enough notes, the fit can be
+/// good overall despite a few notes being shifted, e.g. by one being inserted early on.
+const MAX_RESIDUAL: f64 = 2.0;
+
+/// NR14, NR24, NR34, and NR44.
+fn is_trigger(access: &IoAccess) -> bool {
+    matches!(access.addr, 0xFF14 | 0xFF19 | 0xFF1E | 0xFF23) && access.data & TRIGGER_BIT != 0
+}
+
+/// The tick of each note event, in order; INIT's are skipped, as they don't follow the tempo.
+pub fn note_events(io_log: &[IoAccess]) -> Vec<u64> {
+    io_log
+        .iter()
+        .filter(|access| access.when.tick != 0 && is_trigger(access))
+        .map(|access| access.when.tick)
+        .collect()
+}
+
+/// "after"'s note events occur `rate` times as late as "before"'s.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Drift {
+    pub rate: f64,
+    /// The first ticks, in "before" and "after" respectively, from which both files are more than
+    /// a tick apart.
+    pub cascade_start: (u64, u64),
+}
+
+impl Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "note events in \"after\" occur ~{:.1}% {} over time (more than a tick apart from tick {} on); tempo may have changed",
+            (self.rate - 1.0).abs() * 100.0,
+            if self.rate > 1.0 { "later" } else { "earlier" },
+            self.cascade_start.1,
+        )
+    }
+}
+
+/// Least-squares fit of `after`'s note event ticks against `before`'s, matched by index. `None` if
+/// there is no consistent drift, or if it never amounts to a whole tick.
+pub fn detect(before: &[u64], after: &[u64]) -> Option<Drift> {
+    let pairs: Vec<_> = before
+        .iter()
+        .zip(after)
+        .map(|(&before, &after)| (before as f64, after as f64))
+        .collect();
+    if pairs.len() < MIN_EVENTS {
+        return None;
+    }
+
+    let len = pairs.len() as f64;
+    let mean_x = pairs.iter().map(|(x, _)| x).sum::<f64>() / len;
+    let mean_y = pairs.iter().map(|(_, y)| y).sum::<f64>() / len;
+    let (sxx, sxy, syy) = pairs
+        .iter()
+        .fold((0.0, 0.0, 0.0), |(sxx, sxy, syy), (x, y)| {
+            let (dx, dy) = (x - mean_x, y - mean_y);
+            (sxx + dx * dx, sxy + dx * dy, syy + dy * dy)
+        });
+    // All events on the same tick.
+    if sxx == 0.0 || syy == 0.0 {
+        return None;
+    }
+    let rate = sxy / sxx;
+    let offset = mean_y - rate * mean_x;
+    if (rate - 1.0).abs() < MIN_RATE_CHANGE || sxy * sxy / (sxx * syy) < MIN_FIT {
+        return None;
+    }
+
+    if pairs
+        .iter()
+        .any(|(x, y)| (rate * x + offset - y).abs() > MAX_RESIDUAL)
+    {
+        return None;
+    }
+
+    let cascade_start = before.iter().zip(after).find(|(&before, _)| {
+        let drift = rate * before as f64 + offset - before as f64;
+        drift.abs() >= 1.0
+    })?;
+    Some(Drift {
+        rate,
+        cascade_start: (*cascade_start.0, *cascade_start.1),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A note every 8 ticks.
+    fn timeline() -> Vec<u64> {
+        (1..=40).map(|i| i * 8).collect()
+    }
+
+    #[test]
+    fn constant_timelines_have_no_drift() {
+        let before = timeline();
+        assert_eq!(detect(&before, &before), None);
+        // The song starting later isn't a tempo change.
+        let late: Vec<_> = before.iter().map(|tick| tick + 3).collect();
+        assert_eq!(detect(&before, &late), None);
+        // Nor is a drift too small to ever amount to a tick.
+        let slightly_slower: Vec<_> = before
+            .iter()
+            .map(|&tick| (tick as f64 * 1.003).round() as u64)
+            .collect();
+        assert_eq!(detect(&before, &slightly_slower), None);
+        assert_eq!(detect(&[5; 20], &[7; 20]), None);
+    }
+
+    #[test]
+    fn linear_drift_is_detected() {
+        let before = timeline();
+        let slower: Vec<_> = before.iter().map(|tick| tick * 11 / 10).collect();
+        let drift = detect(&before, &slower).unwrap();
+        assert!((drift.rate - 1.1).abs() < 0.001, "{:?}", drift);
+        // 8 * 1.1 = 8.8, but the drift reaches a whole tick at 16 * 1.1 = 17.6.
+        assert_eq!(drift.cascade_start, (16, 17));
+        assert_eq!(
+            drift.to_string(),
+            "note events in \"after\" occur ~10.0% later over time (more than a tick apart from tick 17 on); tempo may have changed"
+        );
+
+        let faster: Vec<_> = before.iter().map(|tick| tick * 9 / 10).collect();
+        let drift = detect(&before, &faster).unwrap();
+        assert!((drift.rate - 0.9).abs() < 0.001, "{:?}", drift);
+
+        // Too few notes to tell.
+        assert_eq!(detect(&before[..MIN_EVENTS - 1], &slower), None);
+        assert!(detect(&before[..MIN_EVENTS], &slower).is_some());
+    }
+
+    #[test]
+    fn noise_is_tolerated_but_not_mistaken_for_drift() {
+        let before = timeline();
+        // Notes landing a tick early or late here and there, e.g. from swing.
+        let jitter = |i: usize| [0, 1, 0, -1, 1][i % 5];
+        let noisy: Vec<_> = before
+            .iter()
+            .enumerate()
+            .map(|(i, &tick)| (tick as i64 * 11 / 10 + jitter(i)) as u64)
+            .collect();
+        let drift = detect(&before, &noisy).unwrap();
+        assert!((drift.rate - 1.1).abs() < 0.01, "{:?}", drift);
+
+        let noisy: Vec<_> = before
+            .iter()
+            .enumerate()
+            .map(|(i, &tick)| (tick as i64 + jitter(i)) as u64)
+            .collect();
+        assert_eq!(detect(&before, &noisy), None);
+
+        // A note inserted early on shifts every later one by a note, which isn't a tempo change.
+        let mut inserted = before.clone();
+        inserted.insert(4, 36);
+        assert_eq!(detect(&before, &inserted[..before.len()]), None);
+    }
+}
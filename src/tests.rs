@@ -0,0 +1,588 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Tests of the command-line parsing, and of [`crate::run`] as a whole, against the GBS files in
+//! `tests/fixtures`, whose output is pinned by the files in `tests/golden` (which `tests/cli.rs`
+//! checks the binary against).
+//!
+//! Setting `GBSDIFF_BLESS` rewrites the fixtures instead of checking them.
+
+use std::{fs, path::Path};
+
+use argh::FromArgs;
+
+use crate::{
+    gbs::{Code, GbsBuilder},
+    parse_duration_arg, parse_tick_pattern,
+    report::Sink,
+    run, Address, Args, TickWindow, TimeArg, Timestamp, CYCLES_PER_SEC,
+};
+
+/// Plays a note for a few ticks, and then stays silent until the song ends.
+fn song(note: Code) -> GbsBuilder {
+    const NB_NOTE_TICKS: u8 = 4;
+    const COUNTER: u16 = 0xFF80;
+    let play = Code::default()
+        .raw(&[0xF0, COUNTER as u8]) // `ldh a, [COUNTER]`
+        .raw(&[0xFE, NB_NOTE_TICKS, 0xD0]) // `cp NB_NOTE_TICKS; ret nc`
+        .raw(&[0x3C]) // `inc a`
+        .ldh_to(COUNTER)
+        .then(note)
+        .ret();
+    GbsBuilder::default()
+        .stack_ptr(0xDFFE)
+        .init(
+            Code::default()
+                .write(0xFF26, 0x80)
+                .write(0xFF25, 0xFF)
+                .write(COUNTER, 0)
+                .ret(),
+        )
+        .play(play)
+}
+
+fn note() -> Code {
+    Code::default()
+        .write(0xFF12, 0xF0)
+        .write(0xFF13, 0x40)
+        .write(0xFF14, 0x87)
+}
+
+/// A valid file, except that its PLAY address lies past the end of it.
+fn play_out_of_file() -> Vec<u8> {
+    let mut data = song(note()).build();
+    data[10..12].copy_from_slice(&0x7000u16.to_le_bytes());
+    data
+}
+
+/// Each fixture's name and contents.
+fn fixtures() -> [(&'static str, Vec<u8>); 6] {
+    [
+        ("base", song(note()).build()),
+        (
+            "missing_write",
+            song(Code::default().write(0xFF12, 0xF0).write(0xFF14, 0x87)).build(),
+        ),
+        // A few `nop`s push the writes back by less than the default jitter.
+        (
+            "moved",
+            song(Code::default().raw(&[0; 3]).then(note())).build(),
+        ),
+        // `jr @`, so PLAY never returns.
+        ("stuck", song(Code::default().raw(&[0x18, 0xFE])).build()),
+        ("two_songs", song(note()).songs(2, 1).build()),
+        ("play_out_of_file", play_out_of_file()),
+    ]
+}
+
+fn blessing() -> bool {
+    std::env::var_os("GBSDIFF_BLESS").is_some()
+}
+
+fn fixture_path(name: &str) -> String {
+    format!("tests/fixtures/{}.gbs", name)
+}
+
+#[test]
+fn fixtures_are_up_to_date() {
+    for (name, data) in fixtures() {
+        let path = fixture_path(name);
+        if blessing() {
+            fs::create_dir_all("tests/fixtures").unwrap();
+            fs::write(&path, &data).unwrap();
+        } else {
+            let on_disk = fs::read(&path).unwrap_or_else(|err| panic!("{}: {}", path, err));
+            assert!(
+                on_disk == data,
+                "{} is out of date, run the tests with GBSDIFF_BLESS=1",
+                path
+            );
+        }
+    }
+}
+
+/// Runs with the same options as `tests/cli.rs`, returning the exit code and the output.
+fn run_captured(args: &[&str]) -> (i32, String) {
+    let mut strs = vec!["-q", "--color", "never"];
+    strs.extend(args);
+    let args = Args::from_args(&["gbsdiff"], &strs).unwrap();
+    let (sink, buffer) = Sink::buffer();
+    let exit_code = run(args, &sink);
+    let output = String::from_utf8(buffer.take()).unwrap();
+    (exit_code, output)
+}
+
+/// Writes a GBS file that only some test needs, returning its path.
+fn temp_gbs(name: &str, data: &[u8]) -> String {
+    let path =
+        std::env::temp_dir().join(format!("gbsdiff-test-{}-{}.gbs", name, std::process::id()));
+    fs::write(&path, data).unwrap();
+    path.into_os_string().into_string().unwrap()
+}
+
+fn golden(name: &str) -> String {
+    let path = Path::new("tests/golden").join(format!("{}.txt", name));
+    fs::read_to_string(&path).unwrap_or_else(|err| panic!("{}: {}", path.display(), err))
+}
+
+/// Checks `actual` against the golden file `name`, or rewrites the latter if blessing.
+pub(crate) fn assert_golden(name: &str, actual: &str) {
+    if blessing() {
+        let path = Path::new("tests/golden").join(format!("{}.txt", name));
+        fs::write(path, actual).unwrap();
+    } else {
+        assert!(
+            actual == golden(name),
+            "tests/golden/{}.txt is out of date, run the tests with GBSDIFF_BLESS=1",
+            name
+        );
+    }
+}
+
+#[test]
+fn output_goes_to_the_sink() {
+    let base = fixture_path("base");
+    for (name, after, exit_code) in [
+        ("identical", fixture_path("base"), 0),
+        ("missing_write", fixture_path("missing_write"), 1),
+    ] {
+        assert_eq!(
+            run_captured(&[&base, &after]),
+            (exit_code, golden(name)),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn input_errors_are_returned() {
+    for after in ["nonexistent", "play_out_of_file"] {
+        let (exit_code, output) = run_captured(&[&fixture_path("base"), &fixture_path(after)]);
+        assert_eq!(exit_code, 2, "{}", after);
+        assert_eq!(output, "", "{}", after);
+    }
+}
+
+#[test]
+fn tick_patterns_parse() {
+    assert_eq!(
+        parse_tick_pattern("8772,8772,4386,8772"),
+        Ok(vec![8772, 8772, 4386, 8772])
+    );
+    assert_eq!(parse_tick_pattern(" 100 , 200 "), Ok(vec![100, 200]));
+    // Slow timers make for ticks longer than 65535 cycles.
+    assert_eq!(parse_tick_pattern("131072"), Ok(vec![131072]));
+    assert_eq!(parse_tick_pattern("4294967295"), Ok(vec![u32::MAX]));
+
+    assert_eq!(
+        parse_tick_pattern(" "),
+        Err("the tick pattern cannot be empty".into())
+    );
+    assert_eq!(
+        parse_tick_pattern("100,0"),
+        Err("ticks cannot last 0 cycles".into())
+    );
+    assert_eq!(
+        parse_tick_pattern("4294967296"),
+        Err("bad cycle count \"4294967296\": number too large to fit in target type".into())
+    );
+    assert!(parse_tick_pattern("100,,200").is_err());
+    assert!(parse_tick_pattern("-1").is_err());
+}
+
+#[test]
+fn pokes_parse() {
+    use crate::{parse_addr_value_arg, sym::AddrArg};
+
+    assert_eq!(
+        parse_addr_value_arg("c000=2a"),
+        Ok((AddrArg::Addr(0xC000), 0x2A))
+    );
+    assert_eq!(
+        parse_addr_value_arg(" CAFE = FF "),
+        Ok((AddrArg::Addr(0xCAFE), 0xFF))
+    );
+    assert_eq!(
+        parse_addr_value_arg("wConfig.mode=01"),
+        Ok((AddrArg::Name("wConfig.mode".into()), 0x01))
+    );
+
+    let err = |arg| parse_addr_value_arg(arg).unwrap_err();
+    assert_eq!(err("c000"), "expected \"ADDR=VALUE\", e.g. \"CAFE=2A\"");
+    assert_eq!(
+        err("c000=100"),
+        "invalid value: number too large to fit in target type"
+    );
+    assert_eq!(
+        err("c000="),
+        "invalid value: cannot parse integer from empty string"
+    );
+    assert_eq!(
+        err("10000=00"),
+        "invalid address: \"10000\" is neither a hex address nor a symbol name"
+    );
+    assert_eq!(
+        err("w-config=00"),
+        "invalid address: \"w-config\" is neither a hex address nor a symbol name"
+    );
+
+    // Through the command line, along with `--init-regs`.
+    let args = Args::from_args(
+        &["gbsdiff"],
+        &[
+            "--poke",
+            "c000=01",
+            "--poke",
+            "c001=02",
+            "--init-regs",
+            "b=03",
+            "a.gbs",
+        ],
+    )
+    .unwrap();
+    assert_eq!(args.poke.len(), 2);
+    assert_eq!(args.init_regs.to_string(), "b=03");
+    assert!(Args::from_args(&["gbsdiff"], &["--init-regs", "z=00", "a.gbs"]).is_err());
+    assert!(Args::from_args(&["gbsdiff"], &["--poke", "c000", "a.gbs"]).is_err());
+}
+
+#[test]
+fn stub_regs_must_be_io_registers() {
+    use crate::{parse_stub_reg_arg, sym::AddrArg};
+
+    assert_eq!(
+        parse_stub_reg_arg("ff00=cf"),
+        Ok((AddrArg::Addr(0xFF00), 0xCF))
+    );
+    assert_eq!(
+        parse_stub_reg_arg("ff7f=00"),
+        Ok((AddrArg::Addr(0xFF7F), 0x00))
+    );
+    // Symbols are only checked once resolved.
+    assert_eq!(
+        parse_stub_reg_arg("rJOYP=cf"),
+        Ok((AddrArg::Name("rJOYP".into()), 0xCF))
+    );
+    assert_eq!(
+        parse_stub_reg_arg("ff80=00").unwrap_err(),
+        "$ff80 is not an I/O register"
+    );
+    assert_eq!(
+        parse_stub_reg_arg("c000=00").unwrap_err(),
+        "$c000 is not an I/O register"
+    );
+}
+
+#[test]
+fn durations_parse() {
+    let ok = |arg| parse_duration_arg(arg).unwrap();
+    // Plain numbers are still seconds.
+    assert_eq!(ok("90"), TimeArg::Millis(90_000));
+    assert_eq!(ok(" 0 "), TimeArg::Millis(0));
+    assert_eq!(ok("1:30"), TimeArg::Millis(90_000));
+    assert_eq!(ok("0:05"), TimeArg::Millis(5000));
+    assert_eq!(ok("90s"), TimeArg::Millis(90_000));
+    assert_eq!(ok("2m"), TimeArg::Millis(120_000));
+    assert_eq!(ok("1m30s"), TimeArg::Millis(90_000));
+    assert_eq!(ok("1m500ms"), TimeArg::Millis(60_500));
+    assert_eq!(ok("1s500ms"), TimeArg::Millis(1500));
+    assert_eq!(ok("500ms"), TimeArg::Millis(500));
+    assert_eq!(ok("123456c"), TimeArg::Cycles(123_456));
+    assert_eq!(ok("0c"), TimeArg::Cycles(0));
+    assert_eq!(ok("18446744073709551615c"), TimeArg::Cycles(u64::MAX));
+
+    let err = |arg| parse_duration_arg(arg).unwrap_err();
+    assert_eq!(err("-5"), "durations cannot be negative");
+    assert_eq!(err("-5c"), "durations cannot be negative");
+    assert!(err("2.5s").contains("fractions are not supported"));
+    assert!(err("1,5").contains("fractions are not supported"));
+    assert_eq!(
+        err("c"),
+        "invalid duration \"c\": cannot parse integer from empty string"
+    );
+    assert_eq!(
+        err("1m30c"),
+        "invalid duration \"1m30c\": invalid digit found in string"
+    );
+    assert_eq!(
+        err("10cc"),
+        "invalid duration \"10cc\": invalid digit found in string"
+    );
+    assert_eq!(
+        err("18446744073709551616c"),
+        "invalid duration \"18446744073709551616c\": number too large to fit in target type"
+    );
+    assert_eq!(
+        err("1:60"),
+        "expected seconds to be less than 60 in \"MM:SS\""
+    );
+    assert_eq!(
+        err("1h"),
+        "invalid duration \"1h\": unknown unit \"h\" (expected `m`, `s`, `ms`, or a lone `c`)"
+    );
+    assert_eq!(
+        err("ms"),
+        "invalid duration \"ms\": missing number before `ms`"
+    );
+    assert_eq!(
+        err("30s1m"),
+        "invalid duration \"30s1m\": units must go from largest to smallest, each once"
+    );
+    assert_eq!(
+        err("1s1s"),
+        "invalid duration \"1s1s\": units must go from largest to smallest, each once"
+    );
+    assert_eq!(
+        err("18446744073709551615"),
+        "duration \"18446744073709551615\" is too long"
+    );
+    assert_eq!(
+        err("18446744073709551615m"),
+        "duration \"18446744073709551615m\" is too long"
+    );
+    assert!(err("").contains("cannot parse integer from empty string"));
+}
+
+#[test]
+fn durations_convert_to_cycles() {
+    let single_speed = u64::from(CYCLES_PER_SEC);
+    assert_eq!(
+        TimeArg::Millis(1500).cycles(single_speed),
+        Some(single_speed * 3 / 2)
+    );
+    // Two minutes at double speed still fit in the simulator's `u32`s, but not much more does.
+    let two_minutes = TimeArg::Millis(120_000).cycles(single_speed * 2).unwrap();
+    assert_eq!(two_minutes, 120 * 2 * single_speed);
+    assert!(u32::try_from(two_minutes).is_ok());
+    assert!(u32::try_from(
+        TimeArg::Millis(40 * 60_000)
+            .cycles(single_speed * 2)
+            .unwrap()
+    )
+    .is_err());
+    // Cycles don't depend on the CPU speed.
+    assert_eq!(TimeArg::Cycles(1234).cycles(single_speed * 2), Some(1234));
+    assert_eq!(TimeArg::Millis(u64::MAX).cycles(single_speed), None);
+}
+
+#[test]
+fn skipped_ticks_are_cut_out_of_compared_logs() {
+    let log: Vec<_> = (0..6)
+        .map(|tick| run::IoAccess {
+            when: Timestamp { tick, cycle: 0 },
+            pc: Address(0, 0x4000),
+            addr: 0xFF12,
+            data: 0,
+        })
+        .collect();
+    let compared_ticks = |compared, skipped| -> Vec<_> {
+        let window = TickWindow {
+            compared,
+            reported: 0..u64::MAX,
+            skipped,
+            truncated: false,
+            nb_ticks: 6,
+        };
+        window
+            .compared_log(&log)
+            .iter()
+            .map(|access| access.when.tick)
+            .collect()
+    };
+    // Nothing skipped.
+    assert_eq!(compared_ticks(0..6, 1..1), [0, 1, 2, 3, 4, 5]);
+    // `--skip-ticks 2` keeps INIT, unless `--skip-init` is given too.
+    assert_eq!(compared_ticks(0..6, 1..3), [0, 3, 4, 5]);
+    assert_eq!(compared_ticks(0..6, 0..3), [3, 4, 5]);
+    // `--from` past the skipped ticks.
+    assert_eq!(compared_ticks(4..6, 1..3), [4, 5]);
+    // Skipping everything that was simulated, and more.
+    assert_eq!(compared_ticks(0..6, 1..100), [0]);
+    assert_eq!(compared_ticks(0..6, 0..100), []);
+}
+
+#[test]
+fn indirect_findings_are_all_counted() {
+    let (exit_code, output) = run_captured(&[
+        &fixture_path("base"),
+        &fixture_path("missing_write"),
+        "--focus-bank",
+        "5",
+        "--max-reports",
+        "1",
+        "--max-total-reports",
+        "1",
+    ]);
+    assert_eq!(exit_code, 1);
+    assert_eq!(
+        output,
+        "--- Direct findings ---\n--- Indirect findings: 4 ---\nNR13: 4\nFailing song: 1\n"
+    );
+}
+
+#[test]
+fn write_pair_stats_come_with_grown_gaps() {
+    let dir = std::env::temp_dir().join(format!("gbsdiff-test-pairs-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let slow_note = Code::default()
+        .write(0xFF12, 0xF0)
+        .write(0xFF13, 0x40)
+        .raw(&[0; 40])
+        .write(0xFF14, 0x87);
+    let paths = [("base", note()), ("slow", slow_note)].map(|(name, note)| {
+        let path = dir.join(format!("{}.gbs", name));
+        fs::write(&path, song(note).build()).unwrap();
+        path.into_os_string().into_string().unwrap()
+    });
+    let run_verbose = |before: &str, after: &str| {
+        let args = Args::from_args(&["gbsdiff"], &["--color", "never", before, after]).unwrap();
+        let (sink, buffer) = Sink::buffer();
+        run(args, &sink);
+        String::from_utf8(buffer.take()).unwrap()
+    };
+
+    let output = run_verbose(&paths[0], &paths[0]);
+    assert!(!output.contains("Vulnerable write pairs"), "{}", output);
+    let output = run_verbose(&paths[0], &paths[1]);
+    assert!(
+        output.contains("Vulnerable write pairs (before): none\n"),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains("Vulnerable write pairs (after):  4, worst is NR13→NR14 spanning"),
+        "{}",
+        output
+    );
+    assert!(output.contains("NR13→NR14 gap grew from"), "{}", output);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn partial_windows_are_told_apart() {
+    let window = |compared| TickWindow {
+        compared,
+        reported: 0..u64::MAX,
+        skipped: 1..1,
+        truncated: false,
+        nb_ticks: 10,
+    };
+    assert!(!window(0..10).is_partial());
+    // `--from`, or resuming from a snapshot.
+    assert!(window(3..10).is_partial());
+    // `--to`, unless it lies past the end.
+    assert!(window(0..5).is_partial());
+    assert!(window(2..5).is_partial());
+}
+
+#[test]
+fn song_ids_past_255_are_rejected() {
+    let path = temp_gbs("song-overflow", &song(note()).songs(255, 255).build());
+    assert_eq!(run_captured(&[&path]), (2, String::new()));
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn who_writes_rejects_song_ids_past_255() {
+    let path = temp_gbs("who-writes-overflow", &song(note()).songs(255, 255).build());
+    assert_eq!(run_captured(&["--who-writes", &path]), (2, String::new()));
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn gzipped_files_are_read() {
+    let base = fixture_path("base");
+    let gz = temp_gbs("gzipped", &crate::gzip::store(&fs::read(&base).unwrap()));
+    assert_eq!(run_captured(&[&base, &gz]), (0, golden("identical")));
+
+    let mut corrupted = fs::read(&gz).unwrap();
+    corrupted[20] ^= 0x55;
+    fs::write(&gz, corrupted).unwrap();
+    assert_eq!(run_captured(&[&base, &gz]), (2, String::new()));
+    fs::remove_file(gz).unwrap();
+}
+
+#[test]
+fn csv_files_are_named_after_both_songs() {
+    let dir = std::env::temp_dir().join(format!("gbsdiff-test-csv-{}", std::process::id()));
+    let path = temp_gbs("csv", &song(note()).songs(2, 1).build());
+    let dir_str = dir.to_str().unwrap();
+    let (exit_code, _) = run_captured(&[
+        "--csv",
+        dir_str,
+        "--before-song",
+        "1",
+        "--after-song",
+        "2",
+        &path,
+        &path,
+    ]);
+    assert_eq!(exit_code, 0);
+    let mut names: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        ["diff-song-1-2.csv", "song-1-before.csv", "song-2-after.csv"]
+    );
+    fs::remove_dir_all(dir).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn stack_growth_is_warned_about() {
+    let pushy = note().raw(&[0xC5, 0xD5, 0xD1, 0xC1]); // `push bc; push de; pop de; pop bc`
+    let paths = [("stack-base", note()), ("stack-pushy", pushy)]
+        .map(|(name, note)| temp_gbs(name, &song(note).build()));
+    let run_verbose = |args: &[&str]| {
+        let mut strs = vec!["--color", "never"];
+        strs.extend(args);
+        let args = Args::from_args(&["gbsdiff"], &strs).unwrap();
+        let (sink, buffer) = Sink::buffer();
+        run(args, &sink);
+        String::from_utf8(buffer.take()).unwrap()
+    };
+
+    let output = run_verbose(&[&paths[0], &paths[1]]);
+    assert!(
+        output.contains("Max stack usage: 0 bytes (before) vs 4 bytes (after)"),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains("stack usage grew by 4 bytes (0 -> 4)"),
+        "{}",
+        output
+    );
+    let output = run_verbose(&["--stack-growth-threshold", "4", &paths[0], &paths[1]]);
+    assert!(!output.contains("stack usage grew"), "{}", output);
+    for path in paths {
+        fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn addresses_are_labelled_with_their_region() {
+    let fmt = |bank, addr| format!("{:x}", Address(bank, addr));
+    assert_eq!(fmt(0, 0x0150), "00:0150");
+    assert_eq!(fmt(3, 0x4000), "03:4000");
+    // The ROM bank is meaningless outside of ROMX.
+    assert_eq!(fmt(3, 0x3FFF), "00:3fff");
+    assert_eq!(fmt(3, 0x8000), "8000 (VRAM)");
+    assert_eq!(fmt(3, 0xA123), "a123 (SRAM)");
+    assert_eq!(fmt(3, 0xC123), "c123 (WRAM)");
+    // But the WRAM bank is meaningful in WRAMX.
+    assert_eq!(fmt(3, 0xD123), "03:d123 (WRAM)");
+    assert_eq!(fmt(3, 0xE123), "e123 (echo RAM)");
+    assert_eq!(fmt(3, 0xFE00), "fe00 (OAM)");
+    assert_eq!(fmt(3, 0xFEA0), "fea0 (unusable memory)");
+    assert_eq!(fmt(3, 0xFF26), "ff26 (I/O)");
+    assert_eq!(fmt(3, 0xFF80), "ff80 (HRAM)");
+    assert_eq!(fmt(3, 0xFFFF), "ffff (I/O)");
+}
@@ -0,0 +1,121 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with measuring what a run did, and how fast, for the statistics printed at
+//! its end.
+//!
+//! Only songs that were actually simulated are measured; those loaded from the cache are merely
+//! counted.
+
+use std::{fmt::Display, time::Duration};
+
+use crate::{run::Logbook, SongIDs};
+
+/// Abbreviates a count with a metric prefix, e.g. `12.3M`.
+pub fn abbreviate(count: u64) -> String {
+    const PREFIXES: [(f64, &str); 4] = [(1e12, "T"), (1e9, "G"), (1e6, "M"), (1e3, "k")];
+
+    PREFIXES
+        .iter()
+        .find(|(scale, _)| count as f64 >= *scale)
+        .map_or_else(
+            || count.to_string(),
+            |(scale, prefix)| format!("{:.1}{}", count as f64 / scale, prefix),
+        )
+}
+
+/// Always in seconds, so that durations line up.
+pub fn fmt_duration(duration: Duration) -> String {
+    format!("{:.3}s", duration.as_secs_f64())
+}
+
+/// What was simulated for one of the two files.
+#[derive(Debug, Default)]
+pub struct SideStats {
+    pub nb_songs: usize,
+    /// CPU cycles actually executed, which excludes the CPU idling between ticks.
+    pub cycles: u64,
+    /// How long the simulated songs last, in seconds of real hardware time.
+    pub song_secs: f64,
+    pub nb_writes: usize,
+    pub time: Duration,
+}
+
+impl SideStats {
+    /// `song_secs` must account for the file's timer and CPU speed.
+    pub fn add(&mut self, logbook: &Logbook, song_secs: f64, time: Duration) {
+        self.nb_songs += 1;
+        self.cycles += logbook.total_cycles;
+        self.song_secs += song_secs;
+        self.nb_writes += logbook.io_log.len();
+        self.time += time;
+    }
+}
+
+impl Display for SideStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} songs, {} cycles, {} IO writes, {:.1}s of audio in {}",
+            self.nb_songs,
+            abbreviate(self.cycles),
+            abbreviate(self.nb_writes as u64),
+            self.song_secs,
+            fmt_duration(self.time),
+        )?;
+        let secs = self.time.as_secs_f64();
+        if secs != 0.0 {
+            write!(f, " ({:.1}x real time)", self.song_secs / secs)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct RunStats {
+    pub before: SideStats,
+    pub after: SideStats,
+    pub nb_cached: usize,
+    /// How long each song took, simulation and comparison included.
+    pub songs: Vec<(SongIDs, Duration)>,
+    pub total: Duration,
+    /// The most differences reported for a single song.
+    pub peak_diagnostics: usize,
+}
+
+impl RunStats {
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("Simulated (before): {}", self.before),
+            format!("Simulated (after):  {}", self.after),
+        ];
+        if self.nb_cached != 0 {
+            lines.push(format!(
+                "Loaded from the cache (not measured): {} songs",
+                self.nb_cached
+            ));
+        }
+        let width = self
+            .songs
+            .iter()
+            .map(|(songs, _)| songs.to_string().len())
+            .max()
+            .unwrap_or(0);
+        lines.extend(self.songs.iter().map(|(songs, time)| {
+            format!(
+                "Songs {:<width$}  {:>10}",
+                songs.to_string(),
+                fmt_duration(*time)
+            )
+        }));
+        lines.push(format!(
+            "Total: {}, with at most {} differences in a song",
+            fmt_duration(self.total),
+            self.peak_diagnostics
+        ));
+        lines
+    }
+}
@@ -0,0 +1,74 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with laying out the writes of a tick from both files next to each other.
+
+use crate::{
+    diff::{self, RegDispl},
+    run::{self, IoAccess},
+    DiagnosticLevel,
+};
+
+/// A row of the transcript, with both cells already padded to their column's width.
+#[derive(Debug)]
+pub struct Row {
+    pub before: String,
+    pub after: String,
+    /// Set if the two cells differ.
+    pub level: Option<DiagnosticLevel>,
+}
+
+fn cell(access: &IoAccess, reg_width: usize, cycle_width: usize) -> String {
+    format!(
+        "{:>cycle_width$} {:<reg_width$} ${:02x}",
+        access.when.cycle,
+        RegDispl(access.addr).to_string(),
+        access.data,
+    )
+}
+
+/// The rows for the given tick, starting with a header; empty if neither side wrote anything then.
+pub fn rows(before_log: &[IoAccess], after_log: &[IoAccess], tick: u64, jitter: u16) -> Vec<Row> {
+    let ticks = tick..tick + 1;
+    let logs = (
+        run::slice_ticks(before_log, &ticks),
+        run::slice_ticks(after_log, &ticks),
+    );
+    if logs.0.is_empty() && logs.1.is_empty() {
+        return Vec::new();
+    }
+
+    let accesses = || logs.0.iter().chain(logs.1);
+    let reg_width = accesses()
+        .map(|access| RegDispl(access.addr).to_string().len())
+        .max()
+        .unwrap_or(0);
+    let cycle_width = accesses()
+        .map(|access| access.when.cycle.to_string().len())
+        .max()
+        .unwrap_or(0);
+    // The cycle, the register, and the value, separated by spaces.
+    let width = (cycle_width + 1 + reg_width + 1 + 3).max("before".len());
+
+    let mut rows = vec![Row {
+        before: format!("{:<width$}", "before"),
+        after: "after".to_string(),
+        level: None,
+    }];
+    rows.extend(diff::align(logs.0, logs.1, jitter).into_iter().map(|row| {
+        Row {
+            before: row.before.map_or_else(
+                || " ".repeat(width),
+                |access| cell(access, reg_width, cycle_width),
+            ),
+            after: row
+                .after
+                .map_or_else(String::new, |access| cell(access, reg_width, cycle_width)),
+            level: row.level,
+        }
+    }));
+    rows
+}
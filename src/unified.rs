@@ -0,0 +1,443 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with exporting the differences as a unified diff (`--unified`), between
+//! listings of each file's writes, so that they can be read with the usual patch tooling.
+//!
+//! The diff is computed with Myers's linear-space algorithm, which finds a shortest edit script
+//! without having to store every step of its search.
+
+use std::{fmt::Write, ops::Range};
+
+use crate::{diff::RegDispl, run::IoAccess};
+
+/// How many unchanged lines surround each hunk, like `diff -u`'s default.
+const CONTEXT: usize = 3;
+
+/// The writes that have a counterpart within `jitter` cycles (same tick, register, and value) on
+/// the other side, for each side; they are rendered with a `~` instead of their cycle, so that
+/// the jitter doesn't show up in the diff.
+pub fn fuzz(logs: (&[IoAccess], &[IoAccess]), jitter: u16) -> (Vec<bool>, Vec<bool>) {
+    let mut fuzzy = (vec![false; logs.0.len()], vec![false; logs.1.len()]);
+    let mut start = 0;
+    for (i, before) in logs.0.iter().enumerate() {
+        // Both logs are sorted, so the candidates' range only ever moves forward.
+        while logs
+            .1
+            .get(start)
+            .is_some_and(|after| after.when.tick < before.when.tick)
+        {
+            start += 1;
+        }
+        let partner = logs.1[start..]
+            .iter()
+            .take_while(|after| after.when.tick == before.when.tick)
+            .enumerate()
+            .position(|(j, after)| {
+                !fuzzy.1[start + j]
+                    && after.addr == before.addr
+                    && after.data == before.data
+                    && after.when.cycle.abs_diff(before.when.cycle) < u32::from(jitter)
+            });
+        if let Some(j) = partner {
+            fuzzy.0[i] = true;
+            fuzzy.1[start + j] = true;
+        }
+    }
+    fuzzy
+}
+
+/// One line per write, under a header for each tick with any; `fuzzy` is either empty or one of
+/// [`fuzz`]'s results.
+pub fn render(log: &[IoAccess], fuzzy: &[bool]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut tick = None;
+    for (i, access) in log.iter().enumerate() {
+        if tick != Some(access.when.tick) {
+            tick = Some(access.when.tick);
+            lines.push(format!("==== tick {} ====", access.when.tick));
+        }
+        let mut line = String::from("  ");
+        if fuzzy.get(i).copied().unwrap_or(false) {
+            line.push('~');
+        } else {
+            write!(line, "{}", access.when.cycle).unwrap();
+        }
+        write!(line, " {}=${:02x}", RegDispl(access.addr), access.data).unwrap();
+        lines.push(line);
+    }
+    lines
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// A shortest edit script turning `before` into `after`, one op per line (of either side).
+fn edit_script<T: PartialEq>(before: &[T], after: &[T]) -> Vec<Op> {
+    let mut ops = Vec::with_capacity(before.len().max(after.len()));
+    diff_range(before, after, &mut ops);
+    ops
+}
+
+fn diff_range<T: PartialEq>(before: &[T], after: &[T], ops: &mut Vec<Op>) {
+    // Trimming the common ends first keeps the search down to the part that actually changed.
+    let prefix = before
+        .iter()
+        .zip(after)
+        .take_while(|(before, after)| before == after)
+        .count();
+    let suffix = before[prefix..]
+        .iter()
+        .rev()
+        .zip(after[prefix..].iter().rev())
+        .take_while(|(before, after)| before == after)
+        .count();
+    let (middle_before, middle_after) = (
+        &before[prefix..before.len() - suffix],
+        &after[prefix..after.len() - suffix],
+    );
+
+    ops.extend(std::iter::repeat(Op::Equal).take(prefix));
+    if middle_before.is_empty() || middle_after.is_empty() {
+        ops.extend(std::iter::repeat(Op::Delete).take(middle_before.len()));
+        ops.extend(std::iter::repeat(Op::Insert).take(middle_after.len()));
+    } else {
+        match middle_snake(middle_before, middle_after) {
+            Some((x, y)) => {
+                diff_range(&middle_before[..x], &middle_after[..y], ops);
+                diff_range(&middle_before[x..], &middle_after[y..], ops);
+            }
+            // Nothing in common at all.
+            None => {
+                ops.extend(std::iter::repeat(Op::Delete).take(middle_before.len()));
+                ops.extend(std::iter::repeat(Op::Insert).take(middle_after.len()));
+            }
+        }
+    }
+    ops.extend(std::iter::repeat(Op::Equal).take(suffix));
+}
+
+/// Searches for the shortest edit script from both ends at once, and returns where the two
+/// searches meet, which splits the problem in two halves that are still part of a shortest script.
+///
+/// `x` indexes `before`, `y` indexes `after`, and diagonal `k` is where `x - y == k`; the forward
+/// search's furthest `x` on each diagonal is stored in `forward`, and the backward one's (counted
+/// from the ends) in `backward`.
+fn middle_snake<T: PartialEq>(before: &[T], after: &[T]) -> Option<(usize, usize)> {
+    let (n, m) = (before.len() as isize, after.len() as isize);
+    let max_d = (n + m + 1) / 2;
+    let offset = max_d;
+    let len = 2 * max_d + 2;
+    let mut forward = vec![-1; len as usize];
+    let mut backward = vec![-1; len as usize];
+    forward[offset as usize + 1] = 0;
+    backward[offset as usize + 1] = 0;
+    let delta = n - m;
+    // If the delta is odd, the forward search is the one that completes the overlap.
+    let front = delta % 2 != 0;
+    // Diagonals that went past the edges don't need to be searched anymore.
+    let (mut k1_start, mut k1_end, mut k2_start, mut k2_end) = (0, 0, 0, 0);
+
+    for d in 0..max_d {
+        for k1 in (-d + k1_start..=d - k1_end).step_by(2) {
+            let k1_offset = (offset + k1) as usize;
+            let mut x1 = if k1 == -d || (k1 != d && forward[k1_offset - 1] < forward[k1_offset + 1])
+            {
+                forward[k1_offset + 1]
+            } else {
+                forward[k1_offset - 1] + 1
+            };
+            let mut y1 = x1 - k1;
+            while x1 < n && y1 < m && before[x1 as usize] == after[y1 as usize] {
+                x1 += 1;
+                y1 += 1;
+            }
+            forward[k1_offset] = x1;
+            if x1 > n {
+                k1_end += 2;
+            } else if y1 > m {
+                k1_start += 2;
+            } else if front {
+                let k2_offset = offset + delta - k1;
+                if (0..len).contains(&k2_offset) && backward[k2_offset as usize] != -1 {
+                    let x2 = n - backward[k2_offset as usize];
+                    if x1 >= x2 {
+                        return Some((x1 as usize, y1 as usize));
+                    }
+                }
+            }
+        }
+
+        for k2 in (-d + k2_start..=d - k2_end).step_by(2) {
+            let k2_offset = (offset + k2) as usize;
+            let mut x2 =
+                if k2 == -d || (k2 != d && backward[k2_offset - 1] < backward[k2_offset + 1]) {
+                    backward[k2_offset + 1]
+                } else {
+                    backward[k2_offset - 1] + 1
+                };
+            let mut y2 = x2 - k2;
+            while x2 < n && y2 < m && before[(n - x2 - 1) as usize] == after[(m - y2 - 1) as usize]
+            {
+                x2 += 1;
+                y2 += 1;
+            }
+            backward[k2_offset] = x2;
+            if x2 > n {
+                k2_end += 2;
+            } else if y2 > m {
+                k2_start += 2;
+            } else if !front {
+                let k1_offset = offset + delta - k2;
+                if (0..len).contains(&k1_offset) && forward[k1_offset as usize] != -1 {
+                    let x1 = forward[k1_offset as usize];
+                    let y1 = offset + x1 - k1_offset;
+                    if x1 >= n - x2 {
+                        return Some((x1 as usize, y1 as usize));
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+/// The `start,len` part of a hunk header, where an empty range is given by the line before it.
+fn hunk_range(lines: &Range<usize>) -> String {
+    match lines.len() {
+        0 => format!("{},0", lines.start),
+        1 => format!("{}", lines.start + 1),
+        len => format!("{},{}", lines.start + 1, len),
+    }
+}
+
+/// A unified diff between both listings, or an empty string if they are the same; `names` go in
+/// the `---` and `+++` lines.
+pub fn unified_diff(before: &[String], after: &[String], names: (&str, &str)) -> String {
+    let ops = edit_script(before, after);
+    // Each op, with the line it is at on either side.
+    let mut positions = Vec::with_capacity(ops.len());
+    let (mut i, mut j) = (0, 0);
+    for &op in &ops {
+        positions.push((op, i, j));
+        match op {
+            Op::Equal => {
+                i += 1;
+                j += 1;
+            }
+            Op::Delete => i += 1,
+            Op::Insert => j += 1,
+        }
+    }
+
+    // Group the changes whose contexts touch or overlap into hunks, as ranges of ops.
+    let mut hunks: Vec<Range<usize>> = Vec::new();
+    for (idx, &(op, ..)) in positions.iter().enumerate() {
+        if op == Op::Equal {
+            continue;
+        }
+        let start = idx.saturating_sub(CONTEXT);
+        let end = (idx + 1 + CONTEXT).min(positions.len());
+        match hunks.last_mut() {
+            Some(hunk) if hunk.end >= start => hunk.end = end,
+            _ => hunks.push(start..end),
+        }
+    }
+    if hunks.is_empty() {
+        return String::new();
+    }
+
+    let mut text = format!("--- {}\n+++ {}\n", names.0, names.1);
+    for hunk in hunks {
+        let ops = &positions[hunk];
+        let (_, before_start, after_start) = ops[0];
+        let before_len = ops.iter().filter(|(op, ..)| *op != Op::Insert).count();
+        let after_len = ops.iter().filter(|(op, ..)| *op != Op::Delete).count();
+        writeln!(
+            text,
+            "@@ -{} +{} @@",
+            hunk_range(&(before_start..before_start + before_len)),
+            hunk_range(&(after_start..after_start + after_len)),
+        )
+        .unwrap();
+        for &(op, i, j) in ops {
+            match op {
+                Op::Equal => writeln!(text, " {}", before[i]),
+                Op::Delete => writeln!(text, "-{}", before[i]),
+                Op::Insert => writeln!(text, "+{}", after[j]),
+            }
+            .unwrap();
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A small xorshift, so that the random inputs are the same on every run.
+    fn random_lines(seed: &mut u32, len: usize, alphabet: u32) -> Vec<u32> {
+        (0..len)
+            .map(|_| {
+                *seed ^= *seed << 13;
+                *seed ^= *seed >> 17;
+                *seed ^= *seed << 5;
+                *seed % alphabet
+            })
+            .collect()
+    }
+
+    fn lcs_len<T: PartialEq>(before: &[T], after: &[T]) -> usize {
+        let mut row = vec![0; after.len() + 1];
+        for x in before {
+            let mut diag = 0;
+            for (j, y) in after.iter().enumerate() {
+                let above = row[j + 1];
+                row[j + 1] = if x == y {
+                    diag + 1
+                } else {
+                    row[j + 1].max(row[j])
+                };
+                diag = above;
+            }
+        }
+        row[after.len()]
+    }
+
+    /// Applies a patch produced by [`unified_diff`] the way patch(1) would, checking the context.
+    fn apply(before: &[String], patch: &str) -> Vec<String> {
+        let parse_start = |range: &str| -> usize {
+            let (start, len) = range.split_once(',').unwrap_or((range, "1"));
+            let start: usize = start.parse().unwrap();
+            // Empty ranges are given by the line before them.
+            if len == "0" {
+                start
+            } else {
+                start - 1
+            }
+        };
+        let mut lines = patch.lines().skip(2).peekable();
+        let mut result = Vec::new();
+        let mut i = 0;
+        while let Some(header) = lines.next() {
+            let ranges = header
+                .strip_prefix("@@ -")
+                .and_then(|header| header.strip_suffix(" @@"))
+                .unwrap();
+            let (before_range, _) = ranges.split_once(" +").unwrap();
+            let start = parse_start(before_range);
+            result.extend_from_slice(&before[i..start]);
+            i = start;
+            while let Some(line) = lines.next_if(|line| !line.starts_with("@@")) {
+                let (op, text) = line.split_at(1);
+                match op {
+                    " " => {
+                        assert_eq!(before[i], text);
+                        result.push(text.to_string());
+                        i += 1;
+                    }
+                    "-" => {
+                        assert_eq!(before[i], text);
+                        i += 1;
+                    }
+                    "+" => result.push(text.to_string()),
+                    _ => panic!("bad patch line {:?}", line),
+                }
+            }
+        }
+        result.extend_from_slice(&before[i..]);
+        result
+    }
+
+    #[test]
+    fn edit_scripts_are_shortest() {
+        let mut seed = 0x2a2a_2a2a;
+        for _ in 0..500 {
+            let before = random_lines(&mut seed, 12, 4);
+            let len = (seed % 16) as usize;
+            let after = random_lines(&mut seed, len, 4);
+            let ops = edit_script(&before, &after);
+
+            // The script must turn `before` into `after`...
+            let (mut i, mut j) = (0, 0);
+            for op in &ops {
+                match op {
+                    Op::Equal => {
+                        assert_eq!(before[i], after[j]);
+                        i += 1;
+                        j += 1;
+                    }
+                    Op::Delete => i += 1,
+                    Op::Insert => j += 1,
+                }
+            }
+            assert_eq!((i, j), (before.len(), after.len()));
+            // ...while keeping as many lines as possible.
+            let nb_equal = ops.iter().filter(|&&op| op == Op::Equal).count();
+            assert_eq!(
+                nb_equal,
+                lcs_len(&before, &after),
+                "{before:?} -> {after:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn patches_apply() {
+        let mut seed = 0x1337;
+        for _ in 0..200 {
+            let to_lines =
+                |lines: Vec<u32>| -> Vec<String> { lines.iter().map(u32::to_string).collect() };
+            let before = to_lines(random_lines(&mut seed, 30, 3));
+            let len = (seed % 40) as usize;
+            let after = to_lines(random_lines(&mut seed, len, 3));
+            let patch = unified_diff(&before, &after, ("a", "b"));
+            if before == after {
+                assert!(patch.is_empty());
+            } else {
+                assert_eq!(apply(&before, &patch), after);
+            }
+        }
+    }
+
+    #[test]
+    fn hunk_format() {
+        let lines = |text: &str| -> Vec<String> { text.split(' ').map(String::from).collect() };
+        let patch = unified_diff(
+            &lines("a b c d e f g h i j k l"),
+            &lines("a b c d X f g h i j k l m"),
+            ("before", "after"),
+        );
+        assert_eq!(
+            patch,
+            "--- before\n+++ after\n\
+             @@ -2,7 +2,7 @@\n b\n c\n d\n-e\n+X\n f\n g\n h\n\
+             @@ -10,3 +10,4 @@\n j\n k\n l\n+m\n",
+        );
+        // Contexts that touch merge the hunks, like `diff -u` does.
+        assert_eq!(
+            unified_diff(
+                &lines("a b c d e f g h i j k"),
+                &lines("a b c d X f g h i j k l"),
+                ("before", "after"),
+            )
+            .lines()
+            .filter(|line| line.starts_with("@@"))
+            .collect::<Vec<_>>(),
+            ["@@ -2,10 +2,11 @@"],
+        );
+        // Removing everything leaves an empty range, which is given by the line before it.
+        assert_eq!(
+            unified_diff(&lines("a"), &[], ("before", "after")),
+            "--- before\n+++ after\n@@ -1 +0,0 @@\n-a\n",
+        );
+    }
+}
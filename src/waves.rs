@@ -0,0 +1,541 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with the waveforms played by channel 3.
+//!
+//! What matters is not when wave RAM is written, but what it contains when the channel is triggered.
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use gb_cpu_sim::reg::HwReg;
+
+use crate::{fnv1a, run::IoAccess, Timestamp};
+
+pub type Waveform = [u8; 16];
+
+pub const WAVE_RAM: std::ops::RangeInclusive<u16> = HwReg::Wave0 as u16..=HwReg::WaveF as u16;
+
+/// The contents of wave RAM when channel 3 was triggered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TriggerSnapshot {
+    pub when: Timestamp,
+    pub wave: Waveform,
+}
+
+/// Reconstructs the contents of wave RAM at every CH3 trigger in the log.
+pub fn trigger_snapshots(io_log: &[IoAccess]) -> Vec<TriggerSnapshot> {
+    // The simulated APU starts with wave RAM cleared.
+    let mut wave = Waveform::default();
+    io_log
+        .iter()
+        .filter_map(|access| {
+            if WAVE_RAM.contains(&access.addr) {
+                wave[usize::from(access.addr - WAVE_RAM.start())] = access.data;
+                None
+            } else if access.addr == HwReg::Nr34 as u16 && access.data & 0x80 != 0 {
+                Some(TriggerSnapshot {
+                    when: access.when.clone(),
+                    wave,
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// A short, stable identifier for a waveform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaveHash<'a>(pub &'a Waveform);
+
+impl Display for WaveHash<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:06x}", fnv1a(self.0) >> 40)
+    }
+}
+
+/// Renders a waveform as hex bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct WaveHex<'a>(pub &'a Waveform);
+
+impl Display for WaveHex<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Renders a waveform's 32 samples as a bar chart.
+#[derive(Debug, Clone, Copy)]
+pub struct WaveBars<'a>(pub &'a Waveform);
+
+impl Display for WaveBars<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+        for byte in self.0 {
+            for sample in [byte >> 4, byte & 0xF] {
+                write!(f, "{}", BARS[usize::from(sample >> 1)])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Every waveform played in a song, with how many times it was triggered.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Inventory(pub BTreeMap<Waveform, usize>);
+
+impl Inventory {
+    pub fn new(snapshots: &[TriggerSnapshot]) -> Self {
+        let mut inventory = BTreeMap::new();
+        for snapshot in snapshots {
+            *inventory.entry(snapshot.wave).or_default() += 1;
+        }
+        Self(inventory)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn count(&self, wave: &Waveform) -> usize {
+        self.0.get(wave).copied().unwrap_or(0)
+    }
+}
+
+impl Display for Inventory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "none");
+        }
+        for (i, (wave, count)) in self.0.iter().enumerate() {
+            if i != 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} ×{}", WaveHash(wave), count)?;
+        }
+        Ok(())
+    }
+}
+
+/// How two inventories differ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InventoryDiff<'a> {
+    Same,
+    /// Some waveforms are only played by one of the builds.
+    DifferentWaves {
+        before_only: Vec<&'a Waveform>,
+        after_only: Vec<&'a Waveform>,
+    },
+    /// The same waveforms are played, but some are triggered markedly more or less often.
+    DifferentUsage(Vec<(&'a Waveform, usize, usize)>),
+}
+
+/// Usage counts differing by more than this fraction (in percent) are reported.
+const USAGE_TOLERANCE_PERCENT: usize = 25;
+
+pub fn compare<'a>(before: &'a Inventory, after: &'a Inventory) -> InventoryDiff<'a> {
+    let before_only: Vec<_> = before
+        .0
+        .keys()
+        .filter(|wave| after.count(wave) == 0)
+        .collect();
+    let after_only: Vec<_> = after
+        .0
+        .keys()
+        .filter(|wave| before.count(wave) == 0)
+        .collect();
+    if !before_only.is_empty() || !after_only.is_empty() {
+        return InventoryDiff::DifferentWaves {
+            before_only,
+            after_only,
+        };
+    }
+
+    let usage: Vec<_> = before
+        .0
+        .iter()
+        .map(|(wave, &count)| (wave, count, after.count(wave)))
+        .filter(|(_, before, after)| {
+            before.abs_diff(*after) * 100 > before.max(after) * USAGE_TOLERANCE_PERCENT
+        })
+        .collect();
+    if usage.is_empty() {
+        InventoryDiff::Same
+    } else {
+        InventoryDiff::DifferentUsage(usage)
+    }
+}
+
+/// A CH3 trigger that plays different waveforms in the two builds (triggers are paired in order).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TriggerDiff<'a> {
+    /// 0-based.
+    pub index: usize,
+    pub before: &'a TriggerSnapshot,
+    pub after: &'a TriggerSnapshot,
+}
+
+impl Display for TriggerDiff<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "CH3 trigger #{} (tick {} before, {} after) plays a different waveform:",
+            self.index + 1,
+            self.before.when.tick,
+            self.after.when.tick,
+        )?;
+        for (i, (before, after)) in self.before.wave.iter().zip(&self.after.wave).enumerate() {
+            if before != after {
+                write!(
+                    f,
+                    " {:02x}->{:02x} at ${:04x}",
+                    before,
+                    after,
+                    WAVE_RAM.start() + i as u16
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compares the waveforms of the triggers of both builds, in order; triggers past the end of the
+/// shorter log are not compared.
+pub fn compare_triggers<'a>(
+    before: &'a [TriggerSnapshot],
+    after: &'a [TriggerSnapshot],
+) -> Vec<TriggerDiff<'a>> {
+    before
+        .iter()
+        .zip(after)
+        .enumerate()
+        .filter(|(_, (before, after))| before.wave != after.wave)
+        .map(|(index, (before, after))| TriggerDiff {
+            index,
+            before,
+            after,
+        })
+        .collect()
+}
+
+/// A write that matters to how wave RAM is refilled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ch3Event {
+    DacOff,
+    PowerOff,
+    WaveWrite,
+    DacOn,
+    Trigger,
+}
+
+impl Display for Ch3Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::DacOff => "DAC off",
+            Self::PowerOff => "APU off",
+            Self::WaveWrite => "wave write",
+            Self::DacOn => "DAC on",
+            Self::Trigger => "trigger",
+        })
+    }
+}
+
+/// How a tick writes wave RAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refill {
+    None,
+    /// Only while CH3 is stopped.
+    Safe,
+    /// At least one of the writes happens while CH3 is playing, which corrupts the wave on DMG
+    /// and pops on most models.
+    WhilePlaying,
+}
+
+/// The CH3-relevant writes of a tick that refills wave RAM, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefillSequence {
+    pub kind: Refill,
+    /// Consecutive writes of the same kind are counted together.
+    events: Vec<(Ch3Event, usize)>,
+}
+
+/// For ticks that don't write wave RAM.
+static NO_REFILL: RefillSequence = RefillSequence {
+    kind: Refill::None,
+    events: Vec::new(),
+};
+
+impl Display for RefillSequence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self.kind {
+            Refill::None => "doesn't refill wave RAM",
+            Refill::Safe => "performs a safe refill",
+            Refill::WhilePlaying => "writes wave RAM while CH3 is playing",
+        })?;
+        if self.events.is_empty() {
+            return Ok(());
+        }
+        for (i, (event, count)) in self.events.iter().enumerate() {
+            f.write_str(if i == 0 { " (" } else { " → " })?;
+            match count {
+                1 => write!(f, "{}", event)?,
+                _ => write!(f, "{} {}s", count, event)?,
+            }
+        }
+        f.write_str(")")
+    }
+}
+
+/// Classifies each tick that writes wave RAM, by following whether CH3 is playing: triggering it
+/// with its DAC on starts it, and turning the DAC (or the APU) off stops it. Length expiry is not
+/// accounted for.
+pub fn refill_sequences(io_log: &[IoAccess]) -> BTreeMap<u64, RefillSequence> {
+    let mut sequences = BTreeMap::new();
+    let (mut dac_on, mut playing) = (false, false);
+    let mut current: Option<(u64, RefillSequence)> = None;
+
+    for access in io_log {
+        let event = match HwReg::try_from(access.addr) {
+            Ok(HwReg::Nr30) if access.data & 0x80 != 0 => Ch3Event::DacOn,
+            Ok(HwReg::Nr30) => Ch3Event::DacOff,
+            Ok(HwReg::Nr52) if access.data & 0x80 == 0 => Ch3Event::PowerOff,
+            Ok(HwReg::Nr34) if access.data & 0x80 != 0 => Ch3Event::Trigger,
+            _ if WAVE_RAM.contains(&access.addr) => Ch3Event::WaveWrite,
+            _ => continue,
+        };
+
+        let tick = access.when.tick;
+        if current
+            .as_ref()
+            .is_some_and(|(current, _)| *current != tick)
+        {
+            let (tick, sequence) = current.take().unwrap();
+            if sequence.kind != Refill::None {
+                sequences.insert(tick, sequence);
+            }
+        }
+        let (_, sequence) = current.get_or_insert_with(|| (tick, NO_REFILL.clone()));
+        match sequence.events.last_mut() {
+            Some((last, count)) if *last == event => *count += 1,
+            _ => sequence.events.push((event, 1)),
+        }
+
+        match event {
+            Ch3Event::DacOff | Ch3Event::PowerOff => (dac_on, playing) = (false, false),
+            Ch3Event::DacOn => dac_on = true,
+            Ch3Event::Trigger => playing = dac_on,
+            Ch3Event::WaveWrite if playing => sequence.kind = Refill::WhilePlaying,
+            Ch3Event::WaveWrite if sequence.kind == Refill::None => sequence.kind = Refill::Safe,
+            Ch3Event::WaveWrite => (),
+        }
+    }
+    if let Some((tick, sequence)) = current {
+        if sequence.kind != Refill::None {
+            sequences.insert(tick, sequence);
+        }
+    }
+    sequences
+}
+
+/// A tick on which the two builds refill wave RAM differently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefillDiff<'a> {
+    pub tick: u64,
+    pub before: &'a RefillSequence,
+    pub after: &'a RefillSequence,
+}
+
+impl Display for RefillDiff<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "tick {}: before {}, after {}",
+            self.tick, self.before, self.after
+        )
+    }
+}
+
+/// Compares how both builds refill wave RAM, tick by tick; only the classification matters, not
+/// the exact sequence.
+pub fn compare_refills<'a>(
+    before: &'a BTreeMap<u64, RefillSequence>,
+    after: &'a BTreeMap<u64, RefillSequence>,
+) -> Vec<RefillDiff<'a>> {
+    let ticks: std::collections::BTreeSet<_> = before.keys().chain(after.keys()).collect();
+    ticks
+        .into_iter()
+        .map(|&tick| RefillDiff {
+            tick,
+            before: before.get(&tick).unwrap_or(&NO_REFILL),
+            after: after.get(&tick).unwrap_or(&NO_REFILL),
+        })
+        .filter(|diff| diff.before.kind != diff.after.kind)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Address;
+
+    /// Each tick's writes, in order; cycles are made up.
+    fn log(ticks: &[&[(u16, u8)]]) -> Vec<IoAccess> {
+        ticks
+            .iter()
+            .zip(1..)
+            .flat_map(|(writes, tick)| {
+                writes
+                    .iter()
+                    .zip(0..)
+                    .map(move |(&(addr, data), i)| IoAccess {
+                        when: Timestamp {
+                            tick,
+                            cycle: i * 10,
+                        },
+                        pc: Address(1, 0x4000),
+                        addr,
+                        data,
+                    })
+            })
+            .collect()
+    }
+
+    const DAC_ON: (u16, u8) = (0xFF1A, 0x80);
+    const DAC_OFF: (u16, u8) = (0xFF1A, 0x00);
+    const TRIGGER: (u16, u8) = (0xFF1E, 0x87);
+    const WAVE: (u16, u8) = (0xFF30, 0x12);
+
+    fn kinds(ticks: &[&[(u16, u8)]]) -> Vec<(u64, Refill)> {
+        refill_sequences(&log(ticks))
+            .into_iter()
+            .map(|(tick, sequence)| (tick, sequence.kind))
+            .collect()
+    }
+
+    #[test]
+    fn triggers_play_the_wave_ram_written_so_far() {
+        let before = trigger_snapshots(&log(&[
+            &[TRIGGER],
+            &[WAVE, (0xFF3F, 0xFF), TRIGGER],
+            &[(0xFF1E, 0x07), TRIGGER],
+        ]));
+        let mut wave = Waveform::default();
+        assert_eq!(before.len(), 3);
+        assert_eq!(before[0].wave, wave);
+        (wave[0], wave[15]) = (0x12, 0xFF);
+        assert_eq!(before[1].wave, wave);
+        assert_eq!(before[2].wave, wave);
+        assert_eq!(before[2].when.tick, 3);
+
+        let after = trigger_snapshots(&log(&[
+            &[TRIGGER],
+            &[WAVE, (0xFF3F, 0xEE), TRIGGER],
+            &[TRIGGER, TRIGGER],
+        ]));
+        let diffs = compare_triggers(&before, &after);
+        assert_eq!(diffs.len(), 2);
+        assert_eq!(
+            diffs[0].to_string(),
+            "CH3 trigger #2 (tick 2 before, 2 after) plays a different waveform: ff->ee at $ff3f"
+        );
+        // The third trigger still plays that waveform, but the extra fourth one isn't compared.
+        assert_eq!(diffs[1].index, 2);
+
+        // Playing the same waveforms a different number of times isn't the same as playing others.
+        let (before, after) = (Inventory::new(&before), Inventory::new(&after));
+        assert!(matches!(
+            compare(&before, &after),
+            InventoryDiff::DifferentWaves { before_only, after_only }
+                if before_only.len() == 1 && after_only.len() == 1
+        ));
+        let more = Inventory::new(&trigger_snapshots(&log(&[&[TRIGGER, TRIGGER]])));
+        let fewer = Inventory::new(&trigger_snapshots(&log(&[&[TRIGGER]])));
+        assert_eq!(
+            compare(&fewer, &more),
+            InventoryDiff::DifferentUsage(vec![(&Waveform::default(), 1, 2)])
+        );
+        assert_eq!(compare(&more, &more), InventoryDiff::Same);
+    }
+
+    #[test]
+    fn ticks_without_wave_writes_are_not_refills() {
+        assert_eq!(
+            kinds(&[&[DAC_ON, TRIGGER], &[(0xFF1C, 0x20)], &[DAC_OFF]]),
+            []
+        );
+    }
+
+    #[test]
+    fn writes_while_stopped_are_safe() {
+        let sequences = refill_sequences(&log(&[
+            &[DAC_ON, TRIGGER],
+            &[DAC_OFF, WAVE, (0xFF31, 0x34), DAC_ON, TRIGGER],
+        ]));
+        assert_eq!(
+            sequences[&2].to_string(),
+            "performs a safe refill (DAC off → 2 wave writes → DAC on → trigger)"
+        );
+        assert_eq!(sequences.len(), 1);
+
+        // So are writes before CH3 is ever started, and after turning the APU off stops it;
+        // triggering CH3 doesn't start it while its DAC is off.
+        assert_eq!(
+            kinds(&[
+                &[WAVE],
+                &[DAC_ON, TRIGGER, (0xFF26, 0x00), WAVE],
+                &[TRIGGER, WAVE]
+            ]),
+            [(1, Refill::Safe), (2, Refill::Safe), (3, Refill::Safe)]
+        );
+    }
+
+    #[test]
+    fn writes_while_playing_are_flagged() {
+        let sequences = refill_sequences(&log(&[&[DAC_ON, TRIGGER], &[WAVE, WAVE, DAC_OFF]]));
+        assert_eq!(
+            sequences[&2].to_string(),
+            "writes wave RAM while CH3 is playing (2 wave writes → DAC off)"
+        );
+        // One bad write is enough, even after safe ones.
+        assert_eq!(
+            kinds(&[&[DAC_ON, WAVE, TRIGGER, WAVE]]),
+            [(1, Refill::WhilePlaying)]
+        );
+    }
+
+    #[test]
+    fn only_classification_changes_are_reported() {
+        let safe = refill_sequences(&log(&[
+            &[DAC_OFF, WAVE, DAC_ON, TRIGGER],
+            &[],
+            &[DAC_OFF, WAVE],
+        ]));
+        // Same classifications, through another sequence.
+        let also_safe = refill_sequences(&log(&[
+            &[WAVE, WAVE, DAC_ON, TRIGGER],
+            &[],
+            &[DAC_OFF, WAVE],
+        ]));
+        assert_eq!(compare_refills(&safe, &also_safe), []);
+
+        let unsafe_ = refill_sequences(&log(&[&[DAC_ON, TRIGGER, WAVE], &[WAVE], &[]]));
+        let diffs: Vec<_> = compare_refills(&safe, &unsafe_)
+            .iter()
+            .map(|diff| (diff.tick, diff.before.kind, diff.after.kind))
+            .collect();
+        assert_eq!(
+            diffs,
+            [
+                (1, Refill::Safe, Refill::WhilePlaying),
+                (2, Refill::None, Refill::WhilePlaying),
+                (3, Refill::Safe, Refill::None)
+            ]
+        );
+    }
+}
@@ -0,0 +1,55 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with mapping each hardware register to the code that writes it
+//! (`--who-writes`), to find one's way around an unfamiliar driver.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    diff::RegDispl,
+    report::Reporter,
+    run::IoAccess,
+    sym::{self, Symbols},
+    Address,
+};
+
+/// For each register, how many times each PC wrote it, and on which tick it first did.
+pub type Writers = BTreeMap<u16, HashMap<Address, (usize, u64)>>;
+
+pub fn aggregate(io_log: &[IoAccess]) -> Writers {
+    let mut writers = Writers::new();
+    for access in io_log {
+        // So that the same code isn't listed once per bank it was seen "in".
+        let (bank, pc) = access.pc.canonical();
+        writers
+            .entry(access.addr)
+            .or_default()
+            .entry(Address(bank, pc))
+            .and_modify(|(count, _)| *count += 1)
+            .or_insert((1, access.when.tick));
+    }
+    writers
+}
+
+/// Registers are listed by address, and the code writing each of them in the order it started to.
+pub fn report(reporter: &mut dyn Reporter, writers: &Writers, symbols: Option<&Symbols>) {
+    for (reg, pcs) in writers {
+        reporter.line(&format_args!("{}:", RegDispl(*reg)));
+
+        let mut pcs: Vec<_> = pcs.iter().collect();
+        pcs.sort_by_key(|(pc, (_, first_tick))| (*first_tick, pc.canonical()));
+        for (pc, (count, first_tick)) in pcs {
+            reporter.line(&format_args!(
+                "    {}: {} write{}, first on tick {}",
+                sym::Pc(pc, symbols),
+                count,
+                if *count == 1 { "" } else { "s" },
+                first_tick
+            ));
+        }
+    }
+}
@@ -0,0 +1,182 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! This module deals with register writes that must happen close together.
+//!
+//! If the player's interrupt fires between e.g. the NR13 and the NR14 writes, the channel may
+//! briefly play with an inconsistent frequency; the longer the gap, the likelier that is.
+
+use std::{collections::HashMap, fmt::Display};
+
+use gb_cpu_sim::reg::HwReg;
+
+use crate::run::IoAccess;
+
+/// Two registers that should be written back-to-back, in this order.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PairKind {
+    first: u16,
+    second: u16,
+    name: &'static str,
+}
+
+static PAIRS: [PairKind; 5] = [
+    PairKind {
+        first: HwReg::Nr13 as u16,
+        second: HwReg::Nr14 as u16,
+        name: "NR13→NR14",
+    },
+    PairKind {
+        first: HwReg::Nr23 as u16,
+        second: HwReg::Nr24 as u16,
+        name: "NR23→NR24",
+    },
+    PairKind {
+        first: HwReg::Nr33 as u16,
+        second: HwReg::Nr34 as u16,
+        name: "NR33→NR34",
+    },
+    // Turning the DAC on, uploading the wave, and triggering the channel.
+    PairKind {
+        first: HwReg::Nr30 as u16,
+        second: HwReg::Nr34 as u16,
+        name: "NR30→NR34",
+    },
+    PairKind {
+        first: HwReg::Nr43 as u16,
+        second: HwReg::Nr44 as u16,
+        name: "NR43→NR44",
+    },
+];
+
+/// One occurrence of a pair being written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairSpan {
+    pub pair: &'static PairKind,
+    pub tick: u64,
+    pub cycles: u32,
+}
+
+impl Display for PairSpan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} spanning {} cycles at tick {}",
+            self.pair.name, self.cycles, self.tick
+        )
+    }
+}
+
+/// Measures every pair written in the log, in chronological order (of the second write).
+pub fn measure(io_log: &[IoAccess]) -> Vec<PairSpan> {
+    let mut spans = Vec::new();
+    let mut pending = [None; PAIRS.len()];
+    let mut tick = None;
+
+    for access in io_log {
+        // A pair cannot straddle ticks.
+        if tick != Some(access.when.tick) {
+            tick = Some(access.when.tick);
+            pending = [None; PAIRS.len()];
+        }
+
+        for (pair, start) in PAIRS.iter().zip(pending.iter_mut()) {
+            if access.addr == pair.second {
+                if let Some(start) = start.take() {
+                    spans.push(PairSpan {
+                        pair,
+                        tick: access.when.tick,
+                        cycles: access.when.cycle - start,
+                    });
+                }
+            }
+            // A repeated first write restarts the pair.
+            if access.addr == pair.first {
+                *start = Some(access.when.cycle);
+            }
+        }
+    }
+
+    spans
+}
+
+/// The spans that exceed `threshold` cycles.
+#[derive(Debug, Clone)]
+pub struct VulnerablePairs<'a> {
+    pub count: usize,
+    pub worst: Option<&'a PairSpan>,
+}
+
+impl<'a> VulnerablePairs<'a> {
+    pub fn new(spans: &'a [PairSpan], threshold: u16) -> Self {
+        let vulnerable: Vec<_> = spans
+            .iter()
+            .filter(|span| span.cycles > threshold.into())
+            .collect();
+        Self {
+            count: vulnerable.len(),
+            worst: vulnerable.into_iter().max_by_key(|span| span.cycles),
+        }
+    }
+}
+
+impl Display for VulnerablePairs<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.worst {
+            None => write!(f, "none"),
+            Some(worst) => write!(f, "{}, worst is {}", self.count, worst),
+        }
+    }
+}
+
+/// A pair whose gap grew in "after" compared to "before".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanGrowth {
+    pub pair: &'static PairKind,
+    pub tick: u64,
+    pub before: u32,
+    pub after: u32,
+}
+
+impl Display for SpanGrowth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} gap grew from {} to {} cycles at tick {} — more exposure to player interrupt timing",
+            self.pair.name, self.before, self.after, self.tick
+        )
+    }
+}
+
+/// Pairs up the spans of both logs (by pair kind, tick, and occurrence within the tick), and
+/// returns those that grew by more than `threshold` cycles.
+pub fn compare(before: &[PairSpan], after: &[PairSpan], threshold: u16) -> Vec<SpanGrowth> {
+    let key = |span: &PairSpan, occurrences: &mut HashMap<_, usize>| {
+        let occurrence = occurrences.entry((span.pair.name, span.tick)).or_default();
+        *occurrence += 1;
+        (span.pair.name, span.tick, *occurrence)
+    };
+
+    let mut occurrences = HashMap::new();
+    let before: HashMap<_, _> = before
+        .iter()
+        .map(|span| (key(span, &mut occurrences), span.cycles))
+        .collect();
+
+    let mut occurrences = HashMap::new();
+    after
+        .iter()
+        .filter_map(|span| {
+            let before = *before.get(&key(span, &mut occurrences))?;
+            (span.cycles.saturating_sub(before) > threshold.into()).then_some(SpanGrowth {
+                pair: span.pair,
+                tick: span.tick,
+                before,
+                after: span.cycles,
+            })
+        })
+        .collect()
+}
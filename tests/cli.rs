@@ -0,0 +1,116 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Runs the binary against the GBS files in `tests/fixtures` (see `src/tests.rs`), and checks its
+//! exit code and output against the files in `tests/golden`, so that formatting changes don't go
+//! unnoticed by whoever parses it.
+//!
+//! Setting `GBSDIFF_BLESS` rewrites the golden files instead of checking them.
+
+use std::{fs, path::Path, process::Command};
+
+/// The golden file's name, the arguments, and the expected exit code.
+const CASES: [(&str, &[&str], i32); 5] = [
+    (
+        "identical",
+        &["tests/fixtures/base.gbs", "tests/fixtures/base.gbs"],
+        0,
+    ),
+    (
+        "missing_write",
+        &[
+            "tests/fixtures/base.gbs",
+            "tests/fixtures/missing_write.gbs",
+        ],
+        1,
+    ),
+    (
+        "moved",
+        &[
+            "-l",
+            "note",
+            "tests/fixtures/base.gbs",
+            "tests/fixtures/moved.gbs",
+        ],
+        1,
+    ),
+    (
+        "simulation_failure",
+        &["tests/fixtures/base.gbs", "tests/fixtures/stuck.gbs"],
+        1,
+    ),
+    (
+        "song_counts",
+        &["tests/fixtures/base.gbs", "tests/fixtures/two_songs.gbs"],
+        0,
+    ),
+];
+
+#[test]
+fn output_matches_golden_files() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let bless = std::env::var_os("GBSDIFF_BLESS").is_some();
+    for (name, args, exit_code) in CASES {
+        // Statistics include timings, which change from one run to the next.
+        let output = Command::new(env!("CARGO_BIN_EXE_gbsdiff"))
+            .current_dir(root)
+            .args(["-q", "--color", "never"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert_eq!(
+            output.status.code(),
+            Some(exit_code),
+            "{}: {}",
+            name,
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let path = root.join("tests/golden").join(format!("{}.txt", name));
+        if bless {
+            fs::write(&path, &output.stdout).unwrap();
+        } else {
+            let golden = fs::read_to_string(&path)
+                .unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+            assert_eq!(
+                String::from_utf8_lossy(&output.stdout),
+                golden,
+                "{} differs, run the tests with GBSDIFF_BLESS=1 if that's intended",
+                path.display()
+            );
+        }
+    }
+}
+
+#[test]
+fn missing_files_are_errors() {
+    let output = Command::new(env!("CARGO_BIN_EXE_gbsdiff"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(["tests/fixtures/base.gbs", "tests/fixtures/nonexistent.gbs"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("nonexistent.gbs"));
+}
+
+#[test]
+fn malformed_headers_are_errors() {
+    let output = Command::new(env!("CARGO_BIN_EXE_gbsdiff"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args([
+            "tests/fixtures/base.gbs",
+            "tests/fixtures/play_out_of_file.gbs",
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("play_out_of_file.gbs: play address $7000 lies past the end of the file"),
+        "{}",
+        stderr
+    );
+}